@@ -0,0 +1,52 @@
+# SRQemus Meldungen auf Deutsch. Fehlende Schlüssel fallen auf en.ftl
+# zurück.
+
+## Interaktives Menü
+
+menu-title = QEMU-VM-Verwaltung
+menu-create = 1. VM anlegen
+menu-start = 2. VM starten
+menu-stop = 3. VM stoppen
+menu-list = 4. VMs auflisten
+menu-delete = 5. VM löschen
+menu-restore = 6. Gelöschte VM wiederherstellen
+menu-disk = 7. Festplattenwerkzeuge
+menu-network = 8. Netzwerk
+menu-settings = 9. VM-Einstellungen
+menu-adopt = 10. Laufenden QEMU-Prozess übernehmen
+menu-import = 11. VM importieren (libvirt, VirtualBox)
+menu-images = 12. Abbilder und Rezepte
+menu-status = 13. VM-Status
+menu-clone = 14. VM klonen
+menu-exit = 15. Beenden
+invalid-choice = Ungültige Auswahl.
+prompt-vm-name = Name der VM ({ $action }):
+prompt-start-mode = Mit Fenster oder ohne starten? (gui/headless):
+
+## Starten und Stoppen
+
+vm-not-found = VM '{ $name }' nicht gefunden
+mode-gui = Fenster-
+mode-headless = Headless-
+vm-starting = Starte VM '{ $name }' im { $mode }Modus...
+vm-resuming = Setze angehaltene VM '{ $name }' im { $mode }Modus fort...
+vm-stopping = Stoppe VM: { $name }
+vm-shut-down = VM '{ $name }' heruntergefahren.
+vm-not-running = VM '{ $name }' läuft nicht.
+vm-killed = VM '{ $name }' beendet.
+
+## Auflistungen
+
+list-header = Definierte VMs:
+list-state = Zustand: { $state }
+state-running = läuft
+state-paused = pausiert
+state-stopped = aus
+state-summary = { $state }, PID { $pid }, seit { $uptime }, { $rss } RSS
+status-name = NAME
+status-state = ZUSTAND
+status-pid = PID
+status-uptime = LAUFZEIT
+status-rss = RSS
+status-cpu = CPU
+console-of = Konsole von '{ $name }': { $url }
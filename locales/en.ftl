@@ -0,0 +1,53 @@
+# SRQemu's messages in English, which is also the fallback for keys other
+# languages lack. The syntax is a subset of Project Fluent: `key = text`,
+# indented lines continuing the text, and `{ $name }` placeholders.
+
+## Interactive menu
+
+menu-title = QEMU VM Manager
+menu-create = 1. Create VM
+menu-start = 2. Start VM
+menu-stop = 3. Stop VM
+menu-list = 4. List VMs
+menu-delete = 5. Delete VM
+menu-restore = 6. Restore deleted VM
+menu-disk = 7. Disk tools
+menu-network = 8. Network
+menu-settings = 9. VM settings
+menu-adopt = 10. Adopt running QEMU process
+menu-import = 11. Import VM (libvirt, VirtualBox)
+menu-images = 12. Images and recipes
+menu-status = 13. VM status
+menu-clone = 14. Clone VM
+menu-exit = 15. Exit
+invalid-choice = Invalid choice.
+prompt-vm-name = Enter VM name to { $action }:
+prompt-start-mode = Start in GUI or headless mode? (gui/headless):
+
+## Starting and stopping
+
+vm-not-found = VM '{ $name }' not found
+mode-gui = GUI
+mode-headless = headless
+vm-starting = Starting VM '{ $name }' in { $mode } mode...
+vm-resuming = Resuming suspended VM '{ $name }' in { $mode } mode...
+vm-stopping = Stopping VM: { $name }
+vm-shut-down = VM '{ $name }' shut down.
+vm-not-running = VM '{ $name }' is not running.
+vm-killed = VM '{ $name }' killed.
+
+## Listings
+
+list-header = Defined VMs:
+list-state = State: { $state }
+state-running = running
+state-paused = paused
+state-stopped = stopped
+state-summary = { $state }, pid { $pid }, up { $uptime }, { $rss } RSS
+status-name = NAME
+status-state = STATE
+status-pid = PID
+status-uptime = UPTIME
+status-rss = RSS
+status-cpu = CPU
+console-of = Console of '{ $name }': { $url }
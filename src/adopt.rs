@@ -0,0 +1,220 @@
+use crate::config::{VMConfig, VMInfo};
+use crate::network::{self, NetBackend, NicSpec};
+use crate::run;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command as ShellCommand;
+
+/// Records the pid of a QEMU that SRQemu did not launch, so it is still
+/// found after adoption even though its command line does not follow ours.
+pub fn pidfile(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("adopted.pid")
+}
+
+/// QEMU flags that take no value.
+const SWITCHES: &[&str] = &[
+    "enable-kvm", "daemonize", "no-reboot", "snapshot", "S", "s", "nographic", "usb", "nodefaults", "no-user-config", "no-shutdown",
+    "no-hpet", "no-acpi", "full-screen",
+];
+
+pub fn cmdline(pid: u32) -> Option<Vec<String>> {
+    let raw = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    Some(split_cmdline(&raw))
+}
+
+/// Arguments from the NUL-separated form in `/proc/<pid>/cmdline`.
+fn split_cmdline(raw: &[u8]) -> Vec<String> {
+    raw.split(|b| *b == 0).filter(|a| !a.is_empty()).map(|a| String::from_utf8_lossy(a).to_string()).collect()
+}
+
+pub fn is_qemu(args: &[String]) -> bool {
+    args.first().is_some_and(|exe| exe.rsplit('/').next().unwrap_or(exe).starts_with("qemu-system-"))
+}
+
+/// The adopted pid of a VM, if that process is still the QEMU we adopted.
+pub fn adopted_pid(vm_name: &str) -> Option<u32> {
+    let pid: u32 = fs::read_to_string(pidfile(vm_name)).ok()?.trim().parse().ok()?;
+    if cmdline(pid).is_some_and(|args| is_qemu(&args)) {
+        Some(pid)
+    } else {
+        let _ = fs::remove_file(pidfile(vm_name));
+        None
+    }
+}
+
+/// Resolves a pid, or a string matched against running qemu-system command
+/// lines, to exactly one process.
+pub fn find_process(spec: &str) -> Result<u32, String> {
+    if let Ok(pid) = spec.parse::<u32>() {
+        return match cmdline(pid) {
+            Some(args) if is_qemu(&args) => Ok(pid),
+            Some(_) => Err(format!("process {} is not qemu-system", pid)),
+            None => Err(format!("no process {}", pid)),
+        };
+    }
+    let pids = run(ShellCommand::new("pgrep").args(["-f", "qemu-system-"]))?;
+    let matches: Vec<u32> = pids
+        .lines()
+        .filter_map(|l| l.trim().parse().ok())
+        .filter(|pid| cmdline(*pid).is_some_and(|args| is_qemu(&args) && args.iter().any(|a| a.contains(spec))))
+        .collect();
+    match matches.as_slice() {
+        [pid] => Ok(*pid),
+        [] => Err(format!("no qemu-system process matches '{}'", spec)),
+        many => Err(format!("'{}' matches several processes: {:?}; use a pid", spec, many)),
+    }
+}
+
+/// `key=value` from a comma-separated option string; a bare first element
+/// counts as `default_key`.
+fn opt<'a>(value: &'a str, key: &str, default_key: &str) -> Option<&'a str> {
+    value.split(',').enumerate().find_map(|(i, part)| match part.split_once('=') {
+        Some((k, v)) if k == key => Some(v),
+        None if i == 0 && key == default_key => Some(part),
+        _ => None,
+    })
+}
+
+/// The QMP socket a QEMU command line serves: `-qmp unix:<path>`, or a
+/// socket chardev behind `-mon mode=control`. Sockets handed over as file
+/// descriptors, as libvirt does, have no path to connect to.
+pub fn qmp_socket(args: &[String]) -> Option<PathBuf> {
+    let values = |flag: &'static str| args.windows(2).filter(move |pair| pair[0].trim_start_matches('-') == flag).map(|pair| pair[1].as_str());
+    if let Some(address) = values("qmp").chain(values("qmp-pretty")).find_map(|value| value.strip_prefix("unix:")) {
+        return address.split(',').next().filter(|path| !path.is_empty()).map(PathBuf::from);
+    }
+    let monitors: Vec<&str> = values("mon").filter(|value| opt(value, "mode", "") == Some("control")).filter_map(|value| opt(value, "chardev", "chardev")).collect();
+    values("chardev")
+        .filter(|value| value.starts_with("socket,"))
+        .find(|value| opt(value, "id", "").is_some_and(|id| monitors.contains(&id)))
+        .and_then(|value| opt(value, "path", ""))
+        .map(PathBuf::from)
+}
+
+/// Rebuilds a VM definition from a QEMU command line. Options SRQemu does
+/// not model are listed in the returned notes rather than silently dropped.
+/// `name` overrides the process's own `-name`.
+pub fn inspect(pid: u32, name: Option<&str>, config: &VMConfig) -> Result<(VMInfo, Vec<String>), String> {
+    let args = cmdline(pid).ok_or_else(|| format!("cannot read command line of {}", pid))?;
+    from_args(&args, name, config)
+}
+
+fn from_args(args: &[String], name: Option<&str>, config: &VMConfig) -> Result<(VMInfo, Vec<String>), String> {
+    let mut vm = VMInfo { cpu: "host".to_string(), memory: "128M".to_string(), threads: "1".to_string(), ..Default::default() };
+    let mut notes = Vec::new();
+    let mut netdevs: HashMap<String, NetBackend> = HashMap::new();
+    let mut macs: HashMap<String, String> = HashMap::new();
+
+    let mut iter = args.iter().skip(1).peekable();
+    while let Some(arg) = iter.next() {
+        let flag = arg.trim_start_matches('-');
+        if !arg.starts_with('-') {
+            // A bare argument is the legacy form of -hda.
+            if vm.disk.is_empty() {
+                vm.disk = arg.clone();
+            }
+            continue;
+        }
+        if SWITCHES.contains(&flag) {
+            continue;
+        }
+        // Another flag right after means this one is a switch too.
+        let Some(value) = iter.next_if(|next| !next.starts_with('-')) else {
+            notes.push(format!("-{} not adopted", flag));
+            continue;
+        };
+        match flag {
+            "name" => vm.name = opt(value, "guest", "guest").unwrap_or(value).to_string(),
+            "m" => vm.memory = opt(value, "size", "size").unwrap_or(value).to_string(),
+            "cpu" => vm.cpu = value.split(',').next().unwrap_or(value).to_string(),
+            "smp" => vm.threads = opt(value, "cpus", "cpus").unwrap_or(value).to_string(),
+            "hda" if vm.disk.is_empty() => vm.disk = value.clone(),
+            "drive" => match (opt(value, "file", "file"), opt(value, "media", "")) {
+                (Some(file), Some("cdrom")) => vm.iso = file.to_string(),
+                (Some(file), _) if vm.disk.is_empty() => vm.disk = file.to_string(),
+                _ => notes.push(format!("extra drive not adopted: {}", value)),
+            },
+            "cdrom" => vm.iso = value.clone(),
+            "netdev" | "nic" => {
+                let kind = value.split(',').next().unwrap_or_default();
+                let backend = match kind {
+                    "user" => NetBackend::User { ipv6_net: None, hostfwd: Vec::new() },
+                    "bridge" => NetBackend::Bridge { bridge: opt(value, "br", "").unwrap_or("br0").to_string() },
+                    other => {
+                        notes.push(format!("{} network '{}' not adopted", other, value));
+                        continue;
+                    }
+                };
+                let id = opt(value, "id", "").map(str::to_string).unwrap_or_else(|| format!("nic{}", netdevs.len()));
+                if let Some(mac) = opt(value, "mac", "") {
+                    macs.insert(id.clone(), mac.to_string());
+                }
+                netdevs.insert(id, backend);
+            }
+            "device" => {
+                if let (Some(id), Some(mac)) = (opt(value, "netdev", ""), opt(value, "mac", "")) {
+                    macs.insert(id.to_string(), mac.to_string());
+                }
+            }
+            "qmp" | "monitor" | "chardev" | "display" | "vga" | "vnc" | "boot" | "machine" | "M" | "accel" | "pidfile" => {}
+            other => notes.push(format!("-{} {} not adopted", other, value)),
+        }
+    }
+
+    if let Some(name) = name {
+        vm.name = name.to_string();
+    }
+    if vm.name.is_empty() {
+        return Err("process has no -name; give the VM a name when adopting".to_string());
+    }
+    if config.vms.contains_key(&vm.name) {
+        return Err(format!("a VM named '{}' already exists", vm.name));
+    }
+    let mut ids: Vec<&String> = netdevs.keys().collect();
+    ids.sort();
+    for (i, id) in ids.into_iter().enumerate() {
+        let mac = macs.get(id).cloned().unwrap_or_else(|| {
+            notes.push(format!("NIC {} had no fixed MAC; the guest will see a new one", i));
+            network::generate_mac(&vm.name, i)
+        });
+        vm.nics.push(NicSpec { backend: netdevs[id].clone(), mac, impairment: None, virtio: None });
+    }
+    Ok((vm, notes))
+}
+
+pub fn record_pid(vm_name: &str, pid: u32) -> Result<(), String> {
+    fs::create_dir_all(crate::vm_folder(vm_name)).map_err(|e| e.to_string())?;
+    fs::write(pidfile(vm_name), pid.to_string()).map_err(|e| format!("cannot record pid: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adopts_real_command_lines() {
+        let libvirt = b"/usr/bin/qemu-system-x86_64\0-name\0guest=win10,debug-threads=on\0-S\0-machine\0pc-q35-8.2,usb=off\0-accel\0kvm\0-cpu\0host,migratable=on\0-m\0size=8388608k\0-smp\x004,sockets=1,cores=4\0-no-user-config\0-nodefaults\0-no-shutdown\0-no-hpet\0-boot\0strict=on\0-drive\0file=/var/lib/libvirt/images/win10.qcow2,format=qcow2,if=none,id=drive0\0-netdev\0tap,fd=32,id=hostnet0\0-device\0virtio-net-pci,netdev=hostnet0,mac=52:54:00:12:34:56\0";
+        let (vm, notes) = from_args(&split_cmdline(libvirt), None, &VMConfig::default()).unwrap();
+        assert_eq!((vm.name.as_str(), vm.memory.as_str(), vm.threads.as_str(), vm.cpu.as_str()), ("win10", "8388608k", "4", "host"));
+        assert_eq!(vm.disk, "/var/lib/libvirt/images/win10.qcow2");
+        assert_eq!(notes, ["tap network 'tap,fd=32,id=hostnet0' not adopted"]);
+
+        let by_hand = b"qemu-system-x86_64\0-enable-kvm\0-m\x004G\0-s\0-full-screen\0-no-acpi\0-win2k-hack\0-hda\0disk.img\0-cdrom\0debian.iso\0-netdev\0user,id=n0\0-device\0e1000,netdev=n0,mac=52:54:00:ab:cd:ef\0-name\0deb\0";
+        let (vm, notes) = from_args(&split_cmdline(by_hand), None, &VMConfig::default()).unwrap();
+        assert_eq!((vm.name.as_str(), vm.memory.as_str(), vm.disk.as_str(), vm.iso.as_str()), ("deb", "4G", "disk.img", "debian.iso"));
+        assert_eq!(vm.nics.len(), 1);
+        assert_eq!(vm.nics[0].mac, "52:54:00:ab:cd:ef");
+        assert_eq!(notes, ["-win2k-hack not adopted"]);
+    }
+
+    #[test]
+    fn finds_the_qmp_socket_of_a_foreign_qemu() {
+        let qmp = split_cmdline(b"qemu-system-x86_64\0-m\x002G\0-qmp\0unix:/tmp/vm.qmp,server,nowait\0");
+        assert_eq!(qmp_socket(&qmp), Some(PathBuf::from("/tmp/vm.qmp")));
+        let mon = split_cmdline(b"qemu-system-x86_64\0-chardev\0socket,id=ser0,path=/tmp/serial\0-chardev\0socket,id=mon0,path=/run/vm/qmp,server=on,wait=off\0-mon\0chardev=mon0,mode=control\0");
+        assert_eq!(qmp_socket(&mon), Some(PathBuf::from("/run/vm/qmp")));
+        let libvirt = split_cmdline(b"/usr/bin/qemu-system-x86_64\0-chardev\0socket,id=charmonitor,fd=31,server=on,wait=off\0-mon\0chardev=charmonitor,id=monitor,mode=control\0");
+        assert_eq!(qmp_socket(&libvirt), None);
+    }
+}
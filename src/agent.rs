@@ -0,0 +1,240 @@
+use crate::qmp::{self, Qmp};
+use serde_json::json;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Name qemu-ga looks for on the virtio-serial bus.
+const AGENT_PORT: &str = "org.qemu.guest_agent.0";
+
+pub fn socket_path(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("qga.sock")
+}
+
+/// Arguments adding the virtio-serial channel qemu-ga talks over.
+pub fn launch_args(vm_name: &str) -> Vec<String> {
+    vec![
+        "-chardev".to_string(),
+        format!("socket,id=qga0,{}", crate::ipc::chardev(&socket_path(vm_name))),
+        "-device".to_string(),
+        "virtio-serial".to_string(),
+        "-device".to_string(),
+        format!("virtserialport,chardev=qga0,name={}", AGENT_PORT),
+    ]
+}
+
+/// Connects to the agent. It has no greeting, and a reply to an earlier
+/// client may still be queued, so the session starts with a `guest-sync`
+/// whose id identifies our reply.
+pub fn connect(vm_name: &str) -> Result<Qmp, String> {
+    let mut agent = qmp::open(&socket_path(vm_name))?;
+    let id = std::process::id() as u64 ^ now_ns() as u64;
+    for _ in 0..5 {
+        match agent.execute("guest-sync", Some(json!({ "id": id }))) {
+            Ok(reply) if reply.as_u64() == Some(id) => return Ok(agent),
+            Ok(_) => continue,
+            Err(e) => return Err(format!("guest agent not responding (is qemu-ga running in the guest?): {}", e)),
+        }
+    }
+    Err("guest agent replies are out of sync".to_string())
+}
+
+fn now_ns() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
+/// Sets the guest clock to the host's current time.
+pub fn sync_time(vm_name: &str) -> Result<(), String> {
+    let mut agent = connect(vm_name)?;
+    agent.execute("guest-set-time", Some(json!({ "time": now_ns() as u64 })))?;
+    Ok(())
+}
+
+fn base64_decode(data: &str) -> Vec<u8> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let sextets: Vec<u8> = data.bytes().filter_map(value).collect();
+    let mut out = Vec::with_capacity(sextets.len() * 3 / 4);
+    for chunk in sextets.chunks(4) {
+        let bits = chunk.iter().enumerate().fold(0u32, |acc, (i, s)| acc | (*s as u32) << (18 - 6 * i));
+        for i in 0..chunk.len().saturating_sub(1) {
+            out.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    out
+}
+
+/// Output of a command run inside the guest.
+pub struct ExecResult {
+    pub exit_code: i64,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Runs a program inside the guest and waits up to `timeout` for it.
+pub fn exec(vm_name: &str, path: &str, args: &[&str], timeout: Duration) -> Result<ExecResult, String> {
+    let mut agent = connect(vm_name)?;
+    let started = agent.execute("guest-exec", Some(json!({ "path": path, "arg": args, "capture-output": true })))?;
+    let pid = started["pid"].as_i64().ok_or("guest-exec returned no pid")?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        let status = agent.execute("guest-exec-status", Some(json!({ "pid": pid })))?;
+        if status["exited"].as_bool() == Some(true) {
+            let text = |key: &str| String::from_utf8_lossy(&base64_decode(status[key].as_str().unwrap_or(""))).to_string();
+            return Ok(ExecResult {
+                exit_code: status["exitcode"].as_i64().unwrap_or(-1),
+                stdout: text("out-data"),
+                stderr: text("err-data"),
+            });
+        }
+        if Instant::now() > deadline {
+            return Err(format!("{} did not finish within {}s", path, timeout.as_secs()));
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Runs a shell script in the guest and returns its exit code, handing its
+/// output (stdout and stderr together) to `output` while it runs. The agent
+/// only returns captured output once a command has ended, so the script
+/// writes to a file in the guest that is read as it grows.
+pub fn exec_streaming(vm_name: &str, script: &str, timeout: Duration, mut output: impl FnMut(&[u8])) -> Result<i64, String> {
+    let mut agent = connect(vm_name)?;
+    let log = format!("/tmp/srqemu-run-{}.log", now_ns());
+    let wrapped = format!("exec >{} 2>&1\n{}", log, script);
+    let started = agent.execute("guest-exec", Some(json!({ "path": "/bin/sh", "arg": ["-c", wrapped] })))?;
+    let pid = started["pid"].as_i64().ok_or("guest-exec returned no pid")?;
+    let deadline = Instant::now() + timeout;
+    let mut handle = None;
+    loop {
+        // Checked before reading, so the last read after the exit gets
+        // everything.
+        let status = agent.execute("guest-exec-status", Some(json!({ "pid": pid })))?;
+        if handle.is_none() {
+            handle = agent.execute("guest-file-open", Some(json!({ "path": log, "mode": "r" }))).ok().and_then(|h| h.as_i64());
+        }
+        if let Some(handle) = handle {
+            loop {
+                let chunk = agent.execute("guest-file-read", Some(json!({ "handle": handle, "count": 65536 })))?;
+                let data = base64_decode(chunk["buf-b64"].as_str().unwrap_or(""));
+                if data.is_empty() {
+                    break;
+                }
+                output(&data);
+            }
+        }
+        if status["exited"].as_bool() == Some(true) {
+            if let Some(handle) = handle {
+                let _ = agent.execute("guest-file-close", Some(json!({ "handle": handle })));
+            }
+            let _ = agent.execute("guest-exec", Some(json!({ "path": "/bin/rm", "arg": ["-f", log] })));
+            return Ok(status["exitcode"].as_i64().unwrap_or(-1));
+        }
+        if Instant::now() > deadline {
+            return Err(format!("the script did not finish within {}s", timeout.as_secs()));
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// An address the guest reports on one of its interfaces.
+pub struct GuestAddress {
+    pub interface: String,
+    pub address: String,
+    pub prefix: u64,
+}
+
+impl GuestAddress {
+    /// Loopback and link-local addresses, which are of no use from the host.
+    pub fn is_local(&self) -> bool {
+        match self.address.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_link_local(),
+            Ok(IpAddr::V6(ip)) => ip.is_loopback() || ip.is_unicast_link_local(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// The guest's addresses, in the order its interfaces list them.
+pub fn addresses(vm_name: &str) -> Result<Vec<GuestAddress>, String> {
+    let mut agent = connect(vm_name)?;
+    let interfaces = agent.execute("guest-network-get-interfaces", None)?;
+    let mut addresses = Vec::new();
+    for interface in interfaces.as_array().into_iter().flatten() {
+        let name = interface["name"].as_str().unwrap_or_default();
+        for ip in interface["ip-addresses"].as_array().into_iter().flatten() {
+            let Some(address) = ip["ip-address"].as_str() else { continue };
+            addresses.push(GuestAddress {
+                interface: name.to_string(),
+                address: address.to_string(),
+                prefix: ip["prefix"].as_u64().unwrap_or(0),
+            });
+        }
+    }
+    Ok(addresses)
+}
+
+/// What `fsfreeze` does with the guest's filesystems.
+#[derive(Clone, Copy)]
+pub enum FsFreeze {
+    Freeze,
+    Thaw,
+    Status,
+}
+
+/// Freezes or thaws the guest's filesystems, or reports whether they are
+/// frozen. Freeze and thaw return how many filesystems changed state.
+/// Frozen filesystems block every writer in the guest, so they should be
+/// thawed as soon as the host-side copy is taken.
+pub fn fsfreeze(vm_name: &str, action: FsFreeze) -> Result<String, String> {
+    let mut agent = connect(vm_name)?;
+    let command = match action {
+        FsFreeze::Freeze => "guest-fsfreeze-freeze",
+        FsFreeze::Thaw => "guest-fsfreeze-thaw",
+        FsFreeze::Status => "guest-fsfreeze-status",
+    };
+    let reply = agent.execute(command, None)?;
+    Ok(match action {
+        FsFreeze::Status => reply.as_str().unwrap_or("unknown").to_string(),
+        FsFreeze::Freeze => format!("{} filesystem(s) frozen", reply.as_u64().unwrap_or(0)),
+        FsFreeze::Thaw => format!("{} filesystem(s) thawed", reply.as_u64().unwrap_or(0)),
+    })
+}
+
+/// Space on one mounted guest filesystem.
+pub struct FsUsage {
+    pub mountpoint: String,
+    pub used: u64,
+    pub total: u64,
+}
+
+impl FsUsage {
+    pub fn used_pct(&self) -> u64 {
+        (self.used * 100).checked_div(self.total).unwrap_or(0)
+    }
+}
+
+/// Usage of the guest's filesystems. Agents older than QEMU 5.0 do not
+/// report sizes, and pseudo filesystems have none; both are skipped.
+pub fn fs_usage(vm_name: &str) -> Result<Vec<FsUsage>, String> {
+    let mut agent = connect(vm_name)?;
+    let info = agent.execute("guest-get-fsinfo", None)?;
+    Ok(info
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|fs| {
+            Some(FsUsage {
+                mountpoint: fs["mountpoint"].as_str()?.to_string(),
+                used: fs["used-bytes"].as_u64()?,
+                total: fs["total-bytes"].as_u64().filter(|t| *t > 0)?,
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,147 @@
+use crate::config::VMInfo;
+use crate::{provenance, run, storage};
+use tracing::debug;
+use std::fs;
+use std::process::Command as ShellCommand;
+
+const PROFILE_DIR: &str = "/etc/apparmor.d";
+
+pub fn profile_name(vm_name: &str) -> String {
+    format!("srqemu-{}", vm_name)
+}
+
+/// Whether the host kernel enforces AppArmor at all.
+pub fn available() -> bool {
+    fs::read_to_string("/sys/module/apparmor/parameters/enabled").is_ok_and(|s| s.trim() == "Y")
+}
+
+/// A profile in the spirit of libvirt's virt-aa-helper: QEMU may write its
+/// own disk and VM folder, read its ISO, CD, seed and backing images, and use
+/// the host devices virtualization needs; every other file is off limits.
+/// Captures written by QEMU's filter-dump must therefore go to the VM
+/// folder.
+fn profile(vm: &VMInfo) -> String {
+    let folder = crate::vm_folder(&vm.name);
+    let mut rules = vec![
+        format!("\"{}/\" r,", folder),
+        format!("\"{}/**\" rwk,", folder),
+        format!("\"{}\" rwk,", vm.disk_path()),
+    ];
+    // AppArmor matches the file a symlink resolves to.
+    if let Some(location) = storage::locate(&vm.disk_path()).filter(|l| l.symlinked) {
+        rules.push(format!("\"{}\" rwk,", location.real_path));
+    }
+    let mut readonly = provenance::tracked_images(vm);
+    readonly.extend(vm.seed.as_ref().map(|seed| crate::resolve_path(&vm.name, seed)));
+    readonly.extend(vm.cdrom_path());
+    for path in readonly {
+        rules.push(format!("\"{}\" rk,", path));
+    }
+    if !vm.usb_devices.is_empty() {
+        // usb-host finds its devices through sysfs and udev, then opens
+        // them in usbfs.
+        for rule in ["/dev/bus/usb/ r,", "/dev/bus/usb/** rw,", "/sys/bus/usb/devices/ r,", "/sys/devices/**/usb[0-9]*/** r,", "/run/udev/data/+usb:* r,", "/run/udev/data/c189:* r,"] {
+            rules.push(rule.to_string());
+        }
+    }
+    if !vm.pci_devices.is_empty() {
+        for rule in ["/dev/vfio/vfio rw,", "/dev/vfio/[0-9]* rw,", "/sys/kernel/iommu_groups/** r,", "/sys/bus/pci/devices/ r,"] {
+            rules.push(rule.to_string());
+        }
+        for device in &vm.pci_devices {
+            rules.push(format!("\"/sys/devices/pci*/**/{}/**\" rw,", device.address));
+            if let Some(rom) = &device.romfile {
+                rules.push(format!("\"{}\" r,", rom));
+            }
+        }
+    }
+    // virtiofs shares are served by virtiofsd through the VM folder.
+    for share in vm.shares.iter().filter(|s| s.protocol == crate::share::Protocol::NineP) {
+        rules.push(format!("\"{}/\" r,", share.path));
+        rules.push(format!("\"{}/**\" {},", share.path, if share.readonly { "r" } else { "rwl" }));
+    }
+    format!(
+        r#"# Generated by SRQemu for VM '{name}'; rewritten on every start.
+#include <tunables/global>
+
+profile {profile} flags=(attach_disconnected) {{
+  #include <abstractions/base>
+  #include <abstractions/consoles>
+  #include <abstractions/nameservice>
+  #include <abstractions/X>
+  #include <abstractions/wayland>
+  #include <abstractions/dri-enumerate>
+  #include <abstractions/mesa>
+  #include <abstractions/audio>
+
+  capability net_admin,
+  network inet stream,
+  network inet6 stream,
+  network inet dgram,
+  network inet6 dgram,
+  network unix,
+
+  /usr/bin/qemu-system-* mrix,
+  /usr/share/qemu/** r,
+  /usr/share/seabios/** r,
+  /usr/share/OVMF/** r,
+  /usr/share/edk2/** r,
+  /usr/share/edk2-ovmf/** r,
+  /usr/lib/ipxe/** r,
+  /usr/lib/qemu/** mr,
+  /usr/lib/qemu/qemu-bridge-helper Ux,
+  /usr/libexec/qemu-bridge-helper Ux,
+  /etc/qemu/** r,
+
+  /dev/kvm rw,
+  /dev/hugepages/** rw,
+  /dev/net/tun rw,
+  /dev/vhost-net rw,
+  /dev/vhost-vsock rw,
+  /dev/ptmx rw,
+  /dev/pts/* rw,
+  /sys/devices/system/** r,
+  /sys/module/vhost/** r,
+  @{{PROC}}/sys/vm/overcommit_memory r,
+  @{{PROC}}/@{{pid}}/task/*/comm rw,
+
+  {rules}
+}}
+"#,
+        name = vm.name,
+        profile = profile_name(&vm.name),
+        rules = rules.join("\n  ")
+    )
+}
+
+/// Writes the VM's profile and loads it into the kernel, replacing any
+/// older version. Needs root.
+pub fn load(vm: &VMInfo) -> Result<(), String> {
+    if !available() {
+        return Err("AppArmor is not enabled on this host".to_string());
+    }
+    let path = format!("{}/{}", PROFILE_DIR, profile_name(&vm.name));
+    fs::write(&path, profile(vm)).map_err(|e| format!("cannot write {} (run as root?): {}", path, e))?;
+    run(ShellCommand::new("apparmor_parser").args(["-r", &path])).map(|_| ())
+}
+
+/// Unloads and deletes the VM's profile.
+pub fn remove(vm_name: &str) -> Result<(), String> {
+    let path = format!("{}/{}", PROFILE_DIR, profile_name(vm_name));
+    if !std::path::Path::new(&path).exists() {
+        return Ok(());
+    }
+    // A profile that was never loaded cannot be unloaded; the file still goes.
+    if let Err(e) = run(ShellCommand::new("apparmor_parser").args(["-R", &path])) {
+        debug!("unloading {}: {}", path, e);
+    }
+    fs::remove_file(&path).map_err(|e| format!("cannot remove {}: {}", path, e))
+}
+
+/// Command prefix that starts QEMU already confined.
+pub fn exec_prefix(vm: &VMInfo) -> Vec<String> {
+    if !vm.hardening.as_ref().is_some_and(|h| h.apparmor) {
+        return Vec::new();
+    }
+    vec!["aa-exec".to_string(), "-p".to_string(), profile_name(&vm.name), "--".to_string()]
+}
@@ -0,0 +1,201 @@
+//! Guest architectures. Each has its own `qemu-system-*` binary, machine
+//! type and firmware; a guest of the host's architecture runs with KVM,
+//! any other one is emulated with TCG, which works everywhere but runs
+//! many times slower. So does a guest on a host without usable KVM, such
+//! as a container without `/dev/kvm`, unless the VM insists on KVM.
+
+use crate::config::{self, VMConfig, VMInfo};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+
+const KVM_DEVICE: &str = "/dev/kvm";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Arch {
+    #[serde(rename = "x86_64")]
+    X86_64,
+    /// 64-bit ARM on QEMU's `virt` board; UEFI is its only firmware.
+    #[serde(rename = "aarch64")]
+    Aarch64,
+    /// 64-bit RISC-V on QEMU's `virt` board, booting OpenSBI unless the VM
+    /// asks for UEFI.
+    #[serde(rename = "riscv64")]
+    Riscv64,
+}
+
+pub const ARCHES: [Arch; 3] = [Arch::X86_64, Arch::Aarch64, Arch::Riscv64];
+
+impl Arch {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "x86_64" | "amd64" => Some(Arch::X86_64),
+            "aarch64" | "arm64" => Some(Arch::Aarch64),
+            "riscv64" => Some(Arch::Riscv64),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+            Arch::Riscv64 => "riscv64",
+        }
+    }
+
+    /// This machine's architecture, if guests can have it.
+    pub fn host() -> Option<Self> {
+        Arch::parse(std::env::consts::ARCH)
+    }
+
+    pub fn qemu_binary(self) -> String {
+        format!("qemu-system-{}", self.name())
+    }
+
+    /// Whether the guest has PC hardware: IDE, VGA and the BIOS.
+    pub fn is_pc(self) -> bool {
+        self == Arch::X86_64
+    }
+}
+
+/// A VM's choice of accelerator; VMs without one use KVM when they can.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Accel {
+    /// KVM or nothing: the VM does not start without it.
+    #[serde(rename = "kvm")]
+    Kvm,
+    /// Always emulated, e.g. to keep a guest off KVM for debugging.
+    #[serde(rename = "tcg")]
+    Tcg,
+}
+
+impl Accel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Accel::Kvm => "kvm",
+            Accel::Tcg => "tcg",
+        }
+    }
+}
+
+/// `--arch`, with the known ones listed when it is none of them.
+pub fn parse_arg(s: &str) -> Result<Arch, String> {
+    Arch::parse(s).ok_or_else(|| {
+        let known: Vec<&str> = ARCHES.iter().map(|a| a.name()).collect();
+        format!("unknown architecture; use {}", known.join(", "))
+    })
+}
+
+/// The VM's architecture; VMs without one are x86_64.
+pub fn of(vm: &VMInfo) -> Arch {
+    vm.arch.unwrap_or(Arch::X86_64)
+}
+
+/// Whether the VM's architecture is not this machine's, which KVM cannot
+/// run.
+pub fn foreign(vm: &VMInfo) -> bool {
+    Arch::host() != Some(of(vm))
+}
+
+/// Why this process cannot use KVM, if it cannot. The mock backend can
+/// pretend the device is missing.
+pub fn kvm_unavailable() -> Option<String> {
+    let opened = if std::env::var_os(crate::runner::MOCK_ENV).is_some() && std::env::var_os(crate::runner::NO_KVM_ENV).is_some() {
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    } else {
+        fs::OpenOptions::new().read(true).write(true).open(KVM_DEVICE).map(drop)
+    };
+    match opened {
+        Ok(()) => None,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Some(format!("{} does not exist; enable virtualization in the firmware setup, or pass the device into the container", KVM_DEVICE))
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            Some(format!("you may not open {}; add your user to the kvm group (sudo usermod -aG kvm $USER) and log in again", KVM_DEVICE))
+        }
+        Err(e) => Some(format!("cannot open {}: {}", KVM_DEVICE, e)),
+    }
+}
+
+/// Whether the VM runs under TCG, given whether this host has KVM.
+pub fn emulated(vm: &VMInfo, kvm: bool) -> bool {
+    match vm.accel {
+        Some(Accel::Kvm) => false,
+        Some(Accel::Tcg) => true,
+        None => foreign(vm) || !kvm,
+    }
+}
+
+/// Checks before a start: an error when the VM insists on KVM it cannot
+/// have, a warning when it falls back to TCG on its own.
+pub fn check(vm: &VMInfo) -> Result<Option<String>, String> {
+    let unavailable = kvm_unavailable();
+    match (vm.accel, unavailable) {
+        (Some(Accel::Kvm), _) if foreign(vm) => {
+            Err(format!("it is set to KVM, which cannot run {} guests on this host; use `SRQemu accel {} auto`", of(vm).name(), vm.name))
+        }
+        (Some(Accel::Kvm), Some(reason)) => Err(format!("it is set to KVM, but {}", reason)),
+        (None, Some(reason)) if !foreign(vm) => Ok(Some(format!(
+            "KVM is unavailable ({}); running it emulated with TCG, many times slower. `SRQemu accel {} tcg` makes that its setting and quiets this warning.",
+            reason, vm.name
+        ))),
+        _ => Ok(None),
+    }
+}
+
+/// Sets the VM's accelerator from its next start: `kvm`, `tcg`, or `auto`
+/// to clear the choice.
+pub fn set_accel(config: &mut VMConfig, name: &str, accel: &str) -> Result<String, String> {
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    vm.accel = match accel {
+        "auto" => None,
+        "kvm" => Some(Accel::Kvm),
+        "tcg" => Some(Accel::Tcg),
+        other => return Err(format!("unknown accelerator '{}'; use auto, kvm or tcg", other)),
+    };
+    config::save_config(config).map_err(|e| e.to_string())?;
+    Ok(format!("'{}' uses {} from its next start.", name, describe(&config.vms[name])))
+}
+
+/// The VM's accelerator as `accel` shows it.
+pub fn describe(vm: &VMInfo) -> String {
+    match vm.accel {
+        Some(accel) => accel.name().to_string(),
+        None if foreign(vm) => "tcg (auto: not this host's architecture)".to_string(),
+        None => match kvm_unavailable() {
+            Some(reason) => format!("tcg (auto: {})", reason),
+            None => "kvm (auto)".to_string(),
+        },
+    }
+}
+
+/// The `-cpu` model: TCG cannot pass the host's CPU through, so `host`
+/// becomes the emulator's most capable model.
+pub fn cpu_model(vm: &VMInfo, emulated: bool) -> String {
+    if emulated && vm.cpu == "host" { "max".to_string() } else { crate::clock::cpu_model(vm) }
+}
+
+/// Machine type and accelerator; other boards than the PC get input and,
+/// with a window, a display, which the PC has built in.
+pub fn launch_args(vm: &VMInfo, emulated: bool, headless: bool) -> Vec<String> {
+    let mut args = match of(vm) {
+        Arch::X86_64 => Vec::new(),
+        Arch::Aarch64 => vec!["-machine".to_string(), "virt,gic-version=max".to_string()],
+        Arch::Riscv64 => vec!["-machine".to_string(), "virt".to_string()],
+    };
+    if emulated {
+        args.extend(["-accel".to_string(), "tcg,thread=multi".to_string()]);
+    } else {
+        args.push("-enable-kvm".to_string());
+    }
+    if !of(vm).is_pc() && !headless {
+        if vm.display.as_ref().is_none_or(|d| d.model.is_none() && d.resolution.is_none()) {
+            args.extend(["-device".to_string(), "virtio-gpu-pci".to_string()]);
+        }
+        for device in ["qemu-xhci,id=input", "usb-kbd,bus=input.0", "usb-tablet,bus=input.0"] {
+            args.extend(["-device".to_string(), device.to_string()]);
+        }
+    }
+    args
+}
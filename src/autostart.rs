@@ -0,0 +1,136 @@
+use crate::config::{Settings, VMConfig, VMInfo};
+use crate::{agent, guestcron};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::process::Command as ShellCommand;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Marks a VM to be started by `SRQemu autostart`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Autostart {
+    /// Seconds after autostart begins before this VM may launch; lets
+    /// infrastructure VMs (DNS, storage) come up ahead of their clients.
+    #[serde(default)]
+    pub delay: u64,
+}
+
+/// systemd user unit template; each autostart VM is an instance of it.
+const UNIT_TEMPLATE: &str = "srqemu@.service";
+
+/// How often the boot queue is re-checked.
+const POLL: Duration = Duration::from_secs(1);
+
+/// VMs marked for autostart that are not already running, in launch order.
+fn queue(config: &VMConfig) -> Vec<(&VMInfo, u64)> {
+    let mut vms: Vec<(&VMInfo, u64)> = config
+        .vms
+        .values()
+        .filter_map(|vm| vm.autostart.as_ref().map(|a| (vm, a.delay)))
+        .filter(|(vm, _)| !crate::vm_running(&vm.name))
+        .collect();
+    vms.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.name.cmp(&b.0.name)));
+    vms
+}
+
+/// A launched VM stops counting against `autostart_max_parallel` once its
+/// guest agent answers, it exits, or the boot window has passed.
+fn still_booting(vm: &VMInfo, launched: Instant, settings: &Settings) -> bool {
+    if launched.elapsed() >= Duration::from_secs(settings.autostart_boot_secs) || !crate::vm_running(&vm.name) {
+        return false;
+    }
+    !(vm.guest_agent && agent::connect(&vm.name).is_ok())
+}
+
+/// Starts every autostart VM headless, honouring per-VM delays, the
+/// stagger between launches and the cap on VMs booting at once.
+pub fn run(config: &VMConfig) {
+    let settings = &config.settings;
+    let began = Instant::now();
+    let stagger = Duration::from_secs(settings.autostart_stagger);
+    let mut last_launch: Option<Instant> = None;
+    let mut booting: Vec<(&VMInfo, Instant)> = Vec::new();
+
+    for (vm, delay) in queue(config) {
+        let due = began + Duration::from_secs(delay);
+        let next_slot = last_launch.map_or(due, |t| (t + stagger).max(due));
+        if let Some(wait) = next_slot.checked_duration_since(Instant::now()) {
+            sleep(wait);
+        }
+        loop {
+            booting.retain(|(vm, launched)| still_booting(vm, *launched, settings));
+            if settings.autostart_max_parallel == 0 || booting.len() < settings.autostart_max_parallel {
+                break;
+            }
+            sleep(POLL);
+        }
+        crate::start_vm_common(config, vm, true);
+        // The launcher returns before QEMU exists; without this the VM
+        // would look like it had already exited.
+        crate::wait_for_pid(&vm.name);
+        let now = Instant::now();
+        last_launch = Some(now);
+        booting.push((vm, now));
+    }
+}
+
+/// The unit instance for a VM, e.g. `srqemu@web.service`.
+fn instance(vm_name: &str) -> Result<String, String> {
+    let name = crate::run(ShellCommand::new("systemd-escape").arg(format!("--template={}", UNIT_TEMPLATE)).arg(vm_name))?;
+    Ok(name.trim().to_string())
+}
+
+/// Starts the VM through `SRQemu start` when the user's systemd instance
+/// comes up and shuts it down through `SRQemu stop` when it goes away.
+fn template_unit(settings: &Settings) -> Result<String, String> {
+    let exe = std::env::current_exe().map_err(|e| format!("cannot locate this binary: {}", e))?;
+    Ok(format!(
+        "[Unit]\nDescription=SRQemu VM %I\nAfter={daemon}\n\n[Service]\nType=oneshot\nRemainAfterExit=yes\nExecStart={exe} start %I --headless\nExecStop={exe} stop %I\nTimeoutStopSec={}\n\n[Install]\nWantedBy=default.target\n",
+        settings.shutdown_timeout + 30,
+        daemon = crate::daemon::SERVICE,
+        exe = exe.display()
+    ))
+}
+
+/// Installs and enables the unit that brings `vm` up with the user's
+/// session, honouring its delay. It does not start the VM now.
+pub fn install_unit(vm: &VMInfo, settings: &Settings) -> Result<(), String> {
+    let dir = guestcron::unit_dir()?;
+    fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    // Rewritten every time so it follows the binary if that moved.
+    fs::write(dir.join(UNIT_TEMPLATE), template_unit(settings)?).map_err(|e| e.to_string())?;
+    let unit = instance(&vm.name)?;
+    let drop_in = dir.join(format!("{}.d", unit));
+    match vm.autostart.as_ref().map_or(0, |a| a.delay) {
+        0 => {
+            let _ = fs::remove_dir_all(&drop_in);
+        }
+        delay => {
+            fs::create_dir_all(&drop_in).map_err(|e| format!("cannot create {}: {}", drop_in.display(), e))?;
+            fs::write(drop_in.join("delay.conf"), format!("[Service]\nExecStartPre=/bin/sleep {}\n", delay)).map_err(|e| e.to_string())?;
+        }
+    }
+    guestcron::systemctl(&["daemon-reload"])?;
+    guestcron::systemctl(&["enable", &unit])
+}
+
+pub fn uninstall_unit(vm_name: &str) -> Result<(), String> {
+    let unit = instance(vm_name)?;
+    let _ = guestcron::systemctl(&["disable", &unit]);
+    let _ = fs::remove_dir_all(guestcron::unit_dir()?.join(format!("{}.d", unit)));
+    guestcron::systemctl(&["daemon-reload"])
+}
+
+/// Whether systemd keeps the user's units running without a login; without
+/// it autostart VMs come up at the first login instead of at boot.
+pub fn lingering() -> bool {
+    let user = std::env::var("USER").unwrap_or_default();
+    match crate::run(ShellCommand::new("loginctl").args(["show-user", &user, "--property=Linger"])) {
+        Ok(out) => out.trim() == "Linger=yes",
+        Err(e) => {
+            warn!("cannot tell whether lingering is enabled: {}", e);
+            false
+        }
+    }
+}
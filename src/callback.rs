@@ -0,0 +1,173 @@
+//! Host actions a guest may ask for, e.g. to be snapshotted before a risky
+//! step or shut down once its job is done. The guest writes one request per
+//! line to `/dev/virtio-ports/org.srqemu.callback.0` and reads back `ok ...`
+//! or `error: ...`. Only the daemon answers, and only with what the VM's
+//! `guest_callbacks` allow.
+
+use crate::config::{self, VMConfig, VMInfo};
+use crate::snapshot;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use crate::ipc::{self, Stream};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+/// Name the port has on the guest's virtio-serial bus.
+const PORT: &str = "org.srqemu.callback.0";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Callback {
+    /// `snapshot [name]`: a live snapshot, named by the guest or by time.
+    Snapshot,
+    /// `shutdown`: a graceful stop, as `SRQemu stop` does.
+    Shutdown,
+}
+
+impl Callback {
+    pub fn parse(s: &str) -> Option<Callback> {
+        match s {
+            "snapshot" => Some(Callback::Snapshot),
+            "shutdown" => Some(Callback::Shutdown),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Callback::Snapshot => "snapshot",
+            Callback::Shutdown => "shutdown",
+        }
+    }
+}
+
+pub const CALLBACKS: [&str; 2] = ["snapshot", "shutdown"];
+
+/// A request that passed the allowlist.
+#[derive(Debug, PartialEq)]
+enum Request {
+    Snapshot(String),
+    Shutdown,
+}
+
+pub fn socket_path(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("callback.sock")
+}
+
+/// Arguments adding the callback port for VMs that allow any callback.
+pub fn launch_args(vm: &VMInfo) -> Vec<String> {
+    if vm.guest_callbacks.is_empty() {
+        return Vec::new();
+    }
+    let mut args = vec!["-chardev".to_string(), format!("socket,id=srqcb0,{}", ipc::chardev(&socket_path(&vm.name)))];
+    // The guest agent's channel already brings the bus.
+    if !vm.guest_agent {
+        args.extend(["-device".to_string(), "virtio-serial".to_string()]);
+    }
+    args.extend(["-device".to_string(), format!("virtserialport,chardev=srqcb0,name={}", PORT)]);
+    args
+}
+
+fn parse(line: &str, allowed: &[Callback]) -> Result<Request, String> {
+    let mut words = line.split_whitespace();
+    let word = words.next().ok_or("empty request")?;
+    let callback = Callback::parse(word).ok_or_else(|| format!("unknown request '{}'; known are {}", word, CALLBACKS.join(", ")))?;
+    if !allowed.contains(&callback) {
+        return Err(format!("'{}' is not allowed for this VM", word));
+    }
+    Ok(match callback {
+        Callback::Snapshot => {
+            let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            Request::Snapshot(words.next().map_or_else(|| format!("guest-{}", secs), str::to_string))
+        }
+        Callback::Shutdown => Request::Shutdown,
+    })
+}
+
+/// Answers the callback port of a VM the daemon just started, until the
+/// VM goes away.
+pub fn listen(vm_name: &str) {
+    let name = vm_name.to_string();
+    std::thread::spawn(move || {
+        let path = socket_path(&name);
+        // QEMU creates the socket while it starts.
+        let mut stream = None;
+        for _ in 0..20 {
+            match ipc::connect(&path) {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(250)),
+            }
+        }
+        let Some(stream) = stream else {
+            warn!("VM '{}': cannot connect to {}; guest callbacks are off", name, path.display());
+            return;
+        };
+        if let Err(e) = serve(&name, stream) {
+            warn!("VM '{}': guest callback channel closed: {}", name, e);
+        }
+    });
+}
+
+fn serve(vm_name: &str, stream: Stream) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        info!("VM '{}' requests '{}'", vm_name, line.trim());
+        let config = config::load_config().map_err(|e| e.to_string());
+        let allowed = config.as_ref().ok().and_then(|c| c.vms.get(vm_name)).map(|vm| vm.guest_callbacks.clone()).unwrap_or_default();
+        let request = config.and_then(|config| parse(&line, &allowed).map(|request| (config, request)));
+        match request {
+            Ok((mut config, Request::Snapshot(snapshot))) => {
+                match take_snapshot(&mut config, vm_name, &snapshot) {
+                    Ok(()) => {
+                        println!("Snapshot '{}' of '{}' taken for the guest.", snapshot, vm_name);
+                        writeln!(writer, "ok snapshot {}", snapshot)?;
+                    }
+                    Err(e) => writeln!(writer, "error: {}", e)?,
+                }
+            }
+            Ok((config, Request::Shutdown)) => {
+                // Answered first: the port goes away with the VM.
+                writeln!(writer, "ok shutdown")?;
+                if let Err(e) = crate::stop_vm_by_name(&config, vm_name, false) {
+                    error!("VM '{}': failed to stop it for the guest: {}", vm_name, e);
+                }
+                crate::hostpower::update(&config, Some(vm_name));
+                return Ok(());
+            }
+            Err(e) => {
+                error!("VM '{}': refused '{}': {}", vm_name, line.trim(), e);
+                writeln!(writer, "error: {}", e)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn take_snapshot(config: &mut VMConfig, vm_name: &str, snapshot: &str) -> Result<(), String> {
+    let vm = config.vms.get_mut(vm_name).ok_or_else(|| format!("VM '{}' not found", vm_name))?;
+    snapshot::create(vm, snapshot, "requested by the guest")?;
+    config::save_config(config).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_checked_against_the_allowlist() {
+        let allowed = [Callback::Snapshot];
+        assert_eq!(parse("snapshot before-upgrade\n", &allowed), Ok(Request::Snapshot("before-upgrade".to_string())));
+        assert!(matches!(parse("snapshot", &allowed), Ok(Request::Snapshot(name)) if name.starts_with("guest-")));
+        assert!(parse("shutdown", &allowed).unwrap_err().contains("not allowed"));
+        assert!(parse("rm -rf /", &allowed).unwrap_err().contains("unknown request"));
+        assert!(parse("  ", &allowed).is_err());
+    }
+}
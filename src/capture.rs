@@ -0,0 +1,131 @@
+use crate::config::{VMConfig, VMInfo};
+use tracing::debug;
+use crate::{network, qmp};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command as ShellCommand, Stdio};
+
+/// How a running capture was started, so it can be stopped the same way.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "kebab-case")]
+enum CaptureState {
+    /// tcpdump on the NIC's host bridge, filtered to the NIC's MAC.
+    Tcpdump { pid: u32, file: String },
+    /// QEMU's own `filter-dump` object on the NIC's netdev.
+    FilterDump { id: String, file: String },
+}
+
+/// Limits for captures on bridged NICs, passed through to tcpdump.
+pub struct Rotation {
+    /// Size of each file in MB before rotating.
+    pub max_size_mb: u32,
+    /// Number of files kept; 0 means unlimited.
+    pub files: u32,
+}
+
+fn state_file(vm: &VMInfo, nic: usize) -> PathBuf {
+    PathBuf::from(crate::vm_folder(&vm.name)).join(format!("capture-net{}.json", nic))
+}
+
+fn load_state(vm: &VMInfo, nic: usize) -> Option<CaptureState> {
+    serde_json::from_str(&fs::read_to_string(state_file(vm, nic)).ok()?).ok()
+}
+
+pub fn start(
+    config: &VMConfig,
+    vm: &VMInfo,
+    nic: usize,
+    out: &Path,
+    rotation: Option<Rotation>,
+) -> Result<(), String> {
+    let spec = vm.nics.get(nic).ok_or_else(|| format!("VM '{}' has no NIC {}", vm.name, nic))?;
+    if load_state(vm, nic).is_some() {
+        return Err(format!("a capture is already running on NIC {}", nic));
+    }
+    let file = out.display().to_string();
+
+    let state = match network::nic_bridge(config, spec) {
+        Some(bridge) => {
+            let mut cmd = ShellCommand::new("tcpdump");
+            cmd.args(["-i", bridge, "-U", "-w", &file]);
+            if let Some(r) = &rotation {
+                cmd.args(["-C", &r.max_size_mb.to_string()]);
+                if r.files > 0 {
+                    cmd.args(["-W", &r.files.to_string()]);
+                }
+            }
+            cmd.args(["ether", "host", &spec.mac]);
+            // Own process group, so Ctrl-C in the menu does not end it.
+            let child = crate::process::detach(cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()))
+                .spawn()
+                .map_err(|e| format!("failed to run tcpdump: {}", e))?;
+            CaptureState::Tcpdump { pid: child.id(), file: file.clone() }
+        }
+        None => {
+            if rotation.is_some() {
+                println!("Note: size and rotation limits need a bridged NIC; capturing to one file.");
+            }
+            let id = format!("capture-net{}", nic);
+            qmp::connect(&vm.name)?.execute(
+                "object-add",
+                Some(json!({
+                    "qom-type": "filter-dump",
+                    "id": id,
+                    "netdev": format!("net{}", nic),
+                    "file": file,
+                })),
+            )?;
+            CaptureState::FilterDump { id, file: file.clone() }
+        }
+    };
+
+    let json = serde_json::to_string_pretty(&state).map_err(|e| e.to_string())?;
+    fs::write(state_file(vm, nic), json).map_err(|e| format!("cannot record capture: {}", e))?;
+    println!("Capturing NIC {} of '{}' to {}", nic, vm.name, file);
+    Ok(())
+}
+
+pub fn stop(vm: &VMInfo, nic: usize) -> Result<(), String> {
+    let state = load_state(vm, nic).ok_or_else(|| format!("no capture running on NIC {}", nic))?;
+    let file = match state {
+        CaptureState::Tcpdump { pid, file } => {
+            // Already gone is fine: tcpdump exits when its interface does.
+            if let Err(e) = crate::run(ShellCommand::new("kill").arg(pid.to_string())) {
+                debug!("tcpdump {} already gone: {}", pid, e);
+            }
+            file
+        }
+        CaptureState::FilterDump { id, file } => {
+            if let Ok(mut session) = qmp::connect(&vm.name) {
+                session.execute("object-del", Some(json!({ "id": id })))?;
+            }
+            file
+        }
+    };
+    let _ = fs::remove_file(state_file(vm, nic));
+    println!("Capture on NIC {} of '{}' stopped; saved to {}", nic, vm.name, file);
+    Ok(())
+}
+
+/// Drops capture records of a VM whose QEMU is gone: filter-dump objects
+/// died with it, and tcpdump records only stay while tcpdump runs.
+pub fn clear_stale(vm_name: &str) {
+    let Ok(entries) = fs::read_dir(crate::vm_folder(vm_name)) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !(name.starts_with("capture-net") && name.ends_with(".json")) {
+            continue;
+        }
+        let alive = match fs::read_to_string(entry.path()).ok().and_then(|s| serde_json::from_str(&s).ok()) {
+            Some(CaptureState::Tcpdump { pid, .. }) => crate::process::alive(pid),
+            _ => false,
+        };
+        if !alive {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
@@ -0,0 +1,746 @@
+use clap::{ArgAction, Parser, Subcommand};
+
+/// Manage QEMU virtual machines. Without a subcommand the interactive menu
+/// starts.
+#[derive(Parser)]
+#[command(name = "SRQemu", version)]
+pub struct Cli {
+    /// More detail on stderr: -v for progress, -vv for commands run, -vvv
+    /// for everything
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Log as JSON lines, to stderr and the manager log
+    #[arg(long, global = true)]
+    pub log_json: bool,
+    /// Print `list`, `status` and `info` as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Line-oriented output for screen readers and dumb terminals: no
+    /// colors, tables, charts or progress bars; implied by TERM=dumb
+    #[arg(long, global = true)]
+    pub plain: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Numbered menu with every feature
+    Interactive,
+    /// Create a VM and its disk image
+    Create {
+        name: String,
+        /// Profile or VM to inherit unspecified settings from
+        #[arg(long)]
+        extends: Option<String>,
+        /// Template to fill in unspecified settings from once, e.g.
+        /// `linux-server` or `windows-desktop`
+        #[arg(long, conflicts_with = "extends")]
+        template: Option<String>,
+        #[arg(long)]
+        memory: Option<String>,
+        /// Disk size; default 10G
+        #[arg(long, conflicts_with = "disk")]
+        disk_size: Option<String>,
+        /// Disk as `size=40G,bus=nvme,cache=none,iothread=on,pool=ssd`; bus
+        /// is ide, sata, virtio-blk, virtio-scsi or nvme, pool names a
+        /// directory under [settings.pools]
+        #[arg(long)]
+        disk: Option<String>,
+        /// NIC model, virtio or e1000; cloud images default to virtio,
+        /// other VMs to e1000, which needs no guest drivers
+        #[arg(long)]
+        nic_model: Option<String>,
+        #[arg(long)]
+        threads: Option<String>,
+        /// Installation ISO, or the name of one from `images pull`, booted
+        /// first when --start is given
+        #[arg(long, conflicts_with = "cloud_image")]
+        iso: Option<String>,
+        /// Cloud image (Ubuntu, Fedora, Debian ...) to copy as the disk,
+        /// provisioned on first boot through a cloud-init seed; names from
+        /// `images pull` work too
+        #[arg(long)]
+        cloud_image: Option<String>,
+        /// cloud-init user data for --cloud-image; default sets the
+        /// hostname and installs your ~/.ssh/*.pub keys
+        #[arg(long, requires = "cloud_image")]
+        user_data: Option<String>,
+        /// Host to guest port forward on a user-mode NIC, e.g. `2222->22`;
+        /// repeatable
+        #[arg(long)]
+        forward: Vec<String>,
+        /// Boot with UEFI (OVMF) instead of BIOS
+        #[arg(long)]
+        uefi: bool,
+        /// Guest architecture: x86_64 (default), aarch64 or riscv64. Only
+        /// the host's own runs with KVM; others are emulated, many times
+        /// slower
+        #[arg(long, value_parser = crate::arch::parse_arg)]
+        arch: Option<crate::arch::Arch>,
+        /// Add the channel qemu-ga in the guest talks over, for `ip`, `exec`
+        /// and `fsfreeze`
+        #[arg(long)]
+        guest_agent: bool,
+        /// Recipe file or URL to define the VM from instead of the options
+        /// above
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "extends", "template", "memory", "disk_size", "disk", "nic_model", "threads", "iso", "cloud_image", "forward", "uefi", "arch", "guest_agent"
+            ]
+        )]
+        recipe: Option<String>,
+        /// Boot the VM from its ISO right away
+        #[arg(long)]
+        start: bool,
+        #[arg(long)]
+        headless: bool,
+    },
+    /// Start a VM
+    Start {
+        name: String,
+        #[arg(long)]
+        headless: bool,
+        /// ISO to put in the CD drive for this boot only
+        #[arg(long)]
+        iso: Option<String>,
+        /// Boot from the CD drive, e.g. to reinstall; without --iso the
+        /// VM's own CD or installation ISO is used
+        #[arg(long)]
+        boot_cdrom: bool,
+        /// Remote console for this boot: vnc, spice, vnc:<port>,
+        /// spice:<port> or spice:<host>:<port>; a bare protocol picks a
+        /// free port
+        #[arg(long, value_name = "SPEC")]
+        display: Option<String>,
+        /// Boot this once from slot a or b instead of the default one
+        #[arg(long)]
+        slot: Option<String>,
+    },
+    /// Show or set the color and icon telling a VM apart in listings,
+    /// launchers and the tray
+    Label {
+        name: String,
+        /// Color name (red, orange, yellow, green, teal, blue, purple,
+        /// gray), #rrggbb, or none
+        #[arg(long)]
+        color: Option<String>,
+        /// Icon theme name such as `network-server`, an image file, or none
+        #[arg(long)]
+        icon: Option<String>,
+    },
+    /// Show or set whether a VM runs with KVM or emulated with TCG
+    Accel {
+        name: String,
+        /// auto (KVM when the host has it), kvm or tcg
+        accel: Option<String>,
+    },
+    /// Show or change a VM's disk bus and NIC model, from its next start
+    Devices {
+        name: String,
+        /// ide, sata, virtio-blk, virtio-scsi or nvme
+        #[arg(long)]
+        disk_bus: Option<String>,
+        /// virtio or e1000, for every NIC
+        #[arg(long)]
+        nic_model: Option<String>,
+    },
+    /// Shut a VM down, killing it if it ignores the request
+    Stop {
+        name: String,
+        /// Kill right away instead of asking the guest to shut down
+        #[arg(long)]
+        force: bool,
+    },
+    /// Attach this terminal to a VM's serial console; Ctrl-] detaches
+    Console {
+        /// VM to attach to; with --tmux and no name, every running VM
+        name: Option<String>,
+        /// Open the consoles as windows of the `srqemu` tmux session
+        #[arg(long)]
+        tmux: bool,
+    },
+    /// Start a VM with a window, or open a viewer on the console of one
+    /// already running; what app-menu launchers run
+    Open { name: String },
+    /// Show a tray icon listing the VMs with start, stop and console
+    /// actions; needs the daemon and yad
+    Tray,
+    /// Put a launcher for a VM into the desktop's app menu
+    DesktopEntry {
+        name: String,
+        /// Write the .desktop file here instead, e.g. onto the desktop
+        #[arg(long)]
+        output: Option<String>,
+        /// Take the launcher out of the app menu again
+        #[arg(long, conflicts_with = "output")]
+        remove: bool,
+    },
+    /// Schedule commands in a VM's guest
+    Guest {
+        #[command(subcommand)]
+        action: GuestAction,
+    },
+    /// Change a VM's display
+    Display {
+        #[command(subcommand)]
+        action: DisplayAction,
+    },
+    /// List host USB devices, or pass them through to a VM
+    Usb {
+        #[command(subcommand)]
+        action: UsbAction,
+    },
+    /// Pass host PCI devices such as a GPU through to a VM with VFIO
+    Vfio {
+        #[command(subcommand)]
+        action: VfioAction,
+    },
+    /// Share host folders with a VM over virtiofs or 9p
+    Share {
+        #[command(subcommand)]
+        action: ShareAction,
+    },
+    /// Freeze a running VM in place, keeping its memory and devices
+    Pause { name: String },
+    /// Continue a paused VM
+    Resume { name: String },
+    /// Save a running VM's full state and stop it; `restore` or `start`
+    /// resumes it, also after a host reboot
+    Suspend { name: String },
+    /// List VMs with their NICs, firewall rules and warnings
+    List,
+    /// Show the host ports forwarded into VMs
+    Ports { name: Option<String> },
+    /// Show whether VMs run, with their pid, uptime, memory and CPU use
+    Status { name: Option<String> },
+    /// Show a VM's state, disk and full definition
+    Info { name: String },
+    /// Take over a QEMU started outside SRQemu, found by pid or by text
+    /// from its command line such as its -name
+    Adopt {
+        process: String,
+        /// VM name; default is the process's -name
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Define a VM from a libvirt domain or a VirtualBox machine
+    #[command(group = clap::ArgGroup::new("source").required(true))]
+    Import {
+        /// libvirt domain name or domain XML file
+        #[arg(long, group = "source")]
+        libvirt: Option<String>,
+        /// libvirt connection for a domain name
+        #[arg(long, default_value = "qemu:///system")]
+        connect: String,
+        /// VirtualBox machine's .vbox file
+        #[arg(long, group = "source")]
+        vbox: Option<String>,
+        /// VM name; default is the domain's or machine's name
+        #[arg(long)]
+        name: Option<String>,
+        /// keep (use the disk in place), link (symlink it into the VM
+        /// folder) or copy (convert to qcow2)
+        #[arg(long, default_value = "copy")]
+        disk: String,
+    },
+    /// Create a VM as a copy of a stopped one
+    Clone {
+        source: String,
+        name: String,
+        /// Share the source's disk as a frozen qcow2 base instead of copying it
+        #[arg(long)]
+        thin: bool,
+    },
+    /// Change the medium in a VM's CD drive; live while it runs, in its
+    /// definition otherwise
+    Cdrom {
+        name: String,
+        #[command(subcommand)]
+        action: CdromAction,
+    },
+    /// Keep a second system disk to try OS upgrades on, and switch
+    /// between the two
+    Slot {
+        name: String,
+        #[command(subcommand)]
+        action: SlotAction,
+    },
+    /// Boot a throwaway copy of a stopped VM, run a shell script in it
+    /// through the guest agent and exit with the script's exit code (125 if
+    /// it could not be run)
+    Run {
+        /// VM to copy; its disk is only read
+        #[arg(long)]
+        image: String,
+        /// Shell script to run in the guest
+        #[arg(long)]
+        cmd: String,
+        /// Remove the copy afterwards instead of keeping it, stopped, for
+        /// inspection
+        #[arg(long)]
+        rm: bool,
+        /// Seconds the guest agent may take to come up
+        #[arg(long, default_value_t = 300)]
+        boot_timeout: u64,
+        /// Seconds the script may take
+        #[arg(long, default_value_t = 3600)]
+        timeout: u64,
+    },
+    /// Host bridges and packet captures
+    #[command(alias = "net")]
+    Network {
+        #[command(subcommand)]
+        action: NetworkAction,
+    },
+    /// Print the guest's IP addresses as its guest agent reports them
+    Ip {
+        name: String,
+        /// Include loopback and link-local addresses
+        #[arg(long)]
+        all: bool,
+    },
+    /// Run a command in the guest through its agent, passing on its output
+    /// and exit code
+    Exec {
+        name: String,
+        /// Seconds to wait for the command
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+        /// Program and arguments, e.g. `-- systemctl is-active nginx`
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Freeze or thaw the guest's filesystems around a host-side copy
+    Fsfreeze {
+        name: String,
+        #[command(subcommand)]
+        action: FreezeAction,
+    },
+    /// Show or set the host actions a VM's guest may request (snapshot,
+    /// shutdown; `none` for none); the daemon answers them from the next boot
+    Callbacks {
+        name: String,
+        #[arg(value_delimiter = ',')]
+        allow: Vec<String>,
+    },
+    /// Start and stop groups of VMs at set times of day
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleAction,
+    },
+    /// Show the templates `create --template` takes
+    Template {
+        #[command(subcommand)]
+        action: TemplateAction,
+    },
+    /// Download distro ISOs and cloud images into ~/vms/images
+    Images {
+        #[command(subcommand)]
+        action: ImagesAction,
+    },
+    /// Move a VM to the trash
+    Delete { name: String },
+    /// Resume a suspended VM where it left off, or bring back the most
+    /// recently deleted VM of that name
+    Restore {
+        name: String,
+        /// Resume without a window
+        #[arg(long)]
+        headless: bool,
+    },
+    /// Grow a VM's disk, e.g. `40G` or `+10G`
+    Resize {
+        name: String,
+        size: String,
+        /// Also grow the guest's partition and filesystem
+        #[arg(long)]
+        grow: bool,
+        /// Partition to grow on a stopped VM; default is the largest
+        #[arg(long)]
+        partition: Option<usize>,
+    },
+    /// Take, list, restore and delete disk snapshots
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+    /// Export a stopped VM's disk over NBD until interrupted, so forensic
+    /// tools or other hosts can read it without copying the image
+    ServeDisk {
+        name: String,
+        /// Refuse writes; several clients may then read at once
+        #[arg(long)]
+        readonly: bool,
+        /// Address to listen on; anything but loopback exposes the disk to
+        /// the network
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: String,
+        #[arg(long, default_value_t = 10809)]
+        port: u16,
+    },
+    /// Show the partitions, filesystems and OS on a stopped VM's disk
+    Inspect { name: String },
+    /// Mount a partition of a stopped VM's disk on the host until
+    /// `umount-disk`
+    MountDisk {
+        name: String,
+        mountpoint: String,
+        /// Partition number; default is the largest
+        #[arg(long)]
+        partition: Option<usize>,
+        #[arg(long)]
+        read_only: bool,
+    },
+    /// Unmount a disk mounted with `mount-disk`
+    UmountDisk { name: String },
+    /// Work with the files on a stopped VM's disk
+    Disk {
+        #[command(subcommand)]
+        action: DiskAction,
+    },
+    /// List the states a VM can be taken back to, oldest first
+    RestorePoint { name: String },
+    /// Take a VM back to the latest restore point at or before a time,
+    /// given as `YYYY-MM-DD[ HH:MM[:SS]]` in UTC or seconds since the epoch
+    RestoreTo { name: String, timestamp: String },
+    /// Move the directory holding all VM folders, e.g. to a bigger disk
+    Relocate {
+        /// New directory for VM folders, images and trash
+        #[arg(long)]
+        vm_dir: String,
+        /// Move the data there; without it the data must already be copied
+        #[arg(long = "move")]
+        move_data: bool,
+    },
+    /// Check what this host offers VMs and choose where they live and
+    /// what new ones get; runs by itself on the first interactive launch
+    Setup,
+    /// Show the run profiles, or switch to one (`none` to turn it off)
+    RunProfile { name: Option<String> },
+    /// Update guest OS packages of a VM, a tag or `all`
+    Update { target: String },
+    /// Compare the configured VMs with the host's memory, CPUs and disk;
+    /// with VM names or tags, check whether those can start together now
+    Plan { vms: Vec<String> },
+    /// Chart starts, uptime and disk growth per VM and tag
+    Stats {
+        /// Start recording; nothing is kept until then
+        #[arg(long, conflicts_with = "disable")]
+        enable: bool,
+        /// Stop recording, keeping what was recorded
+        #[arg(long)]
+        disable: bool,
+    },
+    /// Start the VMs marked for autostart, or mark one to come up with
+    /// your systemd user session
+    Autostart {
+        #[command(subcommand)]
+        action: Option<AutostartAction>,
+    },
+    /// Watch host memory pressure and relieve low-priority VMs
+    MemoryWatch,
+    /// Run the daemon that owns the VMs it starts and restarts crashed
+    /// ones, or manage it
+    Daemon {
+        #[command(subcommand)]
+        action: Option<DaemonAction>,
+    },
+    /// Share VM definitions through a dotfiles repository
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Called by systemd-sleep around host suspend
+    #[command(hide = true)]
+    SleepHook { phase: String, action: Option<String> },
+    /// Called by the timers of scheduled guest commands
+    #[command(hide = true)]
+    GuestRun { vm: String, job: String },
+    /// Called by the timers of start/stop schedules
+    #[command(hide = true)]
+    ScheduleRun { id: String, phase: String },
+}
+
+#[derive(Subcommand)]
+pub enum FreezeAction {
+    /// Flush and freeze every filesystem; writes in the guest block
+    Freeze,
+    /// Let the guest write again
+    Thaw,
+    /// Show whether the filesystems are frozen
+    Status,
+}
+
+#[derive(Subcommand)]
+pub enum ScheduleAction {
+    /// Add or replace a schedule, e.g. `add farm build-farm --start
+    /// "Mon..Fri 08:00" --stop "Mon..Fri 20:00"`
+    Add {
+        id: String,
+        /// VM name, tag, or `all`
+        target: String,
+        /// systemd calendar time to start the VMs
+        #[arg(long, required_unless_present = "stop")]
+        start: Option<String>,
+        /// systemd calendar time to stop the VMs the schedule started
+        #[arg(long)]
+        stop: Option<String>,
+    },
+    Remove { id: String },
+    /// Show the schedules and the VMs each one started
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum DaemonAction {
+    /// Show whether the daemon runs and which VMs it supervises
+    Status,
+    /// Run the daemon as a systemd user service that starts with the session
+    Install,
+    Uninstall,
+}
+
+#[derive(Subcommand)]
+pub enum AutostartAction {
+    /// Mark a VM for autostart and install its `srqemu@<name>` unit
+    Enable {
+        name: String,
+        /// Seconds to wait before starting it
+        #[arg(long, default_value_t = 0)]
+        delay: u64,
+    },
+    /// Unmark a VM and remove its unit
+    Disable { name: String },
+}
+
+#[derive(Subcommand)]
+pub enum TemplateAction {
+    /// Built-in templates and your own from the config directory's
+    /// `templates/`
+    List,
+    /// Print a template as TOML, e.g. to start your own from it
+    Show { name: String },
+}
+
+#[derive(Subcommand)]
+pub enum ImagesAction {
+    /// Download an image by name, e.g. `ubuntu-24.04`, and check it against
+    /// the distro's checksums
+    Pull { name: String },
+    /// Show the downloaded images
+    List,
+    /// Show the names `pull` knows
+    Catalog,
+}
+
+#[derive(Subcommand)]
+pub enum CdromAction {
+    /// Put an ISO in the drive, replacing the current one
+    Insert { iso: String },
+    /// Empty the drive
+    Eject,
+}
+
+#[derive(Subcommand)]
+pub enum GuestAction {
+    /// Run a shell command in the guest on a systemd calendar schedule,
+    /// e.g. `guest cron web daily "apt-get -y upgrade"`
+    Cron {
+        name: String,
+        schedule: String,
+        command: String,
+        /// Short id for the command; default `cmd1`, `cmd2`, ...
+        #[arg(long)]
+        id: Option<String>,
+    },
+    /// Stop running a scheduled command
+    CronRemove { name: String, id: String },
+    /// Set a user's password on a stopped Linux guest, read from the
+    /// terminal or stdin; on Windows stage the Utilman recovery shell, or
+    /// remove it again
+    ResetPassword {
+        name: String,
+        user: String,
+        /// Partition number; default is the root filesystem
+        #[arg(long)]
+        partition: Option<usize>,
+    },
+    /// Set a running guest's clock to host time through its agent
+    SyncTime { name: String },
+}
+
+#[derive(Subcommand)]
+pub enum DiskAction {
+    /// Copy between the host and a stopped VM's disk; exactly one side is
+    /// `<vm>:/path`, e.g. `disk cp web1:/etc/fstab .`
+    Cp {
+        source: String,
+        destination: String,
+        /// Partition number; default is the root filesystem
+        #[arg(long)]
+        partition: Option<usize>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum NetworkAction {
+    /// Create or remove a host bridge for bridged NICs
+    Bridge {
+        #[command(subcommand)]
+        action: BridgeAction,
+    },
+    /// Record a running VM's NIC traffic to a pcap file
+    Capture {
+        #[command(subcommand)]
+        action: CaptureAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum BridgeAction {
+    Create {
+        #[arg(default_value = "br0")]
+        bridge: String,
+        /// Host interface to enslave to it
+        #[arg(long)]
+        uplink: Option<String>,
+    },
+    Remove {
+        #[arg(default_value = "br0")]
+        bridge: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CaptureAction {
+    Start {
+        name: String,
+        #[arg(long, default_value_t = 0)]
+        nic: usize,
+        /// Default `<vm>-net<nic>.pcap`
+        #[arg(long)]
+        output: Option<String>,
+        /// Start a new file after this many MB
+        #[arg(long)]
+        rotate_mb: Option<u32>,
+        /// Rotated files to keep; 0 keeps all
+        #[arg(long, default_value_t = 0, requires = "rotate_mb")]
+        files: u32,
+    },
+    Stop {
+        name: String,
+        #[arg(long, default_value_t = 0)]
+        nic: usize,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DisplayAction {
+    /// Set the resolution, e.g. 2560x1440: for every start, and right away
+    /// if the VM runs with a VNC console and a virtio or qxl card
+    Resize { name: String, resolution: String },
+}
+
+#[derive(Subcommand)]
+pub enum UsbAction {
+    /// Show the USB devices plugged into the host
+    List,
+    /// Pass a host device (`vendor:product`, see `usb list`) through to a
+    /// VM: right away if it runs, and on every start
+    Attach { name: String, device: String },
+    /// Stop passing a device through
+    Detach { name: String, device: String },
+}
+
+#[derive(Subcommand)]
+pub enum VfioAction {
+    /// Pass a device (`lspci -D` address) through from the VM's next start
+    Add {
+        name: String,
+        address: String,
+        /// The guest's primary display; QEMU's emulated card is left out
+        #[arg(long)]
+        primary_gpu: bool,
+        /// ROM image to show the guest instead of the card's own
+        #[arg(long)]
+        romfile: Option<String>,
+    },
+    /// Stop passing a device through
+    Remove { name: String, address: String },
+    /// Check that this host is ready to pass the VM's devices through
+    Check { name: String },
+}
+
+#[derive(Subcommand)]
+pub enum ShareAction {
+    /// Show the VM's shared folders and how to mount them in the guest
+    List { name: String },
+    /// Share a host folder from the VM's next start
+    Add {
+        name: String,
+        path: String,
+        /// Name the guest mounts it by; defaults to the folder's name
+        #[arg(long)]
+        tag: Option<String>,
+        /// virtiofs (fast; needs virtiofsd on the host) or 9p (built into
+        /// QEMU, for guests without virtiofs)
+        #[arg(long, default_value = "virtiofs")]
+        protocol: String,
+        /// The guest cannot change the folder
+        #[arg(long)]
+        readonly: bool,
+    },
+    /// Stop sharing a folder
+    Remove { name: String, tag: String },
+}
+
+#[derive(Subcommand)]
+pub enum SlotAction {
+    /// Copy the stopped VM's disk into slot b; try it with `start --slot b`
+    Create,
+    /// Boot from this slot (a or b) by default from now on
+    Promote { slot: String },
+    /// Boot from the slot that was the default before the last promotion
+    Rollback,
+    /// Delete the slot that is not the default
+    Drop,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigAction {
+    /// Write VMs, profiles and networks without host-specific settings
+    Export {
+        /// File to write; default is standard output
+        #[arg(long, short)]
+        output: Option<String>,
+    },
+    /// Take the definitions from an exported file, keeping local settings
+    Sync { file: String },
+    /// List the copies kept before deletes, relocations and syncs, newest
+    /// first
+    History,
+    /// Go back to one of those copies; the current config is kept as a
+    /// new one first
+    Rollback {
+        /// Number from `config history`; 1 is the newest
+        #[arg(default_value_t = 1)]
+        number: usize,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SnapshotAction {
+    /// Snapshot a VM; a running VM's RAM is saved too
+    Create {
+        vm: String,
+        name: String,
+        #[arg(long, default_value = "")]
+        description: String,
+    },
+    List { vm: String },
+    /// Revert a VM to a snapshot
+    Restore { vm: String, name: String },
+    Delete { vm: String, name: String },
+}
@@ -0,0 +1,62 @@
+use crate::config::VMInfo;
+use serde::{Deserialize, Serialize};
+
+/// How a VM keeps time. Unset fields keep QEMU's defaults.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct TimeSpec {
+    /// `utc`, or `localtime` for Windows guests that expect the RTC in
+    /// local time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtc_base: Option<String>,
+    /// Replay missed RTC ticks (`-rtc driftfix=slew`) so Windows guests catch
+    /// up after the host was busy or paused.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub driftfix: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hpet: Option<bool>,
+    /// Paravirtual clock for Linux guests; off forces them onto TSC/HPET.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kvmclock: Option<bool>,
+    /// Set the guest clock through the guest agent whenever SRQemu resumes
+    /// the VM, instead of waiting for NTP to notice the jump.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sync_on_resume: bool,
+}
+
+impl TimeSpec {
+    pub fn is_empty(&self) -> bool {
+        *self == TimeSpec::default()
+    }
+}
+
+/// `-cpu` value with the kvmclock choice appended.
+pub fn cpu_model(vm: &VMInfo) -> String {
+    match vm.time.as_ref().and_then(|t| t.kvmclock) {
+        Some(false) => format!("{},kvmclock=off", vm.cpu),
+        Some(true) => format!("{},kvmclock=on", vm.cpu),
+        None => vm.cpu.clone(),
+    }
+}
+
+pub fn launch_args(vm: &VMInfo) -> Vec<String> {
+    let Some(time) = &vm.time else {
+        return Vec::new();
+    };
+    let mut args = Vec::new();
+    let mut rtc = Vec::new();
+    if let Some(base) = &time.rtc_base {
+        rtc.push(format!("base={}", base));
+    }
+    if time.driftfix {
+        rtc.push("driftfix=slew".to_string());
+    }
+    if !rtc.is_empty() {
+        args.push("-rtc".to_string());
+        args.push(rtc.join(","));
+    }
+    if let Some(hpet) = time.hpet {
+        args.push("-machine".to_string());
+        args.push(format!("hpet={}", if hpet { "on" } else { "off" }));
+    }
+    args
+}
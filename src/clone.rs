@@ -0,0 +1,145 @@
+use crate::config::{VMConfig, VMInfo};
+use crate::interrupt::{self, Partial};
+use crate::{firmware, images, network, provenance, run, snapshot};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command as ShellCommand;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// Frozen disks that thin clones and their sources share.
+fn bases_dir() -> PathBuf {
+    images::images_dir().join("clones")
+}
+
+fn create_overlay(disk: &str, base: &str) -> Result<(), String> {
+    run(crate::runner::command("qemu-img").args(["create", "-f", "qcow2", "-F", "qcow2", "-b", base, disk])).map(|_| ())
+}
+
+/// Turns the source's disk into a read-only base and puts a fresh overlay
+/// on it in its place, so the source can keep running without changing
+/// what the clone sees. Returns the base.
+fn freeze_disk(source: &VMInfo) -> Result<String, String> {
+    if !snapshot::on_disk(source)?.is_empty() {
+        return Err(format!("'{}' has internal snapshots, which a thin clone would strand; delete them or make a full clone", source.name));
+    }
+    let stamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    fs::create_dir_all(bases_dir()).map_err(|e| format!("cannot create {}: {}", bases_dir().display(), e))?;
+    let base = bases_dir().join(format!("{}-{}.qcow2", source.name, stamp)).display().to_string();
+    let disk = source.disk_path();
+    // A rename keeps the backing file references inside the disk valid.
+    fs::rename(&disk, &base).map_err(|e| format!("cannot move {} to {}: {}", disk, base, e))?;
+    if let Err(e) = create_overlay(&disk, &base) {
+        let _ = fs::rename(&base, &disk);
+        return Err(e);
+    }
+    crate::runner::best_effort(ShellCommand::new("chmod").args(["a-w", &base]));
+    Ok(base)
+}
+
+/// Creates `name` as a copy of the stopped VM `source`. A full clone gets
+/// a standalone copy of the disk; a thin one a qcow2 overlay on the
+/// source's frozen disk, which the source then also runs on. The clone
+/// gets fresh MACs and no snapshots, autostart or cloud-init seed (its
+/// guest is already set up). Returns the new VM and the source's updated
+/// entry; the files created for the clone are tracked in `partial`.
+pub fn clone(config: &VMConfig, source: &str, name: &str, thin: bool, partial: &mut Partial) -> Result<(VMInfo, VMInfo), String> {
+    let mut source = config.vms.get(source).cloned().ok_or_else(|| format!("VM '{}' not found", source))?;
+    if config.vms.contains_key(name) {
+        return Err(format!("a VM named '{}' already exists", name));
+    }
+    if crate::vm_running(&source.name) {
+        return Err(format!("VM '{}' is running; stop it first", source.name));
+    }
+    let vm_dir = crate::vm_folder(name);
+    partial.track(&vm_dir);
+    fs::create_dir_all(&vm_dir).map_err(|e| format!("cannot create {}: {}", vm_dir, e))?;
+    let disk = format!("{}/{}.qcow2", vm_dir, name);
+
+    if thin {
+        // Either the source runs on the frozen base afterwards or nothing
+        // changed, even on Ctrl-C.
+        let base = interrupt::critical(|| freeze_disk(&source))?;
+        create_overlay(&disk, &base)?;
+        if let Err(e) = provenance::register(&mut source, "frozen for a thin clone", false) {
+            error!("Failed to record image checksums of '{}': {}", source.name, e);
+        }
+    } else {
+        println!("Copying {} to {}...", source.disk_path(), disk);
+        run(crate::runner::command("qemu-img").args(["convert", "-O", "qcow2"]).arg(source.disk_path()).arg(&disk))?;
+    }
+    let nvram = firmware::nvram_path(&source.name);
+    if nvram.exists() {
+        fs::copy(&nvram, firmware::nvram_path(name)).map_err(|e| format!("cannot copy {}: {}", nvram.display(), e))?;
+    }
+
+    let mut vm = VMInfo {
+        name: name.to_string(),
+        disk: crate::relative_to_folder(name, &disk),
+        iso: if source.iso.is_empty() { String::new() } else { source.iso_path() },
+        cdrom: source.cdrom_path(),
+        images: Vec::new(),
+        snapshots: Vec::new(),
+        slots: None,
+        seed: None,
+        autostart: None,
+        ..source.clone()
+    };
+    for (i, nic) in vm.nics.iter_mut().enumerate() {
+        nic.mac = network::generate_mac(name, i);
+    }
+    if let Err(e) = provenance::register(&mut vm, &format!("cloned from {}", source.name), false) {
+        error!("Failed to record image checksums: {}", e);
+    }
+    Ok((vm, source))
+}
+
+/// A throwaway VM `name` on a qcow2 overlay of the stopped VM `source`'s
+/// disk, which stays untouched; QEMU's image locking keeps the source from
+/// starting while the overlay is in use. Forwarded ports, autostart and
+/// the seed are left out, so several can run side by side.
+/// The files created are tracked in `partial`.
+pub fn ephemeral(config: &VMConfig, source: &str, name: &str, partial: &mut Partial) -> Result<VMInfo, String> {
+    let source = config.vms.get(source).ok_or_else(|| format!("VM '{}' not found", source))?;
+    if config.vms.contains_key(name) {
+        return Err(format!("a VM named '{}' already exists", name));
+    }
+    if crate::vm_running(&source.name) {
+        return Err(format!("VM '{}' is running; stop it first", source.name));
+    }
+    let vm_dir = crate::vm_folder(name);
+    partial.track(&vm_dir);
+    fs::create_dir_all(&vm_dir).map_err(|e| format!("cannot create {}: {}", vm_dir, e))?;
+    let disk = format!("{}/{}.qcow2", vm_dir, name);
+    let format = crate::nbd::image_format(&source.disk_path())?;
+    run(crate::runner::command("qemu-img").args(["create", "-f", "qcow2", "-F", &format, "-b", &source.disk_path(), &disk]))?;
+    let nvram = firmware::nvram_path(&source.name);
+    if nvram.exists() {
+        fs::copy(&nvram, firmware::nvram_path(name)).map_err(|e| format!("cannot copy {}: {}", nvram.display(), e))?;
+    }
+
+    let mut vm = VMInfo {
+        name: name.to_string(),
+        tags: vec!["ephemeral".to_string()],
+        disk: crate::relative_to_folder(name, &disk),
+        iso: String::new(),
+        cdrom: source.cdrom_path(),
+        images: Vec::new(),
+        snapshots: Vec::new(),
+        slots: None,
+        seed: None,
+        guest_cron: Vec::new(),
+        usb_devices: Vec::new(),
+        pci_devices: Vec::new(),
+        autostart: None,
+        restart_on_crash: false,
+        ..source.clone()
+    };
+    for (i, nic) in vm.nics.iter_mut().enumerate() {
+        nic.mac = network::generate_mac(name, i);
+        if let Some(forwards) = nic.backend.forwards_mut() {
+            forwards.clear();
+        }
+    }
+    Ok(vm)
+}
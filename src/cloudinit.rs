@@ -0,0 +1,212 @@
+use crate::config::VMInfo;
+use crate::run;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command as ShellCommand;
+use tracing::debug;
+
+/// NoCloud data handed to cloud-init on first boot.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CloudInit {
+    /// `#cloud-config` document or script.
+    pub user_data: String,
+    /// Defaults to an instance id and hostname taken from the VM name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta_data: Option<String>,
+}
+
+fn seed_dir(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("cloud-init")
+}
+
+/// Writes the seed ISO (volume label `cidata`) into the VM folder and
+/// returns its path.
+pub fn create_seed(vm_name: &str, data: &CloudInit) -> Result<String, String> {
+    let dir = seed_dir(vm_name);
+    fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let meta_data = data
+        .meta_data
+        .clone()
+        .unwrap_or_else(|| format!("instance-id: {}\nlocal-hostname: {}\n", vm_name, vm_name));
+    fs::write(dir.join("user-data"), &data.user_data).map_err(|e| format!("cannot write user-data: {}", e))?;
+    fs::write(dir.join("meta-data"), meta_data).map_err(|e| format!("cannot write meta-data: {}", e))?;
+
+    let iso = PathBuf::from(crate::vm_folder(vm_name)).join("seed.iso");
+    for tool in ["genisoimage", "mkisofs", "xorrisofs"] {
+        let mut cmd = ShellCommand::new(tool);
+        cmd.arg("-output").arg(&iso).args(["-volid", "cidata", "-joliet", "-rock"]);
+        cmd.arg(dir.join("user-data")).arg(dir.join("meta-data"));
+        match run(&mut cmd) {
+            Ok(_) => return Ok(iso.display().to_string()),
+            Err(e) => debug!("{} did not build the seed: {}", tool, e),
+        }
+    }
+    // Without an ISO tool the seed is written directly; plain ISO 9660 is
+    // all cloud-init needs.
+    let meta_data = fs::read(dir.join("meta-data")).map_err(|e| format!("cannot read meta-data: {}", e))?;
+    let image = iso9660("cidata", &[("meta-data", &meta_data), ("user-data", data.user_data.as_bytes())]);
+    fs::write(&iso, image).map_err(|e| format!("cannot write {}: {}", iso.display(), e))?;
+    Ok(iso.display().to_string())
+}
+
+/// User data for a cloud image created without any: the VM name as
+/// hostname and the host user's SSH public keys for the image's default
+/// user.
+pub fn default_user_data(vm_name: &str) -> Result<String, String> {
+    let ssh_dir = PathBuf::from(crate::expand_path("~/.ssh"));
+    let mut keys: Vec<String> = fs::read_dir(&ssh_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "pub"))
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect();
+    keys.sort();
+    if keys.is_empty() {
+        return Err(format!("no SSH public key in {}; pass --user-data", ssh_dir.display()));
+    }
+    let mut text = format!("#cloud-config\nhostname: {}\nssh_authorized_keys:\n", vm_name);
+    for key in keys {
+        text.push_str(&format!("  - {}\n", key));
+    }
+    Ok(text)
+}
+
+const SECTOR: usize = 2048;
+
+fn both_u16(value: u16) -> [u8; 4] {
+    let (le, be) = (value.to_le_bytes(), value.to_be_bytes());
+    [le[0], le[1], be[0], be[1]]
+}
+
+fn both_u32(value: u32) -> [u8; 8] {
+    let (le, be) = (value.to_le_bytes(), value.to_be_bytes());
+    [le[0], le[1], le[2], le[3], be[0], be[1], be[2], be[3]]
+}
+
+/// An ISO 9660 directory record; `name` is raw (`\0` for `.`, `\x01`
+/// for `..`).
+fn dir_record(name: &[u8], extent: u32, size: u32, directory: bool) -> Vec<u8> {
+    let len = 33 + name.len() + (name.len() + 1) % 2;
+    let mut record = vec![0u8; len];
+    record[0] = len as u8;
+    record[2..10].copy_from_slice(&both_u32(extent));
+    record[10..18].copy_from_slice(&both_u32(size));
+    record[18] = 70; // 1970-01-01, a fixed date keeps seeds reproducible
+    record[19] = 1;
+    record[20] = 1;
+    record[25] = if directory { 2 } else { 0 };
+    record[28..32].copy_from_slice(&both_u16(1));
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+    record
+}
+
+/// A single-directory ISO 9660 image holding `files`, which must be
+/// sorted by name. Names are stored upper-case with a `;1` version, which
+/// Linux shows as the lower-case name.
+fn iso9660(volume_id: &str, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let sectors = |len: usize| len.div_ceil(SECTOR).max(1);
+    const PVD: usize = 16;
+    const L_TABLE: usize = 18;
+    const M_TABLE: usize = 19;
+    const ROOT: usize = 20;
+    let mut extents = Vec::new();
+    let mut next = ROOT + 1;
+    for (_, data) in files {
+        extents.push(next);
+        next += sectors(data.len());
+    }
+    // Trailing zero sectors, as genisoimage adds: readers that read ahead
+    // of the last file would otherwise fail at the end of the image.
+    next += 150;
+    let mut image = vec![0u8; next * SECTOR];
+
+    let root = dir_record(&[0], ROOT as u32, SECTOR as u32, true);
+    let pvd = &mut image[PVD * SECTOR..(PVD + 1) * SECTOR];
+    pvd[0] = 1;
+    pvd[1..6].copy_from_slice(b"CD001");
+    pvd[6] = 1;
+    pvd[8..72].fill(b' ');
+    pvd[40..40 + volume_id.len().min(32)].copy_from_slice(&volume_id.as_bytes()[..volume_id.len().min(32)]);
+    pvd[80..88].copy_from_slice(&both_u32(next as u32));
+    pvd[120..124].copy_from_slice(&both_u16(1));
+    pvd[124..128].copy_from_slice(&both_u16(1));
+    pvd[128..132].copy_from_slice(&both_u16(SECTOR as u16));
+    pvd[132..140].copy_from_slice(&both_u32(10));
+    pvd[140..144].copy_from_slice(&(L_TABLE as u32).to_le_bytes());
+    pvd[148..152].copy_from_slice(&(M_TABLE as u32).to_be_bytes());
+    pvd[156..190].copy_from_slice(&root);
+    pvd[190..813].fill(b' ');
+    for date in [813, 830, 847, 864] {
+        pvd[date..date + 16].fill(b'0');
+    }
+    pvd[881] = 1;
+
+    let terminator = &mut image[(PVD + 1) * SECTOR..(PVD + 2) * SECTOR];
+    terminator[0] = 255;
+    terminator[1..6].copy_from_slice(b"CD001");
+    terminator[6] = 1;
+
+    // One path table entry, for the root directory.
+    for (table, extent) in [(L_TABLE, (ROOT as u32).to_le_bytes()), (M_TABLE, (ROOT as u32).to_be_bytes())] {
+        let entry = &mut image[table * SECTOR..table * SECTOR + 10];
+        entry[0] = 1;
+        entry[2..6].copy_from_slice(&extent);
+        entry[6..8].copy_from_slice(&if table == L_TABLE { 1u16.to_le_bytes() } else { 1u16.to_be_bytes() });
+    }
+
+    let mut records = root.clone();
+    records.extend(dir_record(&[1], ROOT as u32, SECTOR as u32, true));
+    for ((name, data), extent) in files.iter().zip(&extents) {
+        let name = format!("{};1", name.to_ascii_uppercase());
+        records.extend(dir_record(name.as_bytes(), *extent as u32, data.len() as u32, false));
+        image[extent * SECTOR..extent * SECTOR + data.len()].copy_from_slice(data);
+    }
+    image[ROOT * SECTOR..ROOT * SECTOR + records.len()].copy_from_slice(&records);
+    image
+}
+
+/// Attaches the seed as a second, read-only CD drive.
+pub fn launch_args(vm: &VMInfo) -> Vec<String> {
+    match &vm.seed {
+        Some(seed) => vec!["-drive".to_string(), format!("file={},media=cdrom,readonly=on", crate::resolve_path(&vm.name, seed))],
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Finds a file in the root directory of an image written by `iso9660`.
+    fn read_file(image: &[u8], name: &str) -> Option<Vec<u8>> {
+        let root = u32::from_le_bytes(image[16 * SECTOR + 158..16 * SECTOR + 162].try_into().unwrap()) as usize;
+        let mut offset = root * SECTOR;
+        while image[offset] != 0 {
+            let record = &image[offset..offset + image[offset] as usize];
+            let extent = u32::from_le_bytes(record[2..6].try_into().unwrap()) as usize;
+            let size = u32::from_le_bytes(record[10..14].try_into().unwrap()) as usize;
+            if &record[33..33 + record[32] as usize] == name.as_bytes() {
+                return Some(image[extent * SECTOR..extent * SECTOR + size].to_vec());
+            }
+            offset += record.len();
+        }
+        None
+    }
+
+    #[test]
+    fn seed_image_is_labelled_and_holds_both_files() {
+        let user_data = "#cloud-config\n".repeat(200);
+        let image = iso9660("cidata", &[("meta-data", b"instance-id: web\n"), ("user-data", user_data.as_bytes())]);
+        assert_eq!(image.len() % SECTOR, 0);
+        assert_eq!(&image[16 * SECTOR + 1..16 * SECTOR + 6], b"CD001");
+        assert_eq!(&image[16 * SECTOR + 40..16 * SECTOR + 72], format!("{:<32}", "cidata").as_bytes());
+        assert_eq!(read_file(&image, "META-DATA;1").unwrap(), b"instance-id: web\n");
+        assert_eq!(read_file(&image, "USER-DATA;1").unwrap(), user_data.as_bytes());
+    }
+}
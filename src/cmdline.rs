@@ -0,0 +1,293 @@
+use crate::config::{VMConfig, VMInfo};
+use crate::display::Console;
+use crate::media::{self, BootMedia};
+use crate::storage::{self, DiskLocation};
+use crate::{agent, apparmor, arch, callback, clock, cloudinit, display, firmware, ksm, network, pidfile, pressure, qmp, runprofile, sandbox, serial, share, size, suspend, usb, vfio};
+
+/// What the command line depends on beyond the VM definition, probed
+/// before building it so `qemu_args` itself touches nothing on the host.
+#[derive(Default)]
+pub struct Host {
+    /// OVMF code image for a UEFI VM.
+    pub ovmf_code: Option<String>,
+    /// Bytes of free hugepages.
+    pub free_hugepages: u64,
+    pub root: bool,
+    pub vhost_net: bool,
+    pub disk: Option<DiskLocation>,
+    /// The guest runs under TCG: its architecture is not this machine's,
+    /// the host has no usable KVM, or the VM asks for it.
+    pub emulated: bool,
+}
+
+impl Host {
+    pub fn probe(vm: &VMInfo) -> Host {
+        Host {
+            ovmf_code: firmware::installed_code(vm).map(str::to_string),
+            free_hugepages: runprofile::hugepages_free(),
+            root: sandbox::is_root(),
+            vhost_net: network::vhost_usable(),
+            disk: storage::locate(&vm.disk_path()),
+            emulated: arch::emulated(vm, arch::kvm_unavailable().is_none()),
+        }
+    }
+}
+
+/// Choices that apply to a single boot.
+#[derive(Debug, Clone, Default)]
+pub struct Boot {
+    /// What the CD drive holds.
+    pub media: BootMedia,
+    pub headless: bool,
+    /// Remote console replacing the VM's own VNC setting.
+    pub console: Option<Console>,
+    /// Load the state `suspend` saved instead of booting.
+    pub resume: bool,
+}
+
+impl Boot {
+    /// A boot as the VM is configured.
+    pub fn configured(vm: &VMInfo, headless: bool) -> Boot {
+        Boot { media: BootMedia::configured(vm), headless, console: None, resume: false }
+    }
+}
+
+/// The QEMU invocation for a VM, program first, built as separate
+/// arguments so names and paths reach QEMU unchanged.
+pub fn qemu_args(config: &VMConfig, vm: &VMInfo, boot: &Boot, host: &Host) -> Vec<String> {
+    let mut argv = apparmor::exec_prefix(vm);
+    argv.push(arch::of(vm).qemu_binary());
+    argv.extend(["-name".to_string(), vm.name.clone()]);
+    argv.extend(["-m".to_string(), size::memory(&vm.memory).unwrap_or_else(|_| vm.memory.clone())]);
+    argv.extend(["-cpu".to_string(), arch::cpu_model(vm, host.emulated)]);
+    argv.extend(["-smp".to_string(), vm.threads.clone()]);
+    argv.extend(arch::launch_args(vm, host.emulated, boot.headless));
+    argv.extend(firmware::launch_args(vm, host.ovmf_code.as_deref()));
+    let memory = runprofile::launch_args(&config.settings, vm, host.free_hugepages);
+    argv.extend(share::memory_args(vm, !memory.is_empty()).unwrap_or(memory));
+    argv.extend(storage::drive_args(vm, host.disk.as_ref()));
+    argv.extend(media::launch_args(&boot.media, arch::of(vm)));
+    argv.extend(network::nic_args(config, vm, host.vhost_net));
+    argv.extend(qmp::launch_args(&vm.name));
+    argv.extend(pidfile::launch_args(&vm.name));
+    argv.extend(display::launch_args(vm, boot.console.as_ref()));
+    argv.extend(serial::launch_args(&vm.name));
+    argv.extend(usb::launch_args(vm));
+    argv.extend(vfio::launch_args(vm));
+    argv.extend(share::launch_args(vm));
+    argv.extend(clock::launch_args(vm));
+    if vm.guest_agent {
+        argv.extend(agent::launch_args(&vm.name));
+    }
+    argv.extend(callback::launch_args(vm));
+    argv.extend(cloudinit::launch_args(vm));
+    argv.extend(pressure::launch_args(vm));
+    argv.extend(ksm::launch_args(vm));
+    argv.extend(sandbox::launch_args(config, vm, host.root));
+    if boot.resume {
+        argv.extend(["-loadvm".to_string(), suspend::SNAPSHOT.to_string()]);
+    }
+    if boot.headless {
+        argv.extend(["-display".to_string(), "none".to_string()]);
+    }
+    argv
+}
+
+/// Golden files in `tests/golden`, one argument per line with the VM
+/// directory written as `$VMS`. After an intended change, regenerate them
+/// with `UPDATE_GOLDEN=1 cargo test` and review the diff.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn vm_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join("srqemu-golden");
+        crate::set_vm_dir(Some(dir.display().to_string()));
+        dir
+    }
+
+    fn vm(toml: &str) -> VMInfo {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn check(case: &str, argv: Vec<String>) {
+        let dir = vm_dir().display().to_string();
+        let text: String = argv.iter().map(|arg| arg.replace(&dir, "$VMS") + "\n").collect();
+        let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("{}.args", case));
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&golden, &text).unwrap();
+            return;
+        }
+        let expected = fs::read_to_string(&golden)
+            .unwrap_or_else(|e| panic!("{}: {}; create it with UPDATE_GOLDEN=1", golden.display(), e));
+        assert_eq!(expected, text, "command line of '{}' differs from {}", case, golden.display());
+    }
+
+    #[test]
+    fn bios_with_port_forward() {
+        vm_dir();
+        let vm = vm(r#"
+            name = "plain"
+            memory = "2G"
+            cpu = "host"
+            threads = "2"
+            disk = "plain.qcow2"
+            iso = ""
+            [[nics]]
+            type = "user"
+            mac = "52:54:00:12:34:56"
+            hostfwd = [{ proto = "tcp", host_port = 2222, guest_port = 22 }]
+        "#);
+        let argv = qemu_args(&VMConfig::default(), &vm, &Boot::configured(&vm, false), &Host::default());
+        check("bios_with_port_forward", argv);
+    }
+
+    #[test]
+    fn uefi_hardened_with_vnc() {
+        vm_dir();
+        let vm = vm(r#"
+            name = "secure"
+            memory = "4096"
+            cpu = "host"
+            threads = "4"
+            firmware = "uefi"
+            disk = "secure.qcow2"
+            iso = ""
+            usb = "xhci"
+            [display]
+            model = "virtio"
+            resolution = "1920x1080"
+            vnc = ":3"
+            keymap = "de"
+            [time]
+            rtc_base = "localtime"
+            driftfix = true
+            hpet = false
+            kvmclock = false
+            [hardening]
+            seccomp = true
+            apparmor = true
+        "#);
+        let host = Host { ovmf_code: Some("/usr/share/OVMF/OVMF_CODE_4M.fd".to_string()), ..Default::default() };
+        let argv = qemu_args(&VMConfig::default(), &vm, &Boot::configured(&vm, false), &host);
+        check("uefi_hardened_with_vnc", argv);
+    }
+
+    #[test]
+    fn cloud_init_first_boot() {
+        let dir = vm_dir();
+        let vm = vm(r#"
+            name = "cloud"
+            memory = "1.5G"
+            cpu = "host"
+            threads = "1"
+            disk = "cloud.qcow2"
+            iso = "/isos/installer.iso"
+            seed = "cloud-init/seed.iso"
+            guest_agent = true
+            memory_pressure = "balloon"
+            mem_merge = false
+        "#);
+        assert_eq!(vm.disk_path(), dir.join("cloud/cloud.qcow2").display().to_string());
+        let boot = Boot { media: BootMedia::install(&vm), headless: true, console: None, resume: false };
+        let argv = qemu_args(&VMConfig::default(), &vm, &boot, &Host::default());
+        check("cloud_init_first_boot", argv);
+    }
+
+    #[test]
+    fn nvme_and_virtio_on_bridge() {
+        vm_dir();
+        let vm = vm(r#"
+            name = "fast"
+            memory = "8G"
+            cpu = "host"
+            threads = "4"
+            disk = "fast.qcow2"
+            disk_cache = "none"
+            iso = ""
+            cdrom = "/isos/tools.iso"
+            [disk_device]
+            bus = "nvme"
+            [[nics]]
+            type = "bridge"
+            bridge = "br0"
+            mac = "52:54:00:aa:bb:01"
+            virtio = { vhost = true }
+            [[nics]]
+            type = "user"
+            mac = "52:54:00:aa:bb:02"
+        "#);
+        let mut config = VMConfig::default();
+        config.settings.run_profile = Some("performance".to_string());
+        let host = Host { free_hugepages: 16 << 30, vhost_net: true, ..Default::default() };
+        let vm = runprofile::apply(&config.settings, &vm);
+        let argv = qemu_args(&config, &vm, &Boot::configured(&vm, true), &host);
+        check("nvme_and_virtio_on_bridge", argv);
+    }
+
+    #[test]
+    fn vfio_gpu_with_audio() {
+        vm_dir();
+        let vm = vm(r#"
+            name = "gaming"
+            memory = "16G"
+            cpu = "host"
+            threads = "8"
+            disk = "gaming.qcow2"
+            iso = ""
+            [[pci_devices]]
+            address = "0000:01:00.0"
+            primary_gpu = true
+            romfile = "/usr/share/vgabios/gpu.rom"
+            [[pci_devices]]
+            address = "0000:01:00.1"
+            [[pci_devices]]
+            address = "0000:05:00.0"
+        "#);
+        let argv = qemu_args(&VMConfig::default(), &vm, &Boot::configured(&vm, true), &Host::default());
+        check("vfio_gpu_with_audio", argv);
+    }
+
+    #[test]
+    fn virtiofs_and_9p_shares() {
+        vm_dir();
+        let vm = vm(r#"
+            name = "dev"
+            memory = "4G"
+            cpu = "host"
+            threads = "4"
+            disk = "dev.qcow2"
+            iso = ""
+            [[shares]]
+            tag = "src"
+            path = "/home/me/src"
+            [[shares]]
+            tag = "media"
+            path = "/srv/media"
+            protocol = "9p"
+            readonly = true
+        "#);
+        let argv = qemu_args(&VMConfig::default(), &vm, &Boot::configured(&vm, true), &Host::default());
+        check("virtiofs_and_9p_shares", argv);
+    }
+
+    #[test]
+    fn aarch64_emulated_from_cdrom() {
+        vm_dir();
+        let vm = vm(r#"
+            name = "arm"
+            arch = "aarch64"
+            memory = "2G"
+            cpu = "host"
+            threads = "2"
+            disk = "arm.qcow2"
+            iso = "/isos/debian-arm64.iso"
+            disk_device = { bus = "virtio-blk", iothread = false }
+        "#);
+        let host = Host { ovmf_code: Some("/usr/share/AAVMF/AAVMF_CODE.fd".to_string()), emulated: true, ..Default::default() };
+        let boot = Boot { media: BootMedia::install(&vm), ..Boot::configured(&vm, false) };
+        check("aarch64_emulated_from_cdrom", qemu_args(&VMConfig::default(), &vm, &boot, &host));
+    }
+}
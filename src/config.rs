@@ -0,0 +1,684 @@
+use crate::arch::{Accel, Arch};
+use crate::autostart::Autostart;
+use crate::callback::Callback;
+use crate::clock::TimeSpec;
+use crate::display::DisplaySpec;
+use crate::firewall::FirewallRule;
+use crate::firmware::Firmware;
+use crate::guestcron::GuestJob;
+use crate::network::{NetworkDef, NicSpec};
+use crate::notify::Notifications;
+use crate::pressure::PressureAction;
+use crate::provenance::ImageRecord;
+use crate::runprofile::RunProfile;
+use crate::schedule::Schedule;
+use crate::share::Share;
+use crate::sandbox::Hardening;
+use crate::slot::Slots;
+use crate::snapshot::Snapshot;
+use crate::storage::DiskDevice;
+use crate::usb::UsbDevice;
+use crate::vfio::PciDevice;
+use crate::error::{self, Error};
+use crate::{interrupt, vault};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
+use tracing::error;
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct VMConfig {
+    pub vms: HashMap<String, VMInfo>,
+    /// Partial VM definitions that VMs can `extends` from. Kept raw so a
+    /// profile only has to mention the fields it cares about.
+    #[serde(default)]
+    pub profiles: HashMap<String, Table>,
+    #[serde(default)]
+    pub settings: Settings,
+    #[serde(default)]
+    pub networks: HashMap<String, NetworkDef>,
+    /// Raw entries that failed to resolve, written back untouched on save.
+    #[serde(skip)]
+    unresolved: Table,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct VMInfo {
+    pub name: String,
+    /// Profile (or other VM) this definition inherits unspecified fields from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<String>,
+    /// Free-form labels for acting on groups of VMs at once.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Color name or `#rrggbb` for the VM's name in listings and its
+    /// launcher icon.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Icon theme name or image file for the VM's launcher and tray entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    pub memory: String,
+    pub cpu: String,
+    pub threads: String,
+    /// Guest architecture; unset is x86_64.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<Arch>,
+    /// Unset uses KVM when the host has it and TCG otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accel: Option<Accel>,
+    /// Unset boots with QEMU's default BIOS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<Firmware>,
+    pub disk: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_device: Option<DiskDevice>,
+    /// QEMU `cache=` mode for the disk; unset keeps QEMU's `writeback`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_cache: Option<String>,
+    pub iso: String,
+    /// Medium kept in the CD drive on every boot; `iso` is only inserted
+    /// to install.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cdrom: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nics: Vec<NicSpec>,
+    /// Who may reach the VM's exposed ports; enforced with nftables while
+    /// it runs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub firewall: Vec<FirewallRule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<DisplaySpec>,
+    /// USB controller model (`xhci`, `ehci`, `uhci` or `none`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usb: Option<String>,
+    /// Host USB devices passed through; see `SRQemu usb`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub usb_devices: Vec<UsbDevice>,
+    /// Host PCI devices passed through with VFIO; see `SRQemu vfio`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pci_devices: Vec<PciDevice>,
+    /// Host folders the guest can mount; see `SRQemu share`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shares: Vec<Share>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<TimeSpec>,
+    /// Add the virtio-serial channel for qemu-ga inside the guest.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub guest_agent: bool,
+    /// Host actions the guest may request over its callback port; none
+    /// leaves the port out.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub guest_callbacks: Vec<Callback>,
+    /// Checksums of the ISO and backing images, taken when the VM started
+    /// using them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageRecord>,
+    /// Internal snapshots of the disk (and, when live, of RAM).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub snapshots: Vec<Snapshot>,
+    /// Second system disk to try upgrades on; see `SRQemu slot`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slots: Option<Slots>,
+    /// cloud-init NoCloud seed ISO, attached next to `iso`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<String>,
+    /// Commands run inside the guest on a schedule.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub guest_cron: Vec<GuestJob>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub autostart: Option<Autostart>,
+    /// Have the daemon start the VM again when QEMU dies without being
+    /// stopped.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub restart_on_crash: bool,
+    /// Host power profile (or cpufreq governor) to switch to while this VM
+    /// runs, e.g. `performance`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_power: Option<String>,
+    /// Marks the VM as low priority: what the memory watcher may do to it
+    /// when the host runs short.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_pressure: Option<PressureAction>,
+    /// Let KSM merge identical pages of this guest with others; unset keeps
+    /// QEMU's default (on).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_merge: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hardening: Option<Hardening>,
+}
+
+impl VMInfo {
+    /// The disk image, with a relative `disk` resolved against the VM
+    /// folder.
+    pub fn disk_path(&self) -> String {
+        crate::resolve_path(&self.name, &self.disk)
+    }
+
+    pub fn iso_path(&self) -> String {
+        crate::resolve_path(&self.name, &self.iso)
+    }
+
+    pub fn cdrom_path(&self) -> Option<String> {
+        self.cdrom.as_ref().map(|cdrom| crate::resolve_path(&self.name, cdrom))
+    }
+}
+
+/// Host-wide preferences that are not tied to a single VM.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Settings {
+    /// Directory holding the VM folders, images and trash; `~/vms` when
+    /// unset. Change it with `SRQemu relocate`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_dir: Option<String>,
+    /// Memory, CPU threads and disk size of a new VM that neither its
+    /// template nor its base sets.
+    pub default_memory: String,
+    pub default_threads: String,
+    pub default_disk_size: String,
+    /// Days a deleted VM stays in `<vm_dir>/.trash` before it is purged.
+    pub trash_days: u64,
+    /// Copies of the config kept in `history/` before deleting, relocating
+    /// or syncing VMs; 0 keeps none.
+    pub config_history: usize,
+    /// Refuse downloaded images unless a trusted key signed their checksums.
+    pub require_signed_images: bool,
+    /// curl `--limit-rate` value for image downloads, e.g. `2M`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_rate_limit: Option<String>,
+    /// Guest filesystems at least this full (in percent) are flagged.
+    pub disk_full_warn_pct: u64,
+    pub notifications: Notifications,
+    /// Minimum seconds between two autostart launches.
+    pub autostart_stagger: u64,
+    /// Most autostart VMs booting at the same time; 0 means no limit.
+    pub autostart_max_parallel: usize,
+    /// Seconds an autostarted VM counts as booting unless its guest agent
+    /// answers sooner.
+    pub autostart_boot_secs: u64,
+    /// Memory PSI `full avg10` percentage at which the watcher steps in.
+    pub pressure_threshold: f64,
+    /// Seconds between memory pressure checks.
+    pub pressure_interval: u64,
+    /// Ballooned VMs shrink to this percentage of their memory.
+    pub balloon_pct: u64,
+    /// Seconds a guest gets to shut down before it is killed.
+    pub shutdown_timeout: u64,
+    /// Seconds external tools may run, keyed `qemu-img info`, `qemu-img`
+    /// or `default`; 0 means no limit. Unset keys keep the built-in limits.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub command_timeouts: HashMap<String, u64>,
+    /// Extra attempts for tool calls that only read state.
+    pub command_retries: u32,
+    /// Times of day at which groups of VMs start and stop, by name.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub schedules: HashMap<String, Schedule>,
+    /// Keep starts, uptime and disk growth per VM for `SRQemu stats`, in a
+    /// file on this machine only.
+    pub usage_stats: bool,
+    /// Active run profile, e.g. `performance` or `battery`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_profile: Option<String>,
+    /// User-defined run profiles, also replacing built-ins of the same name.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub run_profiles: HashMap<String, RunProfile>,
+    /// Language of SRQemu's messages, e.g. `de`; unset follows the system
+    /// locale. `SRQEMU_LANG` overrides it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Named directories new disks can be placed in with `--disk pool=`,
+    /// e.g. `ssd = "/mnt/fast/vms"`.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub pools: HashMap<String, String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            vm_dir: None,
+            default_memory: "4G".to_string(),
+            default_threads: "1".to_string(),
+            default_disk_size: "10G".to_string(),
+            trash_days: 30,
+            config_history: 10,
+            require_signed_images: false,
+            download_rate_limit: None,
+            disk_full_warn_pct: 90,
+            notifications: Notifications::default(),
+            autostart_stagger: 5,
+            autostart_max_parallel: 0,
+            autostart_boot_secs: 60,
+            pressure_threshold: 10.0,
+            pressure_interval: 5,
+            balloon_pct: 50,
+            shutdown_timeout: 60,
+            command_timeouts: HashMap::new(),
+            command_retries: 2,
+            schedules: HashMap::new(),
+            usage_stats: false,
+            run_profile: None,
+            run_profiles: HashMap::new(),
+            language: None,
+            pools: HashMap::new(),
+        }
+    }
+}
+
+const CONFIG_FILE: &str = "qemuctl";
+
+/// Fields that always belong to the VM itself and are never dropped as
+/// "inherited" when saving, even if they happen to match the base.
+const OWN_FIELDS: &[&str] = &["name", "extends"];
+
+/// Deserializes one top-level table. A section that does not fit is an
+/// error: falling back to its default would be saved over the user's
+/// settings.
+fn section<T: DeserializeOwned + Default>(raw: &Table, key: &str, text: Option<&str>) -> Result<T, String> {
+    match raw.get(key) {
+        Some(v) => v.clone().try_into().map_err(|e| locate(text, key, &e.to_string())),
+        None => Ok(T::default()),
+    }
+}
+
+/// A table at the top level, which must be one if present.
+fn top_table(raw: &Table, key: &str, text: Option<&str>) -> Result<Table, String> {
+    match raw.get(key) {
+        Some(Value::Table(t)) => Ok(t.clone()),
+        Some(_) => Err(locate(text, key, "expected a table")),
+        None => Ok(Table::new()),
+    }
+}
+
+/// The 1-based line that defines `path` (e.g. `vms.web.display`) in
+/// hand-written TOML: its table header or its `key = value` line. Keys
+/// inside inline tables resolve to the line of the inline table.
+fn line_of(text: &str, path: &str) -> Option<usize> {
+    let normalize = |key: &str| key.split('.').map(|part| part.trim().trim_matches(['"', '\''])).collect::<Vec<_>>().join(".");
+    let mut table = String::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[') {
+            table = normalize(header.trim_start_matches('[').split(']').next().unwrap_or_default());
+            if table == path {
+                return Some(i + 1);
+            }
+        } else if let Some((key, value)) = line.split_once('=')
+            && !line.starts_with('#')
+        {
+            let key = normalize(key);
+            let full = if table.is_empty() { key } else { format!("{}.{}", table, key) };
+            if full == path || (value.trim_start().starts_with('{') && path.starts_with(&format!("{}.", full))) {
+                return Some(i + 1);
+            }
+        }
+    }
+    None
+}
+
+/// A deserialization error for the table at `base`, pointed at the field
+/// it names (or the table) and its line in `text`.
+fn locate(text: Option<&str>, base: &str, message: &str) -> String {
+    let (message, path) = match message.split_once(" for key `") {
+        Some((message, key)) => (message, format!("{}.{}", base, key.split('`').next().unwrap_or_default())),
+        None => (message, base.to_string()),
+    };
+    match text.and_then(|t| line_of(t, &path).or_else(|| line_of(t, base))) {
+        Some(line) => format!("line {}, `{}`: {}", line, path, message),
+        None => format!("`{}`: {}", path, message),
+    }
+}
+
+fn plain_path() -> PathBuf {
+    confy::get_configuration_file_path(CONFIG_FILE, None).unwrap_or_else(|_| PathBuf::from(CONFIG_FILE))
+}
+
+/// The active config file: the encrypted one once encryption is on.
+pub fn active_path() -> PathBuf {
+    if vault::enabled(CONFIG_FILE) { vault::encrypted_path(CONFIG_FILE).unwrap_or_else(plain_path) } else { plain_path() }
+}
+
+/// Where the config as it was before the last save is kept.
+fn backup_path() -> PathBuf {
+    backup_of(&active_path())
+}
+
+fn backup_of(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".bak");
+    PathBuf::from(path)
+}
+
+/// Fails rather than falling back to an empty config, which would
+/// overwrite the real one on the next save.
+pub fn load_config() -> Result<VMConfig, Error> {
+    let path = active_path();
+    let backup = backup_path();
+    let invalid = |message: String| {
+        let hint = if backup.exists() { format!("\nThe configuration before the last save is in {}.", backup.display()) } else { String::new() };
+        Error::ConfigInvalid { path: path.clone(), message: message + &hint }
+    };
+    let text = if vault::enabled(CONFIG_FILE) {
+        vault::read(CONFIG_FILE).map_err(|message| Error::ConfigRead { path: path.clone(), message })?
+    } else {
+        match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(Error::ConfigRead { path: path.clone(), message: e.to_string() }),
+        }
+    };
+    // A file truncated by a crash or a full disk would otherwise read as a
+    // fresh install.
+    if text.trim().is_empty() && path.exists() && fs::metadata(&backup).is_ok_and(|m| m.len() > 0) {
+        return Err(invalid("the file is empty".to_string()));
+    }
+    let raw: Table = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+    resolve_config(raw, Some(&text)).map_err(invalid)
+}
+
+/// Resolves the on-disk layout (sparse VMs, profiles) into a config.
+pub fn from_raw(raw: Table) -> Result<VMConfig, String> {
+    resolve_config(raw, None)
+}
+
+/// `from_raw` for a config read from `text`, whose errors then name the
+/// offending line. A VM that does not resolve is skipped with an error and
+/// written back untouched; everything else has to be valid.
+fn resolve_config(raw: Table, text: Option<&str>) -> Result<VMConfig, String> {
+    let profiles: HashMap<String, Table> = top_table(&raw, "profiles", text)?
+        .iter()
+        .filter_map(|(k, v)| v.as_table().map(|t| (k.clone(), t.clone())))
+        .collect();
+    let settings = section(&raw, "settings", text)?;
+    let networks = section(&raw, "networks", text)?;
+    let raw_vms = top_table(&raw, "vms", text)?;
+
+    let mut vms = HashMap::new();
+    let mut unresolved = Table::new();
+    for (name, raw_vm) in &raw_vms {
+        let base = format!("vms.{}", name);
+        let vm = resolve(name, &raw_vms, &profiles, &mut Vec::new()).map_err(|e| locate(text, &base, &e)).and_then(|t| {
+            Value::Table(t).try_into::<VMInfo>().map_err(|e| locate(text, &base, &e.to_string()))
+        });
+        match vm {
+            Ok(vm) => {
+                vms.insert(name.clone(), vm);
+            }
+            Err(e) => {
+                error!("Skipping VM '{}': {}", name, e);
+                unresolved.insert(name.clone(), raw_vm.clone());
+            }
+        }
+    }
+
+    Ok(VMConfig { vms, profiles, settings, networks, unresolved })
+}
+
+impl VMConfig {
+    /// Resolved fields that a VM declaring `extends = parent` would inherit.
+    pub fn inherited(&self, parent: &str) -> Result<Table, String> {
+        resolve_parent(parent, &self.raw_vms(), &self.profiles, &mut Vec::new())
+    }
+
+    fn raw_vms(&self) -> Table {
+        let mut raw_vms = Table::new();
+        for (name, vm) in &self.vms {
+            match Value::try_from(vm) {
+                Ok(table @ Value::Table(_)) => {
+                    raw_vms.insert(name.clone(), table);
+                }
+                _ => error!("Failed to serialize VM '{}'", name),
+            }
+        }
+        raw_vms
+    }
+}
+
+/// Copies the config about to be replaced to `backup_path()`. A damaged or
+/// empty file is not copied, so the backup stays the last one SRQemu could
+/// read; an encrypted one cannot be checked without its passphrase.
+fn keep_backup() -> Result<(), Error> {
+    let Ok(current) = fs::read(active_path()) else {
+        return Ok(());
+    };
+    let readable = vault::enabled(CONFIG_FILE)
+        || std::str::from_utf8(&current).is_ok_and(|text| !text.trim().is_empty() && toml::from_str::<Table>(text).is_ok());
+    if !readable {
+        return Ok(());
+    }
+    let backup = backup_path();
+    fs::write(&backup, current).map_err(|e| Error::ConfigWrite { path: backup, message: e.to_string() })
+}
+
+/// Writes the config; Ctrl-C waits for the write, which would otherwise
+/// leave a truncated file.
+pub fn save_config(config: &VMConfig) -> Result<(), Error> {
+    let raw = to_raw(config);
+    interrupt::critical(|| {
+        keep_backup()?;
+        if vault::enabled(CONFIG_FILE) {
+            let path = active_path();
+            toml::to_string(&Value::Table(raw))
+                .map_err(|e| e.to_string())
+                .and_then(|text| vault::write(CONFIG_FILE, &text))
+                .map_err(|message| Error::ConfigWrite { path, message })
+        } else {
+            confy::store(CONFIG_FILE, None, raw).map_err(|e| Error::ConfigWrite { path: plain_path(), message: error::chain(&e) })
+        }
+    })
+}
+
+/// Moves the config between confy's plain file and the encrypted one.
+/// Encrypting also encrypts the plain file's backup and checkpoints and
+/// returns how many checkpoints there were; decrypting drops the
+/// encrypted backup, which the next save replaces.
+pub fn set_encrypted(config: &VMConfig, encrypt: bool) -> Result<usize, String> {
+    let raw = to_raw(config);
+    let plain = confy::get_configuration_file_path(CONFIG_FILE, None).map_err(|e| e.to_string())?;
+    let encrypted = vault::encrypted_path(CONFIG_FILE).ok_or("cannot locate the config directory")?;
+    if encrypt {
+        let text = toml::to_string(&Value::Table(raw)).map_err(|e| e.to_string())?;
+        vault::write(CONFIG_FILE, &text)?;
+        if plain.exists() {
+            fs::remove_file(&plain).map_err(|e| format!("cannot remove the plain config {}: {}", plain.display(), e))?;
+        }
+        let backup = backup_of(&plain);
+        if let Ok(contents) = fs::read(&backup) {
+            vault::write_to(&backup_of(&encrypted), &contents)?;
+            fs::remove_file(&backup).map_err(|e| format!("cannot remove the plain backup {}: {}", backup.display(), e))?;
+        }
+        crate::history::encrypt_plain(&plain)
+    } else {
+        confy::store(CONFIG_FILE, None, raw).map_err(|e| e.to_string())?;
+        fs::remove_file(&encrypted).map_err(|e| format!("cannot remove {}: {}", encrypted.display(), e))?;
+        let _ = fs::remove_file(backup_of(&encrypted));
+        Ok(0)
+    }
+}
+
+pub fn is_encrypted() -> bool {
+    vault::enabled(CONFIG_FILE)
+}
+
+/// The on-disk layout: VMs keep only what they do not inherit.
+pub fn to_raw(config: &VMConfig) -> Table {
+    // Only strip inherited fields once every VM is serialized, since a VM can
+    // extend another VM rather than a profile.
+    let mut full = config.raw_vms();
+    for (name, raw_vm) in &config.unresolved {
+        full.entry(name.clone()).or_insert_with(|| raw_vm.clone());
+    }
+    let mut raw_vms = full.clone();
+    for (name, vm) in &config.vms {
+        let Some(parent) = &vm.extends else { continue };
+        let base = match resolve_parent(parent, &full, &config.profiles, &mut vec![name.clone()]) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Saving VM '{}' without inheritance: {}", name, e);
+                continue;
+            }
+        };
+        if let Some(Value::Table(t)) = raw_vms.get_mut(name) {
+            let inherited: Vec<String> = t
+                .iter()
+                .filter(|(k, v)| !OWN_FIELDS.contains(&k.as_str()) && base.get(*k) == Some(*v))
+                .map(|(k, _)| k.clone())
+                .collect();
+            for k in inherited {
+                t.remove(&k);
+            }
+        }
+    }
+
+    let mut raw = Table::new();
+    if let Ok(settings) = Value::try_from(&config.settings) {
+        raw.insert("settings".into(), settings);
+    }
+    if !config.networks.is_empty()
+        && let Ok(networks) = Value::try_from(&config.networks)
+    {
+        raw.insert("networks".into(), networks);
+    }
+    raw.insert("vms".into(), Value::Table(raw_vms));
+    if !config.profiles.is_empty() {
+        let profiles = config
+            .profiles
+            .iter()
+            .map(|(k, v)| (k.clone(), Value::Table(v.clone())))
+            .collect();
+        raw.insert("profiles".into(), Value::Table(profiles));
+    }
+    raw
+}
+
+/// Fully merged table for a VM, following its `extends` chain.
+fn resolve(
+    name: &str,
+    raw_vms: &Table,
+    profiles: &HashMap<String, Table>,
+    stack: &mut Vec<String>,
+) -> Result<Table, String> {
+    let own = raw_vms
+        .get(name)
+        .and_then(Value::as_table)
+        .ok_or_else(|| format!("'{}' is not a table", name))?;
+    stack.push(name.to_string());
+    let merged = match own.get("extends").and_then(Value::as_str) {
+        Some(parent) => {
+            let mut base = resolve_parent(parent, raw_vms, profiles, stack)?;
+            merge(&mut base, own);
+            base
+        }
+        None => own.clone(),
+    };
+    stack.pop();
+    Ok(merged)
+}
+
+/// Resolves what `extends = "<parent>"` refers to: a profile first, then a VM.
+fn resolve_parent(
+    parent: &str,
+    raw_vms: &Table,
+    profiles: &HashMap<String, Table>,
+    stack: &mut Vec<String>,
+) -> Result<Table, String> {
+    if stack.iter().any(|s| s == parent) {
+        return Err(format!("inheritance cycle: {} -> {}", stack.join(" -> "), parent));
+    }
+    if let Some(profile) = profiles.get(parent) {
+        stack.push(parent.to_string());
+        let mut merged = match profile.get("extends").and_then(Value::as_str) {
+            Some(grandparent) => {
+                let mut base = resolve_parent(grandparent, raw_vms, profiles, stack)?;
+                merge(&mut base, profile);
+                base
+            }
+            None => profile.clone(),
+        };
+        stack.pop();
+        // The profile's own lineage is not the VM's.
+        merged.remove("extends");
+        return Ok(merged);
+    }
+    if raw_vms.contains_key(parent) {
+        let mut merged = resolve(parent, raw_vms, profiles, stack)?;
+        merged.remove("extends");
+        merged.remove("name");
+        // NICs carry MAC addresses; two VMs sharing them would collide on
+        // the same bridge.
+        merged.remove("nics");
+        // Firewall rules point at those NICs by index.
+        merged.remove("firewall");
+        // Schedules are installed as timers for the VM that declared them.
+        merged.remove("guest_cron");
+        return Ok(merged);
+    }
+    Err(format!("unknown profile or VM '{}'", parent))
+}
+
+/// Overlays `top` onto `base`; nested tables merge key by key.
+fn merge(base: &mut Table, top: &Table) {
+    for (k, v) in top {
+        match (base.get_mut(k), v) {
+            (Some(Value::Table(b)), Value::Table(t)) => merge(b, t),
+            _ => {
+                base.insert(k.clone(), v.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = r#"
+[settings]
+trash_days = "soon"
+
+[vms.web]
+name = "web"
+memory = 2048
+display = { vnc = ":1" }
+
+[[vms.web.nics]]
+type = "user"
+"#;
+
+    #[test]
+    fn line_of_finds_headers_keys_and_inline_tables() {
+        assert_eq!(line_of(TEXT, "settings"), Some(2));
+        assert_eq!(line_of(TEXT, "settings.trash_days"), Some(3));
+        assert_eq!(line_of(TEXT, "vms.web.memory"), Some(7));
+        assert_eq!(line_of(TEXT, "vms.web.display.vnc"), Some(8));
+        assert_eq!(line_of(TEXT, "vms.web.nics"), Some(10));
+        assert_eq!(line_of(TEXT, "vms.db"), None);
+    }
+
+    #[test]
+    fn invalid_settings_name_the_field_and_line() {
+        let raw: Table = toml::from_str(TEXT).unwrap();
+        let err = resolve_config(raw, Some(TEXT)).unwrap_err();
+        assert!(err.starts_with("line 3, `settings.trash_days`: invalid type"), "{}", err);
+    }
+
+    #[test]
+    fn broken_vm_is_kept_for_saving() {
+        let text = TEXT.replace("trash_days = \"soon\"", "trash_days = 7");
+        let raw: Table = toml::from_str(&text).unwrap();
+        let config = resolve_config(raw, Some(&text)).unwrap();
+        assert!(config.vms.is_empty());
+        assert_eq!(config.settings.trash_days, 7);
+        assert!(to_raw(&config)["vms"].get("web").is_some());
+        assert_eq!(locate(Some(&text), "vms.web", "missing field `cpu`"), "line 5, `vms.web`: missing field `cpu`");
+    }
+
+    #[test]
+    fn non_table_vms_is_an_error() {
+        let raw: Table = toml::from_str("vms = []").unwrap();
+        assert!(resolve_config(raw, None).is_err());
+    }
+}
@@ -0,0 +1,383 @@
+//! The daemon: a long-running process that owns the QEMU processes it
+//! starts. While it listens on its socket, starts and stops from the
+//! command line, the menu, schedules and autostart are handed to it, so
+//! QEMU is its child rather than a detached process nobody waits for. It
+//! notices a VM that dies without being stopped and starts the ones marked
+//! `restart_on_crash` again. Without a daemon, SRQemu launches VMs itself.
+
+use crate::config::{self, VMInfo};
+use crate::ipc::{self, Stream};
+use crate::{guestcron, notify};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+pub const SERVICE: &str = "srqemu-daemon.service";
+/// Crashes within `RESTART_WINDOW` after which a VM is left down.
+const MAX_RESTARTS: usize = 3;
+const RESTART_WINDOW: Duration = Duration::from_secs(600);
+/// Pause before a restart, so a VM that dies right away does not spin.
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set in the daemon itself, which supervises its launches instead of
+/// forwarding them.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// QEMU pid of each supervised VM.
+static CHILDREN: Mutex<BTreeMap<String, u32>> = Mutex::new(BTreeMap::new());
+/// VMs being stopped on purpose; their exit is no crash.
+static STOPPING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+/// Recent crashes per VM, for the restart limit.
+static CRASHES: Mutex<BTreeMap<String, VecDeque<Instant>>> = Mutex::new(BTreeMap::new());
+/// VMs a client is starting or stopping right now.
+static BUSY: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+fn lock<T>(mutex: &'static Mutex<T>) -> MutexGuard<'static, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// One line of JSON from the client.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Request {
+    /// The options of `SRQemu start`.
+    Start {
+        name: String,
+        headless: bool,
+        #[serde(default)]
+        iso: Option<String>,
+        #[serde(default)]
+        boot_cdrom: bool,
+        #[serde(default)]
+        display: Option<String>,
+        #[serde(default)]
+        slot: Option<crate::slot::Slot>,
+    },
+    Stop { name: String, force: bool },
+    Status,
+    /// Every defined VM and whether it runs, for front ends such as the
+    /// tray icon.
+    Vms,
+}
+
+/// A defined VM as `Vms` reports it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct VmState {
+    pub name: String,
+    pub running: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
+    /// For VMs with an icon or color of their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+/// One line of JSON back.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub message: String,
+    /// QEMU pid of each supervised VM, for `Status`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub supervised: BTreeMap<String, u32>,
+    /// Every defined VM, for `Vms`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vms: Vec<VmState>,
+}
+
+impl Response {
+    fn done(message: String) -> Response {
+        Response { ok: true, message, ..Default::default() }
+    }
+
+    fn failed(message: String) -> Response {
+        Response { ok: false, message, ..Default::default() }
+    }
+}
+
+/// Next to the manager log, like the rest of SRQemu's state.
+pub fn socket_path() -> PathBuf {
+    crate::logging::log_file().with_file_name("daemon.sock")
+}
+
+/// Whether this process is the daemon.
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Sends a request to the daemon; None when none listens.
+fn send(request: &Request) -> Option<Result<Response, String>> {
+    let stream = ipc::connect(&socket_path()).ok()?;
+    Some(exchange(stream, request))
+}
+
+fn exchange(mut stream: Stream, request: &Request) -> Result<Response, String> {
+    let line = serde_json::to_string(request).map_err(|e| e.to_string())?;
+    writeln!(stream, "{}", line).map_err(|e| e.to_string())?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).map_err(|e| e.to_string())?;
+    if reply.is_empty() {
+        return Err("the daemon hung up".to_string());
+    }
+    serde_json::from_str(&reply).map_err(|e| format!("unexpected answer: {}", e))
+}
+
+/// Hands `request` to the daemon if one runs and this process is not it,
+/// printing what it answered. Returns whether it succeeded, or None when
+/// the caller has to do the work itself.
+pub fn forward(request: &Request) -> Option<bool> {
+    if active() {
+        return None;
+    }
+    match send(request)? {
+        Ok(response) => {
+            if response.ok {
+                println!("{}", response.message);
+            } else {
+                error!("{}", response.message);
+            }
+            Some(response.ok)
+        }
+        Err(e) => {
+            error!("The daemon did not answer: {}", e);
+            Some(false)
+        }
+    }
+}
+
+/// Marks `name` as stopped on purpose before SRQemu stops it.
+pub fn expect_exit(name: &str) {
+    lock(&STOPPING).insert(name.to_string());
+}
+
+/// Undoes `expect_exit` for a VM that survived its stop, so a later crash
+/// counts as one.
+pub fn cancel_expected_exit(name: &str) {
+    lock(&STOPPING).remove(name);
+}
+
+/// Takes over a QEMU the daemon just launched: waits for it and, if it
+/// dies on its own, cleans up after it and may start the VM again.
+pub fn supervise(vm: &VMInfo, mut qemu: Child, headless: bool) {
+    let name = vm.name.clone();
+    lock(&STOPPING).remove(&name);
+    lock(&CHILDREN).insert(name.clone(), qemu.id());
+    if !vm.guest_callbacks.is_empty() {
+        crate::callback::listen(&name);
+    }
+    std::thread::spawn(move || {
+        let status = qemu.wait();
+        lock(&CHILDREN).remove(&name);
+        if lock(&STOPPING).remove(&name) {
+            return;
+        }
+        crate::qmp::cleanup_runtime(&name);
+        let config = match config::load_config() {
+            Ok(config) => config,
+            Err(e) => {
+                error!("VM '{}' exited; cannot read the configuration: {}", name, e);
+                return;
+            }
+        };
+        crate::stats::stopped(&config.settings, &name);
+        if let Err(e) = crate::firewall::remove(&name) {
+            error!("Failed to remove firewall rules for '{}': {}", name, e);
+        }
+        crate::hostpower::update(&config, Some(&name));
+        match status {
+            // The guest powered off.
+            Ok(status) if status.success() => {
+                info!("VM '{}' shut down.", name);
+                return;
+            }
+            Ok(status) => error!("VM '{}' exited unexpectedly ({}).", name, status),
+            Err(e) => error!("Lost track of VM '{}': {}", name, e),
+        }
+        notify::send(&config.settings.notifications, notify::Event::Crashed { vm: &name });
+        let Some(vm) = config.vms.get(&name).filter(|vm| vm.restart_on_crash) else {
+            return;
+        };
+        if !may_restart(&name) {
+            error!("VM '{}' crashed {} times within {} minutes; leaving it down.", name, MAX_RESTARTS + 1, RESTART_WINDOW.as_secs() / 60);
+            return;
+        }
+        std::thread::sleep(RESTART_DELAY);
+        println!("Restarting VM '{}'.", name);
+        crate::start_vm_common(&config, vm, headless);
+    });
+}
+
+/// Counts a crash and tells whether the VM is still within its restarts.
+fn may_restart(name: &str) -> bool {
+    let now = Instant::now();
+    let mut crashes = lock(&CRASHES);
+    let recent = crashes.entry(name.to_string()).or_default();
+    while recent.front().is_some_and(|at| now.duration_since(*at) > RESTART_WINDOW) {
+        recent.pop_front();
+    }
+    recent.push_back(now);
+    recent.len() <= MAX_RESTARTS
+}
+
+/// Listens for requests until killed. Each client gets a thread of its
+/// own, so a slow start or a client that never sends its request does not
+/// hold up the rest; VMs started before the daemon stay unsupervised.
+pub fn serve() -> Result<(), String> {
+    let path = socket_path();
+    if ipc::connect(&path).is_ok() {
+        return Err(format!("a daemon already listens on {}", path.display()));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    }
+    let listener = Arc::new(ipc::bind(&path).map_err(|e| format!("cannot listen on {}: {}", path.display(), e))?);
+    ACTIVE.store(true, Ordering::SeqCst);
+    println!("Daemon listening on {}.", path.display());
+    loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let listener = Arc::clone(&listener);
+                std::thread::spawn(move || match listener.admit(stream) {
+                    Ok(stream) => serve_one(stream),
+                    Err(e) => warn!("daemon connection refused: {}", e),
+                });
+            }
+            Err(e) => warn!("daemon connection failed: {}", e),
+        }
+    }
+}
+
+fn serve_one(mut stream: Stream) {
+    if let Err(e) = stream.set_read_timeout(Some(REQUEST_TIMEOUT)) {
+        warn!("cannot set a daemon client's timeout: {}", e);
+        return;
+    }
+    let mut line = String::new();
+    if let Err(e) = BufReader::new(&stream).read_line(&mut line) {
+        warn!("cannot read a daemon request: {}", e);
+        return;
+    }
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => {
+            info!("daemon request: {:?}", request);
+            answer(request)
+        }
+        Err(e) => Response::failed(format!("invalid request: {}", e)),
+    };
+    let written = serde_json::to_string(&response).map_err(|e| e.to_string()).and_then(|json| writeln!(stream, "{}", json).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        warn!("cannot answer a daemon client: {}", e);
+    }
+}
+
+/// Carries out a request, refusing to start or stop a VM another client
+/// is already starting or stopping.
+fn answer(request: Request) -> Response {
+    let vm = match &request {
+        Request::Start { name, .. } | Request::Stop { name, .. } => name.clone(),
+        Request::Status | Request::Vms => return answer_now(request),
+    };
+    if !lock(&BUSY).insert(vm.clone()) {
+        return Response::failed(format!("VM '{}' is already being started or stopped.", vm));
+    }
+    let response = answer_now(request);
+    lock(&BUSY).remove(&vm);
+    response
+}
+
+/// Carries out a request with the configuration as it is now, since the
+/// CLI may have changed it since the daemon started.
+fn answer_now(request: Request) -> Response {
+    let config = match config::load_config() {
+        Ok(config) => config,
+        Err(e) => return Response::failed(e.to_string()),
+    };
+    match request {
+        Request::Start { name, headless, iso, boot_cdrom, display, slot } => {
+            let Some(vm) = config.vms.get(&name) else {
+                return Response::failed(format!("VM '{}' not found", name));
+            };
+            if let Err(e) = crate::start_from_cli(&config, vm, headless, iso, boot_cdrom, display, slot) {
+                return Response::failed(format!("Failed to start VM '{}': {}", name, e));
+            }
+            if crate::vm_running(&name) {
+                Response::done(format!("VM '{}' started by the daemon.", name))
+            } else {
+                Response::failed(format!("VM '{}' did not start; the daemon's log {} says why.", name, crate::logging::log_file().display()))
+            }
+        }
+        Request::Stop { name, force } => {
+            match crate::stop_vm_by_name(&config, &name, force) {
+                Ok(()) => Response::done(format!("VM '{}' stopped.", name)),
+                Err(e) => Response::failed(format!("Failed to stop VM '{}': {}", name, e)),
+            }
+        }
+        Request::Status => Response { ok: true, supervised: lock(&CHILDREN).clone(), ..Default::default() },
+        Request::Vms => {
+            let mut vms: Vec<VmState> = config
+                .vms
+                .values()
+                .map(|vm| {
+                    let running = crate::vm_running(&vm.name);
+                    let icon = (vm.icon.is_some() || vm.color.is_some()).then(|| crate::desktop::vm_icon(vm).ok()).flatten();
+                    VmState { name: vm.name.clone(), running, paused: running && crate::qmp::paused(&vm.name), icon }
+                })
+                .collect();
+            vms.sort_by(|a, b| a.name.cmp(&b.name));
+            Response { ok: true, vms, ..Default::default() }
+        }
+    }
+}
+
+/// The defined VMs and their states, as the daemon sees them; None when
+/// no daemon listens.
+pub fn vms() -> Option<Result<Vec<VmState>, String>> {
+    Some(send(&Request::Vms)?.and_then(|response| if response.ok { Ok(response.vms) } else { Err(response.message) }))
+}
+
+/// Prints whether a daemon runs and the VMs it supervises.
+pub fn print_status() {
+    match send(&Request::Status) {
+        None => println!("No daemon is running; VMs are launched directly."),
+        Some(Err(e)) => error!("The daemon did not answer: {}", e),
+        Some(Ok(response)) => {
+            println!("Daemon listening on {}.", socket_path().display());
+            if response.supervised.is_empty() {
+                println!("It supervises no VMs.");
+            }
+            for (name, pid) in &response.supervised {
+                println!("  {} (pid {})", name, pid);
+            }
+        }
+    }
+}
+
+/// Runs the daemon as a systemd user service that starts with the session.
+pub fn install_service() -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("cannot locate this binary: {}", e))?;
+    // KillMode=process: stopping the daemon must not take its VMs down.
+    let unit = format!(
+        "[Unit]\nDescription=SRQemu daemon\n\n[Service]\nExecStart={} daemon\nRestart=on-failure\nKillMode=process\n\n[Install]\nWantedBy=default.target\n",
+        exe.display()
+    );
+    let dir = guestcron::unit_dir()?;
+    fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    fs::write(dir.join(SERVICE), unit).map_err(|e| e.to_string())?;
+    guestcron::systemctl(&["daemon-reload"])?;
+    guestcron::systemctl(&["enable", "--now", SERVICE])
+}
+
+pub fn uninstall_service() -> Result<(), String> {
+    let _ = guestcron::systemctl(&["disable", "--now", SERVICE]);
+    let _ = fs::remove_file(guestcron::unit_dir()?.join(SERVICE));
+    guestcron::systemctl(&["daemon-reload"])
+}
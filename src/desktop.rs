@@ -0,0 +1,180 @@
+//! App-menu launchers for VMs: `desktop-entry` writes a freedesktop
+//! `.desktop` file whose `Exec` runs `SRQemu open`, which starts the VM
+//! with a window or, if it already runs, opens a viewer on its console.
+
+use crate::config::{VMConfig, VMInfo};
+use crate::{display, label};
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::process::{Command as ShellCommand, Stdio};
+
+/// Shared by every launcher: a monitor with a play sign.
+const ICON: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 64 64">
+  <rect x="4" y="8" width="56" height="40" rx="4" fill="#2d3e50"/>
+  <rect x="8" y="12" width="48" height="32" rx="2" fill="#3c8dbc"/>
+  <path d="M27 20 L41 28 L27 36 Z" fill="#ffffff"/>
+  <rect x="26" y="48" width="12" height="6" fill="#2d3e50"/>
+  <rect x="18" y="54" width="28" height="4" rx="2" fill="#2d3e50"/>
+</svg>
+"##;
+
+/// Viewers tried in turn for a running VM's console.
+const VIEWERS: [&str; 2] = ["remote-viewer", "xdg-open"];
+
+/// `~/.local/share`, where desktops look for launchers and icons.
+fn data_home() -> PathBuf {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home::home_dir().unwrap_or_default().join(".local/share"))
+}
+
+pub fn entry_path(vm_name: &str) -> PathBuf {
+    data_home().join("applications").join(format!("srqemu-{}.desktop", vm_name))
+}
+
+fn write_icon(file: &str, svg: &str) -> Result<PathBuf, String> {
+    let icon = data_home().join("icons/hicolor/scalable/apps").join(file);
+    if let Some(dir) = icon.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    }
+    fs::write(&icon, svg).map_err(|e| format!("cannot write {}: {}", icon.display(), e))?;
+    Ok(icon)
+}
+
+/// Writes SRQemu's icon where desktops find it and returns its path.
+pub fn install_icon() -> Result<PathBuf, String> {
+    write_icon("srqemu.svg", ICON)
+}
+
+/// The icon standing for `vm`: its own, SRQemu's with the screen in the
+/// VM's color, or SRQemu's.
+pub fn vm_icon(vm: &VMInfo) -> Result<String, String> {
+    if let Some(icon) = &vm.icon {
+        return Ok(icon.clone());
+    }
+    let icon = match vm.color.as_deref().and_then(label::hex) {
+        Some(hex) => write_icon(&format!("srqemu-{}.svg", vm.name), &ICON.replace("#3c8dbc", hex))?,
+        None => install_icon()?,
+    };
+    Ok(icon.display().to_string())
+}
+
+/// Quotes one `Exec` argument as the Desktop Entry spec asks: reserved
+/// characters force double quotes, inside which `"`, `` ` ``, `$` and `\`
+/// are escaped, and the key's own string escaping doubles backslashes
+/// once more. `%` starts a field code, so it is doubled.
+fn exec_arg(arg: &str) -> String {
+    let arg = arg.replace('%', "%%");
+    if !arg.chars().any(|c| " \t\n\"'\\><~|&;$*?#()`".contains(c)) {
+        return arg;
+    }
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\\\\\"),
+            '"' | '`' | '$' => {
+                quoted.push_str("\\\\");
+                quoted.push(c);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The `.desktop` file opening `vm` with `exe`.
+pub fn entry(vm: &VMInfo, exe: &str, icon: &str) -> String {
+    let mut keywords = vec!["VM".to_string(), "QEMU".to_string()];
+    keywords.extend(vm.tags.iter().cloned());
+    format!(
+        "[Desktop Entry]\nType=Application\nVersion=1.0\nName={}\nComment=Virtual machine managed by SRQemu\nExec={} open {}\nIcon={}\nTerminal=false\nCategories=System;Emulator;\nKeywords={};\nStartupNotify=true\n",
+        vm.name,
+        exec_arg(exe),
+        exec_arg(&vm.name),
+        icon,
+        keywords.join(";")
+    )
+}
+
+/// Writes the VM's launcher to `output`, or into the app menu, along with
+/// its icon, and returns where it went.
+pub fn install(vm: &VMInfo, output: Option<PathBuf>) -> Result<PathBuf, String> {
+    let exe = std::env::current_exe().map_err(|e| format!("cannot locate this binary: {}", e))?;
+    let icon = vm_icon(vm)?;
+    let path = output.unwrap_or_else(|| entry_path(&vm.name));
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    }
+    fs::write(&path, entry(vm, &exe.display().to_string(), &icon)).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+    // Desktops only launch files on the desktop itself when executable.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).map_err(|e| format!("cannot make {} executable: {}", path.display(), e))?;
+    }
+    Ok(path)
+}
+
+/// Takes the VM's launcher out of the app menu.
+pub fn remove(vm_name: &str) -> Result<PathBuf, String> {
+    let path = entry_path(vm_name);
+    fs::remove_file(&path).map_err(|e| format!("cannot remove {}: {}", path.display(), e))?;
+    // A tinted icon is the launcher's alone.
+    let _ = fs::remove_file(data_home().join("icons/hicolor/scalable/apps").join(format!("srqemu-{}.svg", vm_name)));
+    Ok(path)
+}
+
+fn view(console: &display::Console) -> Result<(), String> {
+    let url = console.url();
+    for viewer in VIEWERS {
+        let spawned = crate::process::detach(ShellCommand::new(viewer).arg(&url).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())).spawn();
+        match spawned {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("cannot run {}: {}", viewer, e)),
+        }
+    }
+    Err(crate::error::Error::ToolMissing { program: VIEWERS[0].to_string() }.to_string())
+}
+
+/// What a launcher does: starts the VM with a window, or shows the console
+/// of one already running.
+pub fn open(config: &VMConfig, vm: &VMInfo) -> Result<String, String> {
+    if !crate::vm_running(&vm.name) {
+        crate::start_vm_common(config, vm, false);
+        return match crate::vm_running(&vm.name) {
+            true => Ok(format!("VM '{}' started.", vm.name)),
+            false => Err(format!("VM '{}' did not start; `SRQemu start {}` in a terminal shows why", vm.name, vm.name)),
+        };
+    }
+    match display::recorded(&vm.name) {
+        Some(console) => {
+            view(&console)?;
+            Ok(format!("Opened the console of '{}' at {}.", vm.name, console.url()))
+        }
+        None => Ok(format!("VM '{}' is already running; its window is open unless it was started headless.", vm.name)),
+    }
+}
+
+/// Reports a launcher's outcome where its user sees it: in the terminal,
+/// or as a desktop notification when started from the app menu.
+pub fn report(message: &str) {
+    if io::stderr().is_terminal() || ShellCommand::new("notify-send").args(["-a", "SRQemu", "SRQemu", message]).status().is_err() {
+        eprintln!("{}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_arguments_are_quoted_per_the_spec() {
+        assert_eq!(exec_arg("/usr/bin/SRQemu"), "/usr/bin/SRQemu");
+        assert_eq!(exec_arg("/home/me/My Apps/SRQemu"), "\"/home/me/My Apps/SRQemu\"");
+        assert_eq!(exec_arg("a$b\"c"), "\"a\\\\$b\\\\\"c\"");
+        assert_eq!(exec_arg("50%"), "50%%");
+    }
+}
@@ -0,0 +1,114 @@
+use crate::config::VMInfo;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Child;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// How long QEMU has to survive after launch to count as started.
+const STARTUP_GRACE: Duration = Duration::from_secs(3);
+
+/// QEMU error messages and what usually fixes them, checked in order.
+const KNOWN_ERRORS: &[(&str, &str)] = &[
+    ("failed to execute", "QEMU could not be run at all; install it for the guest's architecture (qemu-system-x86, qemu-system-arm, qemu-system-misc or qemu-full) and make sure it is on PATH."),
+    (
+        "Could not access KVM kernel module: Permission denied",
+        "Your user may not open /dev/kvm. Add it to the kvm group (sudo usermod -aG kvm $USER) and log in again.",
+    ),
+    (
+        "Could not access KVM kernel module: No such file or directory",
+        "KVM is not available. Enable virtualization (VT-x/AMD-V) in the firmware setup and load kvm_intel or kvm_amd, or let the VM run emulated with `SRQemu accel <name> auto`.",
+    ),
+    (
+        "failed to initialize kvm",
+        "KVM could not be initialized; check that no other hypervisor (VirtualBox, VMware) holds the CPU's virtualization extensions.",
+    ),
+    (
+        "Failed to get \"write\" lock",
+        "Another process holds the disk image: a second QEMU with this disk, or a host mount. Stop it or unmount the disk first.",
+    ),
+    (
+        "Could not set up host forwarding rule",
+        "A forwarded host port is already taken. Pick another one under Network > Port forwards, or stop what listens on it.",
+    ),
+    (
+        "There is no option group 'spice'",
+        "This QEMU was built without SPICE. Install its SPICE module (qemu-ui-spice-core or qemu-full) or start with --display vnc.",
+    ),
+    (
+        "Address already in use",
+        "A port or socket QEMU wants to listen on is taken, often the VNC display or a forward. Change it or stop the other user.",
+    ),
+    (
+        "No such file or directory",
+        "A file the VM refers to is missing: check the disk, ISO and seed paths shown by `SRQemu list`.",
+    ),
+    (
+        "bridge helper",
+        "qemu-bridge-helper failed. Allow the bridge in /etc/qemu/bridge.conf (`allow br0`) and make sure the helper is setuid root.",
+    ),
+    (
+        "Operation not permitted",
+        "QEMU lacks a privilege it needs, e.g. to create a tap device. Check the network backend and the VM's sandbox settings.",
+    ),
+    (
+        "Cannot allocate memory",
+        "The host cannot provide the VM's memory. Lower its memory, free RAM, or reserve more hugepages.",
+    ),
+    (
+        "pflash",
+        "The UEFI firmware could not be loaded. Check that OVMF is installed, or switch the VM back to BIOS.",
+    ),
+    (
+        "invalid option",
+        "This QEMU does not know one of the options SRQemu passed; it is probably older than the feature needs. Update QEMU or turn the feature off.",
+    ),
+    (
+        "is not a valid",
+        "QEMU rejected a setting value; the message above names it. Fix it in the VM settings.",
+    ),
+    (
+        "not found",
+        "QEMU does not provide a device or property SRQemu asked for; a newer QEMU or a different model may be needed.",
+    ),
+];
+
+/// Where QEMU's stderr goes; rewritten on every launch.
+pub fn log_path(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("qemu.log")
+}
+
+/// Explanations for the errors QEMU printed, most specific first.
+pub fn explain(stderr: &str) -> Vec<&'static str> {
+    let stderr = stderr.to_lowercase();
+    KNOWN_ERRORS.iter().filter(|(pattern, _)| stderr.contains(&pattern.to_lowercase())).map(|(_, hint)| *hint).take(2).collect()
+}
+
+/// Waits out QEMU's start-up. If it exits in that time, prints its last
+/// messages and what likely went wrong. Returns QEMU if it still runs.
+pub fn watch_start(vm: &VMInfo, mut qemu: Child) -> Option<Child> {
+    let started = Instant::now();
+    let mut exited = false;
+    while !exited && started.elapsed() < STARTUP_GRACE {
+        std::thread::sleep(Duration::from_millis(250));
+        exited = !matches!(qemu.try_wait(), Ok(None));
+    }
+    if !exited {
+        return Some(qemu);
+    }
+    let log = log_path(&vm.name);
+    let stderr = fs::read_to_string(&log).unwrap_or_default();
+    error!("VM '{}' exited right after starting.", vm.name);
+    let lines: Vec<&str> = stderr.lines().filter(|l| !l.trim().is_empty()).collect();
+    for line in &lines[lines.len().saturating_sub(10)..] {
+        error!("  {}", line);
+    }
+    let hints = explain(&stderr);
+    if hints.is_empty() {
+        error!("No known cause matched; the full output is in {}.", log.display());
+    }
+    for hint in hints {
+        error!("Likely cause: {}", hint);
+    }
+    None
+}
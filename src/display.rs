@@ -0,0 +1,405 @@
+use crate::config::{self, VMConfig, VMInfo};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// The VM's graphics card and the mode it advertises to the guest.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DisplaySpec {
+    /// `virtio`, `qxl` or `std`; unset keeps QEMU's default card.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Preferred resolution such as `2560x1440`, offered to the guest
+    /// through the card's EDID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolution: Option<String>,
+    /// VNC display such as `:1`; a host part (`0.0.0.0:1`) overrides
+    /// `listen`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vnc: Option<String>,
+    /// Address the console listens on. Unset means loopback only; anything
+    /// else exposes the console to the network and must be chosen
+    /// explicitly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen: Option<String>,
+    /// Keyboard layout QEMU assumes for VNC clients that send keysyms
+    /// rather than raw scancodes, e.g. `de`. GUI windows ignore it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keymap: Option<String>,
+    /// Delay between the key events QEMU replays to the guest; slow guests
+    /// drop keys from clients that paste or type quickly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vnc_key_delay_ms: Option<u32>,
+}
+
+impl DisplaySpec {
+    pub fn is_empty(&self) -> bool {
+        *self == DisplaySpec::default()
+    }
+}
+
+pub const MODELS: [&str; 3] = ["virtio", "qxl", "std"];
+
+/// Layouts shipped in QEMU's `pc-bios/keymaps`.
+pub const KEYMAPS: [&str; 34] = [
+    "ar", "bepo", "cz", "da", "de", "de-ch", "en-gb", "en-us", "es", "et", "fi", "fo", "fr", "fr-be", "fr-ca",
+    "fr-ch", "hr", "hu", "is", "it", "ja", "lt", "lv", "mk", "nl", "no", "pl", "pt", "pt-br", "ru", "sl", "sv",
+    "th", "tr",
+];
+
+const DEFAULT_LISTEN: &str = "127.0.0.1";
+
+/// The VNC server's `host:display`. QEMU itself binds every interface when
+/// given a bare `:N`, so the host is always filled in.
+pub fn vnc_address(display: &DisplaySpec, vnc: &str) -> String {
+    match vnc.strip_prefix(':') {
+        Some(number) => {
+            let listen = display.listen.as_deref().unwrap_or(DEFAULT_LISTEN);
+            if listen.contains(':') { format!("[{}]:{}", listen, number) } else { format!("{}:{}", listen, number) }
+        }
+        None => vnc.to_string(),
+    }
+}
+
+fn is_loopback(address: &str) -> bool {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host == "localhost" || host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Parses `WIDTHxHEIGHT`.
+pub fn parse_resolution(spec: &str) -> Result<(u32, u32), String> {
+    let (w, h) = spec
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got '{}'", spec))?;
+    match (w.trim().parse::<u32>(), h.trim().parse::<u32>()) {
+        (Ok(w), Ok(h)) if w >= 320 && h >= 200 => Ok((w, h)),
+        _ => Err(format!("invalid resolution '{}'", spec)),
+    }
+}
+
+/// Protocols a remote console can speak.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Vnc,
+    Spice,
+}
+
+/// VNC display numbers count from this port.
+const VNC_BASE_PORT: u16 = 5900;
+
+/// RFB pseudo-encoding announcing and reporting desktop size changes.
+const EXTENDED_DESKTOP_SIZE: i32 = -308;
+
+/// Where a running VM's remote console listens.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Console {
+    pub protocol: Protocol,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Console {
+    /// Parses `vnc`, `spice`, `vnc:<port>` or `spice:<host>:<port>`. Without
+    /// a port the first free one from 5900 is taken; without a host the
+    /// VM's `listen` address, loopback by default.
+    pub fn parse(spec: &str, vm: &VMInfo) -> Result<Console, String> {
+        let (protocol, rest) = spec.split_once(':').unwrap_or((spec, ""));
+        let protocol = match protocol {
+            "vnc" => Protocol::Vnc,
+            "spice" => Protocol::Spice,
+            _ => return Err(format!("unknown display '{}'; use vnc:<port> or spice:<port>", spec)),
+        };
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) => (host.trim_start_matches('[').trim_end_matches(']').to_string(), port),
+            None => (vm.display.as_ref().and_then(|d| d.listen.clone()).unwrap_or_else(|| DEFAULT_LISTEN.to_string()), rest),
+        };
+        let port = match port {
+            "" => free_port(&host)?,
+            port => port.parse().map_err(|_| format!("invalid port '{}'", port))?,
+        };
+        if protocol == Protocol::Vnc && port < VNC_BASE_PORT {
+            return Err(format!("VNC ports start at {}", VNC_BASE_PORT));
+        }
+        Ok(Console { protocol, host, port })
+    }
+
+    /// The console the VM's own VNC setting opens.
+    pub fn configured(vm: &VMInfo) -> Option<Console> {
+        let display = vm.display.as_ref()?;
+        let address = vnc_address(display, display.vnc.as_deref()?);
+        let (host, number) = address.rsplit_once(':')?;
+        let port = VNC_BASE_PORT.checked_add(number.parse().ok()?)?;
+        Some(Console { protocol: Protocol::Vnc, host: host.trim_start_matches('[').trim_end_matches(']').to_string(), port })
+    }
+
+    fn host_for_args(&self) -> String {
+        if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() }
+    }
+
+    /// A URL viewers such as remote-viewer open directly.
+    pub fn url(&self) -> String {
+        let scheme = match self.protocol {
+            Protocol::Vnc => "vnc",
+            Protocol::Spice => "spice",
+        };
+        format!("{}://{}:{}", scheme, self.host_for_args(), self.port)
+    }
+}
+
+fn free_port(host: &str) -> Result<u16, String> {
+    (VNC_BASE_PORT..VNC_BASE_PORT + 100)
+        .find(|port| TcpListener::bind((host, *port)).is_ok())
+        .ok_or_else(|| format!("no free console port on {} between 5900 and 5999", host))
+}
+
+fn console_file(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("console.json")
+}
+
+/// Remembers where a just-started VM's console listens, for `status`.
+pub fn record(vm_name: &str, console: &Console) -> Result<(), String> {
+    let path = console_file(vm_name);
+    let json = serde_json::to_string(console).map_err(|e| e.to_string())?;
+    fs::write(&path, json).map_err(|e| format!("cannot write {}: {}", path.display(), e))
+}
+
+pub fn recorded(vm_name: &str) -> Option<Console> {
+    serde_json::from_str(&fs::read_to_string(console_file(vm_name)).ok()?).ok()
+}
+
+pub fn clear(vm_name: &str) {
+    let _ = fs::remove_file(console_file(vm_name));
+}
+
+/// Makes `WIDTHxHEIGHT` the VM's resolution, and the running guest's too.
+/// QEMU fixes the card's `xres`/`yres` once it is created, so a running
+/// VM is resized the way a viewer window would: its VNC console forwards
+/// the size to the card, which hands it to the guest as a new preferred
+/// mode. That needs a VNC console and a virtio or qxl card.
+pub fn resize(config: &mut VMConfig, name: &str, spec: &str) -> Result<String, String> {
+    let (w, h) = parse_resolution(spec)?;
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    vm.display.get_or_insert_with(DisplaySpec::default).resolution = Some(format!("{}x{}", w, h));
+    let running = crate::vm_running(name).then(|| resize_running(vm, w, h));
+    config::save_config(config).map_err(|e| e.to_string())?;
+    match running {
+        None => Ok(format!("'{}' starts at {}x{} from now on.", name, w, h)),
+        Some(Ok(())) => Ok(format!("'{}' is at {}x{} now and on every start.", name, w, h)),
+        Some(Err(e)) => Err(format!("'{}' starts at {}x{} from now on, but is not resized now: {}", name, w, h, e)),
+    }
+}
+
+/// Asks the running VM's card for a new mode through its VNC console.
+pub fn resize_running(vm: &VMInfo, w: u32, h: u32) -> Result<(), String> {
+    let console = recorded(&vm.name)
+        .or_else(|| Console::configured(vm))
+        .filter(|c| c.protocol == Protocol::Vnc)
+        .ok_or("it has no VNC console to pass the size through")?;
+    let (w, h) = (u16::try_from(w).map_err(|_| "width too large")?, u16::try_from(h).map_err(|_| "height too large")?);
+    // A console on every address is reachable on loopback too.
+    let host = match console.host.as_str() {
+        "0.0.0.0" | "::" => DEFAULT_LISTEN,
+        host => host,
+    };
+    let stream = TcpStream::connect((host, console.port)).map_err(|e| format!("cannot reach the console at {}: {}", console.url(), e))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5))).map_err(|e| e.to_string())?;
+    rfb_set_size(stream, w, h).map_err(|e| format!("VNC console at {}: {}", console.url(), e))
+}
+
+fn read_exact<const N: usize>(stream: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn rfb_error(message: impl Into<String>) -> io::Error {
+    io::Error::other(message.into())
+}
+
+/// RFB 3.8 without authentication, then SetDesktopSize, answered with an
+/// ExtendedDesktopSize update whose status says whether the card took it.
+fn rfb_set_size(mut stream: impl Read + Write, w: u16, h: u16) -> io::Result<()> {
+    read_exact::<12>(&mut stream)?;
+    stream.write_all(b"RFB 003.008\n")?;
+    let [count] = read_exact::<1>(&mut stream)?;
+    let mut types = vec![0u8; count as usize];
+    stream.read_exact(&mut types)?;
+    if !types.contains(&1) {
+        return Err(rfb_error("it asks for a password; resize from a viewer instead"));
+    }
+    stream.write_all(&[1])?;
+    if read_exact::<4>(&mut stream)? != [0; 4] {
+        return Err(rfb_error("it refused the connection"));
+    }
+    // Shared, so viewers already connected stay.
+    stream.write_all(&[1])?;
+    let init = read_exact::<24>(&mut stream)?;
+    let mut name = vec![0u8; u32::from_be_bytes([init[20], init[21], init[22], init[23]]) as usize];
+    stream.read_exact(&mut name)?;
+
+    let mut encodings = vec![2, 0, 0, 1];
+    encodings.extend(EXTENDED_DESKTOP_SIZE.to_be_bytes());
+    stream.write_all(&encodings)?;
+    let mut request = vec![251, 0];
+    request.extend(w.to_be_bytes());
+    request.extend(h.to_be_bytes());
+    request.extend([1, 0]);
+    // One screen, id 0, covering the whole framebuffer.
+    request.extend([0u8; 8]);
+    request.extend(w.to_be_bytes());
+    request.extend(h.to_be_bytes());
+    request.extend([0u8; 4]);
+    stream.write_all(&request)?;
+
+    // Updates the server sends of its own accord have reason 0; the answer
+    // to this client's request has reason 1 and a status.
+    loop {
+        let update = read_exact::<4>(&mut stream)?;
+        if update[0] != 0 {
+            return Err(rfb_error("this QEMU does not resize displays from the console"));
+        }
+        for _ in 0..u16::from_be_bytes([update[2], update[3]]) {
+            let rect = read_exact::<12>(&mut stream)?;
+            if i32::from_be_bytes([rect[8], rect[9], rect[10], rect[11]]) != EXTENDED_DESKTOP_SIZE {
+                return Err(rfb_error("this QEMU does not resize displays from the console"));
+            }
+            let [screens, ..] = read_exact::<4>(&mut stream)?;
+            io::copy(&mut (&mut stream).take(16 * screens as u64), &mut io::sink())?;
+            match (u16::from_be_bytes([rect[0], rect[1]]), u16::from_be_bytes([rect[2], rect[3]])) {
+                (0, _) => continue,
+                (_, 0 | 4) => return Ok(()),
+                (_, 3) => return Err(rfb_error("the graphics card cannot change mode while running; use the virtio or qxl card")),
+                (_, status) => return Err(rfb_error(format!("the size was refused (status {})", status))),
+            }
+        }
+    }
+}
+
+fn console_args(vm: &VMInfo, console: &Console) -> Vec<String> {
+    let address = format!("{}:{}", console.host_for_args(), console.port);
+    if !is_loopback(&address) {
+        warn!("VM '{}': the console at {} is reachable from the network without a password.", vm.name, console.url());
+    }
+    match console.protocol {
+        Protocol::Vnc => {
+            let mut arg = format!("{}:{}", console.host_for_args(), console.port - VNC_BASE_PORT);
+            if let Some(delay) = vm.display.as_ref().and_then(|d| d.vnc_key_delay_ms) {
+                arg.push_str(&format!(",key-delay-ms={}", delay));
+            }
+            vec!["-vnc".to_string(), arg]
+        }
+        Protocol::Spice => vec!["-spice".to_string(), format!("port={},addr={},disable-ticketing=on", console.port, console.host)],
+    }
+}
+
+/// Arguments for the graphics card and VNC server. A resolution needs a
+/// card that takes one, so it implies `virtio` when no model is set.
+/// `console` replaces the VM's own VNC setting for this boot.
+pub fn launch_args(vm: &VMInfo, console: Option<&Console>) -> Vec<String> {
+    let Some(display) = &vm.display else {
+        return console.map(|c| console_args(vm, c)).unwrap_or_default();
+    };
+    let mut args = Vec::new();
+    let model = match (display.model.as_deref(), &display.resolution) {
+        (Some(model), _) => Some(model),
+        (None, Some(_)) => Some("virtio"),
+        (None, None) => None,
+    };
+    if let Some(model) = model {
+        let device = match model {
+            // Only the PC has VGA.
+            _ if !crate::arch::of(vm).is_pc() => "virtio-gpu-pci",
+            "qxl" => "qxl-vga",
+            "std" => "VGA",
+            _ => "virtio-vga",
+        };
+        let mut arg = format!("{},id=display0", device);
+        if let Some(resolution) = &display.resolution {
+            match parse_resolution(resolution) {
+                // qxl has no EDID; the guest driver reads xres/yres directly.
+                Ok((w, h)) if device == "qxl-vga" => arg.push_str(&format!(",xres={},yres={}", w, h)),
+                Ok((w, h)) => arg.push_str(&format!(",edid=on,xres={},yres={}", w, h)),
+                Err(e) => error!("VM '{}': ignoring display resolution: {}", vm.name, e),
+            }
+        }
+        args.push("-device".to_string());
+        args.push(arg);
+    }
+    if let Some(console) = console {
+        args.extend(console_args(vm, console));
+    } else if let Some(vnc) = &display.vnc {
+        let mut arg = vnc_address(display, vnc);
+        if !is_loopback(&arg) {
+            warn!("VM '{}': the VNC console at {} is reachable from the network.", vm.name, arg);
+        }
+        if let Some(delay) = display.vnc_key_delay_ms {
+            arg.push_str(&format!(",key-delay-ms={}", delay));
+        }
+        args.push("-vnc".to_string());
+        args.push(arg);
+    }
+    if let Some(keymap) = &display.keymap {
+        args.push("-k".to_string());
+        args.push(keymap.clone());
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays QEMU's side: no authentication, a server-side layout update
+    /// first, then the answer to the request.
+    fn serve(status: u16) -> (u16, std::thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            client.write_all(b"RFB 003.008\n").unwrap();
+            read_exact::<12>(&mut client).unwrap();
+            client.write_all(&[2, 2, 1]).unwrap();
+            assert_eq!(read_exact::<1>(&mut client).unwrap(), [1]);
+            client.write_all(&[0; 4]).unwrap();
+            read_exact::<1>(&mut client).unwrap();
+            let mut init = vec![0x04, 0x00, 0x03, 0x00];
+            init.extend([0; 16]);
+            init.extend(4u32.to_be_bytes());
+            init.extend(b"test");
+            client.write_all(&init).unwrap();
+            read_exact::<8>(&mut client).unwrap();
+            let request = read_exact::<24>(&mut client).unwrap().to_vec();
+            for (reason, status) in [(0u16, 0u16), (1, status)] {
+                let mut update = vec![0, 0, 0, 1];
+                update.extend(reason.to_be_bytes());
+                update.extend(status.to_be_bytes());
+                update.extend([0x04, 0x00, 0x03, 0x00]);
+                update.extend(EXTENDED_DESKTOP_SIZE.to_be_bytes());
+                update.extend([1, 0, 0, 0]);
+                update.extend([0; 16]);
+                client.write_all(&update).unwrap();
+            }
+            request
+        });
+        (port, server)
+    }
+
+    #[test]
+    fn resizes_through_the_vnc_console() {
+        let (port, server) = serve(4);
+        rfb_set_size(TcpStream::connect(("127.0.0.1", port)).unwrap(), 2560, 1440).unwrap();
+        let request = server.join().unwrap();
+        assert_eq!(request[..6], [251, 0, 0x0a, 0x00, 0x05, 0xa0]);
+
+        let (port, server) = serve(3);
+        let refused = rfb_set_size(TcpStream::connect(("127.0.0.1", port)).unwrap(), 2560, 1440).unwrap_err();
+        assert!(refused.to_string().contains("virtio or qxl"), "{}", refused);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use crate::config::{self, VMConfig};
+use crate::history;
+use std::fs;
+use toml::value::{Table, Value};
+
+/// Top-level sections that describe VMs rather than this host. `settings`
+/// stays local: it holds notification credentials and host tuning.
+const SHARED_SECTIONS: &[&str] = &["vms", "profiles", "networks"];
+
+/// VM fields that only make sense on the host that wrote them: checksums
+/// of local image files, snapshots inside the local disk, whether the host boots the VM, the host's power
+/// profile, and users or directories that may not exist elsewhere.
+const LOCAL_FIELDS: &[&str] = &["images", "snapshots", "autostart", "host_power", "hardening"];
+
+const HEADER: &str = "# VM definitions exported by SRQemu. Host-specific settings are left out;\n# pull changes into another machine with `SRQemu config sync <file>`.\n\n";
+
+/// The shareable part of the config as TOML, suitable for a dotfiles repo.
+pub fn export(config: &VMConfig) -> Result<String, String> {
+    let mut raw = config::to_raw(config);
+    for key in raw.keys().filter(|k| !SHARED_SECTIONS.contains(&k.as_str())).cloned().collect::<Vec<_>>() {
+        raw.remove(&key);
+    }
+    if let Some(Value::Table(vms)) = raw.get_mut("vms") {
+        for (_, vm) in vms.iter_mut() {
+            if let Value::Table(t) = vm {
+                for field in LOCAL_FIELDS {
+                    t.remove(*field);
+                }
+            }
+        }
+    }
+    let text = toml::to_string(&Value::Table(raw)).map_err(|e| e.to_string())?;
+    Ok(format!("{}{}", HEADER, text))
+}
+
+/// What a sync changed, for the summary printed afterwards.
+#[derive(Default)]
+pub struct SyncReport {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    /// Defined here but absent from the file; left alone.
+    pub local_only: Vec<String>,
+}
+
+/// Takes VM, profile and network definitions from an exported file. The
+/// file wins for every shared field; local-only fields of VMs that already
+/// exist here are kept.
+pub fn sync(config: &mut VMConfig, path: &str) -> Result<SyncReport, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let shared: Table = toml::from_str(&text).map_err(|e| format!("{} is not a valid export: {}", path, e))?;
+    let mut raw = config::to_raw(config);
+    let mut report = SyncReport::default();
+
+    for section in SHARED_SECTIONS {
+        let Some(Value::Table(incoming)) = shared.get(*section) else { continue };
+        let local = match raw.entry(section.to_string()).or_insert_with(|| Value::Table(Table::new())) {
+            Value::Table(t) => t,
+            _ => return Err(format!("local [{}] is not a table", section)),
+        };
+        for (name, value) in incoming {
+            let mut value = value.clone();
+            if *section == "vms" {
+                let Value::Table(vm) = &mut value else {
+                    return Err(format!("VM '{}' in {} is not a table", name, path));
+                };
+                match local.get(name) {
+                    Some(Value::Table(old)) => {
+                        for field in LOCAL_FIELDS {
+                            match old.get(*field) {
+                                Some(v) => vm.insert(field.to_string(), v.clone()),
+                                None => vm.remove(*field),
+                            };
+                        }
+                        if old != vm {
+                            report.updated.push(name.clone());
+                        }
+                    }
+                    _ => report.added.push(name.clone()),
+                }
+            }
+            local.insert(name.clone(), value);
+        }
+        if *section == "vms" {
+            report.local_only = local.keys().filter(|name| !incoming.contains_key(*name)).cloned().collect();
+        }
+    }
+
+    report.added.sort();
+    report.updated.sort();
+    report.local_only.sort();
+    *config = config::from_raw(raw).map_err(|e| format!("{} does not fit this config: {}", path, e))?;
+    history::checkpoint(&config.settings, "sync")?;
+    config::save_config(config)?;
+    Ok(report)
+}
@@ -0,0 +1,42 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Failures worth more than a one-off message: the ones a user has to act
+/// on, like a damaged config or a missing tool. Modules that report plain
+/// strings still take these through `?`.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("cannot read the configuration {}: {message}", path.display())]
+    ConfigRead { path: PathBuf, message: String },
+    #[error("the configuration {} is damaged: {message}\nFix or move the file; SRQemu will not overwrite it.", path.display())]
+    ConfigInvalid { path: PathBuf, message: String },
+    #[error("cannot save the configuration to {}: {message}", path.display())]
+    ConfigWrite { path: PathBuf, message: String },
+    #[error("cannot create {}: {source}", path.display())]
+    CreateDir { path: PathBuf, source: std::io::Error },
+    #[error("{program} is not installed or not on PATH")]
+    ToolMissing { program: String },
+    #[error("{program} failed: {message}")]
+    ToolFailed { program: String, message: String },
+    #[error("{program} did not finish within {secs}s and was stopped; raise \"{key}\" under [settings.command_timeouts] if it needs longer")]
+    ToolTimedOut { program: String, secs: u64, key: String },
+}
+
+impl From<Error> for String {
+    fn from(e: Error) -> String {
+        e.to_string()
+    }
+}
+
+/// An error with the causes it wraps, which libraries like confy keep out
+/// of their own message.
+pub fn chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
@@ -0,0 +1,200 @@
+use crate::config::VMInfo;
+use crate::run;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::process::{Command as ShellCommand, Stdio};
+use tracing::error;
+
+/// nftables table (in both the `inet` and `bridge` families) that holds
+/// every chain SRQemu creates, so nothing else on the host is touched.
+const TABLE: &str = "srqemu";
+
+/// Who may reach an exposed port.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Exposure {
+    /// Anyone who can route to the host.
+    Lan,
+    /// Only the host itself.
+    Localhost,
+    /// Only sources inside this IPv4 or IPv6 network.
+    Cidr(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FirewallRule {
+    pub port: u16,
+    #[serde(default = "crate::network::default_proto")]
+    pub proto: String,
+    pub allow: Exposure,
+    /// Bridged NIC whose guest service this guards. Unset means a port on
+    /// the host itself, such as a user-mode `hostfwd` forward.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nic: Option<usize>,
+}
+
+impl Exposure {
+    pub fn parse(spec: &str) -> Result<Exposure, String> {
+        match spec {
+            "lan" => Ok(Exposure::Lan),
+            "localhost" => Ok(Exposure::Localhost),
+            cidr if cidr.contains('/') => Ok(Exposure::Cidr(cidr.to_string())),
+            other => Err(format!("expected lan, localhost or a CIDR, got '{}'", other)),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Exposure::Lan => "lan".to_string(),
+            Exposure::Localhost => "localhost".to_string(),
+            Exposure::Cidr(c) => c.clone(),
+        }
+    }
+}
+
+fn chain_name(vm_name: &str) -> String {
+    format!("vm-{}", vm_name)
+}
+
+fn source_match(cidr: &str) -> &'static str {
+    if cidr.contains(':') { "ip6 saddr" } else { "ip saddr" }
+}
+
+/// Rules for ports on the host (input hook).
+fn host_rule(rule: &FirewallRule) -> Vec<String> {
+    let port = format!("{} dport {}", rule.proto, rule.port);
+    match &rule.allow {
+        Exposure::Lan => vec![format!("{} accept", port)],
+        Exposure::Localhost => vec![format!("{} iifname != \"lo\" drop", port)],
+        Exposure::Cidr(cidr) => {
+            // The other address family must not slip past a one-family CIDR.
+            let other = if cidr.contains(':') { "ipv4" } else { "ipv6" };
+            vec![
+                format!("{} {} != {} drop", port, source_match(cidr), cidr),
+                format!("meta nfproto {} {} drop", other, port),
+            ]
+        }
+    }
+}
+
+/// Rules for services on a bridged guest (bridge forward hook), matched by
+/// the guest NIC's MAC.
+fn bridge_rule(rule: &FirewallRule, mac: &str) -> Vec<String> {
+    let target = format!("ether daddr {} {} dport {}", mac, rule.proto, rule.port);
+    match &rule.allow {
+        Exposure::Lan => vec![format!("{} accept", target)],
+        // Traffic from the host itself never crosses the bridge forward path.
+        Exposure::Localhost => vec![format!("{} drop", target)],
+        Exposure::Cidr(cidr) => {
+            let other = if cidr.contains(':') { "ip" } else { "ip6" };
+            vec![
+                format!("{} {} != {} drop", target, source_match(cidr), cidr),
+                format!("ether daddr {} ether type {} {} dport {} drop", mac, other, rule.proto, rule.port),
+            ]
+        }
+    }
+}
+
+fn nft_script(script: &str) -> Result<(), String> {
+    let mut child = ShellCommand::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run nft: {}", e))?;
+    child
+        .stdin
+        .take()
+        .ok_or("nft stdin unavailable")?
+        .write_all(script.as_bytes())
+        .map_err(|e| e.to_string())?;
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("nft failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+fn chain_exists(family: &str, chain: &str) -> bool {
+    run(ShellCommand::new("nft").args(["list", "chain", family, TABLE, chain])).is_ok()
+}
+
+/// Installs a VM's rules as its own base chains, replacing earlier ones.
+pub fn apply(vm: &VMInfo) -> Result<(), String> {
+    remove(&vm.name)?;
+    if vm.firewall.is_empty() {
+        return Ok(());
+    }
+    let chain = chain_name(&vm.name);
+    let mut host = Vec::new();
+    let mut bridged = Vec::new();
+    for rule in &vm.firewall {
+        match rule.nic {
+            None => host.extend(host_rule(rule)),
+            Some(i) => match vm.nics.get(i) {
+                Some(nic) => bridged.extend(bridge_rule(rule, &nic.mac)),
+                None => error!("VM '{}': firewall rule for port {} names missing NIC {}", vm.name, rule.port, i),
+            },
+        }
+    }
+
+    let mut script = String::new();
+    for (family, hook, rules) in [("inet", "input", &host), ("bridge", "forward", &bridged)] {
+        if rules.is_empty() {
+            continue;
+        }
+        script.push_str(&format!("table {} {} {{\n  chain {} {{\n", family, TABLE, chain));
+        script.push_str(&format!("    type filter hook {} priority -10; policy accept;\n", hook));
+        for rule in rules.iter() {
+            script.push_str(&format!("    {}\n", rule));
+        }
+        script.push_str("  }\n}\n");
+    }
+    nft_script(&script)?;
+    println!("Firewall rules for '{}' applied ({} rule(s)).", vm.name, vm.firewall.len());
+    Ok(())
+}
+
+/// Flush-and-delete script for chains that exist; empty when none do.
+fn delete_chains(families: &[&str], chain: &str) -> String {
+    let mut script = String::new();
+    for family in families {
+        if chain_exists(family, chain) {
+            script.push_str(&format!("flush chain {f} {t} {c}\ndelete chain {f} {t} {c}\n", f = family, t = TABLE, c = chain));
+        }
+    }
+    script
+}
+
+/// Drops a VM's chains; a no-op when none were installed.
+pub fn remove(vm_name: &str) -> Result<(), String> {
+    let script = delete_chains(&["inet", "bridge"], &chain_name(vm_name));
+    if script.is_empty() {
+        return Ok(());
+    }
+    nft_script(&script)
+}
+
+/// Masquerades a managed network's subnets when they leave through any
+/// interface other than its bridge.
+pub fn nat_up(network: &str, bridge: &str, subnets: &[String]) -> Result<(), String> {
+    nat_down(network)?;
+    let mut script = format!(
+        "table inet {} {{\n  chain nat-{} {{\n    type nat hook postrouting priority 100; policy accept;\n",
+        TABLE, network
+    );
+    for subnet in subnets {
+        let family = if subnet.contains(':') { "ip6" } else { "ip" };
+        script.push_str(&format!("    {} saddr {} oifname != \"{}\" masquerade\n", family, subnet, bridge));
+    }
+    script.push_str("  }\n}\n");
+    nft_script(&script)
+}
+
+pub fn nat_down(network: &str) -> Result<(), String> {
+    let script = delete_chains(&["inet"], &format!("nat-{}", network));
+    if script.is_empty() {
+        return Ok(());
+    }
+    nft_script(&script)
+}
@@ -0,0 +1,115 @@
+use crate::arch::{self, Arch};
+use crate::config::VMInfo;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Firmware {
+    /// SeaBIOS, QEMU's default.
+    Bios,
+    /// OVMF with a writable NVRAM store in the VM folder, for guests that
+    /// require UEFI.
+    Uefi,
+}
+
+impl Firmware {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "bios" => Some(Firmware::Bios),
+            "uefi" => Some(Firmware::Uefi),
+            _ => None,
+        }
+    }
+}
+
+/// Where distributions install OVMF, as (code, NVRAM template) pairs. The
+/// 4M builds come first; a VM's NVRAM only works with the matching code.
+const OVMF_PAIRS: &[(&str, &str)] = &[
+    ("/usr/share/OVMF/OVMF_CODE_4M.fd", "/usr/share/OVMF/OVMF_VARS_4M.fd"),
+    ("/usr/share/edk2/x64/OVMF_CODE.4m.fd", "/usr/share/edk2/x64/OVMF_VARS.4m.fd"),
+    ("/usr/share/edk2/ovmf/OVMF_CODE.fd", "/usr/share/edk2/ovmf/OVMF_VARS.fd"),
+    ("/usr/share/OVMF/OVMF_CODE.fd", "/usr/share/OVMF/OVMF_VARS.fd"),
+    ("/usr/share/edk2-ovmf/x64/OVMF_CODE.fd", "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd"),
+    ("/usr/share/qemu/edk2-x86_64-code.fd", "/usr/share/qemu/edk2-i386-vars.fd"),
+];
+
+/// The same for 64-bit ARM (AAVMF), padded to the size of QEMU's flash.
+const AAVMF_PAIRS: &[(&str, &str)] = &[
+    ("/usr/share/AAVMF/AAVMF_CODE.fd", "/usr/share/AAVMF/AAVMF_VARS.fd"),
+    ("/usr/share/edk2/aarch64/QEMU_EFI-pflash.raw", "/usr/share/edk2/aarch64/vars-template-pflash.raw"),
+    ("/usr/share/qemu/edk2-aarch64-code.fd", "/usr/share/qemu/edk2-arm-vars.fd"),
+];
+
+const RISCV_PAIRS: &[(&str, &str)] = &[
+    ("/usr/share/qemu-efi-riscv64/RISCV_VIRT_CODE.fd", "/usr/share/qemu-efi-riscv64/RISCV_VIRT_VARS.fd"),
+    ("/usr/share/edk2/riscv/RISCV_VIRT_CODE.fd", "/usr/share/edk2/riscv/RISCV_VIRT_VARS.fd"),
+    ("/usr/share/qemu/edk2-riscv-code.fd", "/usr/share/qemu/edk2-riscv-vars.fd"),
+];
+
+/// The firmware builds for the VM's architecture, and the packages
+/// installing them.
+fn pairs(vm: &VMInfo) -> (&'static [(&'static str, &'static str)], &'static str) {
+    match arch::of(vm) {
+        Arch::X86_64 => (OVMF_PAIRS, "ovmf or edk2-ovmf"),
+        Arch::Aarch64 => (AAVMF_PAIRS, "qemu-efi-aarch64 or edk2-aarch64"),
+        Arch::Riscv64 => (RISCV_PAIRS, "qemu-efi-riscv64 or edk2-riscv64"),
+    }
+}
+
+pub fn nvram_path(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("OVMF_VARS.fd")
+}
+
+fn size(path: &Path) -> Option<u64> {
+    fs::metadata(path).ok().map(|m| m.len())
+}
+
+/// The installed OVMF pair; once the VM has its NVRAM, the one whose
+/// template has the same size.
+fn locate(vm: &VMInfo) -> Result<(&'static str, &'static str), String> {
+    let nvram = size(&nvram_path(&vm.name));
+    let (pairs, packages) = pairs(vm);
+    pairs
+        .iter()
+        .copied()
+        .find(|(code, vars)| Path::new(code).exists() && nvram.is_none_or(|n| size(Path::new(vars)) == Some(n)))
+        .ok_or_else(|| match nvram {
+            Some(_) => "no installed OVMF build matches the VM's NVRAM size".to_string(),
+            None => format!("UEFI firmware for {} is not installed (package {})", arch::of(vm).name(), packages),
+        })
+}
+
+/// 64-bit ARM boards have no other firmware.
+fn is_uefi(vm: &VMInfo) -> bool {
+    vm.firmware == Some(Firmware::Uefi) || arch::of(vm) == Arch::Aarch64
+}
+
+/// Gives a UEFI VM its own copy of the NVRAM template on first start, so
+/// boot entries and settings persist.
+pub fn prepare(vm: &VMInfo) -> Result<(), String> {
+    if !is_uefi(vm) || nvram_path(&vm.name).exists() {
+        return Ok(());
+    }
+    let (_, vars) = locate(vm)?;
+    fs::copy(vars, nvram_path(&vm.name)).map_err(|e| format!("cannot copy {}: {}", vars, e))?;
+    Ok(())
+}
+
+/// The installed OVMF code image for a UEFI VM.
+pub fn installed_code(vm: &VMInfo) -> Option<&'static str> {
+    if is_uefi(vm) { locate(vm).ok().map(|(code, _)| code) } else { None }
+}
+
+pub fn launch_args(vm: &VMInfo, code: Option<&str>) -> Vec<String> {
+    let (true, Some(code)) = (is_uefi(vm), code) else {
+        return Vec::new();
+    };
+    vec![
+        "-drive".to_string(),
+        format!("if=pflash,format=raw,unit=0,readonly=on,file={}", code),
+        "-drive".to_string(),
+        format!("if=pflash,format=raw,unit=1,file={}", nvram_path(&vm.name).display()),
+    ]
+}
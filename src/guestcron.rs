@@ -0,0 +1,163 @@
+use crate::config::{self, VMConfig, VMInfo};
+use crate::{agent, run};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command as ShellCommand;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Longest a scheduled command may run before it is reported as hung.
+const JOB_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// A command run inside the guest on a schedule, through the guest agent.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GuestJob {
+    pub id: String,
+    /// systemd calendar expression, e.g. `daily` or `Mon..Fri 02:30`.
+    pub schedule: String,
+    /// Shell command line, run with `/bin/sh -c` in the guest.
+    pub command: String,
+}
+
+pub fn unit_dir() -> Result<PathBuf, String> {
+    let home = home::home_dir().ok_or("cannot find the home directory")?;
+    Ok(home.join(".config/systemd/user"))
+}
+
+/// Whether `name` can go into a unit's file name and `ExecStart` as is.
+fn unit_safe(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn unit_name(vm_name: &str, id: &str) -> Result<String, String> {
+    if !unit_safe(vm_name) {
+        return Err(format!("VM name '{}' cannot name a systemd timer; rename the VM to letters, digits, '-', '_' and '.'", vm_name));
+    }
+    if !unit_safe(id) {
+        return Err(format!("invalid command id '{}'", id));
+    }
+    Ok(format!("srqemu-cron-{}-{}", vm_name, id))
+}
+
+pub fn log_dir(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("logs")
+}
+
+pub fn systemctl(args: &[&str]) -> Result<(), String> {
+    run(ShellCommand::new("systemctl").arg("--user").args(args)).map(|_| ())
+}
+
+/// Rejects schedules systemd would not accept, before any unit is written.
+pub fn check_schedule(schedule: &str) -> Result<(), String> {
+    run(ShellCommand::new("systemd-analyze").args(["calendar", schedule]))
+        .map(|_| ())
+        .map_err(|e| format!("invalid schedule '{}': {}", schedule, e))
+}
+
+/// Writes and starts the user timer that triggers a job.
+pub fn install(vm_name: &str, job: &GuestJob) -> Result<(), String> {
+    let unit = unit_name(vm_name, &job.id)?;
+    let dir = unit_dir()?;
+    fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let exe = std::env::current_exe().map_err(|e| format!("cannot locate this binary: {}", e))?;
+    let service = format!(
+        "[Unit]\nDescription=SRQemu scheduled command '{}' in VM {}\n\n[Service]\nType=oneshot\nExecStart={} guest-run {} {}\n",
+        job.id,
+        vm_name,
+        exe.display(),
+        vm_name,
+        job.id
+    );
+    let timer = format!(
+        "[Unit]\nDescription=Schedule for {}\n\n[Timer]\nOnCalendar={}\nPersistent=true\n\n[Install]\nWantedBy=timers.target\n",
+        unit, job.schedule
+    );
+    fs::write(dir.join(format!("{}.service", unit)), service).map_err(|e| e.to_string())?;
+    fs::write(dir.join(format!("{}.timer", unit)), timer).map_err(|e| e.to_string())?;
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", "--now", &format!("{}.timer", unit)])
+}
+
+pub fn uninstall(vm_name: &str, id: &str) -> Result<(), String> {
+    let unit = unit_name(vm_name, id)?;
+    let _ = systemctl(&["disable", "--now", &format!("{}.timer", unit)]);
+    let dir = unit_dir()?;
+    for ext in ["timer", "service"] {
+        let _ = fs::remove_file(dir.join(format!("{}.{}", unit, ext)));
+    }
+    systemctl(&["daemon-reload"])
+}
+
+/// Schedules `command` in the VM's guest under `id`, or the first free
+/// `cmdN`.
+pub fn add(config: &mut VMConfig, name: &str, id: Option<String>, schedule: &str, command: &str) -> Result<String, String> {
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    if !vm.guest_agent {
+        return Err(format!("VM '{}' has no guest agent channel; enable it under time synchronization", name));
+    }
+    let id = id.unwrap_or_else(|| (1..).map(|n| format!("cmd{}", n)).find(|id| vm.guest_cron.iter().all(|j| &j.id != id)).unwrap_or_default());
+    if !unit_safe(&id) || vm.guest_cron.iter().any(|j| j.id == id) {
+        return Err(format!("invalid or duplicate id '{}'", id));
+    }
+    check_schedule(schedule)?;
+    let job = GuestJob { id, schedule: schedule.to_string(), command: command.to_string() };
+    install(name, &job).map_err(|e| format!("cannot install the timer: {}", e))?;
+    let done = format!("Scheduled '{}' in '{}'; output goes to {}/cron-{}.log.", job.id, name, log_dir(name).display(), job.id);
+    vm.guest_cron.push(job);
+    config::save_config(config).map_err(|e| e.to_string())?;
+    Ok(done)
+}
+
+/// Removes a scheduled command and its timer.
+pub fn remove(config: &mut VMConfig, name: &str, id: &str) -> Result<String, String> {
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    if !vm.guest_cron.iter().any(|j| j.id == id) {
+        return Err(format!("no scheduled command '{}'", id));
+    }
+    if let Err(e) = uninstall(name, id) {
+        warn!("Failed to remove the timer: {}", e);
+    }
+    vm.guest_cron.retain(|j| j.id != id);
+    config::save_config(config).map_err(|e| e.to_string())?;
+    Ok(format!("Removed '{}' from '{}'.", id, name))
+}
+
+/// Entry point for the timer: runs the job and appends its output to
+/// `logs/cron-<id>.log` in the VM folder.
+pub fn run_job(config: &VMConfig, vm_name: &str, id: &str) -> Result<(), String> {
+    let vm: &VMInfo = config.vms.get(vm_name).ok_or_else(|| format!("VM '{}' not found", vm_name))?;
+    let job = vm
+        .guest_cron
+        .iter()
+        .find(|j| j.id == id)
+        .ok_or_else(|| format!("VM '{}' has no scheduled command '{}'", vm_name, id))?;
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let outcome = if crate::vm_running(vm_name) {
+        agent::exec(vm_name, "/bin/sh", &["-c", &job.command], JOB_TIMEOUT)
+    } else {
+        Err("VM is not running".to_string())
+    };
+
+    fs::create_dir_all(log_dir(vm_name)).map_err(|e| e.to_string())?;
+    let log_path = log_dir(vm_name).join(format!("cron-{}.log", id));
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .map_err(|e| format!("cannot open {}: {}", log_path.display(), e))?;
+    let entry = match &outcome {
+        Ok(result) => format!(
+            "=== {} {} (exit {})\n{}{}",
+            started, job.command, result.exit_code, result.stdout, result.stderr
+        ),
+        Err(e) => format!("=== {} {} (not run: {})\n", started, job.command, e),
+    };
+    log.write_all(entry.as_bytes()).map_err(|e| e.to_string())?;
+    match outcome {
+        Ok(result) if result.exit_code == 0 => Ok(()),
+        Ok(result) => Err(format!("'{}' exited with {}", job.command, result.exit_code)),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,541 @@
+use crate::config::VMInfo;
+use crate::nbd::{self, Mounted, Partition};
+use crate::runner;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command as ShellCommand;
+
+/// Symlinks followed while resolving one guest path, as in Linux.
+const MAX_SYMLINKS: usize = 40;
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[0])
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+/// Total and available bytes of a mounted filesystem.
+pub fn fs_usage(dir: &Path) -> Option<(u64, u64)> {
+    let output = ShellCommand::new("df")
+        .args(["-B1", "--output=size,avail"])
+        .arg(dir)
+        .output()
+        .ok()?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let mut fields = text.lines().nth(1)?.split_whitespace();
+    let size = fields.next()?.parse().ok()?;
+    let avail = fields.next()?.parse().ok()?;
+    Some((size, avail))
+}
+
+/// `guest_path` on the guest filesystem mounted at `root`, resolved as
+/// the guest would: symlinks in it are followed with absolute targets
+/// taken from `root` and `..` stopping there, so the result never leaves
+/// the mount. The last component is only followed with `follow_last`;
+/// otherwise a symlink there is returned as itself.
+fn resolve_in_guest(root: &Path, guest_path: &str, follow_last: bool) -> Result<PathBuf, String> {
+    let components = |path: &Path| -> VecDeque<OsString> {
+        path.components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_os_string()),
+                Component::ParentDir => Some(OsString::from("..")),
+                _ => None,
+            })
+            .collect()
+    };
+    let mut pending = components(Path::new(guest_path));
+    let mut resolved = root.to_path_buf();
+    let mut followed = 0;
+    while let Some(part) = pending.pop_front() {
+        if part == ".." {
+            if resolved != root {
+                resolved.pop();
+            }
+            continue;
+        }
+        let next = resolved.join(&part);
+        let is_symlink = fs::symlink_metadata(&next).is_ok_and(|m| m.file_type().is_symlink());
+        if !is_symlink || (pending.is_empty() && !follow_last) {
+            resolved = next;
+            continue;
+        }
+        followed += 1;
+        if followed > MAX_SYMLINKS {
+            return Err(format!("{}: too many levels of symbolic links", guest_path));
+        }
+        let target = fs::read_link(&next).map_err(|e| format!("cannot read link {}: {}", next.display(), e))?;
+        if target.is_absolute() {
+            resolved = root.to_path_buf();
+        }
+        for component in components(&target).into_iter().rev() {
+            pending.push_front(component);
+        }
+    }
+    // Every step above stays under root; check it all the same.
+    if !resolved.starts_with(root) {
+        return Err(format!("{} resolves outside the guest filesystem", guest_path));
+    }
+    Ok(resolved)
+}
+
+/// Opens a file of the guest without following a symlink in its place,
+/// which could point into the host once the guest's disk is mounted.
+#[cfg(unix)]
+fn open_in_guest(path: &Path, options: &mut fs::OpenOptions) -> Result<fs::File, String> {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.custom_flags(libc::O_NOFOLLOW).open(path).map_err(|e| match e.raw_os_error() {
+        Some(libc::ELOOP) => format!("{} is a symlink; refusing to follow it", path.display()),
+        _ => format!("cannot open {}: {}", path.display(), e),
+    })
+}
+
+/// Windows cannot attach guest disks through qemu-nbd, so this only
+/// serves disks mounted some other way, and checks before opening.
+#[cfg(windows)]
+fn open_in_guest(path: &Path, options: &mut fs::OpenOptions) -> Result<fs::File, String> {
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+        return Err(format!("{} is a symlink; refusing to follow it", path.display()));
+    }
+    options.open(path).map_err(|e| format!("cannot open {}: {}", path.display(), e))
+}
+
+/// Best-effort guess at which OS lives on a mounted filesystem.
+fn detect_os(root: &Path) -> Option<String> {
+    let os_release = resolve_in_guest(root, "/etc/os-release", true);
+    if let Some(release) = os_release.ok().and_then(|path| fs::read_to_string(path).ok()) {
+        for line in release.lines() {
+            if let Some(name) = line.strip_prefix("PRETTY_NAME=") {
+                return Some(name.trim_matches('"').to_string());
+            }
+        }
+        return Some("Linux (unknown distribution)".to_string());
+    }
+    if root.join("Windows/System32").is_dir() {
+        return Some("Windows".to_string());
+    }
+    None
+}
+
+fn describe_partition(part: &Partition, index: usize) -> String {
+    let mut line = format!(
+        "  {}  {:>8}  {}",
+        part.path,
+        human_size(part.size),
+        if part.fstype.is_empty() { "-" } else { &part.fstype }
+    );
+    if !part.label.is_empty() {
+        line.push_str(&format!("  [{}]", part.label));
+    }
+    if !part.mountable() {
+        return line;
+    }
+    let mounted: Mounted = match nbd::mount(part, &nbd::scratch_dir("inspect", index), true) {
+        Ok(m) => m,
+        Err(e) => {
+            line.push_str(&format!("  (not mounted: {})", e));
+            return line;
+        }
+    };
+    if let Some((size, avail)) = fs_usage(&mounted.dir) {
+        line.push_str(&format!("  {} free of {}", human_size(avail), human_size(size)));
+    }
+    if let Some(os) = detect_os(&mounted.dir) {
+        line.push_str(&format!("  OS: {}", os));
+    }
+    line
+}
+
+/// Reports partitions, filesystems, free space and guest OS of a stopped VM
+/// by exporting its disk read-only through qemu-nbd.
+pub fn inspect(vm: &VMInfo) -> Result<(), String> {
+    let disk = vm.disk_path();
+    if !Path::new(&disk).exists() {
+        return Err(format!("disk image {} does not exist", disk));
+    }
+
+    let format = nbd::image_format(&disk)?;
+    let image_size = fs::metadata(&disk).map(|m| m.len()).unwrap_or(0);
+    println!("\nDisk: {} ({}, {} on host)", disk, format, human_size(image_size));
+
+    let device = nbd::connect(&disk, true)?;
+    let partitions = device.partitions()?;
+    if partitions.is_empty() {
+        println!("  No partitions or filesystems found.");
+    }
+    for (i, part) in partitions.iter().enumerate() {
+        println!("{}", describe_partition(part, i));
+    }
+    Ok(())
+}
+
+/// An NBD connection left mounted on the host by `mount_disk`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiskMount {
+    pub device: String,
+    pub partition: String,
+    pub mountpoint: String,
+}
+
+fn mount_state_file(vm: &VMInfo) -> PathBuf {
+    PathBuf::from(crate::vm_folder(&vm.name)).join("disk-mount.json")
+}
+
+pub fn mounted_disk(vm: &VMInfo) -> Option<DiskMount> {
+    let data = fs::read_to_string(mount_state_file(vm)).ok()?;
+    serde_json::from_str(&data).ok()
+}
+
+/// Prints the numbered partition table of a connected disk.
+fn print_partitions(partitions: &[Partition]) {
+    for (i, part) in partitions.iter().enumerate() {
+        println!(
+            "  {}. {}  {:>8}  {}",
+            i + 1,
+            part.path,
+            human_size(part.size),
+            if part.fstype.is_empty() { "-" } else { &part.fstype }
+        );
+    }
+}
+
+/// Default partition to operate on: the largest mountable one, which is the
+/// root filesystem on nearly every single-disk guest.
+pub fn largest_mountable(partitions: &[Partition]) -> Option<usize> {
+    partitions
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| p.mountable())
+        .max_by_key(|(_, p)| p.size)
+        .map(|(i, _)| i + 1)
+}
+
+/// Connects a stopped VM's disk through qemu-nbd and mounts one partition at
+/// `mountpoint`, leaving both in place until `umount_disk`.
+pub fn mount_disk(
+    vm: &VMInfo,
+    partition: Option<usize>,
+    mountpoint: &Path,
+    read_only: bool,
+) -> Result<(), String> {
+    if let Some(existing) = mounted_disk(vm) {
+        return Err(format!("disk is already mounted at {}", existing.mountpoint));
+    }
+    let disk = vm.disk_path();
+    let device = nbd::connect(&disk, read_only)?;
+    let partitions = device.partitions()?;
+
+    let index = match partition {
+        Some(n) => n,
+        None => {
+            print_partitions(&partitions);
+            largest_mountable(&partitions).ok_or("no mountable partition found")?
+        }
+    };
+    let part = index
+        .checked_sub(1)
+        .and_then(|i| partitions.get(i))
+        .ok_or_else(|| format!("partition {} does not exist ({} found)", index, partitions.len()))?;
+
+    let mounted = nbd::mount(part, mountpoint, read_only)?;
+    let state = DiskMount {
+        device: device.path.clone(),
+        partition: part.path.clone(),
+        mountpoint: mountpoint.display().to_string(),
+    };
+    let json = serde_json::to_string_pretty(&state).map_err(|e| e.to_string())?;
+    fs::write(mount_state_file(vm), json).map_err(|e| format!("cannot record mount: {}", e))?;
+
+    mounted.persist();
+    device.persist();
+    println!("Mounted {} at {}{}", part.path, mountpoint.display(), if read_only { " (read-only)" } else { "" });
+    Ok(())
+}
+
+pub fn umount_disk(vm: &VMInfo) -> Result<(), String> {
+    let state = mounted_disk(vm).ok_or("disk is not mounted")?;
+    nbd::unmount(Path::new(&state.mountpoint))?;
+    nbd::disconnect(&state.device)?;
+    let _ = fs::remove_file(mount_state_file(vm));
+    println!("Unmounted {} and released {}", state.mountpoint, state.device);
+    Ok(())
+}
+
+/// Which side of a `disk cp` lives inside the guest image.
+pub enum CopyDirection {
+    FromGuest,
+    IntoGuest,
+}
+
+/// Splits a `vm:/path` spec. Host paths never match because the guest side
+/// must be absolute right after the colon.
+pub fn parse_guest_path(spec: &str) -> Option<(&str, &str)> {
+    let (vm, path) = spec.split_once(':')?;
+    if vm.is_empty() || vm.contains('/') || !path.starts_with('/') {
+        return None;
+    }
+    Some((vm, path))
+}
+
+/// Finds the partition holding the guest's root filesystem by looking for an
+/// OS on each one, falling back to the largest mountable partition.
+fn find_root(partitions: &[Partition]) -> Option<usize> {
+    for (i, part) in partitions.iter().enumerate().filter(|(_, p)| p.mountable()) {
+        if let Ok(mounted) = nbd::mount(part, &nbd::scratch_dir("probe", i), true)
+            && detect_os(&mounted.dir).is_some()
+        {
+            return Some(i + 1);
+        }
+    }
+    largest_mountable(partitions)
+}
+
+/// Mounts the chosen (or detected root) partition of a stopped VM's disk in a
+/// scratch directory for the duration of `f`.
+pub fn with_guest_fs<T>(
+    vm: &VMInfo,
+    partition: Option<usize>,
+    read_only: bool,
+    f: impl FnOnce(&Path) -> Result<T, String>,
+) -> Result<T, String> {
+    if let Some(existing) = mounted_disk(vm) {
+        return Err(format!("disk is mounted at {}; unmount it first", existing.mountpoint));
+    }
+    let disk = vm.disk_path();
+    let device = nbd::connect(&disk, read_only)?;
+    let partitions = device.partitions()?;
+    let index = match partition {
+        Some(n) => n,
+        None => find_root(&partitions).ok_or("no mountable partition found")?,
+    };
+    let part = index
+        .checked_sub(1)
+        .and_then(|i| partitions.get(i))
+        .ok_or_else(|| format!("partition {} does not exist ({} found)", index, partitions.len()))?;
+    let mounted = nbd::mount(part, &nbd::scratch_dir("guestfs", index), read_only)?;
+    f(&mounted.dir)
+}
+
+pub fn copy(
+    vm: &VMInfo,
+    guest_path: &str,
+    host_path: &str,
+    direction: CopyDirection,
+    partition: Option<usize>,
+) -> Result<(), String> {
+    let read_only = matches!(direction, CopyDirection::FromGuest);
+    if Path::new(guest_path).components().any(|c| c == Component::ParentDir) {
+        return Err(format!("{} contains '..'; give the guest path without it", guest_path));
+    }
+    with_guest_fs(vm, partition, read_only, |root| {
+        let (src, dst) = match direction {
+            CopyDirection::FromGuest => (resolve_in_guest(root, guest_path, false)?, PathBuf::from(host_path)),
+            CopyDirection::IntoGuest => {
+                let host = PathBuf::from(host_path);
+                let mut dst = resolve_in_guest(root, guest_path, false)?;
+                // Into an existing directory, resolved so the name inside
+                // it is checked too.
+                if dst.is_dir() && !dst.is_symlink() {
+                    let name = host.file_name().ok_or_else(|| format!("{} has no file name", host_path))?;
+                    let inside = Path::new(guest_path).join(name);
+                    dst = resolve_in_guest(root, &inside.to_string_lossy(), false)?;
+                }
+                // cp would write through a link, or merge into a directory
+                // whose entries could be links.
+                if dst.is_symlink() {
+                    return Err(format!("{} is a symlink in the guest; refusing to write through it", dst.display()));
+                }
+                if host.is_dir() && dst.exists() {
+                    return Err(format!("{} already exists in the guest; copy the folder to a new path", dst.display()));
+                }
+                (host, dst)
+            }
+        };
+        if fs::symlink_metadata(&src).is_err() {
+            return Err(format!("{} does not exist", src.display()));
+        }
+        // cp -a keeps ownership and modes, which matters for files like
+        // /etc/shadow or ~/.ssh/authorized_keys; -P copies the guest's
+        // symlinks as links instead of the host files they name, and -T
+        // never puts the copy inside a directory the checks above did not
+        // resolve.
+        let output = ShellCommand::new("cp")
+            .args(["-a", "-P", "-T", "--remove-destination"])
+            .arg(&src)
+            .arg(&dst)
+            .output()
+            .map_err(|e| format!("failed to run cp: {}", e))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        runner::best_effort(&mut ShellCommand::new("sync"));
+        Ok(())
+    })?;
+    match direction {
+        CopyDirection::FromGuest => println!("Copied {}:{} to {}", vm.name, guest_path, host_path),
+        CopyDirection::IntoGuest => println!("Copied {} to {}:{}", host_path, vm.name, guest_path),
+    }
+    Ok(())
+}
+
+/// Backup name for the original Utilman.exe while the sticky-keys style
+/// recovery shell is staged.
+const UTILMAN_BACKUP: &str = "Utilman.exe.srqemu-bak";
+
+fn hash_password(password: &str) -> Result<String, String> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = ShellCommand::new("openssl")
+        .args(["passwd", "-6", "-stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run openssl: {}", e))?;
+    child
+        .stdin
+        .take()
+        .ok_or("openssl stdin unavailable")?
+        .write_all(password.as_bytes())
+        .map_err(|e| e.to_string())?;
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err("openssl passwd failed".to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn set_shadow_hash(root: &Path, user: &str, hash: &str) -> Result<(), String> {
+    let shadow_path = resolve_in_guest(root, "/etc/shadow", false)?;
+    let mut shadow = String::new();
+    io::Read::read_to_string(&mut open_in_guest(&shadow_path, fs::OpenOptions::new().read(true))?, &mut shadow)
+        .map_err(|e| format!("cannot read /etc/shadow: {}", e))?;
+    let mut found = false;
+    let updated: Vec<String> = shadow
+        .lines()
+        .map(|line| {
+            let mut fields: Vec<&str> = line.split(':').collect();
+            if fields.len() > 1 && fields[0] == user {
+                found = true;
+                fields[1] = hash;
+                fields.join(":")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    if !found {
+        return Err(format!("user '{}' not found in /etc/shadow", user));
+    }
+    let mut file = open_in_guest(&shadow_path, fs::OpenOptions::new().write(true).truncate(true))?;
+    io::Write::write_all(&mut file, (updated.join("\n") + "\n").as_bytes()).map_err(|e| format!("cannot write /etc/shadow: {}", e))
+}
+
+/// The guest's System32 folder, its own symlinks resolved inside it.
+fn system32(root: &Path) -> Result<PathBuf, String> {
+    resolve_in_guest(root, "/Windows/System32", true)
+}
+
+/// Swaps Utilman.exe for cmd.exe so the accessibility button on the login
+/// screen opens a SYSTEM shell where `net user` can reset the password.
+fn stage_utilman(root: &Path, user: &str) -> Result<(), String> {
+    let system32 = system32(root)?;
+    let utilman = system32.join("Utilman.exe");
+    let backup = system32.join(UTILMAN_BACKUP);
+    let mut cmd = open_in_guest(&system32.join("cmd.exe"), fs::OpenOptions::new().read(true))?;
+    if !backup.exists() {
+        // Checked first: the backup is what restore puts back.
+        open_in_guest(&utilman, fs::OpenOptions::new().read(true))?;
+        fs::rename(&utilman, &backup).map_err(|e| format!("cannot back up Utilman.exe: {}", e))?;
+    } else if fs::symlink_metadata(&utilman).is_ok() {
+        fs::remove_file(&utilman).map_err(|e| format!("cannot replace Utilman.exe: {}", e))?;
+    }
+    let mut staged = open_in_guest(&utilman, fs::OpenOptions::new().write(true).create_new(true))?;
+    io::copy(&mut cmd, &mut staged).map_err(|e| format!("cannot stage cmd.exe: {}", e))?;
+    println!("Staged a recovery shell. Boot the VM, click the accessibility icon on the");
+    println!("login screen, then run:  net user {} <new password>", user);
+    println!("Afterwards stop the VM and run the password reset again to restore Utilman.exe.");
+    Ok(())
+}
+
+fn restore_utilman(system32: &Path) -> Result<(), String> {
+    // A link put there would otherwise become Utilman.exe.
+    open_in_guest(&system32.join(UTILMAN_BACKUP), fs::OpenOptions::new().read(true))?;
+    fs::rename(system32.join(UTILMAN_BACKUP), system32.join("Utilman.exe"))
+        .map_err(|e| format!("cannot restore Utilman.exe: {}", e))?;
+    println!("Restored the original Utilman.exe.");
+    Ok(())
+}
+
+/// Resets a guest user's password with the VM off. Linux guests get a new
+/// shadow hash; Windows guests get the Utilman recovery shell staged (or
+/// removed again if it already was).
+pub fn reset_password(
+    vm: &VMInfo,
+    user: &str,
+    password: impl FnOnce() -> String,
+    partition: Option<usize>,
+) -> Result<(), String> {
+    with_guest_fs(vm, partition, false, |root| match detect_os(root).as_deref() {
+        Some("Windows") => {
+            let system32 = system32(root)?;
+            if system32.join(UTILMAN_BACKUP).exists() {
+                restore_utilman(&system32)
+            } else {
+                stage_utilman(root, user)
+            }
+        }
+        Some(_) => {
+            let hash = hash_password(&password())?;
+            set_shadow_hash(root, user, &hash)?;
+            runner::best_effort(&mut ShellCommand::new("sync"));
+            println!("Password for '{}' on '{}' has been reset.", user, vm.name);
+            Ok(())
+        }
+        None => Err("no Linux or Windows installation found on that partition".to_string()),
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn guest_paths_stay_inside_the_mount() {
+        let root = std::env::temp_dir().join(format!("srqemu-guestfs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        symlink("usr/lib", root.join("lib")).unwrap();
+        symlink("/root", root.join("home")).unwrap();
+        symlink("../../../../../etc", root.join("usr/up")).unwrap();
+        symlink("/etc/shadow", root.join("etc/passwd")).unwrap();
+
+        assert_eq!(resolve_in_guest(&root, "/lib/libc.so", false).unwrap(), root.join("usr/lib/libc.so"));
+        // Absolute and climbing links land inside the guest, as after chroot.
+        assert_eq!(resolve_in_guest(&root, "/home/.ssh", false).unwrap(), root.join("root/.ssh"));
+        assert_eq!(resolve_in_guest(&root, "/usr/up/hosts", false).unwrap(), root.join("etc/hosts"));
+        assert_eq!(resolve_in_guest(&root, "/../../etc", false).unwrap(), root.join("etc"));
+        // The last link is returned as itself unless asked for.
+        assert_eq!(resolve_in_guest(&root, "/etc/passwd", false).unwrap(), root.join("etc/passwd"));
+        assert_eq!(resolve_in_guest(&root, "/etc/passwd", true).unwrap(), root.join("etc/shadow"));
+        assert!(open_in_guest(&root.join("etc/passwd"), fs::OpenOptions::new().read(true)).unwrap_err().contains("is a symlink"));
+
+        symlink("loop", root.join("loop")).unwrap();
+        assert!(resolve_in_guest(&root, "/loop/x", false).unwrap_err().contains("too many levels"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+//! Copies of the config taken before operations that drop or rewrite
+//! definitions, kept next to it in `history/` so `config rollback` can go
+//! back to an earlier inventory.
+
+use crate::config::{self, Settings, VMConfig};
+use crate::vault;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+pub struct Checkpoint {
+    pub path: PathBuf,
+    pub taken_at: SystemTime,
+    /// What was about to happen, e.g. `delete-web`.
+    pub reason: String,
+}
+
+impl Checkpoint {
+    pub fn age(&self) -> String {
+        let secs = SystemTime::now().duration_since(self.taken_at).map(|d| d.as_secs()).unwrap_or(0);
+        match secs {
+            s if s < 3600 => format!("{} min ago", s / 60),
+            s if s < 86400 => format!("{} h ago", s / 3600),
+            s => format!("{} days ago", s / 86400),
+        }
+    }
+}
+
+fn history_dir() -> PathBuf {
+    let active = config::active_path();
+    active.parent().map(|dir| dir.join("history")).unwrap_or_else(|| PathBuf::from("history"))
+}
+
+/// Checkpoints end in the active file's name, so an encrypted one is never
+/// mistaken for plain TOML or the other way round.
+fn suffix() -> String {
+    format!(".{}", config::active_path().file_name().unwrap_or_default().to_string_lossy())
+}
+
+/// Checkpoints of the active config file, newest first.
+pub fn list() -> Vec<Checkpoint> {
+    let suffix = suffix();
+    let Ok(files) = fs::read_dir(history_dir()) else {
+        return Vec::new();
+    };
+    let mut checkpoints: Vec<Checkpoint> = files
+        .flatten()
+        .filter_map(|f| {
+            let file_name = f.file_name().to_string_lossy().to_string();
+            let (millis, reason) = file_name.strip_suffix(&suffix)?.split_once('-')?;
+            Some(Checkpoint {
+                path: f.path(),
+                taken_at: UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?),
+                reason: reason.to_string(),
+            })
+        })
+        .collect();
+    checkpoints.sort_by_key(|c| std::cmp::Reverse(c.taken_at));
+    checkpoints
+}
+
+/// Copies the config file as it is now before `reason` changes it, then
+/// drops all but the newest `settings.config_history` copies. Nothing is
+/// kept while that is 0 or before the first save.
+pub fn checkpoint(settings: &Settings, reason: &str) -> Result<Option<PathBuf>, String> {
+    let Ok(current) = fs::read(config::active_path()) else {
+        return Ok(None);
+    };
+    if settings.config_history == 0 {
+        return Ok(None);
+    }
+    let dir = history_dir();
+    fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let reason: String = reason.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '-' }).collect();
+    let path = dir.join(format!("{}-{}{}", millis, reason, suffix()));
+    fs::write(&path, current).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+    for old in list().into_iter().skip(settings.config_history) {
+        if let Err(e) = fs::remove_file(&old.path) {
+            warn!("cannot remove old config checkpoint {}: {}", old.path.display(), e);
+        }
+    }
+    Ok(Some(path))
+}
+
+/// Encrypts the checkpoints taken while the config was stored as `plain`,
+/// which would otherwise keep it readable next to the encrypted one.
+/// Returns how many there were.
+pub fn encrypt_plain(plain: &Path) -> Result<usize, String> {
+    let suffix = format!(".{}", plain.file_name().unwrap_or_default().to_string_lossy());
+    let Ok(files) = fs::read_dir(history_dir()) else {
+        return Ok(0);
+    };
+    let mut encrypted = 0;
+    for path in files.flatten().map(|f| f.path()) {
+        if !path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(&suffix)) {
+            continue;
+        }
+        let contents = fs::read(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let mut target = path.clone().into_os_string();
+        target.push(".enc");
+        vault::write_to(Path::new(&target), &contents)?;
+        fs::remove_file(&path).map_err(|e| format!("cannot remove {}: {}", path.display(), e))?;
+        encrypted += 1;
+    }
+    Ok(encrypted)
+}
+
+/// VMs that a rollback brought back or took away.
+#[derive(Default)]
+pub struct RollbackReport {
+    pub restored: Vec<String>,
+    pub dropped: Vec<String>,
+}
+
+/// Makes `checkpoint` the active config, after checkpointing the current
+/// one so the rollback itself can be undone. A checkpoint that does not
+/// load is put back out of the way and reported.
+pub fn rollback(config: &mut VMConfig, checkpoint: &Checkpoint) -> Result<RollbackReport, String> {
+    let active = config::active_path();
+    let saved = fs::read(&checkpoint.path).map_err(|e| format!("cannot read {}: {}", checkpoint.path.display(), e))?;
+    let current = fs::read(&active).ok();
+    self::checkpoint(&config.settings, "rollback")?;
+    fs::write(&active, saved).map_err(|e| format!("cannot write {}: {}", active.display(), e))?;
+    let restored = match config::load_config() {
+        Ok(restored) => restored,
+        Err(e) => {
+            let undo = match &current {
+                Some(bytes) => fs::write(&active, bytes),
+                None => fs::remove_file(&active),
+            };
+            if let Err(undo) = undo {
+                return Err(format!("{}; putting the config back failed too: {}", e, undo));
+            }
+            return Err(format!("the checkpoint does not load: {}", e));
+        }
+    };
+    let mut report = RollbackReport {
+        restored: restored.vms.keys().filter(|name| !config.vms.contains_key(*name)).cloned().collect(),
+        dropped: config.vms.keys().filter(|name| !restored.vms.contains_key(*name)).cloned().collect(),
+    };
+    report.restored.sort();
+    report.dropped.sort();
+    *config = restored;
+    Ok(report)
+}
@@ -0,0 +1,108 @@
+use crate::config::VMConfig;
+use crate::{run, runprofile};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command as ShellCommand;
+use tracing::error;
+
+const CPU_SYSFS: &str = "/sys/devices/system/cpu";
+
+/// What SRQemu changed, so the host can be put back once no VM wants it.
+#[derive(Serialize, Deserialize)]
+struct Applied {
+    previous: String,
+    current: String,
+}
+
+fn state_file() -> PathBuf {
+    PathBuf::from(crate::get_vm_folder()).join(".host-power.json")
+}
+
+/// power-profiles-daemon works without root; raw cpufreq governors are the
+/// fallback on hosts without it.
+fn has_profiles_daemon() -> bool {
+    run(ShellCommand::new("powerprofilesctl").arg("get")).is_ok()
+}
+
+fn governor_files() -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(CPU_SYSFS) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().strip_prefix("cpu").is_some_and(|n| n.parse::<u32>().is_ok()))
+        .map(|e| e.path().join("cpufreq/scaling_governor"))
+        .filter(|p| p.exists())
+        .collect();
+    files.sort();
+    files
+}
+
+fn current() -> Result<String, String> {
+    if has_profiles_daemon() {
+        return run(ShellCommand::new("powerprofilesctl").arg("get")).map(|s| s.trim().to_string());
+    }
+    let first = governor_files().into_iter().next().ok_or("host has neither power-profiles-daemon nor cpufreq governors")?;
+    fs::read_to_string(&first).map(|s| s.trim().to_string()).map_err(|e| e.to_string())
+}
+
+fn set(profile: &str) -> Result<(), String> {
+    if has_profiles_daemon() {
+        return run(ShellCommand::new("powerprofilesctl").args(["set", profile])).map(|_| ());
+    }
+    let files = governor_files();
+    if files.is_empty() {
+        return Err("host has neither power-profiles-daemon nor cpufreq governors".to_string());
+    }
+    for file in files {
+        fs::write(&file, profile).map_err(|e| format!("cannot set governor in {} (run as root?): {}", file.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Switches the host to the profile a running VM asks for, or back to what
+/// it was once none does. `stopping` names a VM being shut down whose
+/// process may not have exited yet.
+pub fn update(config: &VMConfig, stopping: Option<&str>) {
+    let mut wanted: Vec<(&String, String)> = config
+        .vms
+        .iter()
+        .filter(|(name, _)| Some(name.as_str()) != stopping)
+        .filter_map(|(name, vm)| runprofile::apply(&config.settings, vm).host_power.map(|p| (name, p)))
+        .filter(|(name, _)| crate::vm_running(name))
+        .collect();
+    wanted.sort();
+    let applied: Option<Applied> = fs::read_to_string(state_file()).ok().and_then(|s| serde_json::from_str(&s).ok());
+
+    let result = match (wanted.first(), applied) {
+        (Some((vm, profile)), applied) => {
+            if applied.as_ref().is_some_and(|a| &a.current == profile) {
+                return;
+            }
+            let previous = match applied {
+                Some(a) => a.previous,
+                None => match current() {
+                    Ok(p) => p,
+                    Err(e) => {
+                        error!("Cannot read the host power profile: {}", e);
+                        return;
+                    }
+                },
+            };
+            set(profile).map(|_| {
+                println!("Host power profile set to '{}' for VM '{}'.", profile, vm);
+                let state = Applied { previous, current: profile.to_string() };
+                let _ = fs::write(state_file(), serde_json::to_string(&state).unwrap_or_default());
+            })
+        }
+        (None, Some(applied)) => set(&applied.previous).map(|_| {
+            println!("Host power profile restored to '{}'.", applied.previous);
+            let _ = fs::remove_file(state_file());
+        }),
+        (None, None) => Ok(()),
+    };
+    if let Err(e) = result {
+        error!("Failed to change the host power profile: {}", e);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::config::VMConfig;
+use std::fs;
+use std::path::PathBuf;
+use tracing::error;
+
+/// systemd-sleep runs every executable here with `pre|post <action>`
+/// around suspend and hibernate.
+const HOOK_PATH: &str = "/usr/lib/systemd/system-sleep/srqemu";
+
+/// VMs paused by the last `pre` call, so `post` leaves alone any the user
+/// had paused themselves.
+fn paused_file() -> PathBuf {
+    PathBuf::from(crate::get_vm_folder()).join(".sleep-paused.json")
+}
+
+fn is_running_state(vm_name: &str) -> Result<bool, String> {
+    Ok(crate::qmp::run_state(vm_name)? == "running")
+}
+
+/// Pauses every running VM before the host sleeps.
+pub fn pre(config: &VMConfig) {
+    let mut paused = Vec::new();
+    for name in config.vms.keys() {
+        if !crate::vm_running(name) {
+            continue;
+        }
+        match is_running_state(name) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                error!("VM '{}': cannot query state: {}", name, e);
+                continue;
+            }
+        }
+        match crate::qmp::command(name, "stop", None) {
+            Ok(_) => {
+                println!("Paused VM '{}' for host sleep.", name);
+                paused.push(name.clone());
+            }
+            Err(e) => error!("Failed to pause VM '{}': {}", name, e),
+        }
+    }
+    let json = serde_json::to_string(&paused).unwrap_or_default();
+    if let Err(e) = fs::write(paused_file(), json) {
+        error!("Cannot record paused VMs: {}", e);
+    }
+}
+
+/// Resumes what `pre` paused and, where asked for, resets guest clocks.
+pub fn post(config: &VMConfig) {
+    let paused: Vec<String> = fs::read_to_string(paused_file())
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    let _ = fs::remove_file(paused_file());
+    for name in paused {
+        match crate::qmp::command(&name, "cont", None) {
+            Ok(_) => println!("Resumed VM '{}'.", name),
+            Err(e) => {
+                error!("Failed to resume VM '{}': {}", name, e);
+                continue;
+            }
+        }
+        if let Some(vm) = config.vms.get(&name) {
+            crate::after_resume(vm);
+        }
+    }
+}
+
+/// Installs the systemd-sleep hook, running this binary as `user` so it
+/// finds that user's config and VM folder.
+pub fn install(user: &str, home: &str) -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("cannot locate this binary: {}", e))?;
+    let script = format!(
+        "#!/bin/sh\n# Installed by SRQemu: pauses VMs across host sleep.\nexec runuser -u {} -- env HOME={} {} sleep-hook \"$1\" \"$2\"\n",
+        user,
+        home,
+        exe.display()
+    );
+    fs::write(HOOK_PATH, script).map_err(|e| format!("cannot write {} (run as root?): {}", HOOK_PATH, e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(HOOK_PATH, fs::Permissions::from_mode(0o755)).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+pub fn uninstall() -> Result<(), String> {
+    fs::remove_file(HOOK_PATH).map_err(|e| format!("cannot remove {}: {}", HOOK_PATH, e))
+}
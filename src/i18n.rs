@@ -0,0 +1,134 @@
+//! Translated messages. Catalogs in `locales/` are compiled in, one per
+//! language, in a subset of Fluent; the language comes from
+//! `SRQEMU_LANG`, the `language` setting, or the system locale, in that
+//! order. Keys missing from a catalog fall back to English.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+/// Catalogs by language code; the first is the fallback.
+const CATALOGS: [(&str, &str); 2] = [("en", include_str!("../locales/en.ftl")), ("de", include_str!("../locales/de.ftl"))];
+
+/// Overrides the `language` setting and the system locale.
+pub const LANG_ENV: &str = "SRQEMU_LANG";
+
+static LANGUAGE: OnceLock<&'static str> = OnceLock::new();
+
+/// Messages by key. Comments and blank lines are skipped; indented lines
+/// continue the message before them.
+fn parse(ftl: &str) -> HashMap<&str, String> {
+    let mut messages: HashMap<&str, String> = HashMap::new();
+    let mut last: Option<&str> = None;
+    for line in ftl.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some(message) = last.and_then(|key| messages.get_mut(key)) {
+                message.push('\n');
+                message.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((key, text)) = line.split_once('=') {
+            let key = key.trim();
+            messages.insert(key, text.trim().to_string());
+            last = Some(key);
+        }
+    }
+    messages
+}
+
+fn catalogs() -> &'static HashMap<&'static str, HashMap<&'static str, String>> {
+    static PARSED: OnceLock<HashMap<&str, HashMap<&str, String>>> = OnceLock::new();
+    PARSED.get_or_init(|| CATALOGS.iter().map(|(language, ftl)| (*language, parse(ftl))).collect())
+}
+
+/// The catalog for a language setting or locale such as `de`, `de_AT.UTF-8`
+/// or `de:en`, if there is one.
+fn catalog_for(locale: &str) -> Option<&'static str> {
+    let first = locale.split(':').next().unwrap_or(locale);
+    let language = first.split(['_', '-', '.', '@']).next().unwrap_or(first).to_ascii_lowercase();
+    CATALOGS.iter().map(|(code, _)| *code).find(|code| *code == language)
+}
+
+/// The language to speak, from `env` and the `language` setting.
+fn choose(env: impl Fn(&str) -> Option<String>, configured: Option<&str>) -> &'static str {
+    let set = |value: Option<String>| value.filter(|v| !v.is_empty());
+    if let Some(language) = set(env(LANG_ENV)).or_else(|| configured.map(str::to_string)) {
+        return catalog_for(&language).unwrap_or(CATALOGS[0].0);
+    }
+    // As gettext does: LANGUAGE only counts with a locale set.
+    let locale = set(env("LC_ALL")).or_else(|| set(env("LC_MESSAGES"))).or_else(|| set(env("LANG")));
+    if locale.as_deref().is_none_or(|l| l == "C" || l == "POSIX") {
+        return CATALOGS[0].0;
+    }
+    set(env("LANGUAGE")).and_then(|l| catalog_for(&l)).or_else(|| locale.and_then(|l| catalog_for(&l))).unwrap_or(CATALOGS[0].0)
+}
+
+/// Settles the language once the config is loaded; messages before that
+/// follow the environment alone.
+pub fn init(configured: Option<&str>) {
+    let _ = LANGUAGE.set(choose(|key| std::env::var(key).ok(), configured));
+}
+
+fn language() -> &'static str {
+    LANGUAGE.get_or_init(|| choose(|key| std::env::var(key).ok(), None))
+}
+
+fn format(text: &str, args: &[(&str, &dyn Display)]) -> String {
+    let mut out = text.to_string();
+    for (name, value) in args {
+        out = out.replace(&format!("{{ ${} }}", name), &value.to_string());
+    }
+    out
+}
+
+/// The message `key` in the current language with `{ $name }` filled in
+/// from `args`.
+pub fn t(key: &str, args: &[(&str, &dyn Display)]) -> String {
+    let catalogs = catalogs();
+    let text = catalogs.get(language()).and_then(|c| c.get(key)).or_else(|| catalogs[CATALOGS[0].0].get(key));
+    match text {
+        Some(text) => format(text, args),
+        None => key.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placeholders(text: &str) -> Vec<&str> {
+        let mut names: Vec<&str> = text.split("{ $").skip(1).filter_map(|rest| rest.split_once(" }").map(|(name, _)| name)).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn catalogs_match_english() {
+        let english = &catalogs()["en"];
+        for (language, _) in &CATALOGS[1..] {
+            let catalog = &catalogs()[language];
+            for (key, text) in catalog {
+                let original = english.get(key).unwrap_or_else(|| panic!("{}: '{}' is not an English key", language, key));
+                assert_eq!(placeholders(text), placeholders(original), "{}: '{}' has other placeholders", language, key);
+            }
+            let missing: Vec<&&str> = english.keys().filter(|key| !catalog.contains_key(*key)).collect();
+            assert!(missing.is_empty(), "{} lacks {:?}", language, missing);
+        }
+    }
+
+    #[test]
+    fn language_follows_override_setting_then_locale() {
+        let env = |vars: &'static [(&'static str, &'static str)]| move |key: &str| vars.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string());
+        assert_eq!(choose(env(&[("LANG", "de_DE.UTF-8")]), None), "de");
+        assert_eq!(choose(env(&[("LANG", "de_DE.UTF-8"), ("LC_ALL", "C")]), None), "en");
+        assert_eq!(choose(env(&[("LANG", "en_US.UTF-8"), ("LANGUAGE", "de:en")]), None), "de");
+        assert_eq!(choose(env(&[("LANG", "de_DE.UTF-8")]), Some("en")), "en");
+        assert_eq!(choose(env(&[("SRQEMU_LANG", "de"), ("LANG", "fr_FR")]), Some("en")), "de");
+        assert_eq!(choose(env(&[("LANG", "fr_FR.UTF-8")]), None), "en");
+        assert_eq!(format("VM '{ $name }' in { $mode } mode", &[("name", &"web"), ("mode", &"GUI")]), "VM 'web' in GUI mode");
+    }
+}
@@ -0,0 +1,413 @@
+use crate::config::Settings;
+use crate::{provenance, run};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command as ShellCommand;
+use tracing::{error, warn};
+
+/// How much a downloaded image was checked before it was kept.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Trust {
+    /// Matched a checksum file whose signature a trusted key made.
+    Signed,
+    /// Matched a checksum file that carried no valid signature.
+    Checksum,
+    /// Nothing to check against.
+    None,
+}
+
+impl Trust {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Trust::Signed => "signed checksum",
+            Trust::Checksum => "unsigned checksum",
+            Trust::None => "unverified",
+        }
+    }
+}
+
+/// A downloaded image, listed in `~/vms/images/index.json`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CachedImage {
+    pub file: String,
+    pub url: String,
+    pub sha256: String,
+    pub trust: Trust,
+    /// Catalog name it was pulled as, usable in place of the path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Installer booted from the CD drive.
+    Iso,
+    /// Ready-made disk for `create --cloud-image`.
+    Cloud,
+}
+
+/// An image `images pull` knows where to find.
+pub struct Known {
+    pub name: &'static str,
+    pub kind: Kind,
+    /// Directory the image and its checksums are published in.
+    base: &'static str,
+    /// File name; one `*` stands for a point release that changes over time,
+    /// looked up in the checksum file.
+    file: &'static str,
+    sums: &'static str,
+    signature: Option<&'static str>,
+}
+
+pub const CATALOG: &[Known] = &[
+    Known {
+        name: "ubuntu-24.04",
+        kind: Kind::Iso,
+        base: "https://releases.ubuntu.com/24.04/",
+        file: "ubuntu-24.04*-live-server-amd64.iso",
+        sums: "SHA256SUMS",
+        signature: Some("SHA256SUMS.gpg"),
+    },
+    Known {
+        name: "ubuntu-24.04-cloud",
+        kind: Kind::Cloud,
+        base: "https://cloud-images.ubuntu.com/releases/noble/release/",
+        file: "ubuntu-24.04-server-cloudimg-amd64.img",
+        sums: "SHA256SUMS",
+        signature: Some("SHA256SUMS.gpg"),
+    },
+    Known {
+        name: "ubuntu-22.04",
+        kind: Kind::Iso,
+        base: "https://releases.ubuntu.com/22.04/",
+        file: "ubuntu-22.04*-live-server-amd64.iso",
+        sums: "SHA256SUMS",
+        signature: Some("SHA256SUMS.gpg"),
+    },
+    Known {
+        name: "ubuntu-22.04-cloud",
+        kind: Kind::Cloud,
+        base: "https://cloud-images.ubuntu.com/releases/jammy/release/",
+        file: "ubuntu-22.04-server-cloudimg-amd64.img",
+        sums: "SHA256SUMS",
+        signature: Some("SHA256SUMS.gpg"),
+    },
+    Known {
+        name: "debian-13",
+        kind: Kind::Iso,
+        base: "https://cdimage.debian.org/debian-cd/current/amd64/iso-cd/",
+        file: "debian-13.*-amd64-netinst.iso",
+        sums: "SHA256SUMS",
+        signature: Some("SHA256SUMS.sign"),
+    },
+];
+
+pub fn known(name: &str) -> Option<&'static Known> {
+    CATALOG.iter().find(|k| k.name == name)
+}
+
+/// Where the distro publishes the checksums for an image.
+pub struct ChecksumSource {
+    /// `SHA256SUMS`, `CHECKSUM` or similar; may be clearsigned.
+    pub url: String,
+    /// Detached signature of the checksum file; unset for clearsigned files
+    /// or publishers that do not sign.
+    pub signature_url: Option<String>,
+}
+
+pub fn images_dir() -> PathBuf {
+    PathBuf::from(crate::get_vm_folder()).join("images")
+}
+
+/// Public keys whose signatures are trusted, one binary keyring per file.
+fn keys_dir() -> PathBuf {
+    images_dir().join("trusted-keys")
+}
+
+fn index_file() -> PathBuf {
+    images_dir().join("index.json")
+}
+
+pub fn list() -> Vec<CachedImage> {
+    fs::read_to_string(index_file()).ok().and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default()
+}
+
+fn save_index(images: &[CachedImage]) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(images).map_err(|e| e.to_string())?;
+    fs::write(index_file(), json).map_err(|e| format!("cannot write image index: {}", e))
+}
+
+/// Points index entries under `old` to the same file under `new`, after the
+/// image directory moved.
+pub fn relocate_index(old: &Path, new: &Path) -> Result<(), String> {
+    let mut images = list();
+    if images.is_empty() {
+        return Ok(());
+    }
+    for image in images.iter_mut() {
+        if let Ok(rest) = Path::new(&image.file).strip_prefix(old) {
+            image.file = new.join(rest).display().to_string();
+        }
+    }
+    save_index(&images)
+}
+
+/// Fetches a small file such as a checksum list or signature.
+fn download(url: &str, dest: &Path) -> Result<(), String> {
+    let mut cmd = ShellCommand::new("curl");
+    cmd.args(["-fL", "--retry", "3", "-o"]).arg(dest).arg(url);
+    run(&mut cmd).map(|_| ())
+}
+
+/// Seconds a mirror takes to answer a HEAD request; unreachable mirrors
+/// sort last.
+fn probe(url: &str) -> f64 {
+    let out = run(ShellCommand::new("curl").args(["-sfIL", "--max-time", "5", "-o", "/dev/null", "-w", "%{time_total}", url]));
+    out.ok().and_then(|t| t.trim().parse().ok()).unwrap_or(f64::MAX)
+}
+
+/// Downloads a large image from the fastest answering mirror into
+/// `<dest>.part`, resuming whatever an interrupted run left there, and
+/// moves on to the next mirror if one fails.
+fn download_image(urls: &[String], dest: &Path, rate_limit: Option<&str>) -> Result<(), String> {
+    let part = PathBuf::from(format!("{}.part", dest.display()));
+    let mut mirrors: Vec<(f64, &String)> = urls.iter().map(|u| (if urls.len() > 1 { probe(u) } else { 0.0 }, u)).collect();
+    mirrors.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut last_error = String::new();
+    for (_, url) in mirrors {
+        if part.exists() {
+            println!("Resuming {} from {}...", dest.display(), url);
+        } else {
+            println!("Downloading {}...", url);
+        }
+        let mut cmd = ShellCommand::new("curl");
+        // Plain output keeps curl's errors but drops its progress bar.
+        cmd.args(["-fL", if crate::output::plain() { "-sS" } else { "-#" }, "-C", "-", "--retry", "3"]);
+        if let Some(limit) = rate_limit {
+            cmd.args(["--limit-rate", limit]);
+        }
+        cmd.arg("-o").arg(&part).arg(url);
+        match cmd.status() {
+            Ok(status) if status.success() => {
+                return fs::rename(&part, dest).map_err(|e| format!("cannot move {} into place: {}", part.display(), e));
+            }
+            Ok(status) => last_error = format!("curl failed on {} ({})", url, status),
+            Err(e) => last_error = format!("failed to run curl: {}", e),
+        }
+        error!("{}", last_error);
+    }
+    Err(format!("{}; run the download again to resume", last_error))
+}
+
+/// Adds a distro signing key (armored or binary) to the trusted set.
+pub fn trust_key(key_file: &Path) -> Result<PathBuf, String> {
+    fs::create_dir_all(keys_dir()).map_err(|e| format!("cannot create {}: {}", keys_dir().display(), e))?;
+    let name = key_file.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_else(|| "key".to_string());
+    let dest = keys_dir().join(format!("{}.gpg", name));
+    // gpgv only reads binary keyrings.
+    let mut cmd = ShellCommand::new("gpg");
+    cmd.args(["--batch", "--yes", "--dearmor", "-o"]).arg(&dest).arg(key_file);
+    if run(&mut cmd).is_err() {
+        fs::copy(key_file, &dest).map_err(|e| format!("cannot copy {}: {}", key_file.display(), e))?;
+    }
+    Ok(dest)
+}
+
+fn trusted_keyrings() -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(keys_dir()) else {
+        return Vec::new();
+    };
+    entries.flatten().map(|e| e.path()).filter(|p| p.extension().is_some_and(|x| x == "gpg")).collect()
+}
+
+/// Checks a checksum file's signature against the trusted keys and returns
+/// the signed text. Without a detached signature the file has to be
+/// clearsigned.
+fn verify_signature(sums: &Path, signature: Option<&Path>) -> Result<String, String> {
+    let keyrings = trusted_keyrings();
+    if keyrings.is_empty() {
+        return Err("no trusted signing keys; add the distro's key first".to_string());
+    }
+    let mut cmd = ShellCommand::new("gpgv");
+    for keyring in &keyrings {
+        cmd.arg("--keyring").arg(keyring);
+    }
+    match signature {
+        Some(signature) => {
+            cmd.arg(signature).arg(sums);
+            run(&mut cmd)?;
+            fs::read_to_string(sums).map_err(|e| e.to_string())
+        }
+        None => {
+            cmd.args(["--output", "-"]).arg(sums);
+            run(&mut cmd)
+        }
+    }
+}
+
+/// File names and SHA-256 hashes of a checksum listing, in either GNU
+/// (`<hash>  <name>`) or BSD (`SHA256 (<name>) = <hash>`) form.
+fn checksum_entries(sums: &str) -> impl Iterator<Item = (&str, String)> {
+    sums.lines().filter_map(|line| {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("SHA256 (") {
+            let (name, hash) = rest.split_once(") = ")?;
+            return Some((name, hash.trim().to_lowercase()));
+        }
+        let (hash, name) = line.split_once(char::is_whitespace)?;
+        (hash.len() == 64).then(|| (name.trim().trim_start_matches('*'), hash.to_lowercase()))
+    })
+}
+
+fn expected_sha256(sums: &str, file: &str) -> Option<String> {
+    checksum_entries(sums).find(|(name, _)| *name == file).map(|(_, hash)| hash)
+}
+
+/// The newest file in a checksum listing that `pattern` matches.
+fn newest_match(sums: &str, pattern: &str) -> Option<String> {
+    let (prefix, suffix) = pattern.split_once('*').unwrap_or((pattern, ""));
+    checksum_entries(sums)
+        .map(|(name, _)| name)
+        .filter(|name| name.len() >= prefix.len() + suffix.len() && name.starts_with(prefix) && name.ends_with(suffix))
+        .max_by_key(|name| release_key(&name[prefix.len()..name.len() - suffix.len()]))
+        .map(str::to_string)
+}
+
+/// Orders point releases numerically, so `.10` comes after `.9`.
+fn release_key(release: &str) -> Vec<u64> {
+    release.split(|c: char| !c.is_ascii_digit()).filter_map(|n| n.parse().ok()).collect()
+}
+
+/// Downloads an image into the image cache and checks it against the
+/// publisher's checksums. `urls` are mirrors of the same file. With
+/// `require_signed_images` set, anything short of a signed match is
+/// deleted instead of kept.
+pub fn fetch(urls: &[String], checksums: Option<&ChecksumSource>, settings: &Settings) -> Result<CachedImage, String> {
+    let url = urls.first().ok_or("no image URL given")?;
+    let file = url.rsplit('/').next().filter(|f| !f.is_empty()).ok_or_else(|| format!("no file name in {}", url))?;
+    fs::create_dir_all(images_dir()).map_err(|e| format!("cannot create {}: {}", images_dir().display(), e))?;
+    let dest = images_dir().join(file);
+    download_image(urls, &dest, settings.download_rate_limit.as_deref())?;
+    let sha256 = provenance::sha256(&dest.display().to_string())?;
+
+    let trust = match checksums {
+        Some(source) => {
+            let sums = images_dir().join(format!("{}.sums", file));
+            download(&source.url, &sums)?;
+            let signature = match &source.signature_url {
+                Some(sig_url) => {
+                    let sig = images_dir().join(format!("{}.sums.sig", file));
+                    download(sig_url, &sig)?;
+                    Some(sig)
+                }
+                None => None,
+            };
+            let verified = verify_signature(&sums, signature.as_deref());
+            let (text, trust) = match verified {
+                Ok(text) => (text, Trust::Signed),
+                Err(e) => {
+                    warn!("Checksum file is not signed by a trusted key: {}", e);
+                    (fs::read_to_string(&sums).unwrap_or_default(), Trust::Checksum)
+                }
+            };
+            let _ = fs::remove_file(&sums);
+            if let Some(sig) = signature {
+                let _ = fs::remove_file(sig);
+            }
+            match expected_sha256(&text, file) {
+                Some(expected) if expected == sha256 => trust,
+                Some(expected) => {
+                    let _ = fs::remove_file(&dest);
+                    return Err(format!("{} does not match its published checksum ({} instead of {})", file, sha256, expected));
+                }
+                None => {
+                    error!("{} is not listed in the checksum file.", file);
+                    Trust::None
+                }
+            }
+        }
+        None => Trust::None,
+    };
+    if settings.require_signed_images && trust != Trust::Signed {
+        let _ = fs::remove_file(&dest);
+        return Err(format!("{} has no valid signed checksum and only signed images are allowed", file));
+    }
+
+    let image = CachedImage { file: dest.display().to_string(), url: url.to_string(), sha256, trust, alias: None };
+    let mut index = list();
+    index.retain(|i| i.file != image.file);
+    index.push(image.clone());
+    save_index(&index)?;
+    Ok(image)
+}
+
+/// Downloads a catalog image and makes its name an alias for the file.
+/// An older release pulled under the same name stays on disk for the VMs
+/// using it, but loses the alias.
+pub fn pull(name: &str, settings: &Settings) -> Result<CachedImage, String> {
+    let known = known(name).ok_or_else(|| {
+        let names: Vec<&str> = CATALOG.iter().map(|k| k.name).collect();
+        format!("no image called '{}'; known are {}", name, names.join(", "))
+    })?;
+    let sums_url = format!("{}{}", known.base, known.sums);
+    let file = if known.file.contains('*') {
+        let listing = run(ShellCommand::new("curl").args(["-fsL", "--retry", "3", &sums_url]))?;
+        newest_match(&listing, known.file).ok_or_else(|| format!("{} lists no file like {}", sums_url, known.file))?
+    } else {
+        known.file.to_string()
+    };
+    let checksums = ChecksumSource { url: sums_url, signature_url: known.signature.map(|sig| format!("{}{}", known.base, sig)) };
+    let mut image = fetch(&[format!("{}{}", known.base, file)], Some(&checksums), settings)?;
+    image.alias = Some(name.to_string());
+    let mut index = list();
+    for cached in index.iter_mut() {
+        if cached.file == image.file {
+            cached.alias = image.alias.clone();
+        } else if cached.alias == image.alias {
+            cached.alias = None;
+        }
+    }
+    save_index(&index)?;
+    Ok(image)
+}
+
+/// The file behind an image alias, or `path` unchanged when it is none.
+/// An alias for the other `kind` of image is refused.
+pub fn resolve(path: &str, kind: Kind) -> Result<String, String> {
+    let Some(known) = known(path).filter(|_| !Path::new(path).exists()) else {
+        return Ok(path.to_string());
+    };
+    if known.kind != kind {
+        let option = if known.kind == Kind::Cloud { "--cloud-image" } else { "--iso" };
+        return Err(format!("'{}' is not that kind of image; pass it as {}", path, option));
+    }
+    match list().into_iter().find(|i| i.alias.as_deref() == Some(path)) {
+        Some(image) => Ok(image.file),
+        None => Err(format!("image '{}' is not downloaded yet; run `SRQemu images pull {}`", path, path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUMS: &str = "\
+3f2c...  not-a-hash.iso
+c3514bf0056180d09376462a7a1b4f213c1d6e8ea67fae5c25099c6fd3d8274b *ubuntu-24.04.3-live-server-amd64.iso
+d6dab0c3a657988501b4bd76f1297c053df710e06e0c3aece60dead24f270b4d *ubuntu-24.04.10-live-server-amd64.iso
+SHA256 (ubuntu-24.04.9-desktop-amd64.iso) = 5B5ACE4E2B4D6E1E1EDB7F1B4C9E2BAA8E5E0EF0C1A3F4A3E5D1C0B7A6F5E4D3
+";
+
+    #[test]
+    fn point_releases_are_found_in_checksum_files() {
+        assert_eq!(newest_match(SUMS, "ubuntu-24.04*-live-server-amd64.iso").as_deref(), Some("ubuntu-24.04.10-live-server-amd64.iso"));
+        assert_eq!(newest_match(SUMS, "debian-13.*-amd64-netinst.iso"), None);
+        assert_eq!(
+            expected_sha256(SUMS, "ubuntu-24.04.9-desktop-amd64.iso").as_deref(),
+            Some("5b5ace4e2b4d6e1e1edb7f1b4c9e2baa8e5e0ef0c1a3f4a3e5d1c0b7a6f5e4d3")
+        );
+        assert_eq!(expected_sha256(SUMS, "not-a-hash.iso"), None);
+    }
+}
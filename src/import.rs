@@ -0,0 +1,347 @@
+use crate::clock::TimeSpec;
+use crate::config::{VMConfig, VMInfo};
+use crate::display::DisplaySpec;
+use crate::network::{self, NetBackend, NicSpec};
+use crate::xml::{self, Element};
+use crate::{nbd, run};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command as ShellCommand;
+
+/// What to do with a disk found in an imported machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiskMode {
+    /// Use the image where it is.
+    Keep,
+    /// Symlink it into the VM folder.
+    Link,
+    /// Convert it to qcow2 inside the VM folder, leaving the original alone.
+    Copy,
+}
+
+impl DiskMode {
+    pub fn parse(spec: &str) -> Result<DiskMode, String> {
+        match spec {
+            "keep" => Ok(DiskMode::Keep),
+            "link" => Ok(DiskMode::Link),
+            "copy" => Ok(DiskMode::Copy),
+            _ => Err(format!("unknown disk mode '{}' (keep, link or copy)", spec)),
+        }
+    }
+}
+
+/// A VM rebuilt from another manager's definition, before its disk is
+/// brought over. Notes list what could not be mapped.
+pub struct Imported {
+    pub vm: VMInfo,
+    pub notes: Vec<String>,
+}
+
+/// Formats a size in KiB the way VM definitions spell memory.
+fn memory_from_kib(kib: u64) -> String {
+    if kib.is_multiple_of(1024 * 1024) {
+        format!("{}G", kib / (1024 * 1024))
+    } else {
+        format!("{}M", kib / 1024)
+    }
+}
+
+/// libvirt's `<memory unit=...>` in KiB.
+fn libvirt_kib(element: &Element) -> Option<u64> {
+    let value: u64 = element.text.trim().parse().ok()?;
+    let factor = match element.attr("unit").unwrap_or("KiB") {
+        "b" | "bytes" => return Some(value / 1024),
+        "KB" => return Some(value * 1000 / 1024),
+        "k" | "KiB" => 1,
+        "M" | "MiB" => 1024,
+        "MB" => return Some(value * 1000 * 1000 / 1024),
+        "G" | "GiB" => 1024 * 1024,
+        "GB" => return Some(value * 1000 * 1000 * 1000 / 1024),
+        _ => return None,
+    };
+    Some(value * factor)
+}
+
+/// Reads a domain's XML either from a file or from `virsh dumpxml`.
+pub fn libvirt_xml(source: &str, uri: &str) -> Result<String, String> {
+    if Path::new(source).is_file() {
+        return fs::read_to_string(source).map_err(|e| format!("cannot read {}: {}", source, e));
+    }
+    run(ShellCommand::new("virsh").args(["-c", uri, "dumpxml", source]))
+}
+
+/// Maps a libvirt domain onto a VM definition. `name` overrides the
+/// domain's own name.
+pub fn from_libvirt(doc: &str, name: Option<&str>, config: &VMConfig) -> Result<Imported, String> {
+    let domain = xml::parse(doc)?;
+    if domain.name != "domain" {
+        return Err(format!("expected a <domain>, found <{}>", domain.name));
+    }
+    let mut notes = Vec::new();
+    let mut vm = VMInfo { cpu: "host".to_string(), memory: "1G".to_string(), threads: "1".to_string(), ..Default::default() };
+    vm.name = match name {
+        Some(name) => name.to_string(),
+        None => domain.child("name").map(|n| n.text.clone()).unwrap_or_default(),
+    };
+    if vm.name.is_empty() {
+        return Err("the domain has no name; give the VM one when importing".to_string());
+    }
+    if config.vms.contains_key(&vm.name) {
+        return Err(format!("a VM named '{}' already exists", vm.name));
+    }
+    if let Some(kib) = domain.child("memory").and_then(libvirt_kib) {
+        vm.memory = memory_from_kib(kib);
+    }
+    if let Some(vcpu) = domain.child("vcpu") {
+        vm.threads = vcpu.text.trim().to_string();
+    }
+    if let Some(cpu) = domain.child("cpu") {
+        match (cpu.attr("mode"), cpu.child("model")) {
+            (Some("custom"), Some(model)) => vm.cpu = model.text.trim().to_string(),
+            (Some("custom"), None) | (Some("host-passthrough" | "host-model" | "maximum"), _) | (None, _) => {}
+            (Some(other), _) => notes.push(format!("CPU mode '{}' mapped to host", other)),
+        }
+    }
+    if domain.find("os/loader").is_some() {
+        notes.push("UEFI firmware is not supported; the guest will boot with SeaBIOS".to_string());
+    }
+
+    let Some(devices) = domain.child("devices") else {
+        return Ok(Imported { vm, notes });
+    };
+    for disk in devices.children("disk") {
+        let file = disk.child("source").and_then(|s| s.attr("file").or(s.attr("dev")));
+        match (disk.attr("device").unwrap_or("disk"), file) {
+            ("cdrom", Some(file)) if vm.iso.is_empty() => vm.iso = file.to_string(),
+            ("cdrom", None) => {}
+            ("disk", Some(file)) if vm.disk.is_empty() => vm.disk = file.to_string(),
+            (_, Some(file)) => notes.push(format!("extra disk {} not imported", file)),
+            (kind, None) => notes.push(format!("{} without a file source not imported", kind)),
+        }
+    }
+    for (i, iface) in devices.children("interface").enumerate() {
+        let source = iface.child("source");
+        let backend = match iface.attr("type") {
+            Some("bridge") => NetBackend::Bridge { bridge: source.and_then(|s| s.attr("bridge")).unwrap_or("br0").to_string() },
+            Some("user") => NetBackend::User { ipv6_net: None, hostfwd: Vec::new() },
+            Some("network") => {
+                let net = source.and_then(|s| s.attr("network")).unwrap_or("default");
+                if config.networks.contains_key(net) {
+                    NetBackend::Network { network: net.to_string() }
+                } else {
+                    notes.push(format!("NIC {} on libvirt network '{}' mapped to user-mode networking", i, net));
+                    NetBackend::User { ipv6_net: None, hostfwd: Vec::new() }
+                }
+            }
+            other => {
+                notes.push(format!("NIC {} of type '{}' not imported", i, other.unwrap_or("?")));
+                continue;
+            }
+        };
+        let mac = match iface.child("mac").and_then(|m| m.attr("address")) {
+            Some(mac) => mac.to_string(),
+            None => network::generate_mac(&vm.name, vm.nics.len()),
+        };
+        vm.nics.push(NicSpec { backend, mac, impairment: None, virtio: None });
+    }
+
+    let mut display = DisplaySpec::default();
+    if let Some(model) = devices.find("video/model").and_then(|m| m.attr("type")) {
+        display.model = match model {
+            "virtio" | "qxl" => Some(model.to_string()),
+            "vga" => Some("std".to_string()),
+            _ => {
+                notes.push(format!("video model '{}' not supported; QEMU's default card is used", model));
+                None
+            }
+        };
+    }
+    for graphics in devices.children("graphics") {
+        match (graphics.attr("type"), graphics.attr("port").and_then(|p| p.parse::<i32>().ok())) {
+            (Some("vnc"), Some(port)) if port >= 5900 => {
+                display.vnc = Some(format!(":{}", port - 5900));
+                display.listen = graphics.attr("listen").filter(|l| *l != "127.0.0.1").map(str::to_string);
+                display.keymap = graphics.attr("keymap").map(str::to_string);
+            }
+            (Some("vnc"), _) => notes.push("VNC with an automatic port not imported".to_string()),
+            (Some(kind), _) => notes.push(format!("{} graphics not imported", kind)),
+            _ => {}
+        }
+    }
+    vm.display = if display.is_empty() { None } else { Some(display) };
+
+    for controller in devices.children("controller").filter(|c| c.attr("type") == Some("usb")) {
+        vm.usb = match controller.attr("model") {
+            Some("qemu-xhci" | "nec-xhci") => Some("xhci".to_string()),
+            Some(m) if m.starts_with("ich9-ehci") || m == "ehci" => Some("ehci".to_string()),
+            Some("piix3-uhci" | "piix4-uhci") => Some("uhci".to_string()),
+            Some("none") => Some("none".to_string()),
+            _ => None,
+        };
+    }
+    vm.guest_agent = devices
+        .children("channel")
+        .any(|c| c.find("target").and_then(|t| t.attr("name")) == Some("org.qemu.guest_agent.0"));
+
+    if let Some(clock) = domain.child("clock") {
+        let mut time = TimeSpec::default();
+        if clock.attr("offset") == Some("localtime") {
+            time.rtc_base = Some("localtime".to_string());
+        }
+        for timer in clock.children("timer") {
+            let present = timer.attr("present").map(|p| p == "yes");
+            match timer.attr("name") {
+                Some("hpet") => time.hpet = present,
+                Some("kvmclock") => time.kvmclock = present,
+                Some("rtc") if timer.attr("tickpolicy") == Some("catchup") => time.driftfix = true,
+                _ => {}
+            }
+        }
+        vm.time = if time.is_empty() { None } else { Some(time) };
+    }
+    Ok(Imported { vm, notes })
+}
+
+/// VirtualBox stores MACs as bare hex, e.g. `080027AABBCC`.
+fn vbox_mac(raw: &str) -> Option<String> {
+    if raw.len() != 12 || !raw.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let pairs: Vec<&str> = (0..6).map(|i| &raw[i * 2..i * 2 + 2]).collect();
+    Some(pairs.join(":").to_lowercase())
+}
+
+/// Maps a VirtualBox machine (`.vbox` file) onto a VM definition. Media
+/// paths are resolved against the `.vbox` file's folder.
+pub fn from_vbox(path: &Path, name: Option<&str>, config: &VMConfig) -> Result<Imported, String> {
+    let doc = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let root = xml::parse(&doc)?;
+    let machine = root.child("Machine").ok_or("not a VirtualBox machine file (no <Machine>)")?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    let mut notes = Vec::new();
+    let mut vm = VMInfo { cpu: "host".to_string(), memory: "1G".to_string(), threads: "1".to_string(), ..Default::default() };
+    vm.name = name.or(machine.attr("name")).unwrap_or_default().to_string();
+    if vm.name.is_empty() {
+        return Err("the machine has no name; give the VM one when importing".to_string());
+    }
+    if config.vms.contains_key(&vm.name) {
+        return Err(format!("a VM named '{}' already exists", vm.name));
+    }
+
+    let hardware = machine.child("Hardware").ok_or("machine has no <Hardware>")?;
+    if let Some(mb) = hardware.child("Memory").and_then(|m| m.attr("RAMSize")).and_then(|m| m.parse::<u64>().ok()) {
+        vm.memory = memory_from_kib(mb * 1024);
+    }
+    if let Some(count) = hardware.child("CPU").and_then(|c| c.attr("count")) {
+        vm.threads = count.to_string();
+    }
+    if hardware.find("Firmware").and_then(|f| f.attr("type")).is_some_and(|t| t.starts_with("EFI")) {
+        notes.push("UEFI firmware is not supported; the guest will boot with SeaBIOS".to_string());
+    }
+    // VirtualBox keeps the RTC in local time unless told otherwise.
+    if hardware.child("RTC").and_then(|r| r.attr("localOrUTC")) != Some("UTC") {
+        vm.time = Some(TimeSpec { rtc_base: Some("localtime".to_string()), ..Default::default() });
+    }
+    if let Some(usb) = hardware.find("USB/Controllers/Controller") {
+        vm.usb = match usb.attr("type") {
+            Some("XHCI") => Some("xhci".to_string()),
+            Some("EHCI") => Some("ehci".to_string()),
+            other => {
+                notes.push(format!("{} USB controller mapped to QEMU's default", other.unwrap_or("unknown")));
+                None
+            }
+        };
+    }
+
+    let adapters = hardware.find("Network").map(|n| n.children("Adapter").collect::<Vec<_>>()).unwrap_or_default();
+    for adapter in adapters.into_iter().filter(|a| a.attr("enabled") == Some("true")) {
+        let slot = adapter.attr("slot").unwrap_or("?");
+        let backend = if adapter.child("NAT").is_some() {
+            NetBackend::User { ipv6_net: None, hostfwd: Vec::new() }
+        } else if let Some(host_if) = adapter.child("BridgedInterface").and_then(|b| b.attr("name")) {
+            if Path::new("/sys/class/net").join(host_if).join("bridge").exists() {
+                NetBackend::Bridge { bridge: host_if.to_string() }
+            } else {
+                notes.push(format!(
+                    "adapter {} bridged to {}, which is not a Linux bridge; mapped to user-mode networking",
+                    slot, host_if
+                ));
+                NetBackend::User { ipv6_net: None, hostfwd: Vec::new() }
+            }
+        } else {
+            notes.push(format!("adapter {} attachment not supported; mapped to user-mode networking", slot));
+            NetBackend::User { ipv6_net: None, hostfwd: Vec::new() }
+        };
+        let mac = match adapter.attr("MACAddress").and_then(vbox_mac) {
+            Some(mac) => mac,
+            None => network::generate_mac(&vm.name, vm.nics.len()),
+        };
+        vm.nics.push(NicSpec { backend, mac, impairment: None, virtio: None });
+    }
+
+    // Attachments refer to media by uuid; the registry maps those to
+    // files. Differencing images nest under their parent.
+    let media: Vec<&Element> = ["HardDisk", "Image"]
+        .iter()
+        .flat_map(|kind| machine.child("MediaRegistry").map(|r| r.descendants(kind)).unwrap_or_default())
+        .collect();
+    let location = |uuid: &str| {
+        let medium = media.iter().find(|m| m.attr("uuid") == Some(uuid))?;
+        Some((base_dir.join(medium.attr("location")?), medium.attr("format").unwrap_or("")))
+    };
+    for attached in machine.descendants("AttachedDevice") {
+        let Some(uuid) = attached.child("Image").and_then(|i| i.attr("uuid")) else { continue };
+        let Some((file, format)) = location(uuid) else {
+            notes.push(format!("medium {} is not registered in the machine file", uuid));
+            continue;
+        };
+        let file = file.display().to_string();
+        match attached.attr("type") {
+            Some("DVD") if vm.iso.is_empty() => vm.iso = file,
+            Some("HardDisk") if vm.disk.is_empty() => {
+                if media.iter().any(|m| m.children("HardDisk").any(|c| c.attr("uuid") == Some(uuid))) {
+                    notes.push("the disk is a snapshot; only merged images convert cleanly, delete VirtualBox snapshots first".to_string());
+                }
+                if !format.is_empty() && !format.eq_ignore_ascii_case("VDI") && !format.eq_ignore_ascii_case("VMDK") {
+                    notes.push(format!("disk format {} may not be readable by qemu-img", format));
+                }
+                vm.disk = file;
+            }
+            _ => notes.push(format!("extra medium {} not imported", file)),
+        }
+    }
+    Ok(Imported { vm, notes })
+}
+
+/// Brings an imported VM's disk under SRQemu. VMs are started with
+/// `format=qcow2`, so anything else has to be converted.
+pub fn adopt_disk(vm: &mut VMInfo, mode: DiskMode) -> Result<(), String> {
+    let vm_dir = PathBuf::from(crate::vm_folder(&vm.name));
+    fs::create_dir_all(&vm_dir).map_err(|e| format!("cannot create {}: {}", vm_dir.display(), e))?;
+    if vm.disk.is_empty() {
+        return Ok(());
+    }
+    let source = vm.disk.clone();
+    let format = nbd::image_format(&source)?;
+    let target = vm_dir.join(format!("{}.qcow2", vm.name));
+    match mode {
+        DiskMode::Keep | DiskMode::Link if format != "qcow2" => {
+            return Err(format!("{} is {}, not qcow2; import it with the copy mode", source, format));
+        }
+        DiskMode::Keep => return Ok(()),
+        #[cfg(unix)]
+        DiskMode::Link => std::os::unix::fs::symlink(&source, &target)
+            .map_err(|e| format!("cannot link {}: {}", source, e))?,
+        #[cfg(windows)]
+        DiskMode::Link => std::os::windows::fs::symlink_file(&source, &target)
+            .map_err(|e| format!("cannot link {}: {}", source, e))?,
+        DiskMode::Copy => {
+            println!("Converting {} ({}) to {}...", source, format, target.display());
+            let mut cmd = crate::runner::command("qemu-img");
+            cmd.args(["convert", "-f", &format, "-O", "qcow2", &source]).arg(&target);
+            run(&mut cmd)?;
+        }
+    }
+    vm.disk = crate::relative_to_folder(&vm.name, &target.display().to_string());
+    Ok(())
+}
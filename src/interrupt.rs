@@ -0,0 +1,128 @@
+//! Ctrl-C handling. Steps that create files before the VM they belong to
+//! is saved register them with a `Partial`; an interrupt removes whatever
+//! is still registered and exits, so a half-created VM leaves neither
+//! files nor a config entry behind. Config writes run as a `critical`
+//! section, which an interrupt waits for.
+
+use std::cell::Cell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Files and directories of unfinished work, removed on interrupt.
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+/// Held by the thread in a critical section and by the interrupt handler.
+static SECTION: Mutex<()> = Mutex::new(());
+/// Set while a prompt has switched terminal echo off.
+static ECHO_OFF: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+fn lock<T>(mutex: &'static Mutex<T>) -> MutexGuard<'static, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Handles SIGINT, or Ctrl-C on Windows, from now on. Fails quietly: the
+/// default action, exiting without cleanup, is what happened before.
+pub fn install() {
+    let Ok(runtime) = tokio::runtime::Builder::new_current_thread().enable_io().build() else { return };
+    // Registered here rather than in the thread so an early Ctrl-C is
+    // already caught.
+    #[cfg(unix)]
+    let registered = runtime.block_on(async { tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt()) });
+    #[cfg(windows)]
+    let registered = runtime.block_on(async { tokio::signal::windows::ctrl_c() });
+    let Ok(mut interrupts) = registered else { return };
+    std::thread::spawn(move || {
+        if runtime.block_on(interrupts.recv()).is_some() {
+            interrupted();
+        }
+    });
+}
+
+fn interrupted() -> ! {
+    let _section = lock(&SECTION);
+    if ECHO_OFF.load(Ordering::SeqCst) {
+        let _ = std::process::Command::new("stty").arg("echo").status();
+    }
+    eprintln!();
+    for path in lock(&PENDING).drain(..).rev() {
+        remove(&path);
+        eprintln!("Interrupted; removed {}", path.display());
+    }
+    std::process::exit(130);
+}
+
+fn remove(path: &Path) {
+    let _ = if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) };
+}
+
+/// Runs `f` to completion even if Ctrl-C is pressed meanwhile; the
+/// interrupt is handled afterwards. Sections nest.
+pub fn critical<T>(f: impl FnOnce() -> T) -> T {
+    let _section = (DEPTH.get() == 0).then(|| lock(&SECTION));
+    DEPTH.set(DEPTH.get() + 1);
+    let result = f();
+    DEPTH.set(DEPTH.get() - 1);
+    result
+}
+
+/// Tells the handler whether terminal echo is off, so it can be turned
+/// back on.
+pub fn set_echo_off(off: bool) {
+    ECHO_OFF.store(off, Ordering::SeqCst);
+}
+
+/// Paths created for something not yet saved. Dropping it unfinished
+/// removes them, as does an interrupt.
+#[derive(Default)]
+pub struct Partial {
+    paths: Vec<PathBuf>,
+}
+
+impl Partial {
+    pub fn new() -> Partial {
+        Partial::default()
+    }
+
+    /// Registers `path` if it does not exist yet; what was already there is
+    /// never removed.
+    pub fn track(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        if path.exists() {
+            return;
+        }
+        lock(&PENDING).push(path.clone());
+        self.paths.push(path);
+    }
+
+    /// Runs `save` as a critical section and, if it succeeds, keeps the
+    /// tracked paths.
+    pub fn commit<T, E>(mut self, save: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        critical(|| {
+            let result = save()?;
+            self.release();
+            Ok(result)
+        })
+    }
+
+    fn release(&mut self) {
+        lock(&PENDING).retain(|p| !self.paths.contains(p));
+        self.paths.clear();
+    }
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        let paths = std::mem::take(&mut self.paths);
+        critical(|| {
+            for path in paths.iter().rev() {
+                remove(path);
+            }
+            lock(&PENDING).retain(|p| !paths.contains(p));
+        });
+    }
+}
@@ -0,0 +1,133 @@
+//! Local sockets to QEMU and to the daemon. On Unix they are Unix domain
+//! sockets at their paths. Windows' standard library has none, so there a
+//! path stands for a loopback TCP port: QEMU's sockets get a port derived
+//! from the path, and the daemon writes its port and a random token to the
+//! path and admits only clients that send the token first. QEMU's TCP
+//! ports cannot be restricted that way and are open to every local user,
+//! so a Windows host running VMs should not be shared.
+
+use std::fs;
+use std::io;
+#[cfg(windows)]
+use std::io::Write;
+use std::path::Path;
+
+#[cfg(unix)]
+pub type Stream = std::os::unix::net::UnixStream;
+#[cfg(windows)]
+pub type Stream = std::net::TcpStream;
+
+#[cfg(windows)]
+const LOOPBACK: &str = "127.0.0.1";
+
+/// QEMU's ports on Windows, below the range Windows hands out to clients.
+#[cfg(windows)]
+const PORTS: std::ops::Range<u32> = 20000..40000;
+
+/// The port standing for `path`, the same in every SRQemu version so
+/// VMs outlive upgrades.
+#[cfg(windows)]
+fn port_for(path: &Path) -> u16 {
+    // FNV-1a; std's hashers are not stable across releases.
+    let hash = path.to_string_lossy().bytes().fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+    (PORTS.start + hash % (PORTS.end - PORTS.start)) as u16
+}
+
+/// The `-chardev socket` options putting QEMU's end at `path`, as a server.
+pub fn chardev(path: &Path) -> String {
+    #[cfg(unix)]
+    let address = format!("path={}", path.display());
+    #[cfg(windows)]
+    let address = format!("host={},port={}", LOOPBACK, port_for(path));
+    format!("{},server=on,wait=off", address)
+}
+
+/// The `-qmp` value serving QMP at `path`.
+pub fn qmp(path: &Path) -> String {
+    #[cfg(unix)]
+    let address = format!("unix:{}", path.display());
+    #[cfg(windows)]
+    let address = format!("tcp:{}:{}", LOOPBACK, port_for(path));
+    format!("{},server=on,wait=off", address)
+}
+
+#[cfg(unix)]
+pub fn connect(path: &Path) -> io::Result<Stream> {
+    Stream::connect(path)
+}
+
+/// Connects to the daemon through the port and token in its file, or
+/// to QEMU on the port standing for `path`.
+#[cfg(windows)]
+pub fn connect(path: &Path) -> io::Result<Stream> {
+    let Ok(endpoint) = fs::read_to_string(path) else {
+        return Stream::connect((LOOPBACK, port_for(path)));
+    };
+    let (port, token) = endpoint.trim().split_once(' ').ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed endpoint file"))?;
+    let port: u16 = port.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed endpoint file"))?;
+    let mut stream = Stream::connect((LOOPBACK, port))?;
+    writeln!(stream, "{}", token)?;
+    Ok(stream)
+}
+
+/// SRQemu's own end of a socket, for the daemon.
+pub struct Listener {
+    #[cfg(unix)]
+    inner: std::os::unix::net::UnixListener,
+    #[cfg(windows)]
+    inner: std::net::TcpListener,
+    #[cfg(windows)]
+    token: String,
+}
+
+/// Listens at `path`, which only this user may connect to.
+#[cfg(unix)]
+pub fn bind(path: &Path) -> io::Result<Listener> {
+    use std::os::unix::fs::PermissionsExt;
+    // Left behind by a daemon that was killed.
+    let _ = fs::remove_file(path);
+    let inner = std::os::unix::net::UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(Listener { inner })
+}
+
+#[cfg(windows)]
+pub fn bind(path: &Path) -> io::Result<Listener> {
+    use std::hash::BuildHasher;
+    let inner = std::net::TcpListener::bind((LOOPBACK, 0))?;
+    // RandomState's keys come from the system's random source.
+    let token: String = (0..2).map(|i| format!("{:016x}", std::collections::hash_map::RandomState::new().hash_one(i))).collect();
+    fs::write(path, format!("{} {}\n", inner.local_addr()?.port(), token))?;
+    Ok(Listener { inner, token })
+}
+
+impl Listener {
+    /// The next client, not yet admitted.
+    pub fn accept(&self) -> io::Result<Stream> {
+        self.inner.accept().map(|(stream, _)| stream)
+    }
+
+    /// Lets `stream` in; on Windows only if it sends the token. Kept apart
+    /// from `accept` so a client that sends nothing holds up only the
+    /// thread admitting it.
+    #[cfg(unix)]
+    pub fn admit(&self, stream: Stream) -> io::Result<Stream> {
+        Ok(stream)
+    }
+
+    #[cfg(windows)]
+    pub fn admit(&self, stream: Stream) -> io::Result<Stream> {
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+        // Byte by byte, leaving the request after it unread.
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while line.len() <= self.token.len() && io::Read::read(&mut &stream, &mut byte)? == 1 && byte[0] != b'\n' {
+            line.push(byte[0]);
+        }
+        if line != self.token.as_bytes() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "client without the daemon's token"));
+        }
+        stream.set_read_timeout(None)?;
+        Ok(stream)
+    }
+}
@@ -0,0 +1,63 @@
+use crate::config::VMInfo;
+use crate::run;
+use std::fs;
+use std::process::Command as ShellCommand;
+
+const KSM_SYSFS: &str = "/sys/kernel/mm/ksm";
+
+/// `-machine mem-merge=` for VMs that chose; QEMU marks guest RAM mergeable
+/// by default, so only an explicit choice is passed on.
+pub fn launch_args(vm: &VMInfo) -> Vec<String> {
+    match vm.mem_merge {
+        Some(merge) => vec!["-machine".to_string(), format!("mem-merge={}", if merge { "on" } else { "off" })],
+        None => Vec::new(),
+    }
+}
+
+fn read(name: &str) -> Option<u64> {
+    fs::read_to_string(format!("{}/{}", KSM_SYSFS, name)).ok()?.trim().parse().ok()
+}
+
+fn write(name: &str, value: u64) -> Result<(), String> {
+    let path = format!("{}/{}", KSM_SYSFS, name);
+    fs::write(&path, value.to_string()).map_err(|e| format!("cannot write {} (run as root?): {}", path, e))
+}
+
+fn page_size() -> u64 {
+    run(ShellCommand::new("getconf").arg("PAGESIZE")).ok().and_then(|s| s.trim().parse().ok()).unwrap_or(4096)
+}
+
+/// ksmd's state and what it has merged so far.
+pub struct KsmStatus {
+    pub running: bool,
+    pub pages_to_scan: u64,
+    pub sleep_millisecs: u64,
+    /// Bytes no longer backed by their own page.
+    pub saved: u64,
+    /// Savings minus ksmd's own bookkeeping, on kernels that report it.
+    pub profit: Option<u64>,
+}
+
+pub fn status() -> Result<KsmStatus, String> {
+    let run = read("run").ok_or("this kernel has no KSM support")?;
+    Ok(KsmStatus {
+        running: run == 1,
+        pages_to_scan: read("pages_to_scan").unwrap_or(0),
+        sleep_millisecs: read("sleep_millisecs").unwrap_or(0),
+        saved: read("pages_sharing").unwrap_or(0) * page_size(),
+        profit: read("general_profit"),
+    })
+}
+
+/// Starts or stops ksmd. Stopping leaves merged pages merged; unmerging
+/// everything (`run=2`) could spike memory use on a loaded host.
+pub fn set_running(on: bool) -> Result<(), String> {
+    write("run", if on { 1 } else { 0 })
+}
+
+/// Pages scanned per wake-up and the pause between wake-ups; more pages
+/// or shorter sleeps merge faster at the cost of ksmd CPU time.
+pub fn tune(pages_to_scan: u64, sleep_millisecs: u64) -> Result<(), String> {
+    write("pages_to_scan", pages_to_scan)?;
+    write("sleep_millisecs", sleep_millisecs)
+}
@@ -0,0 +1,51 @@
+//! Cosmetic labels telling similar VMs apart: a color that paints the VM's
+//! name in listings and its launcher icon, and an icon for launchers and
+//! the tray.
+
+use std::io::{self, IsTerminal};
+
+/// Colors by name, as hex.
+pub const COLORS: [(&str, &str); 8] = [
+    ("red", "#e01b24"),
+    ("orange", "#ff7800"),
+    ("yellow", "#f6d32d"),
+    ("green", "#33d17a"),
+    ("teal", "#1aa3a3"),
+    ("blue", "#3584e4"),
+    ("purple", "#9141ac"),
+    ("gray", "#77767b"),
+];
+
+/// Reads a color name or `#rrggbb` into the form stored in the config.
+pub fn parse_color(s: &str) -> Result<String, String> {
+    let s = s.trim().to_ascii_lowercase();
+    let hex = s.strip_prefix('#').is_some_and(|digits| digits.len() == 6 && digits.chars().all(|c| c.is_ascii_hexdigit()));
+    if hex || COLORS.iter().any(|(name, _)| *name == s) {
+        return Ok(s);
+    }
+    let names: Vec<&str> = COLORS.iter().map(|(name, _)| *name).collect();
+    Err(format!("unknown color '{}'; use {} or #rrggbb", s, names.join(", ")))
+}
+
+/// The color as `#rrggbb`.
+pub fn hex(color: &str) -> Option<&str> {
+    COLORS.iter().find(|(name, _)| *name == color).map(|(_, hex)| *hex).or_else(|| color.starts_with('#').then_some(color))
+}
+
+fn rgb(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = hex(color)?.strip_prefix('#')?;
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// `text` in bold and the VM's color when printing to a terminal; padding
+/// inside `text` keeps table columns aligned.
+pub fn paint(color: Option<&str>, text: &str) -> String {
+    let Some((r, g, b)) = color.and_then(rgb) else {
+        return text.to_string();
+    };
+    if !io::stdout().is_terminal() || std::env::var_os("NO_COLOR").is_some() || crate::output::plain() {
+        return text.to_string();
+    }
+    format!("\x1b[1;38;2;{};{};{}m{}\x1b[0m", r, g, b, text)
+}
@@ -0,0 +1,78 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+
+/// The manager log is moved aside once it grows past this.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+/// `~/.local/state/qemuctl/manager.log`, the file to attach when asking
+/// for help.
+pub fn log_file() -> PathBuf {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| home::home_dir().unwrap_or_default().join(".local/state"));
+    state.join("qemuctl").join("manager.log")
+}
+
+/// Terminal output as the messages always looked: errors bare, warnings
+/// prefixed, and the extra detail of `-v` tagged with its level.
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        match *event.metadata().level() {
+            Level::ERROR => {}
+            Level::WARN => write!(writer, "Warning: ")?,
+            level => write!(writer, "[{}] ", level.as_str().to_lowercase())?,
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+fn level(verbosity: u8) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+fn open_log() -> Option<fs::File> {
+    let path = log_file();
+    fs::create_dir_all(path.parent()?).ok()?;
+    if fs::metadata(&path).is_ok_and(|m| m.len() > MAX_LOG_SIZE) {
+        let _ = fs::rename(&path, path.with_extension("log.1"));
+    }
+    OpenOptions::new().create(true).append(true).open(&path).ok()
+}
+
+/// Sends messages to stderr at the verbosity of `-v`/`-vv`/`-vvv` and, at
+/// least at info level, to the manager log. With `json` both get one JSON
+/// object per line.
+pub fn init(verbosity: u8, json: bool) {
+    let terminal = level(verbosity);
+    let stderr = if json {
+        tracing_subscriber::fmt::layer().json().with_writer(std::io::stderr).with_filter(terminal).boxed()
+    } else {
+        tracing_subscriber::fmt::layer().event_format(Plain).with_writer(std::io::stderr).with_filter(terminal).boxed()
+    };
+    let file = open_log().map(|file| {
+        let layer = tracing_subscriber::fmt::layer().with_ansi(false).with_writer(Mutex::new(file));
+        let recorded = terminal.max(LevelFilter::INFO);
+        if json { layer.json().with_filter(recorded).boxed() } else { layer.with_filter(recorded).boxed() }
+    });
+    tracing_subscriber::registry().with(stderr).with(file).init();
+}
@@ -1,307 +1,4058 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::process::Command as ShellCommand;
-use std::io::{self, Write};
+mod adopt;
+mod agent;
+mod apparmor;
+mod arch;
+mod autostart;
+mod callback;
+mod capture;
+mod cli;
+mod clone;
+mod clock;
+mod cmdline;
+mod cloudinit;
+mod config;
+mod daemon;
+mod desktop;
+mod diagnose;
+mod display;
+mod error;
+mod dotfiles;
+mod firewall;
+mod firmware;
+mod guestcron;
+mod guestdisk;
+mod history;
+mod i18n;
+mod hostpower;
+mod hostsleep;
+mod images;
+mod import;
+mod interrupt;
+mod ipc;
+mod ksm;
+mod label;
+mod logging;
+mod media;
+#[cfg(unix)]
+mod mockqemu;
+mod nbd;
+mod network;
+mod notify;
+mod output;
+mod pidfile;
+mod plan;
+mod pressure;
+mod process;
+mod provenance;
+mod qmp;
+mod recipe;
+mod relocate;
+mod runprofile;
+mod resize;
+mod runner;
+mod sandbox;
+mod serial;
+mod setup;
+mod share;
+mod schedule;
+mod size;
+mod slot;
+mod snapshot;
+mod stats;
+mod status;
+mod storage;
+mod suspend;
+mod template;
+mod tray;
+mod trash;
+mod update;
+mod usb;
+mod vfio;
+mod vault;
+mod xml;
+
+use clap::Parser;
+use i18n::t;
+use cli::{AutostartAction, BridgeAction, CaptureAction, CdromAction, Command, ConfigAction, DaemonAction, DiskAction, DisplayAction, FreezeAction, GuestAction, ImagesAction, NetworkAction, ScheduleAction, ShareAction, SlotAction, SnapshotAction, TemplateAction, UsbAction, VfioAction};
+use config::{load_config, save_config, VMConfig, VMInfo};
+use runner::run;
+use std::process::{Command as ShellCommand, Stdio};
+use std::io::{self, IsTerminal, Write};
 use std::fs;
-use std::path::PathBuf;
-use confy;
-use home;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tracing::{error, info, warn};
 
-#[derive(Debug, Serialize, Deserialize, Default)]
-struct VMConfig {
-    vms: HashMap<String, VMInfo>,
+fn expand_path(path: &str) -> String {
+    if path.starts_with("~")
+        && let Some(home) = home::home_dir()
+    {
+        return path.replacen("~", &home.to_string_lossy(), 1);
+    }
+    path.to_string()
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct VMInfo {
-    name: String,
-    memory: String,
-    cpu: String,
-    threads: String,  
-    disk: String,
-    iso: String,
+/// Disk, ISO and seed paths may be relative to the VM folder, so `~/vms`
+/// can move to another disk or machine without rewriting every definition.
+fn resolve_path(vm_name: &str, path: &str) -> String {
+    if path.is_empty() || path.starts_with('/') || path.starts_with('~') {
+        expand_path(path)
+    } else {
+        format!("{}/{}", vm_folder(vm_name), path)
+    }
 }
 
-const CONFIG_FILE: &str = "qemuctl";
+/// `path` relative to the VM folder if it lies inside it, unchanged
+/// otherwise.
+fn relative_to_folder(vm_name: &str, path: &str) -> String {
+    let folder = vm_folder(vm_name);
+    match Path::new(&expand_path(path)).strip_prefix(&folder) {
+        Ok(relative) if !relative.as_os_str().is_empty() => relative.display().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+/// Where VM folders live; `~/vms` unless `settings.vm_dir` moved them.
+static VM_DIR: RwLock<Option<String>> = RwLock::new(None);
+
+fn set_vm_dir(dir: Option<String>) {
+    *VM_DIR.write().unwrap_or_else(|e| e.into_inner()) = dir;
+}
 
-fn load_config() -> VMConfig {
-    confy::load(CONFIG_FILE, None).unwrap_or_default()
+fn get_vm_folder() -> String {
+    let configured = VM_DIR.read().unwrap_or_else(|e| e.into_inner()).clone();
+    let base_dir = expand_path(configured.as_deref().unwrap_or(setup::DEFAULT_VM_DIR));
+    // main() already reported a directory that cannot be created.
+    let _ = fs::create_dir_all(&base_dir);
+    base_dir
 }
 
-fn save_config(config: &VMConfig) {
-    confy::store(CONFIG_FILE, None, config).expect("Failed to save config");
+fn vm_folder(name: &str) -> String {
+    format!("{}/{}", get_vm_folder(), name)
 }
 
-fn expand_path(path: &str) -> String {
-    if path.starts_with("~") {
-        if let Some(home) = home::home_dir() {
-            return path.replacen("~", home.to_str().unwrap(), 1);
+
+fn prompt(message: &str) -> String {
+    print!("{}", message);
+    let _ = io::stdout().flush();
+    let mut input = String::new();
+    // Unreadable input counts as an empty answer.
+    let _ = io::stdin().read_line(&mut input);
+    input.trim().to_string()
+}
+
+/// Reads a line without echoing it, for passwords.
+fn prompt_secret(message: &str) -> String {
+    interrupt::set_echo_off(true);
+    let _ = ShellCommand::new("stty").arg("-echo").status();
+    let input = prompt(message);
+    let _ = ShellCommand::new("stty").arg("echo").status();
+    interrupt::set_echo_off(false);
+    println!();
+    input
+}
+
+fn prompt_or(message: &str, default: &str) -> String {
+    let input = prompt(&format!("{} (default {}): ", message, default));
+    if input.is_empty() { default.to_string() } else { input }
+}
+
+/// Lists the defined VMs and asks for one by name.
+fn select_vm<'a>(config: &'a VMConfig, action: &str) -> Option<&'a VMInfo> {
+    list_defined_vms(config);
+    let name = prompt(&format!("{} ", t("prompt-vm-name", &[("action", &action)])));
+    let vm = config.vms.get(&name);
+    if vm.is_none() {
+        error!("{}", t("vm-not-found", &[("name", &name)]));
+    }
+    vm
+}
+
+fn vm_running(name: &str) -> bool {
+    vm_pid(name).is_some()
+}
+
+fn vm_pid(name: &str) -> Option<u32> {
+    adopt::adopted_pid(name).or_else(|| pidfile::read(name)).or_else(|| pidfile::find_by_socket(name))
+}
+
+/// Polls for a freshly launched VM's process; the launcher returns before
+/// QEMU has finished starting.
+fn wait_for_pid(name: &str) -> Option<u32> {
+    for _ in 0..50 {
+        if let Some(pid) = vm_pid(name) {
+            return Some(pid);
         }
+        std::thread::sleep(std::time::Duration::from_millis(100));
     }
-    path.to_string()
+    None
 }
 
-fn get_vm_folder() -> String {
-    let base_dir = expand_path("~/vms");
-    fs::create_dir_all(&base_dir).expect("Failed to create VM directory");
-    base_dir
+/// Work that needs the QEMU process to exist, run after every launch.
+fn post_start(config: &VMConfig, vm: &VMInfo) {
+    if vm.host_power.is_some() && wait_for_pid(&vm.name).is_some() {
+        hostpower::update(config, None);
+    }
+    let wants_shaping = vm.nics.iter().any(|n| n.impairment.as_ref().is_some_and(|i| !i.is_empty()));
+    if !wants_shaping {
+        return;
+    }
+    let Some(pid) = wait_for_pid(&vm.name) else {
+        error!("VM '{}' did not come up; network impairments not applied.", vm.name);
+        return;
+    };
+    // Taps are attached while QEMU initialises its netdevs.
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    match network::apply_impairments(config, vm, pid) {
+        Ok(n) if n > 0 => println!("Applied network impairments to {} NIC(s).", n),
+        Ok(_) => {}
+        Err(e) => error!("Failed to apply network impairments: {}", e),
+    }
+}
+
+/// Records where the remote console of a just-started VM listens and
+/// prints how to reach it, since a headless VM is otherwise invisible.
+fn announce_console(vm: &VMInfo, boot: &cmdline::Boot) {
+    let Some(console) = boot.console.clone().or_else(|| display::Console::configured(vm)) else {
+        return;
+    };
+    if let Err(e) = display::record(&vm.name, &console) {
+        warn!("VM '{}': {}", vm.name, e);
+    }
+    println!("Console: {}", console.url());
+}
+
+/// Follow-up after SRQemu unpauses a VM.
+fn after_resume(vm: &VMInfo) {
+    if vm.time.as_ref().is_some_and(|t| t.sync_on_resume) {
+        match agent::sync_time(&vm.name) {
+            Ok(()) => println!("Guest clock of '{}' synced.", vm.name),
+            Err(e) => error!("Failed to sync clock of '{}': {}", vm.name, e),
+        }
+    }
 }
 
+/// Answers for a new VM, from the menu or the command line. Unset values
+/// come from the template, the base the VM extends, or built-in defaults.
+struct NewVm {
+    name: String,
+    extends: Option<String>,
+    memory: Option<String>,
+    disk: storage::DiskSpec,
+    threads: Option<String>,
+    iso: String,
+    /// Host→guest forwards; when given the VM gets a user-mode NIC carrying
+    /// them.
+    forwards: Vec<network::HostForward>,
+    firmware: Option<firmware::Firmware>,
+    guest_agent: Option<bool>,
+    /// Image copied as the disk instead of creating an empty one.
+    cloud_image: Option<String>,
+    /// Seed for the first boot of a cloud image.
+    cloud_init: Option<cloudinit::CloudInit>,
+    /// `virtio` or `e1000`, ahead of the template's.
+    nic_model: Option<String>,
+    /// None for x86_64.
+    arch: Option<arch::Arch>,
+    /// Fills in what was not given explicitly, ahead of the base.
+    template: template::Template,
+}
 
+/// Values a VM extending `extends` would inherit.
+fn base_values(config: &VMConfig, extends: &Option<String>) -> toml::value::Table {
+    match extends {
+        Some(parent) => config.inherited(parent).unwrap_or_else(|e| {
+            error!("Ignoring base: {}", e);
+            Default::default()
+        }),
+        None => Default::default(),
+    }
+}
 
+/// Creates the VM's folder and disk image and saves its definition.
+fn define_vm(config: &mut VMConfig, mut spec: NewVm) -> Result<VMInfo, String> {
+    if config.vms.contains_key(&spec.name) {
+        return Err(format!("a VM named '{}' already exists", spec.name));
+    }
+    let name = spec.name;
+    let inherited = base_values(config, &spec.extends);
+    let default_for = |key: &str, fallback: &str| {
+        inherited.get(key).and_then(|v| v.as_str()).unwrap_or(fallback).to_string()
+    };
+    let template = std::mem::take(&mut spec.template);
+    if spec.disk == storage::DiskSpec::default()
+        && let Some(disk) = &template.disk
+    {
+        spec.disk = storage::DiskSpec::parse(disk)?;
+    }
+    let memory = size::memory(&spec.memory.or(template.vm.memory).unwrap_or_else(|| default_for("memory", &config.settings.default_memory)))?;
+    // The other boards have no IDE, and their guests all carry virtio drivers.
+    let pc = spec.arch.is_none_or(arch::Arch::is_pc);
+    if !pc && spec.disk.device.is_none() {
+        spec.disk.device = Some(storage::DiskDevice { bus: storage::DiskBus::VirtioBlk, iothread: true });
+    }
+    let nic_model = spec.nic_model.or(template.vm.nic_model).or_else(|| (!pc).then(|| "virtio".to_string()));
+    let virtio = nic_model.as_deref().map(network::parse_nic_model).transpose()?.flatten();
+    let disk_size = spec.disk.size.clone().unwrap_or_else(|| config.settings.default_disk_size.clone());
+    let disk_dir = match &spec.disk.pool {
+        Some(pool) => expand_path(config.settings.pools.get(pool).ok_or_else(|| {
+            format!("no storage pool '{}'; define it under [settings.pools] as {} = \"/path\"", pool, pool)
+        })?),
+        None => vm_folder(&name),
+    };
+    let vm_dir = vm_folder(&name);
+    let disk_path = format!("{}/{}.qcow2", disk_dir, name);
+    if spec.disk.pool.is_some() && Path::new(&disk_path).exists() {
+        return Err(format!("{} already exists", disk_path));
+    }
+    // Aliases of downloaded images stand for their files from here on.
+    let iso = if spec.iso.is_empty() { String::new() } else { expand_path(&images::resolve(&spec.iso, images::Kind::Iso)?) };
+    let cloud_image = spec.cloud_image.as_deref().map(|image| images::resolve(image, images::Kind::Cloud)).transpose()?;
+    // Removed again unless the VM gets saved.
+    let mut partial = interrupt::Partial::new();
+    partial.track(&vm_dir);
+    fs::create_dir_all(&vm_dir).map_err(|e| format!("cannot create {}: {}", vm_dir, e))?;
+    partial.track(&disk_dir);
+    fs::create_dir_all(&disk_dir).map_err(|e| format!("cannot create {}: {}", disk_dir, e))?;
+    partial.track(&disk_path);
 
-fn create_vm(config: &mut VMConfig) {
-    let mut input = String::new();
+    match &cloud_image {
+        Some(image) => copy_cloud_image(image, &disk_path, &disk_size)?,
+        None => {
+            println!("Creating disk image at {}...", disk_path);
+            run(crate::runner::command("qemu-img").args(["create", "-f", "qcow2", &disk_path, &disk_size]))?;
+        }
+    }
 
-    print!("Enter VM name: ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut input).unwrap();
-    let name = input.trim().to_string();
-
-    let vm_dir = expand_path(&format!("{}/{}", get_vm_folder(), name));
-    fs::create_dir_all(&vm_dir).expect("Failed to create VM directory");
-
-    let disk_path = expand_path(&format!("{}/{}.qcow2", vm_dir, name));
-
-    print!("Memory (default 4G): ");
-    io::stdout().flush().unwrap();
-    input.clear();
-    io::stdin().read_line(&mut input).unwrap();
-    let memory = if input.trim().is_empty() { "4G".to_string() } else { input.trim().to_string() };
-
-    print!("Disk size (default 10G): ");
-    io::stdout().flush().unwrap();
-    input.clear();
-    io::stdin().read_line(&mut input).unwrap();
-    let disk_size = if input.trim().is_empty() { "10G".to_string() } else { input.trim().to_string() };
-
-    print!("CPU threads (default 1): ");
-    io::stdout().flush().unwrap();
-    input.clear();
-    io::stdin().read_line(&mut input).unwrap();
-    let cpu_threads = if input.trim().is_empty() { "1".to_string() } else { input.trim().to_string() };
-
-    print!("ISO path (leave empty if none): ");
-    io::stdout().flush().unwrap();
-    input.clear();
-    io::stdin().read_line(&mut input).unwrap();
-    let iso = expand_path(input.trim());
-
-    println!("Creating disk image at {}...", disk_path);
-    let _ = ShellCommand::new("qemu-img")
-        .arg("create")
-        .arg("-f")
-        .arg("qcow2")
-        .arg(&disk_path)
-        .arg(&disk_size)
-        .status();
-
-    let vm = VMInfo {
+    let mut backends = template.vm.nics.iter().map(|nic| network::NetBackend::parse(nic, config)).collect::<Result<Vec<_>, _>>()?;
+    if !spec.forwards.is_empty() {
+        match backends.iter_mut().find_map(|b| b.forwards_mut()) {
+            Some(forwards) => forwards.extend(spec.forwards),
+            None => backends.push(network::NetBackend::User { ipv6_net: None, hostfwd: spec.forwards }),
+        }
+    }
+    let nics = backends
+        .into_iter()
+        .enumerate()
+        .map(|(i, backend)| network::NicSpec { backend, mac: network::generate_mac(&name, i), impairment: None, virtio: virtio.clone() })
+        .collect();
+    let mut vm = VMInfo {
         name: name.clone(),
+        cpu: template.vm.cpu.unwrap_or_else(|| default_for("cpu", "host")),
+        extends: spec.extends,
+        tags: Vec::new(),
+        color: None,
+        icon: None,
         memory,
-        cpu: "host".to_string(),
-        threads: cpu_threads,
-        disk: disk_path,
+        threads: spec.threads.or(template.vm.threads).unwrap_or_else(|| default_for("threads", &config.settings.default_threads)),
+        arch: spec.arch,
+        accel: None,
+        firmware: spec.firmware.or(template.firmware).filter(|f| *f != firmware::Firmware::Bios),
+        disk: relative_to_folder(&name, &disk_path),
+        disk_device: spec.disk.device,
+        disk_cache: spec.disk.cache,
         iso,
+        cdrom: None,
+        nics,
+        firewall: Vec::new(),
+        display: template.vm.display,
+        usb: template.vm.usb,
+        usb_devices: Vec::new(),
+        pci_devices: Vec::new(),
+        shares: Vec::new(),
+        time: template.vm.time,
+        guest_agent: spec.guest_agent.unwrap_or(template.vm.guest_agent),
+        guest_callbacks: Vec::new(),
+        images: Vec::new(),
+        snapshots: Vec::new(),
+        slots: None,
+        seed: None,
+        guest_cron: Vec::new(),
+        autostart: None,
+        restart_on_crash: false,
+        host_power: None,
+        memory_pressure: None,
+        mem_merge: None,
+        hardening: None,
     };
 
-    config.vms.insert(name.clone(), vm.clone());
-    save_config(config);
+    if let Some(data) = &spec.cloud_init {
+        vm.seed = Some(relative_to_folder(&name, &cloudinit::create_seed(&name, data)?));
+    }
+    let origin = cloud_image.as_ref().map_or("created".to_string(), |image| format!("created from {}", image));
+    if let Err(e) = provenance::register(&mut vm, &origin, false) {
+        error!("Failed to record image checksums: {}", e);
+    }
+    partial.commit(|| {
+        config.vms.insert(name.clone(), vm.clone());
+        save_config(config).inspect_err(|_| {
+            config.vms.remove(&name);
+        })
+    })?;
 
     println!("VM '{}' created and saved.", name);
+    Ok(vm)
+}
 
-    if !vm.iso.is_empty() {
-        print!("Start in GUI or headless mode? (gui/headless): ");
-        io::stdout().flush().unwrap();
-        input.clear();
-        io::stdin().read_line(&mut input).unwrap();
-
-        let mode = input.trim().to_lowercase();
-        let display_flag = if mode == "headless" { "-display none" } else { "" };
-
-        // First boot should pass ISO and boot order
-        let cmd = format!(
-            "setsid qemu-system-x86_64 -name {} -m {} -cpu {} -smp {} -enable-kvm -drive file={},format=qcow2 -cdrom {} -boot order=d {} > /dev/null 2>&1 &",
-            vm.name,
-            vm.memory,
-            vm.cpu,
-            vm.threads,
-            vm.disk,
-            vm.iso,
-            display_flag
-        );
-
-        println!("Starting VM '{}' in {} mode...", vm.name, mode);
-        if let Err(e) = ShellCommand::new("sh").arg("-c").arg(&cmd).spawn() {
-            eprintln!("Failed to start VM '{}': {}", vm.name, e);
-        }
-    }
-}
-
-fn start_vm_common(vm: &VMInfo, headless: bool) {
-    let display_flag = if headless { "-display none" } else { "" };
-
-    let cmd = format!(
-        "setsid qemu-system-x86_64 -name {} -m {} -cpu {} -smp {} -enable-kvm -drive file={},format=qcow2 {} > /dev/null 2>&1 &",
-        vm.name,
-        vm.memory,
-        vm.cpu,
-        vm.threads,
-        vm.disk,
-        display_flag
-    );
+/// Copies a cloud image to a new qcow2 disk, grown to `size` if that is
+/// larger; cloud-init grows the root filesystem on first boot.
+fn copy_cloud_image(image: &str, disk: &str, size: &str) -> Result<(), String> {
+    let image = expand_path(image);
+    if !Path::new(&image).exists() {
+        return Err(format!("cloud image {} does not exist", image));
+    }
+    println!("Copying {} to {}...", image, disk);
+    run(crate::runner::command("qemu-img").args(["convert", "-O", "qcow2", &image, disk]))?;
+    let wanted = size::parse(size, size::Bare::Bytes)?;
+    if wanted > resize::offline_size(disk)? {
+        run(crate::runner::command("qemu-img").args(["resize", disk, &wanted.to_string()]))?;
+    }
+    Ok(())
+}
+
+fn create_vm(config: &mut VMConfig) {
+    let name = prompt("Enter VM name: ");
+    let names: Vec<String> = template::list().into_iter().map(|(name, _)| name).collect();
+    let template = match optional(&format!("Template, one of {} (leave empty for none): ", names.join(", "))) {
+        Some(name) => match template::load(&name) {
+            Ok(template) => Some(template),
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let extends = match &template {
+        Some(_) => None,
+        None => optional("Extend profile or VM (leave empty for none): "),
+    };
+    let template = template.unwrap_or_default();
+    let inherited = base_values(config, &extends);
+    let default_for = |key: &str, fallback: &str| {
+        inherited.get(key).and_then(|v| v.as_str()).unwrap_or(fallback).to_string()
+    };
+
+    let memory = prompt_or("Memory", &template.vm.memory.clone().unwrap_or_else(|| default_for("memory", &config.settings.default_memory)));
+    let disk_default = template.disk.clone().unwrap_or_else(|| config.settings.default_disk_size.clone());
+    let disk = match storage::DiskSpec::parse(&prompt_or("Disk size, or a spec like 40G,bus=nvme,cache=none,pool=ssd", &disk_default)) {
+        Ok(disk) => disk,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let threads = prompt_or("CPU threads", &template.vm.threads.clone().unwrap_or_else(|| default_for("threads", &config.settings.default_threads)));
+    let iso = prompt("ISO path (leave empty if none): ");
+    let forwards = match network::parse_forwards(&prompt("Port forwards, comma separated, e.g. 2222->22,8080->80 (leave empty for none): ")) {
+        Ok(forwards) => forwards,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    let arch = match arch::Arch::parse(&prompt_or("Architecture (x86_64, aarch64 or riscv64; others are emulated, slowly)", arch::Arch::host().unwrap_or(arch::Arch::X86_64).name())) {
+        Some(arch) => (arch != arch::Arch::X86_64).then_some(arch),
+        None => {
+            error!("Unknown architecture; use x86_64, aarch64 or riscv64.");
+            return;
+        }
+    };
+    let firmware_default = if template.firmware == Some(firmware::Firmware::Uefi) { "uefi" } else { "bios" };
+    let firmware = match firmware::Firmware::parse(&prompt_or("Firmware (bios, or uefi for guests that require it)", firmware_default)) {
+        Some(firmware) => Some(firmware),
+        None => {
+            error!("Unknown firmware; use bios or uefi.");
+            return;
+        }
+    };
 
-    println!("Starting VM '{}' in {} mode...", vm.name, if headless { "headless" } else { "GUI" });
-    if let Err(e) = ShellCommand::new("sh").arg("-c").arg(&cmd).spawn() {
-        eprintln!("Failed to start VM '{}': {}", vm.name, e);
+    let agent_default = if template.vm.guest_agent { "y" } else { "n" };
+    let guest_agent = prompt_or("Guest agent channel for IP lookup and commands (y/n)", agent_default) == "y";
+    let spec = NewVm {
+        name,
+        extends,
+        memory: Some(memory),
+        disk,
+        threads: Some(threads),
+        iso,
+        forwards,
+        firmware,
+        guest_agent: Some(guest_agent),
+        cloud_image: None,
+        cloud_init: None,
+        nic_model: None,
+        arch,
+        template,
+    };
+    let vm = match define_vm(config, spec) {
+        Ok(vm) => vm,
+        Err(e) => {
+            error!("Failed to create VM: {}", e);
+            return;
+        }
+    };
+    if !vm.iso.is_empty() {
+        let mode = prompt(&format!("{} ", t("prompt-start-mode", &[]))).to_lowercase();
+        if let Err(e) = first_boot(config, &vm, mode == "headless") {
+            error!("{}", e);
+        }
     }
 }
 
-fn list_defined_vms(config: &VMConfig) {
-    println!("\nDefined VMs:");
-    for (name, vm) in &config.vms {
-        println!("- {}: {} CPU, {} threads, {} RAM, Disk: {}", name, vm.cpu, vm.threads, vm.memory, vm.disk);
+/// Boots a new VM from its ISO.
+fn first_boot(config: &VMConfig, vm: &VMInfo, headless: bool) -> Result<(), String> {
+    let vm = &runprofile::apply(&config.settings, vm);
+    firmware::prepare(vm).map_err(|e| format!("Failed to set up UEFI for '{}': {}", vm.name, e))?;
+    // A new VM has no accelerator set, so there is at most a warning.
+    if let Ok(Some(warning)) = arch::check(vm) {
+        warn!("VM '{}': {}", vm.name, warning);
     }
+    println!("{}", t("vm-starting", &[("name", &vm.name), ("mode", &mode_name(headless))]));
+    confine(vm).map_err(|e| format!("Failed to load the AppArmor profile for '{}': {}", vm.name, e))?;
+    firewall::apply(vm).map_err(|e| format!("Failed to apply firewall rules for '{}': {}", vm.name, e))?;
+    let boot = cmdline::Boot { media: media::BootMedia::install(vm), headless, console: None, resume: false };
+    launch(config, vm, &boot);
+    if vm_running(&vm.name) { Ok(()) } else { Err(format!("VM '{}' did not start; {} says why.", vm.name, diagnose::log_path(&vm.name).display())) }
 }
 
-fn start_vm(config: &VMConfig) {
-    list_defined_vms(config);
+fn mode_name(headless: bool) -> String {
+    t(if headless { "mode-headless" } else { "mode-gui" }, &[])
+}
 
-    let mut input = String::new();
-    print!("Enter VM name to start: ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut input).unwrap();
-    let name = input.trim();
-
-    if let Some(vm) = config.vms.get(name) {
-        print!("Start in GUI or headless mode? (gui/headless): ");
-        io::stdout().flush().unwrap();
-        input.clear();
-        io::stdin().read_line(&mut input).unwrap();
-
-        let mode = input.trim().to_lowercase();
-        start_vm_common(vm, mode == "headless");
-    } else {
-        eprintln!("VM '{}' not found", name);
+/// Loads the VM's AppArmor profile if it asks for one; starting it
+/// unconfined instead would silently drop the protection.
+fn confine(vm: &VMInfo) -> Result<(), String> {
+    if vm.hardening.as_ref().is_some_and(|h| h.apparmor) { apparmor::load(vm) } else { Ok(()) }
+}
+
+/// Prints a warning for every image that no longer matches its recorded
+/// checksum.
+fn warn_changed_images(vm: &VMInfo) {
+    for warning in provenance::verify(vm) {
+        warn!("VM '{}': {}", vm.name, warning);
     }
 }
 
-fn stop_vm(config: &VMConfig) {
-    list_defined_vms(config);
+fn start_vm_common(config: &VMConfig, vm: &VMInfo, headless: bool) {
+    let request = daemon::Request::Start { name: vm.name.clone(), headless, iso: None, boot_cdrom: false, display: None, slot: None };
+    if daemon::forward(&request).is_none() {
+        start_vm_with(config, vm, &cmdline::Boot::configured(vm, headless));
+    }
+}
 
-    let mut input = String::new();
-    print!("Enter VM name to stop: ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut input).unwrap();
-    let name = input.trim();
-
-    if config.vms.contains_key(name) {
-        let pattern = format!("qemu-system-x86_64 -name {}", name);
-        println!("Stopping VM: {}", name);
-
-        // First, try stopping using pkill (direct command match)
-        let pkill_status = ShellCommand::new("pkill")
-            .arg("-f")
-            .arg(&pattern)
-            .status();
-
-        // If pkill fails, try using pgrep + kill (to catch orphaned processes)
-        if pkill_status.is_err() {
-            if let Ok(output) = ShellCommand::new("pgrep")
-                .arg("-f")
-                .arg(&pattern)
-                .output()
-            {
-                let pids: Vec<String> = String::from_utf8_lossy(&output.stdout)
-                    .lines()
-                    .map(|s| s.to_string())
-                    .collect();
+/// Starts a VM with the one-off options of `SRQemu start`.
+fn start_from_cli(
+    config: &VMConfig,
+    vm: &VMInfo,
+    headless: bool,
+    iso: Option<String>,
+    boot_cdrom: bool,
+    display: Option<String>,
+    slot: Option<slot::Slot>,
+) -> Result<(), String> {
+    let vm = &match slot {
+        Some(slot) => slot::for_boot(vm, slot)?,
+        None => vm.clone(),
+    };
+    let media = media::BootMedia::for_start(vm, iso, boot_cdrom)?;
+    let console = display.map(|spec| display::Console::parse(&spec, vm)).transpose()?;
+    start_vm_with(config, vm, &cmdline::Boot { media, headless, console, resume: false });
+    Ok(())
+}
 
-                for pid in pids {
-                    println!("Killing VM process: PID {}", pid);
-                    let _ = ShellCommand::new("kill").arg("-9").arg(&pid).status();
+fn start_vm_with(config: &VMConfig, vm: &VMInfo, boot: &cmdline::Boot) {
+    // The AppArmor profile has to cover this boot's medium.
+    let vm = &VMInfo { cdrom: boot.media.iso.clone(), ..runprofile::apply(&config.settings, vm) };
+    // A suspended VM picks up where it left off instead of booting.
+    let boot = &cmdline::Boot { resume: suspend::is_suspended(vm), ..boot.clone() };
+    if let Some(mount) = guestdisk::mounted_disk(vm) {
+        error!("VM '{}' disk is mounted on the host at {}; unmount it first.", vm.name, mount.mountpoint);
+        return;
+    }
+    if let Err(e) = network::ensure_networks(config, vm) {
+        error!("Failed to bring up networks for '{}': {}", vm.name, e);
+        return;
+    }
+    if let Err(e) = firewall::apply(vm) {
+        error!("Failed to apply firewall rules for '{}': {}", vm.name, e);
+        return;
+    }
+    warn_changed_images(vm);
+    for warning in storage::warnings(vm) {
+        warn!("VM '{}': {}", vm.name, warning);
+    }
+    if let Err(e) = firmware::prepare(vm) {
+        error!("Failed to set up UEFI for '{}': {}", vm.name, e);
+        return;
+    }
+    match arch::check(vm) {
+        Ok(Some(warning)) => warn!("VM '{}': {}", vm.name, warning),
+        Ok(None) => {}
+        Err(e) => {
+            error!("Cannot start '{}': {}", vm.name, e);
+            return;
+        }
+    }
+    let problems = vfio::problems(vm);
+    if !problems.is_empty() {
+        error!("Cannot pass PCI devices through to '{}':\n  {}", vm.name, problems.join("\n  "));
+        return;
+    }
+    if let Err(e) = share::start_daemons(vm) {
+        error!("Failed to share folders with '{}': {}", vm.name, e);
+        return;
+    }
+    if let Err(e) = confine(vm) {
+        error!("Failed to load the AppArmor profile for '{}': {}", vm.name, e);
+        return;
+    }
+    let key = if boot.resume { "vm-resuming" } else { "vm-starting" };
+    println!("{}", t(key, &[("name", &vm.name), ("mode", &mode_name(boot.headless))]));
+    launch(config, vm, boot);
+}
+
+/// The QEMU invocation for one boot of a VM on this host.
+fn qemu_command(config: &VMConfig, vm: &VMInfo, boot: &cmdline::Boot) -> ShellCommand {
+    let argv = cmdline::qemu_args(config, vm, boot, &cmdline::Host::probe(vm));
+    let mut cmd = runner::command(&argv[0]);
+    cmd.args(&argv[1..]);
+    cmd
+}
+
+/// Starts QEMU in its own process group, so Ctrl-C or closing the terminal
+/// leaves it running, with its stderr in the VM's log for diagnosis. In
+/// the daemon, QEMU stays its supervised child.
+fn launch(config: &VMConfig, vm: &VMInfo, boot: &cmdline::Boot) {
+    let mut cmd = qemu_command(config, vm, boot);
+    let log = diagnose::log_path(&vm.name);
+    let stderr = match fs::File::create(&log) {
+        Ok(file) => Stdio::from(file),
+        Err(e) => {
+            warn!("cannot write {}: {}", log.display(), e);
+            Stdio::null()
+        }
+    };
+    process::detach(cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(stderr));
+    info!("launching VM '{}': {:?}", vm.name, cmd);
+    match cmd.spawn() {
+        Ok(child) => {
+            if let Some(mut qemu) = diagnose::watch_start(vm, child) {
+                if daemon::active() {
+                    daemon::supervise(vm, qemu, boot.headless);
+                } else {
+                    if !vm.guest_callbacks.is_empty() {
+                        warn!("VM '{}': only the daemon answers guest callbacks; run `SRQemu daemon` before starting it.", vm.name);
+                    }
+                    // Reap QEMU when it exits while this process still runs.
+                    std::thread::spawn(move || qemu.wait());
                 }
+                if boot.resume {
+                    suspend::resumed(vm);
+                    after_resume(vm);
+                }
+                schedule::release(&vm.name);
+                stats::started(&config.settings, vm);
+                announce_console(vm, boot);
+                post_start(config, vm);
             }
-        } else {
-            println!("VM '{}' stopped.", name);
         }
-    } else {
-        eprintln!("VM '{}' not found", name);
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let program = cmd.get_program().to_string_lossy().to_string();
+            error!("Failed to start VM '{}': {}", vm.name, error::Error::ToolMissing { program });
+        }
+        Err(e) => error!("Failed to start VM '{}': {}", vm.name, e),
     }
 }
 
-fn delete_vm(config: &mut VMConfig) {
-    list_defined_vms(config);
+fn list_defined_vms(config: &VMConfig) {
+    println!("\n{}", t("list-header", &[]));
+    for vm in config.vms.values() {
+        print_vm_summary(config, vm);
+    }
+}
 
-    let mut input = String::new();
-    print!("Enter VM name to delete: ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut input).unwrap();
-    let name = input.trim();
+/// The lines `list` shows for one VM; `info` adds the definition.
+fn print_vm_summary(config: &VMConfig, vm: &VMInfo) {
+    let name = &vm.name;
+    let base = vm.extends.as_ref().map(|p| format!(" (extends {})", p)).unwrap_or_default();
+    let tags = if vm.tags.is_empty() { String::new() } else { format!(" [{}]", vm.tags.join(", ")) };
+    let name = label::paint(vm.color.as_deref(), name);
+    let arch = match vm.arch {
+        Some(arch) if arch::foreign(vm) => format!("{} (emulated), ", arch.name()),
+        Some(arch) => format!("{}, ", arch.name()),
+        None => String::new(),
+    };
+    println!("- {}{}{}: {}{} CPU, {} threads, {} RAM, Disk: {}", name, base, tags, arch, vm.cpu, vm.threads, vm.memory, vm.disk_path());
+    println!("    {}", t("list-state", &[("state", &status::summary(vm))]));
+    if let Some(location) = storage::describe_location(vm) {
+        println!("    Disk lives at {}", location);
+    }
+    if let Some(accel) = vm.accel {
+        println!("    Accelerator: {}", accel.name());
+    }
+    if let Some(slots) = slot::describe(vm) {
+        println!("    Slots: {}", slots);
+    }
+    for (i, nic) in vm.nics.iter().enumerate() {
+        let impairment = match &nic.impairment {
+            Some(imp) if !imp.is_empty() => format!(" [{}]", imp.describe()),
+            _ => String::new(),
+        };
+        let model = nic.virtio.as_ref().map_or("e1000".to_string(), |v| v.describe());
+        println!("    NIC {}: {} ({}, {}){}", i, nic.backend.describe(), nic.mac, model, impairment);
+    }
+    for share in &vm.shares {
+        let readonly = if share.readonly { ", read-only" } else { "" };
+        println!("    Share {}: {} ({}{})", share.tag, share.path, share.protocol.name(), readonly);
+    }
+    for rule in &vm.firewall {
+        let target = rule.nic.map(|i| format!(" on NIC {}", i)).unwrap_or_default();
+        println!("    Port {}/{}{}: {}", rule.port, rule.proto, target, rule.allow.describe());
+    }
+    for restriction in sandbox::describe(config, vm) {
+        println!("    Hardening: {}", restriction);
+    }
+    warn_full_guest_disks(config, vm);
+}
 
-    if let Some(vm) = config.vms.remove(name) {
-        // Stop VM first (if it's running)
-        let pattern = format!("qemu-system-x86_64 -name {}", name);
-        if let Ok(_) = ShellCommand::new("pgrep").arg("-f").arg(&pattern).output() {
-            println!("Stopping VM '{}' before deletion...", name);
-            stop_vm(config);
-        }
+/// Guest mountpoints already notified as nearly full, one per line.
+fn full_disks_file(vm_name: &str) -> PathBuf {
+    PathBuf::from(vm_folder(vm_name)).join("full-disks")
+}
 
-        // Remove disk file
-        let disk_path = PathBuf::from(expand_path(&vm.disk));
-        if disk_path.exists() {
-            println!("Deleting disk: {}", disk_path.display());
-            if let Err(e) = fs::remove_file(&disk_path) {
-                eprintln!("Failed to delete disk file: {}", e);
+/// Flags guest filesystems past the configured threshold; full guest disks
+/// otherwise only show up as services failing inside the guest. Only a
+/// filesystem that crossed the threshold since the last look is notified,
+/// so listing VMs does not repeat the notification.
+fn warn_full_guest_disks(config: &VMConfig, vm: &VMInfo) {
+    if !vm.guest_agent || !vm_running(&vm.name) {
+        return;
+    }
+    match agent::fs_usage(&vm.name) {
+        Ok(filesystems) => {
+            let file = full_disks_file(&vm.name);
+            let notified = fs::read_to_string(&file).unwrap_or_default();
+            let mut full = String::new();
+            for fs in filesystems.iter().filter(|fs| fs.used_pct() >= config.settings.disk_full_warn_pct) {
+                if !notified.lines().any(|mountpoint| mountpoint == fs.mountpoint) {
+                    let event = notify::Event::DiskNearlyFull { vm: &vm.name, mountpoint: &fs.mountpoint, used_pct: fs.used_pct() };
+                    notify::send(&config.settings.notifications, event);
+                }
+                full.push_str(&format!("{}\n", fs.mountpoint));
+                println!(
+                    "    Warning: guest filesystem {} is {}% full ({} free)",
+                    fs.mountpoint,
+                    fs.used_pct(),
+                    guestdisk::human_size(fs.total.saturating_sub(fs.used))
+                );
+            }
+            if full != notified {
+                let recorded = if full.is_empty() { fs::remove_file(&file) } else { fs::write(&file, &full) };
+                if let Err(e) = recorded {
+                    warn!("cannot record full guest disks of '{}': {}", vm.name, e);
+                }
             }
         }
+        Err(e) => println!("    (guest disk usage unavailable: {})", e),
+    }
+}
+
+fn start_vm(config: &VMConfig) {
+    if let Some(vm) = select_vm(config, "start") {
+        let mode = prompt("Start in GUI or headless mode? (gui/headless): ").to_lowercase();
+        start_vm_common(config, vm, mode == "headless");
+    }
+}
 
-        // Remove VM folder
-        let vm_dir = PathBuf::from(expand_path(&format!("{}/{}", get_vm_folder(), name)));
-        if vm_dir.exists() {
-            println!("Deleting VM folder: {}", vm_dir.display());
-            if let Err(e) = fs::remove_dir_all(&vm_dir) {
-                eprintln!("Failed to delete VM folder: {}", e);
+/// Shuts a VM down through ACPI, killing it only if the guest ignores the
+/// request for longer than the configured timeout. A kill can leave guest
+/// filesystems inconsistent, so `force` is for hung guests.
+fn stop_vm_by_name(config: &VMConfig, name: &str, force: bool) -> Result<(), String> {
+    match daemon::forward(&daemon::Request::Stop { name: name.to_string(), force }) {
+        Some(true) => return Ok(()),
+        Some(false) => return Err(format!("the daemon did not stop VM '{}'", name)),
+        None => {}
+    }
+    println!("{}", t("vm-stopping", &[("name", &name)]));
+    daemon::expect_exit(name);
+    let timeout = std::time::Duration::from_secs(config.settings.shutdown_timeout);
+    // A paused guest cannot react to the power button.
+    if !force && qmp::paused(name) {
+        let _ = qmp::command(name, "cont", None);
+    }
+    let graceful = if force { Ok(false) } else { qmp::powerdown(name, timeout) };
+    let stopped = match graceful {
+        Ok(true) => {
+            println!("{}", t("vm-shut-down", &[("name", &name)]));
+            Ok(())
+        }
+        Ok(false) => {
+            if !force {
+                error!("VM '{}' did not shut down within {}s; killing it.", name, timeout.as_secs());
             }
+            kill_vm(name)
         }
+        Err(e) => {
+            error!("Cannot ask VM '{}' to shut down ({}); killing it.", name, e);
+            kill_vm(name)
+        }
+    };
 
-        // Save updated config
-        save_config(config);
+    if let Err(e) = firewall::remove(name) {
+        error!("Failed to remove firewall rules for '{}': {}", name, e);
+    }
+    if vm_running(name) {
+        daemon::cancel_expected_exit(name);
+        return Err(stopped.err().unwrap_or_else(|| format!("VM '{}' is still running.", name)));
+    }
+    qmp::cleanup_runtime(name);
+    stats::stopped(&config.settings, name);
+    Ok(())
+}
 
-        println!("VM '{}' deleted.", name);
-    } else {
-        eprintln!("VM '{}' not found", name);
+/// Stops a running VM's CPUs; QEMU keeps its memory and devices as they
+/// are.
+fn pause_vm(name: &str) -> Result<String, String> {
+    if !vm_running(name) {
+        return Err(format!("VM '{}' is not running", name));
     }
+    if qmp::paused(name) {
+        return Ok(format!("VM '{}' is already paused.", name));
+    }
+    qmp::command(name, "stop", None)?;
+    Ok(format!("VM '{}' paused; `SRQemu resume {}` continues it.", name, name))
 }
 
+fn resume_vm(vm: &VMInfo) -> Result<String, String> {
+    if !vm_running(&vm.name) {
+        return Err(format!("VM '{}' is not running", vm.name));
+    }
+    if !qmp::paused(&vm.name) {
+        return Ok(format!("VM '{}' is not paused.", vm.name));
+    }
+    qmp::command(&vm.name, "cont", None)?;
+    after_resume(vm);
+    Ok(format!("VM '{}' resumed.", vm.name))
+}
 
-fn main() {
-    let mut config = load_config();
+fn kill_vm(name: &str) -> Result<(), String> {
+    let Some(pid) = vm_pid(name) else {
+        println!("{}", t("vm-not-running", &[("name", &name)]));
+        return Ok(());
+    };
+    if let Err(e) = process::kill(pid) {
+        warn!("{}", e);
+    }
+    // SIGKILL is delivered asynchronously.
+    for _ in 0..20 {
+        if !vm_running(name) {
+            // QEMU only removes its pidfile on a clean exit.
+            pidfile::remove(name);
+            let _ = fs::remove_file(adopt::pidfile(name));
+            println!("{}", t("vm-killed", &[("name", &name)]));
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    Err(format!("VM '{}' is still running.", name))
+}
 
-    loop {
-        println!("\n=== QEMU VM Manager ===");
-        println!("1. Create VM");
-        println!("2. Start VM");
-        println!("3. Stop VM");
-        println!("4. List VMs");
-        println!("5. Delete VM");
-        println!("6. Exit");
+fn stop_vm(config: &VMConfig) {
+    if let Some(vm) = select_vm(config, "stop") {
+        if let Err(e) = stop_vm_by_name(config, &vm.name, false) {
+            error!("Failed to stop VM '{}': {}", vm.name, e);
+        }
+        hostpower::update(config, Some(&vm.name));
+    }
+}
+
+fn delete_vm(config: &mut VMConfig) {
+    list_defined_vms(config);
+    let name = prompt("Enter VM name to delete: ");
+    match delete_vm_by_name(config, &name) {
+        Ok(()) => println!("Use 'Restore deleted VM' to bring it back; it is purged after {} days.", config.settings.trash_days),
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Stops the VM if needed and moves it to the trash; the caller tells how
+/// to bring it back.
+fn delete_vm_by_name(config: &mut VMConfig, name: &str) -> Result<(), String> {
+    let Some(vm) = config.vms.get(name).cloned() else {
+        return Err(t("vm-not-found", &[("name", &name)]));
+    };
+    if let Some(mount) = guestdisk::mounted_disk(&vm) {
+        return Err(format!("VM '{}' disk is mounted on the host at {}; unmount it first.", name, mount.mountpoint));
+    }
+
+    // Stop VM first (if it's running)
+    if vm_running(name) {
+        println!("Stopping VM '{}' before deletion...", name);
+        let stopped = stop_vm_by_name(config, name, false);
+        hostpower::update(config, Some(name));
+        stopped.map_err(|e| format!("Failed to stop VM '{}', so it was not deleted: {}", name, e))?;
+    }
+
+    for job in &vm.guest_cron {
+        if let Err(e) = guestcron::uninstall(name, &job.id) {
+            error!("Failed to remove schedule '{}': {}", job.id, e);
+        }
+    }
+    if vm.autostart.is_some()
+        && let Err(e) = autostart::uninstall_unit(name)
+    {
+        error!("Failed to remove the autostart unit: {}", e);
+    }
+    if let Err(e) = apparmor::remove(name) {
+        error!("Failed to remove the AppArmor profile: {}", e);
+    }
+    history::checkpoint(&config.settings, &format!("delete-{}", name)).map_err(|e| format!("Failed to keep a copy of the configuration: {}", e))?;
+    let entry = trash::move_to_trash(&vm).map_err(|e| format!("Failed to delete VM '{}': {}", name, e))?;
+    config.vms.remove(name);
+    save_config(config).map_err(|e| e.to_string())?;
+    println!("VM '{}' moved to {}.", name, entry.display());
+    Ok(())
+}
+
+fn restore_deleted_vm(config: &mut VMConfig) {
+    let entries = trash::list();
+    if entries.is_empty() {
+        println!("Trash is empty.");
+        return;
+    }
+    println!("\nDeleted VMs:");
+    for (i, entry) in entries.iter().enumerate() {
+        println!("{}. {} (deleted {})", i + 1, entry.name, entry.age());
+    }
+    let choice = prompt("Restore which? ");
+    let Some(entry) = choice.parse::<usize>().ok().and_then(|n| entries.get(n.wrapping_sub(1))) else {
+        error!("{}", t("invalid-choice", &[]));
+        return;
+    };
+    if let Err(e) = restore_entry(config, entry) {
+        error!("{}", e);
+    }
+}
+
+fn restore_entry(config: &mut VMConfig, entry: &trash::TrashEntry) -> Result<(), String> {
+    trash::restore(entry, config).map_err(|e| format!("Failed to restore '{}': {}", entry.name, e))?;
+    save_config(config).map_err(|e| e.to_string())?;
+    println!("VM '{}' restored.", entry.name);
+    if let Some(vm) = config.vms.get(&entry.name) {
+        reinstall_units(vm, &config.settings);
+    }
+    Ok(())
+}
 
-        print!("\nSelect an option: ");
-        io::stdout().flush().unwrap();
+/// Takes the files of a VM whose definition a config rollback brought back
+/// out of the trash, if they are there.
+fn restore_rolled_back(config: &VMConfig, name: &str) {
+    let Some(vm) = config.vms.get(name) else { return };
+    if Path::new(&vm_folder(name)).exists() {
+        return;
+    }
+    let Some(entry) = trash::list().into_iter().rev().find(|e| e.name == name) else {
+        println!("VM '{}' has no files at {}.", name, vm_folder(name));
+        return;
+    };
+    match trash::put_back(&entry) {
+        Ok(()) => println!("VM '{}' taken back out of the trash.", name),
+        Err(e) => error!("Failed to restore the files of '{}': {}", name, e),
+    }
+    reinstall_units(vm, &config.settings);
+}
+
+/// Puts back the timers and autostart unit of a VM that was brought back.
+fn reinstall_units(vm: &VMInfo, settings: &config::Settings) {
+    for job in &vm.guest_cron {
+        if let Err(e) = guestcron::install(&vm.name, job) {
+            error!("Failed to reinstall schedule '{}': {}", job.id, e);
+        }
+    }
+    if vm.autostart.is_some()
+        && let Err(e) = autostart::install_unit(vm, settings)
+    {
+        error!("Failed to reinstall the autostart unit: {}", e);
+    }
+}
+
+/// Offline disk operations only make sense while QEMU has the image closed.
+fn select_stopped_vm<'a>(config: &'a VMConfig, action: &str) -> Option<&'a VMInfo> {
+    let vm = select_vm(config, action)?;
+    if vm_running(&vm.name) {
+        error!("VM '{}' is running; stop it first.", vm.name);
+        return None;
+    }
+    Some(vm)
+}
+
+fn disk_tools_menu(config: &mut VMConfig) {
+    output::heading("Disk tools");
+    println!("1. Inspect guest disk");
+    println!("2. Mount disk on host");
+    println!("3. Unmount disk");
+    println!("4. Copy files to/from guest");
+    println!("5. Reset guest password");
+    println!("6. Verify image checksums");
+    println!("7. Re-register images after an intended change");
+    println!("8. Resize disk");
+    println!("9. Disk bus, IOThread and cache");
+    println!("10. Store paths relative to the VM folders");
+    println!("11. Snapshots");
+    println!("12. CD drive (insert or eject an ISO)");
+    println!("13. Back");
+
+    match prompt("\nSelect an option: ").as_str() {
+        "1" => {
+            if let Some(vm) = select_stopped_vm(config, "inspect")
+                && let Err(e) = guestdisk::inspect(vm)
+            {
+                error!("Failed to inspect '{}': {}", vm.name, e);
+            }
+        }
+        "2" => {
+            if let Some(vm) = select_stopped_vm(config, "mount") {
+                let partition = prompt("Partition number (leave empty for the largest): ");
+                let partition = match partition.parse::<usize>() {
+                    Ok(n) => Some(n),
+                    Err(_) if partition.is_empty() => None,
+                    Err(_) => {
+                        error!("Invalid partition number '{}'", partition);
+                        return;
+                    }
+                };
+                let mountpoint = PathBuf::from(expand_path(&prompt("Mountpoint: ")));
+                let read_only = prompt("Mount read-only? (y/N): ").eq_ignore_ascii_case("y");
+                if let Err(e) = guestdisk::mount_disk(vm, partition, &mountpoint, read_only) {
+                    error!("Failed to mount '{}': {}", vm.name, e);
+                }
+            }
+        }
+        "3" => {
+            if let Some(vm) = select_vm(config, "unmount")
+                && let Err(e) = guestdisk::umount_disk(vm)
+            {
+                error!("Failed to unmount '{}': {}", vm.name, e);
+            }
+        }
+        "4" => copy_guest_files(config),
+        "5" => {
+            if let Some(vm) = select_stopped_vm(config, "reset a password on") {
+                let user = prompt("Guest user: ");
+                let partition = prompt("Partition number (leave empty to detect the root filesystem): ").parse().ok();
+                let password = || prompt_secret("New password: ");
+                if let Err(e) = guestdisk::reset_password(vm, &user, password, partition) {
+                    error!("Password reset failed: {}", e);
+                }
+            }
+        }
+        "6" => {
+            let Some(vm) = select_vm(config, "verify") else { return };
+            let warnings = provenance::verify(vm);
+            if warnings.is_empty() {
+                println!("All {} registered image(s) of '{}' match their checksums.", vm.images.len(), vm.name);
+            }
+            for warning in warnings {
+                warn!("{}", warning);
+            }
+        }
+        "7" => {
+            let Some(name) = select_vm(config, "re-register images of").map(|vm| vm.name.clone()) else { return };
+            let Some(vm) = config.vms.get_mut(&name) else { return };
+            match provenance::register(vm, "re-registered", true) {
+                Ok(n) => {
+                    if let Err(e) = save_config(config) {
+                        error!("{}", e);
+                        return;
+                    }
+                    println!("Recorded {} image(s) for '{}'.", n, name);
+                }
+                Err(e) => error!("Failed to register images: {}", e),
+            }
+        }
+        "8" => {
+            if let Some(vm) = select_vm(config, "resize") {
+                resize_disk(vm);
+            }
+        }
+        "9" => set_disk_device(config),
+        "10" => relativize_paths(config),
+        "11" => snapshot_menu(config),
+        "12" => cdrom_menu(config),
+        "13" => {}
+        _ => println!("{}", t("invalid-choice", &[])),
+    }
+}
 
-        let mut choice = String::new();
-        io::stdin().read_line(&mut choice).unwrap();
+fn snapshot_menu(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "manage snapshots of").map(|vm| vm.name.clone()) else {
+        return;
+    };
+    print_snapshots(&config.vms[&name]);
+    println!("\n1. Take snapshot");
+    println!("2. Restore snapshot");
+    println!("3. Delete snapshot");
+    println!("4. Back");
+    let action = match prompt("\nSelect an option: ").as_str() {
+        "1" => {
+            let snapshot = prompt("Snapshot name: ");
+            SnapshotAction::Create { vm: name, name: snapshot, description: prompt("Description (optional): ") }
+        }
+        "2" => SnapshotAction::Restore { vm: name, name: prompt("Snapshot name: ") },
+        "3" => SnapshotAction::Delete { vm: name, name: prompt("Snapshot name: ") },
+        _ => return,
+    };
+    snapshot_action(config, action);
+}
 
-        match choice.trim() {
-            "1" => create_vm(&mut config),
-            "2" => start_vm(&config),
-            "3" => stop_vm(&config),
-            "4" => list_defined_vms(&config),
-            "5" => delete_vm(&mut config),
-            "6" => break,
-            _ => println!("Invalid choice."),
+fn print_snapshots(vm: &VMInfo) {
+    match snapshot::describe(vm) {
+        Ok(lines) if lines.is_empty() => println!("'{}' has no snapshots.", vm.name),
+        Ok(lines) => {
+            println!("Snapshots of '{}':", vm.name);
+            for line in lines {
+                println!("  {}", line);
+            }
         }
+        Err(e) => error!("Cannot read snapshots of '{}': {}", vm.name, e),
     }
 }
 
+fn snapshot_action(config: &mut VMConfig, action: SnapshotAction) -> bool {
+    let (name, snapshot, result) = match action {
+        SnapshotAction::List { vm } => {
+            if let Some(vm) = config.vms.get(&vm) {
+                print_snapshots(vm);
+            }
+            return true;
+        }
+        SnapshotAction::Create { vm, name, description } => {
+            let Some(info) = config.vms.get_mut(&vm) else { return false };
+            let result = snapshot::create(info, &name, &description).map(|()| "taken");
+            (vm, name, result)
+        }
+        SnapshotAction::Restore { vm, name } => {
+            let Some(info) = config.vms.get(&vm) else { return false };
+            let result = snapshot::restore(info, &name).map(|()| "restored");
+            (vm, name, result)
+        }
+        SnapshotAction::Delete { vm, name } => {
+            let Some(info) = config.vms.get_mut(&vm) else { return false };
+            let result = snapshot::delete(info, &name).map(|()| "deleted");
+            (vm, name, result)
+        }
+    };
+    match result {
+        Ok(done) => {
+            if let Err(e) = save_config(config) {
+                error!("{}", e);
+                return false;
+            }
+            println!("Snapshot '{}' of '{}' {}.", snapshot, name, done);
+            true
+        }
+        Err(e) => {
+            error!("Snapshot '{}' of '{}' failed: {}", snapshot, name, e);
+            false
+        }
+    }
+}
+
+/// Rewrites absolute disk, ISO, CD and seed paths that point into a VM's own
+/// folder, e.g. for definitions made before paths could be relative.
+fn relativize_paths(config: &mut VMConfig) {
+    let mut changed = 0;
+    for (name, vm) in config.vms.iter_mut() {
+        let disk = relative_to_folder(name, &vm.disk);
+        let iso = relative_to_folder(name, &vm.iso);
+        let seed = vm.seed.as_ref().map(|s| relative_to_folder(name, s));
+        let cdrom = vm.cdrom.as_ref().map(|c| relative_to_folder(name, c));
+        if disk != vm.disk || iso != vm.iso || seed != vm.seed || cdrom != vm.cdrom {
+            vm.disk = disk;
+            vm.iso = iso;
+            vm.seed = seed;
+            vm.cdrom = cdrom;
+            changed += 1;
+        }
+    }
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("Updated {} VM(s); paths outside the VM folders stay absolute.", changed);
+}
+
+/// Inserts `iso` into a VM's CD drive, or ejects with `None`. A running
+/// VM's drive changes until it shuts down; a stopped VM keeps the medium
+/// for every boot.
+fn change_cdrom(config: &mut VMConfig, name: &str, iso: Option<&str>) -> Result<String, String> {
+    let iso = iso.map(expand_path);
+    if let Some(path) = &iso
+        && !Path::new(path).exists()
+    {
+        return Err(format!("ISO {} does not exist", path));
+    }
+    if vm_running(name) {
+        return match &iso {
+            Some(path) => media::insert(name, path).map(|_| format!("Inserted {} into '{}' until it shuts down.", path, name)),
+            None => media::eject(name).map(|_| format!("Ejected the CD of '{}' until it shuts down.", name)),
+        };
+    }
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    vm.cdrom = iso.as_ref().map(|path| relative_to_folder(name, path));
+    save_config(config)?;
+    Ok(match iso {
+        Some(path) => format!("'{}' boots with {} in its CD drive.", name, path),
+        None => format!("'{}' boots with an empty CD drive.", name),
+    })
+}
+
+fn cdrom_menu(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "change the CD of").map(|vm| vm.name.clone()) else { return };
+    let iso = prompt("ISO to insert (leave empty to eject): ");
+    match change_cdrom(config, &name, (!iso.is_empty()).then_some(iso.as_str())) {
+        Ok(done) => println!("{}", done),
+        Err(e) => error!("Failed to change the CD of '{}': {}", name, e),
+    }
+}
+
+/// Sets the VM's color and icon; `none` clears either.
+fn set_label(vm: &mut VMInfo, color: Option<&str>, icon: Option<&str>) -> Result<(), String> {
+    if let Some(color) = color {
+        vm.color = if color == "none" { None } else { Some(label::parse_color(color)?) };
+    }
+    if let Some(icon) = icon {
+        vm.icon = match icon {
+            "none" => None,
+            // Theme icons are bare names; anything else is a file.
+            name if !name.contains('/') && !name.starts_with('~') => Some(name.to_string()),
+            file => {
+                let file = expand_path(file);
+                if !Path::new(&file).exists() {
+                    return Err(format!("icon {} does not exist", file));
+                }
+                Some(file)
+            }
+        };
+    }
+    Ok(())
+}
+
+/// Sets the disk bus and the model of every NIC, keeping the IOThread and
+/// virtio queue settings where they still apply, and describes the result.
+fn set_devices(config: &mut VMConfig, name: &str, disk_bus: Option<&str>, nic_model: Option<&str>) -> Result<String, String> {
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    if let Some(bus) = disk_bus {
+        vm.disk_device = match bus {
+            "ide" => None,
+            other => {
+                let bus = storage::DiskBus::parse(other).ok_or_else(|| format!("unknown disk bus '{}'; use ide, sata, virtio-blk, virtio-scsi or nvme", other))?;
+                let iothread = vm.disk_device.as_ref().is_none_or(|d| d.iothread) && bus.takes_iothread();
+                Some(storage::DiskDevice { bus, iothread })
+            }
+        };
+    }
+    if let Some(model) = nic_model {
+        let virtio = network::parse_nic_model(model)?;
+        for nic in &mut vm.nics {
+            if virtio.is_none() || nic.virtio.is_none() {
+                nic.virtio = virtio.clone();
+            }
+        }
+    }
+    let bus = vm.disk_device.as_ref().map_or("ide", |d| d.bus.name());
+    let models: Vec<&str> = vm.nics.iter().map(|nic| if nic.virtio.is_some() { "virtio" } else { "e1000" }).collect();
+    let nics = if models.is_empty() { "QEMU's default NIC".to_string() } else { format!("NICs {}", models.join(", ")) };
+    let summary = format!("'{}' has its disk on {} and {}", name, bus, nics);
+    if disk_bus.is_none() && nic_model.is_none() {
+        return Ok(format!("{}.", summary));
+    }
+    save_config(config).map_err(|e| e.to_string())?;
+    let guest = if bus.starts_with("virtio") || models.contains(&"virtio") { " The guest needs virtio drivers for them." } else { "" };
+    Ok(format!("{} from its next start.{}", summary, guest))
+}
+
+fn set_disk_device(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "change the disk bus of").map(|vm| vm.name.clone()) else { return };
+    let Some(vm) = config.vms.get_mut(&name) else { return };
+    let current = vm.disk_device.as_ref().map_or("default", |d| d.bus.name());
+    let bus = prompt_or("Bus (default, sata, virtio-blk, virtio-scsi or nvme; virtio needs guest drivers)", current);
+    vm.disk_device = match bus.as_str() {
+        "default" => None,
+        other => {
+            let Some(bus) = storage::DiskBus::parse(other) else {
+                error!("Unknown disk bus '{}'", other);
+                return;
+            };
+            let iothread = vm.disk_device.as_ref().is_none_or(|d| d.iothread);
+            let iothread = bus.takes_iothread() && prompt_or("Dedicated IOThread? (y/n)", if iothread { "y" } else { "n" }) == "y";
+            Some(storage::DiskDevice { bus, iothread })
+        }
+    };
+    if let Some(location) = storage::locate(&vm.disk_path()) {
+        println!("The disk is on {} ({}).", location.fs_type, location.real_path);
+    }
+    let cache = prompt_or(&format!("Cache mode ({} or default)", storage::CACHE_MODES.join(", ")), vm.disk_cache.as_deref().unwrap_or("default"));
+    vm.disk_cache = match cache.as_str() {
+        "default" => None,
+        mode if storage::CACHE_MODES.contains(&mode) => Some(mode.to_string()),
+        other => {
+            error!("Unknown cache mode '{}'", other);
+            return;
+        }
+    };
+    for warning in storage::warnings(vm) {
+        warn!("{}", warning);
+    }
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("Disk settings updated; they take effect on the next start of '{}'.", name);
+}
+
+fn resize_disk(vm: &VMInfo) {
+    if let Some(mount) = guestdisk::mounted_disk(vm) {
+        error!("VM '{}' disk is mounted on the host at {}; unmount it first.", vm.name, mount.mountpoint);
+        return;
+    }
+    let spec = prompt("New size, e.g. 40G, or +10G to grow by that much: ");
+    match resize::resize(vm, &spec) {
+        Ok(size) => println!("Disk of '{}' is now {}.", vm.name, guestdisk::human_size(size)),
+        Err(e) => {
+            error!("Failed to resize '{}': {}", vm.name, e);
+            return;
+        }
+    }
+    if prompt_or("Grow the guest's partition and filesystem too? (y/n)", "y") != "y" {
+        println!("The guest sees the extra space as unpartitioned until you grow it.");
+        return;
+    }
+    let partition = if vm_running(&vm.name) {
+        None
+    } else {
+        prompt("Partition number (leave empty for the largest): ").parse().ok()
+    };
+    if let Err(e) = grow_guest(vm, partition) {
+        error!("{}", e);
+    }
+}
+
+/// Grows the guest's partition and filesystem into new disk space, through
+/// the guest agent while the VM runs and offline otherwise.
+fn grow_guest(vm: &VMInfo, partition: Option<usize>) -> Result<(), String> {
+    let result = if vm_running(&vm.name) {
+        if !vm.guest_agent {
+            return Err(format!("VM '{}' is running without a guest agent; stop it to grow the filesystem offline.", vm.name));
+        }
+        resize::grow_guest_online(vm).map(|out| print!("{}", out))
+    } else {
+        resize::grow_guest_offline(vm, partition)
+    };
+    result.map_err(|e| format!("Failed to grow the guest filesystem: {}", e))
+}
+
+fn network_menu(config: &mut VMConfig) {
+    output::heading("Network");
+    println!("1. Create host bridge");
+    println!("2. Remove host bridge");
+    println!("3. List managed networks");
+    println!("4. Create managed network");
+    println!("5. Bring managed network up");
+    println!("6. Bring managed network down");
+    println!("7. Link managed network over WireGuard");
+    println!("8. Add WireGuard peer");
+    println!("9. Attach VM NIC");
+    println!("10. Detach VM NIC");
+    println!("11. Start packet capture");
+    println!("12. Stop packet capture");
+    println!("13. Set NIC impairments (latency/loss)");
+    println!("14. Firewall rules for exposed ports");
+    println!("15. NIC model (e1000 or virtio)");
+    println!("16. Port forwards");
+    println!("17. Back");
+
+    match prompt("\nSelect an option: ").as_str() {
+        "1" => {
+            let bridge = prompt_or("Bridge name", "br0");
+            let uplink = prompt("Uplink interface to enslave (leave empty for none): ");
+            let uplink = if uplink.is_empty() { None } else { Some(uplink.as_str()) };
+            if let Err(e) = network::bridge_create(&bridge, uplink) {
+                error!("Failed to create bridge {}: {}", bridge, e);
+            }
+        }
+        "2" => {
+            let bridge = prompt_or("Bridge name", "br0");
+            if let Err(e) = network::bridge_teardown(&bridge) {
+                error!("Failed to remove bridge {}: {}", bridge, e);
+            }
+        }
+        "3" => list_networks(config),
+        "4" => {
+            let name = prompt("Network name: ");
+            let kind = prompt_or("Type: bridge, socket (isolated) or vde (isolated)", "bridge");
+            let result = match kind.as_str() {
+                "bridge" => network::network_create(config, &name).map(configure_bridge_network),
+                "socket" | "vde" => network::isolated_create(config, &name, kind == "vde"),
+                _ => Err(format!("unknown network type '{}'", kind)),
+            };
+            match result {
+                Ok(()) => {
+                    if let Err(e) = save_config(config) {
+                        error!("{}", e);
+                        return;
+                    }
+                    println!("Network '{}' created.", name);
+                }
+                Err(e) => error!("Failed to create network: {}", e),
+            }
+        }
+        choice @ ("5" | "6") => {
+            let Some(name) = select_network(config) else { return };
+            let def = &config.networks[&name];
+            let result = if choice == "5" {
+                network::network_up(&name, def)
+            } else {
+                network::network_down(&name, def)
+            };
+            if let Err(e) = result {
+                error!("Failed to change network '{}': {}", name, e);
+            }
+        }
+        "7" => {
+            let Some(name) = select_network(config) else { return };
+            if config.networks[&name].isolated.is_some() {
+                error!("Network '{}' is isolated; only bridge networks can be linked.", name);
+                return;
+            }
+            let port = prompt_or("WireGuard listen port", "51820");
+            let Ok(port) = port.parse::<u16>() else {
+                error!("Invalid port '{}'", port);
+                return;
+            };
+            let tunnel = prompt("This host's tunnel address, e.g. 10.200.0.1/24: ");
+            let vni = prompt_or("VXLAN id (same on every host)", "4242");
+            let Ok(vni) = vni.parse::<u32>() else {
+                error!("Invalid VXLAN id '{}'", vni);
+                return;
+            };
+            match network::wireguard_init(&name, port, &tunnel, vni) {
+                Ok((link, public_key)) => {
+                    if let Some(def) = config.networks.get_mut(&name) {
+                        def.wireguard = Some(link);
+                    }
+                    if let Err(e) = save_config(config) {
+                        error!("{}", e);
+                        return;
+                    }
+                    println!("WireGuard enabled for '{}'. Give peers this public key:", name);
+                    println!("  {}", public_key);
+                }
+                Err(e) => error!("Failed to set up WireGuard: {}", e),
+            }
+        }
+        "8" => {
+            let Some(name) = select_network(config) else { return };
+            let Some(wg) = config.networks.get_mut(&name).and_then(|d| d.wireguard.as_mut()) else {
+                error!("Network '{}' has no WireGuard link yet.", name);
+                return;
+            };
+            let public_key = prompt("Peer public key: ");
+            let endpoint = prompt("Peer endpoint host:port (leave empty if it dials in): ");
+            let tunnel_ip = prompt("Peer tunnel address, e.g. 10.200.0.2: ");
+            wg.peers.push(network::WireGuardPeer {
+                public_key,
+                endpoint: if endpoint.is_empty() { None } else { Some(endpoint) },
+                tunnel_ip,
+            });
+            if let Err(e) = save_config(config) {
+                error!("{}", e);
+                return;
+            }
+            println!("Peer added; bring the network down and up again to apply.");
+        }
+        "9" => {
+            let Some(name) = select_vm(config, "attach a NIC to").map(|vm| vm.name.clone()) else { return };
+            list_networks(config);
+            let spec = prompt("Network (user, passt, bridge:<name> or managed network; default user): ");
+            let mut backend = match network::NetBackend::parse(&spec, config) {
+                Ok(b) => b,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            let mac = network::generate_mac(&name, config.vms[&name].nics.len());
+            if let Err(e) = configure_nic(config, &mut backend, &mac) {
+                error!("{}", e);
+                return;
+            }
+            if let network::NetBackend::Network { network } = &backend
+                && let Some(def) = config.networks.get(network)
+                && def.dhcp.is_some()
+                && def.is_up()
+                && let Err(e) = network::dhcp_restart(network, def)
+            {
+                error!("Failed to restart DHCP on '{}': {}", network, e);
+            }
+            if let Some(vm) = config.vms.get_mut(&name) {
+                vm.nics.push(network::NicSpec { backend, mac, impairment: None, virtio: None });
+            }
+            if let Err(e) = save_config(config) {
+                error!("{}", e);
+                return;
+            }
+            println!("NIC attached; it takes effect on the next start of '{}'.", name);
+        }
+        "10" => {
+            let Some(name) = select_vm(config, "detach a NIC from").map(|vm| vm.name.clone()) else { return };
+            let index = prompt("NIC number: ");
+            let Some(vm) = config.vms.get_mut(&name) else { return };
+            match index.parse::<usize>() {
+                Ok(i) if i < vm.nics.len() => {
+                    let nic = vm.nics.remove(i);
+                    drop_leases(config, &nic.mac);
+                    if let Err(e) = save_config(config) {
+                        error!("{}", e);
+                        return;
+                    }
+                    println!("NIC {} detached from '{}'.", i, name);
+                }
+                _ => error!("Invalid NIC number '{}'", index),
+            }
+        }
+        "11" => {
+            let Some(vm) = select_vm(config, "capture") else { return };
+            if !vm_running(&vm.name) {
+                error!("VM '{}' is not running.", vm.name);
+                return;
+            }
+            let Ok(nic) = prompt_or("NIC number", "0").parse::<usize>() else {
+                error!("Invalid NIC number");
+                return;
+            };
+            let out = PathBuf::from(expand_path(&prompt_or("Output file", &format!("{}-net{}.pcap", vm.name, nic))));
+            let size = prompt("Rotate after N MB (leave empty for no limit): ");
+            let rotation = match size.parse::<u32>() {
+                Ok(max_size_mb) => {
+                    let files = prompt_or("Files to keep (0 = all)", "0").parse().unwrap_or(0);
+                    Some(capture::Rotation { max_size_mb, files })
+                }
+                Err(_) => None,
+            };
+            if let Err(e) = capture::start(config, vm, nic, &out, rotation) {
+                error!("Failed to start capture: {}", e);
+            }
+        }
+        "12" => {
+            let Some(vm) = select_vm(config, "stop capturing on") else { return };
+            let Ok(nic) = prompt_or("NIC number", "0").parse::<usize>() else {
+                error!("Invalid NIC number");
+                return;
+            };
+            if let Err(e) = capture::stop(vm, nic) {
+                error!("Failed to stop capture: {}", e);
+            }
+        }
+        "13" => set_impairments(config),
+        "14" => edit_firewall(config),
+        "15" => set_nic_model(config),
+        "16" => edit_forwards(config),
+        "17" => {}
+        _ => println!("{}", t("invalid-choice", &[])),
+    }
+}
+
+fn optional(msg: &str) -> Option<String> {
+    let value = prompt(msg);
+    if value.is_empty() { None } else { Some(value) }
+}
+
+fn configure_bridge_network(def: &mut network::NetworkDef) {
+    def.address = optional("Host IPv4 address on the network, e.g. 10.10.0.1/24 (leave empty for none): ");
+    def.address6 = optional("Host IPv6 address on the network, e.g. fd10:10::1/64 (leave empty for none): ");
+    def.nat = prompt_or("NAT guests out of the host's other interfaces? (y/n)", "n") == "y";
+    if prompt_or("Run DHCP/router advertisements with dnsmasq? (y/n)", "n") == "y" {
+        def.dhcp = Some(network::Dhcp {
+            range: optional("IPv4 pool, e.g. 10.10.0.100,10.10.0.200 (leave empty for none): "),
+            range6: def
+                .address6
+                .as_ref()
+                .and_then(|_| optional("DHCPv6 pool, e.g. ::100,::1ff (leave empty for SLAAC only): ")),
+            leases: Vec::new(),
+        });
+    }
+}
+
+/// Replaces the forwards of a user-mode or passt NIC.
+fn edit_forwards(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "edit port forwards of").map(|vm| vm.name.clone()) else { return };
+    print_ports(&config.vms[&name]);
+    let index = prompt_or("NIC number", "0");
+    let Some(vm) = config.vms.get_mut(&name) else { return };
+    let Some(forwards) = index.parse::<usize>().ok().and_then(|i| vm.nics.get_mut(i)).and_then(|nic| nic.backend.forwards_mut()) else {
+        error!("NIC {} does not exist or is not user-mode or passt.", index);
+        return;
+    };
+    let current = forwards.iter().map(|f| f.describe()).collect::<Vec<_>>().join(",");
+    println!("Current: {}", if current.is_empty() { "none" } else { &current });
+    let input = prompt("New forwards, comma separated, e.g. 2222->22 (leave empty to keep, 'none' to clear): ");
+    *forwards = match input.as_str() {
+        "" => return,
+        "none" => Vec::new(),
+        specs => match network::parse_forwards(specs) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        },
+    };
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("Port forwards updated; they take effect on the next start of '{}'.", name);
+}
+
+fn print_ports(vm: &VMInfo) {
+    let mut any = false;
+    for (i, nic) in vm.nics.iter().enumerate() {
+        for fwd in nic.backend.forwards().into_iter().flatten() {
+            let host = fwd.host_addr.as_deref().unwrap_or("*");
+            let guest = fwd.guest_addr.as_deref().unwrap_or("guest");
+            println!("  {}: {} {}:{} -> {}:{} (NIC {})", vm.name, fwd.proto, host, fwd.host_port, guest, fwd.guest_port, i);
+            any = true;
+        }
+    }
+    if !any {
+        println!("  {}: no port forwards", vm.name);
+    }
+}
+
+/// Asks for the per-NIC details of a backend: IPv6 and forwards for
+/// user-mode, a static lease on managed networks that run DHCP.
+fn configure_nic(config: &mut VMConfig, backend: &mut network::NetBackend, mac: &str) -> Result<(), String> {
+    match backend {
+        network::NetBackend::User { ipv6_net, hostfwd } => {
+            *ipv6_net = optional("Guest IPv6 prefix, e.g. fd00:1::/64 (leave empty for QEMU's default): ");
+            *hostfwd = network::parse_forwards(&prompt("Port forwards, comma separated, e.g. 2222->22,tcp:[::]:8080-:80 (leave empty for none): "))?;
+        }
+        network::NetBackend::Network { network } => {
+            let Some(dhcp) = config.networks.get_mut(network.as_str()).and_then(|d| d.dhcp.as_mut()) else {
+                return Ok(());
+            };
+            let ipv4 = optional("Static IPv4 lease (leave empty for a dynamic one): ");
+            let ipv6 = optional("Static IPv6 lease (leave empty for a dynamic one): ");
+            if ipv4.is_some() || ipv6.is_some() {
+                dhcp.leases.push(network::StaticLease { mac: mac.to_string(), ipv4, ipv6 });
+            }
+        }
+        network::NetBackend::Passt { forwards } => {
+            *forwards = network::parse_forwards(&prompt("Port forwards, comma separated, e.g. 2222->22,udp:127.0.0.1:5353-:53 (leave empty for none): "))?;
+        }
+        network::NetBackend::Bridge { .. } => {}
+    }
+    Ok(())
+}
+
+/// Forgets static leases held by a MAC, restarting dnsmasq where it runs.
+fn drop_leases(config: &mut VMConfig, mac: &str) {
+    for (name, def) in config.networks.iter_mut() {
+        let Some(dhcp) = def.dhcp.as_mut() else { continue };
+        let before = dhcp.leases.len();
+        dhcp.leases.retain(|l| !l.mac.eq_ignore_ascii_case(mac));
+        if dhcp.leases.len() != before
+            && def.is_up()
+            && let Err(e) = network::dhcp_restart(name, def)
+        {
+            error!("Failed to restart DHCP on '{}': {}", name, e);
+        }
+    }
+}
+
+fn set_impairments(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "impair").map(|vm| vm.name.clone()) else { return };
+    let index = prompt_or("NIC number", "0");
+    let Some(nic) = index.parse::<usize>().ok().and_then(|i| config.vms.get_mut(&name)?.nics.get_mut(i)) else {
+        error!("Invalid NIC number '{}'", index);
+        return;
+    };
+    println!("Leave a field empty to disable it; leave all empty to clear.");
+    let impairment = network::Impairment {
+        delay_ms: prompt("Delay (ms): ").parse().ok(),
+        jitter_ms: prompt("Jitter (ms): ").parse().ok(),
+        loss_pct: prompt("Loss (%): ").parse().ok(),
+        reorder_pct: prompt("Reorder (%): ").parse().ok(),
+    };
+    nic.impairment = if impairment.is_empty() { None } else { Some(impairment) };
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+
+    let vm = &config.vms[&name];
+    if let Some(pid) = vm_pid(&name) {
+        match network::apply_impairments(config, vm, pid) {
+            Ok(_) => println!("Impairments updated on running VM '{}'.", name),
+            Err(e) => error!("Saved, but failed to apply now: {}", e),
+        }
+    } else {
+        println!("Saved; impairments apply when '{}' starts.", name);
+    }
+}
+
+fn set_nic_model(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "change a NIC of").map(|vm| vm.name.clone()) else { return };
+    let index = prompt_or("NIC number", "0");
+    let Some(vm) = config.vms.get_mut(&name) else { return };
+    let Some(nic) = index.parse::<usize>().ok().and_then(|i| vm.nics.get_mut(i)) else {
+        error!("Invalid NIC number '{}'", index);
+        return;
+    };
+    let current = if nic.virtio.is_some() { "virtio" } else { "e1000" };
+    nic.virtio = match prompt_or("Model (e1000 or virtio; virtio needs guest drivers)", current).as_str() {
+        "e1000" => None,
+        "virtio" => {
+            let vhost = prompt_or("Use vhost-net? (y/n)", "y") == "y";
+            let queues = prompt("Queue pairs (leave empty for one per vCPU): ");
+            let queues = match queues.as_str() {
+                "" => None,
+                q => match q.parse::<u32>() {
+                    Ok(q) if q > 0 => Some(q),
+                    _ => {
+                        error!("Invalid queue count '{}'", q);
+                        return;
+                    }
+                },
+            };
+            Some(network::VirtioNet { vhost, queues })
+        }
+        other => {
+            error!("Unknown NIC model '{}'", other);
+            return;
+        }
+    };
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("NIC model updated; it takes effect on the next start of '{}'.", name);
+}
+
+fn edit_firewall(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "manage firewall rules for").map(|vm| vm.name.clone()) else { return };
+    let action = prompt_or("Add or remove a rule? (add/remove)", "add");
+    let Some(vm) = config.vms.get_mut(&name) else { return };
+    if action == "remove" {
+        let port = prompt("Port: ");
+        let before = vm.firewall.len();
+        vm.firewall.retain(|r| r.port.to_string() != port);
+        if vm.firewall.len() == before {
+            error!("No rule for port {}", port);
+            return;
+        }
+    } else {
+        let Ok(port) = prompt("Port: ").parse::<u16>() else {
+            error!("Invalid port");
+            return;
+        };
+        let proto = prompt_or("Protocol (tcp/udp)", "tcp");
+        if proto != "tcp" && proto != "udp" {
+            error!("Invalid protocol '{}'", proto);
+            return;
+        }
+        let allow = match firewall::Exposure::parse(&prompt_or("Allow from: lan, localhost or a CIDR", "localhost")) {
+            Ok(allow) => allow,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        let nic = prompt("Bridged NIC number the service listens on (leave empty for a host port): ");
+        let nic = match nic.parse::<usize>() {
+            Ok(i) if i < vm.nics.len() => Some(i),
+            Ok(i) => {
+                error!("VM '{}' has no NIC {}", name, i);
+                return;
+            }
+            Err(_) => None,
+        };
+        vm.firewall.retain(|r| !(r.port == port && r.proto == proto && r.nic == nic));
+        vm.firewall.push(firewall::FirewallRule { port, proto, allow, nic });
+    }
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+
+    if vm_running(&name) {
+        if let Err(e) = firewall::apply(&config.vms[&name]) {
+            error!("Saved, but failed to apply now: {}", e);
+        }
+    } else {
+        println!("Saved; rules apply when '{}' starts.", name);
+    }
+}
+
+fn list_networks(config: &VMConfig) {
+    println!("\nManaged networks:");
+    for (name, def) in &config.networks {
+        let state = if def.is_up() { "up" } else { "down" };
+        let wg = match &def.wireguard {
+            Some(wg) => format!(", WireGuard {} with {} peer(s)", wg.interface, wg.peers.len()),
+            None => String::new(),
+        };
+        println!("- {} ({}): {}{}", name, state, def.describe(), wg);
+    }
+}
+
+fn select_network(config: &VMConfig) -> Option<String> {
+    list_networks(config);
+    let name = prompt("Network name: ");
+    if config.networks.contains_key(&name) {
+        Some(name)
+    } else {
+        error!("Network '{}' not found", name);
+        None
+    }
+}
+
+fn copy_guest_files(config: &VMConfig) {
+    println!("Use <vm>:/path for the guest side, e.g. web1:/etc/fstab");
+    let src = prompt("Source: ");
+    let dst = prompt("Destination: ");
+    let partition = || prompt("Partition number (leave empty to detect the root filesystem): ").parse().ok();
+    if let Err(e) = copy_files(config, &src, &dst, partition) {
+        error!("{}", e);
+    }
+}
+
+/// Copies between the host and a stopped VM's disk; exactly one of `src`
+/// and `dst` is `<vm>:/path`. `partition` is only asked once both are
+/// known to be usable.
+fn copy_files(config: &VMConfig, src: &str, dst: &str, partition: impl FnOnce() -> Option<usize>) -> Result<(), String> {
+    let (name, guest_path, host_path, direction) = match (guestdisk::parse_guest_path(src), guestdisk::parse_guest_path(dst)) {
+        (Some((name, path)), None) => (name, path, expand_path(dst), guestdisk::CopyDirection::FromGuest),
+        (None, Some((name, path))) => (name, path, expand_path(src), guestdisk::CopyDirection::IntoGuest),
+        _ => return Err("Exactly one of source and destination must be <vm>:/path".to_string()),
+    };
+    let vm = config.vms.get(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    if vm_running(name) {
+        return Err(format!("VM '{}' is running; stop it first.", name));
+    }
+    guestdisk::copy(vm, guest_path, &host_path, direction, partition()).map_err(|e| format!("Copy failed: {}", e))
+}
+
+fn adopt_vm(config: &mut VMConfig) {
+    let spec = prompt("PID or text from the QEMU command line (e.g. its -name): ");
+    let pid = match adopt::find_process(&spec) {
+        Ok(pid) => pid,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let name = optional("VM name (leave empty to use the process's -name): ");
+    let (vm, notes) = match adopt::inspect(pid, name.as_deref(), config) {
+        Ok(found) => found,
+        Err(e) => {
+            error!("Cannot adopt process {}: {}", pid, e);
+            return;
+        }
+    };
+    println!("Process {}: {} CPU, {} threads, {} RAM, disk {}", pid, vm.cpu, vm.threads, vm.memory, vm.disk);
+    for note in &notes {
+        println!("  note: {}", note);
+    }
+    if prompt_or("Adopt it?", "y") != "y" {
+        return;
+    }
+    match adopt_found(config, pid, vm) {
+        Ok(done) => println!("{}", done),
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Defines `vm` as found in QEMU process `pid` and ties it to that process.
+fn adopt_found(config: &mut VMConfig, pid: u32, mut vm: VMInfo) -> Result<String, String> {
+    adopt::record_pid(&vm.name, pid)?;
+    if let Err(e) = provenance::register(&mut vm, "adopted", false) {
+        error!("Failed to record image checksums: {}", e);
+    }
+    let done = if adopt::cmdline(pid).and_then(|args| adopt::qmp_socket(&args)).is_some() {
+        format!("VM '{}' adopted; SRQemu talks to it over its own QMP socket.", vm.name)
+    } else {
+        format!("VM '{}' adopted. It has no QMP socket; restart it from SRQemu to get QMP-based features.", vm.name)
+    };
+    config.vms.insert(vm.name.clone(), vm);
+    save_config(config).map_err(|e| e.to_string())?;
+    Ok(done)
+}
+
+fn import_vm(config: &mut VMConfig) {
+    let kind = prompt_or("Import from libvirt or virtualbox", "libvirt");
+    let imported = match kind.as_str() {
+        "libvirt" => {
+            let source = prompt("libvirt domain name or domain XML file: ");
+            let uri = if std::path::Path::new(&source).is_file() {
+                String::new()
+            } else {
+                prompt_or("libvirt connection", "qemu:///system")
+            };
+            import::libvirt_xml(&source, &uri).and_then(|xml| {
+                let name = optional("VM name (leave empty to keep the domain's name): ");
+                import::from_libvirt(&xml, name.as_deref(), config)
+            })
+        }
+        "virtualbox" => {
+            let path = PathBuf::from(expand_path(&prompt("Path to the machine's .vbox file: ")));
+            let name = optional("VM name (leave empty to keep the machine's name): ");
+            import::from_vbox(&path, name.as_deref(), config)
+        }
+        _ => Err(format!("unknown source '{}'", kind)),
+    };
+    let imported = match imported {
+        Ok(imported) => imported,
+        Err(e) => {
+            error!("Cannot import: {}", e);
+            return;
+        }
+    };
+    print_imported(&imported);
+    let mode = prompt_or("Disk: keep (use in place), link (symlink into the VM folder) or copy (convert to qcow2)", "copy");
+    match finish_import(config, imported, &kind, &mode) {
+        Ok(done) => println!("{}", done),
+        Err(e) => error!("{}", e),
+    }
+}
+
+fn print_imported(imported: &import::Imported) {
+    let vm = &imported.vm;
+    println!("{}: {} CPU, {} threads, {} RAM, disk {}, {} NIC(s)", vm.name, vm.cpu, vm.threads, vm.memory, vm.disk, vm.nics.len());
+    for note in &imported.notes {
+        println!("  note: {}", note);
+    }
+}
+
+/// Brings in the disk of a VM read from `kind` the way `mode` says and
+/// defines it.
+fn finish_import(config: &mut VMConfig, mut imported: import::Imported, kind: &str, mode: &str) -> Result<String, String> {
+    let mode = import::DiskMode::parse(mode)?;
+    import::adopt_disk(&mut imported.vm, mode).map_err(|e| format!("Failed to import the disk: {}", e))?;
+    if let Err(e) = provenance::register(&mut imported.vm, &format!("imported from {}", kind), false) {
+        error!("Failed to record image checksums: {}", e);
+    }
+    let done = format!("VM '{}' imported. Shut the original machine down before starting it here.", imported.vm.name);
+    config.vms.insert(imported.vm.name.clone(), imported.vm);
+    save_config(config).map_err(|e| e.to_string())?;
+    Ok(done)
+}
+
+fn print_images() {
+    for image in images::list() {
+        let alias = image.alias.as_ref().map_or(String::new(), |alias| format!(" as '{}'", alias));
+        println!("- {}{} ({})\n    from {}\n    sha256 {}", image.file, alias, image.trust.describe(), image.url, image.sha256);
+    }
+}
+
+fn images_menu(config: &mut VMConfig) {
+    output::heading("Images and recipes");
+    println!("1. List downloaded images");
+    println!("2. Download image");
+    println!("3. Add trusted signing key");
+    println!("4. Require signed images (currently {})", if config.settings.require_signed_images { "on" } else { "off" });
+    println!("5. Download rate limit (currently {})", config.settings.download_rate_limit.as_deref().unwrap_or("none"));
+    println!("6. Create VM from recipe");
+    println!("7. Export VM as recipe");
+    println!("8. Back");
+
+    match prompt("\nSelect an option: ").as_str() {
+        "1" => {
+            println!("\nDownloaded images:");
+            print_images();
+        }
+        "2" => {
+            let names: Vec<&str> = images::CATALOG.iter().map(|k| k.name).collect();
+            let url = prompt(&format!("Image URL, or one of {}: ", names.join(", ")));
+            if images::known(&url).is_some() {
+                match images::pull(&url, &config.settings) {
+                    Ok(image) => println!("Saved {} as '{}' ({}).", image.file, url, image.trust.describe()),
+                    Err(e) => error!("Download failed: {}", e),
+                }
+                return;
+            }
+            let mut urls = vec![url];
+            let mirrors = prompt("Mirror URLs of the same file, comma separated (leave empty for none): ");
+            urls.extend(mirrors.split(',').map(str::trim).filter(|m| !m.is_empty()).map(str::to_string));
+            let checksums = optional("Checksum file URL, e.g. .../SHA256SUMS (leave empty for none): ").map(|url| {
+                images::ChecksumSource {
+                    url,
+                    signature_url: optional("Detached signature URL, e.g. .../SHA256SUMS.gpg (leave empty if clearsigned): "),
+                }
+            });
+            match images::fetch(&urls, checksums.as_ref(), &config.settings) {
+                Ok(image) => println!("Saved {} ({}).", image.file, image.trust.describe()),
+                Err(e) => error!("Download failed: {}", e),
+            }
+        }
+        "3" => {
+            let key = PathBuf::from(expand_path(&prompt("Public key file: ")));
+            match images::trust_key(&key) {
+                Ok(path) => println!("Key stored as {}.", path.display()),
+                Err(e) => error!("Failed to add key: {}", e),
+            }
+        }
+        "4" => {
+            config.settings.require_signed_images = !config.settings.require_signed_images;
+            if let Err(e) = save_config(config) {
+                error!("{}", e);
+                return;
+            }
+            println!("Signed images are now {}.", if config.settings.require_signed_images { "required" } else { "optional" });
+        }
+        "5" => {
+            let limit = prompt_or("Bytes per second, e.g. 500K or 2M (or 'none')", config.settings.download_rate_limit.as_deref().unwrap_or("none"));
+            let valid = limit.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G']).parse::<u64>().is_ok();
+            config.settings.download_rate_limit = match limit.as_str() {
+                "none" => None,
+                _ if valid => Some(limit),
+                _ => {
+                    error!("Invalid rate '{}'", limit);
+                    return;
+                }
+            };
+            if let Err(e) = save_config(config) {
+                error!("{}", e);
+            }
+        }
+        "6" => {
+            let source = prompt("Recipe file or URL: ");
+            let recipe = match recipe::load(&source) {
+                Ok(recipe) => recipe,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            let name = if recipe.name.is_empty() { prompt("VM name: ") } else { prompt_or("VM name", &recipe.name) };
+            if let Err(e) = create_from_recipe(config, &recipe, &name, &source) {
+                error!("Failed to create '{}': {}", name, e);
+            }
+        }
+        "7" => {
+            let Some(vm) = select_vm(config, "export") else { return };
+            // Via a Value so tables are written after plain keys.
+            let text = recipe::export(vm)
+                .and_then(|r| toml::Value::try_from(&r).and_then(|v| toml::to_string(&v)).map_err(|e| e.to_string()));
+            let text = match text {
+                Ok(text) => text,
+                Err(e) => {
+                    error!("Cannot export '{}': {}", vm.name, e);
+                    return;
+                }
+            };
+            let out = expand_path(&prompt_or("Recipe file", &format!("{}.toml", vm.name)));
+            match fs::write(&out, text) {
+                Ok(()) => println!("Recipe written to {}.", out),
+                Err(e) => error!("Cannot write {}: {}", out, e),
+            }
+        }
+        "8" => {}
+        _ => println!("{}", t("invalid-choice", &[])),
+    }
+}
+
+fn sync_guest_time(vm: &VMInfo) -> Result<String, String> {
+    if !vm.guest_agent {
+        return Err(format!("VM '{}' has no guest agent channel; enable it under time synchronization.", vm.name));
+    }
+    if !vm_running(&vm.name) {
+        return Err(format!("VM '{}' is not running.", vm.name));
+    }
+    agent::sync_time(&vm.name).map_err(|e| format!("Failed to sync time: {}", e))?;
+    Ok(format!("Guest clock of '{}' set to host time.", vm.name))
+}
+
+/// Defines VM `name` from a recipe loaded from `source`.
+fn create_from_recipe(config: &mut VMConfig, recipe: &recipe::Recipe, name: &str, source: &str) -> Result<VMInfo, String> {
+    let vm = recipe::instantiate(recipe, name, source, config)?;
+    config.vms.insert(name.to_string(), vm.clone());
+    save_config(config).map_err(|e| e.to_string())?;
+    println!("VM '{}' created from {}.", name, source);
+    Ok(vm)
+}
+
+fn vm_settings_menu(config: &mut VMConfig) {
+    output::heading("VM settings");
+    println!("1. Display resolution");
+    println!("2. Keyboard layout and VNC console");
+    println!("3. USB controller");
+    println!("4. Time synchronization");
+    println!("5. Sync guest clock now");
+    println!("6. Install host sleep hook (pause VMs on suspend)");
+    println!("7. Remove host sleep hook");
+    println!("8. Scheduled guest commands");
+    println!("9. Tags, color and icon");
+    println!("10. Update guest OS packages");
+    println!("11. Notifications");
+    println!("12. Autostart");
+    println!("13. Host power profile while running");
+    println!("14. Memory pressure policy");
+    println!("15. Memory merging (KSM)");
+    println!("16. Sandbox and privileges");
+    println!("17. Configuration encryption");
+    println!("18. Firmware (BIOS or UEFI)");
+    println!("19. Run profile (performance, battery)");
+    println!("20. Back");
+
+    match prompt("\nSelect an option: ").as_str() {
+        "1" => set_display(config),
+        "2" => set_console(config),
+        "3" => set_usb(config),
+        "4" => set_time_policy(config),
+        "5" => {
+            let Some(vm) = select_vm(config, "sync the clock of") else { return };
+            match sync_guest_time(vm) {
+                Ok(done) => println!("{}", done),
+                Err(e) => error!("{}", e),
+            }
+        }
+        "6" => {
+            let user = std::env::var("SUDO_USER").or_else(|_| std::env::var("USER")).unwrap_or_default();
+            let user = prompt_or("Run the hook as user", &user);
+            let home = prompt_or("That user's home", &home::home_dir().map(|h| h.display().to_string()).unwrap_or_default());
+            match hostsleep::install(&user, &home) {
+                Ok(()) => println!("Running VMs will now be paused across host suspend and resumed on wake."),
+                Err(e) => error!("{}", e),
+            }
+        }
+        "7" => match hostsleep::uninstall() {
+            Ok(()) => println!("Host sleep hook removed."),
+            Err(e) => error!("{}", e),
+        },
+        "8" => edit_guest_cron(config),
+        "9" => {
+            let Some(name) = select_vm(config, "tag").map(|vm| vm.name.clone()) else { return };
+            let Some(vm) = config.vms.get_mut(&name) else { return };
+            let tags = prompt_or("Tags, comma separated (or 'none')", &if vm.tags.is_empty() { "none".to_string() } else { vm.tags.join(",") });
+            vm.tags = tags.split(',').map(str::trim).filter(|t| !t.is_empty() && *t != "none").map(str::to_string).collect();
+            let names: Vec<&str> = label::COLORS.iter().map(|(name, _)| *name).collect();
+            let color = prompt_or(&format!("Color ({}, #rrggbb or none)", names.join(", ")), vm.color.as_deref().unwrap_or("none"));
+            let icon = prompt_or("Icon name or file (or none)", vm.icon.as_deref().unwrap_or("none"));
+            if let Err(e) = set_label(vm, Some(&color), Some(&icon)) {
+                error!("{}", e);
+                return;
+            }
+            if let Err(e) = save_config(config) {
+                error!("{}", e);
+            }
+        }
+        "10" => update_guests(config),
+        "11" => edit_notifications(config),
+        "12" => edit_autostart(config),
+        "13" => set_host_power(config),
+        "14" => edit_memory_pressure(config),
+        "15" => ksm_menu(config),
+        "16" => set_hardening(config),
+        "17" => toggle_encryption(config),
+        "18" => set_firmware(config),
+        "19" => {
+            println!("Run profiles: {}", runprofile::names(&config.settings).join(", "));
+            let current = config.settings.run_profile.clone().unwrap_or_else(|| "none".to_string());
+            set_run_profile(config, &prompt_or("Active profile, or none", &current));
+        }
+        "20" => {}
+        _ => println!("{}", t("invalid-choice", &[])),
+    }
+}
+
+fn update_guests(config: &VMConfig) {
+    list_defined_vms(config);
+    let spec = prompt("VM name, tag or 'all' to update: ");
+    if let Err(e) = run_updates(config, &spec) {
+        error!("{}", e);
+    }
+}
+
+/// Updates the VMs `spec` names and prints a summary table; fails when any
+/// of them failed.
+fn run_updates(config: &VMConfig, spec: &str) -> Result<(), String> {
+    let vms = update::targets(config, spec);
+    if vms.is_empty() {
+        return Err(format!("No VM or tag '{}'", spec));
+    }
+    println!("Updating {} VM(s); this can take a while...", vms.len());
+    let reports = update::update(&vms);
+    if !output::plain() {
+        println!("\n{:<20} {:<10} {:>8}  details", "VM", "result", "time");
+    }
+    for report in &reports {
+        if !matches!(report.outcome, update::Outcome::Skipped(_)) {
+            let ok = matches!(report.outcome, update::Outcome::Updated);
+            notify::send(&config.settings.notifications, notify::Event::UpdateFinished { vm: &report.vm, ok });
+        }
+        let (result, details) = match &report.outcome {
+            update::Outcome::Updated => ("updated", String::new()),
+            update::Outcome::Failed(e) => ("FAILED", e.clone()),
+            update::Outcome::Skipped(why) => ("skipped", why.clone()),
+        };
+        let log = report.log.as_ref().map(|l| format!(" (log: {})", l)).unwrap_or_default();
+        if output::plain() {
+            println!("{}", output::line(&report.vm, &[("result", result.to_string()), ("time", format!("{}s", report.elapsed.as_secs())), ("details", format!("{}{}", details, log).trim().to_string())]));
+        } else {
+            println!("{:<20} {:<10} {:>7}s  {}{}", report.vm, result, report.elapsed.as_secs(), details, log);
+        }
+    }
+    let count = |f: fn(&update::Outcome) -> bool| reports.iter().filter(|r| f(&r.outcome)).count();
+    let failed = count(|o| matches!(o, update::Outcome::Failed(_)));
+    println!(
+        "{} updated, {} failed, {} skipped.",
+        count(|o| matches!(o, update::Outcome::Updated)),
+        failed,
+        count(|o| matches!(o, update::Outcome::Skipped(_)))
+    );
+    if failed > 0 { Err(format!("{} VM(s) failed to update", failed)) } else { Ok(()) }
+}
+
+/// Switches the host-wide run profile. Host power and ballooning follow
+/// right away; hugepages and IOThreads on the next start of each VM.
+fn set_run_profile(config: &mut VMConfig, name: &str) -> bool {
+    if name != "none" && runprofile::get(&config.settings, name).is_none() {
+        error!("Unknown run profile '{}'; known: {}", name, runprofile::names(&config.settings).join(", "));
+        return false;
+    }
+    config.settings.run_profile = (name != "none").then(|| name.to_string());
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return false;
+    }
+    hostpower::update(config, None);
+    println!("Run profile is now '{}'; hugepages and IOThreads change on the next start of each VM.", name);
+    true
+}
+
+fn set_firmware(config: &mut VMConfig) {
+    let Some(name) = select_stopped_vm(config, "change the firmware of").map(|vm| vm.name.clone()) else { return };
+    let Some(vm) = config.vms.get_mut(&name) else { return };
+    let current = if vm.firmware == Some(firmware::Firmware::Uefi) { "uefi" } else { "bios" };
+    let Some(choice) = firmware::Firmware::parse(&prompt_or("Firmware (bios or uefi)", current)) else {
+        error!("Unknown firmware; use bios or uefi.");
+        return;
+    };
+    vm.firmware = (choice == firmware::Firmware::Uefi).then_some(choice);
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("Firmware set; an installed guest usually only boots with the firmware it was installed under.");
+}
+
+fn toggle_encryption(config: &VMConfig) {
+    if config::is_encrypted() {
+        if prompt_or("The configuration is encrypted. Store it in plain text again? (y/n)", "n") != "y" {
+            return;
+        }
+        match config::set_encrypted(config, false) {
+            Ok(_) => {
+                vault::keyring_clear();
+                println!("Configuration decrypted.");
+            }
+            Err(e) => error!("Failed to decrypt the configuration: {}", e),
+        }
+        return;
+    }
+    println!("Config checkpoints in history/ are encrypted along with it.");
+    let passphrase = prompt_secret("New passphrase: ");
+    if passphrase.is_empty() || passphrase != prompt_secret("Repeat it: ") {
+        error!("Passphrases are empty or differ.");
+        return;
+    }
+    if prompt_or("Remember it in the desktop keyring? (y/n)", "y") == "y"
+        && let Err(e) = vault::keyring_store(&passphrase)
+    {
+        error!("{}; you will be asked for it on every run.", e);
+    }
+    vault::set_passphrase(passphrase);
+    match config::set_encrypted(config, true) {
+        Ok(checkpoints) => {
+            if checkpoints > 0 {
+                println!("Encrypted {} config checkpoint(s) in history/.", checkpoints);
+            }
+            println!("Configuration encrypted. Timers and hooks need the keyring or SRQEMU_PASSPHRASE to read it.");
+        }
+        Err(e) => error!("Failed to encrypt the configuration: {}", e),
+    }
+}
+
+fn set_hardening(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "harden").map(|vm| vm.name.clone()) else { return };
+    let Some(vm) = config.vms.get_mut(&name) else { return };
+    let current = vm.hardening.clone().unwrap_or_default();
+    let seccomp = prompt_or("Enable the seccomp sandbox? (y/n)", if current.seccomp { "y" } else { "n" }) == "y";
+    let runas = prompt_or("Run QEMU as user, root only (or 'none')", current.runas.as_deref().unwrap_or("none"));
+    let chroot = prompt_or("Chroot directory, root only (or 'none')", current.chroot.as_deref().unwrap_or("none"));
+    let apparmor = prompt_or("Confine with an AppArmor profile, root only? (y/n)", if current.apparmor { "y" } else { "n" }) == "y";
+    if apparmor && !apparmor::available() {
+        error!("AppArmor is not enabled on this host.");
+        return;
+    }
+    let hardening = sandbox::Hardening {
+        seccomp,
+        runas: (runas != "none").then_some(runas),
+        chroot: (chroot != "none").then_some(chroot),
+        apparmor,
+    };
+    vm.hardening = if hardening.is_empty() { None } else { Some(hardening) };
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("Hardening for '{}' saved; it applies on the next start.", name);
+}
+
+fn ksm_menu(config: &mut VMConfig) {
+    match ksm::status() {
+        Ok(status) => {
+            println!(
+                "ksmd is {} (scanning {} pages every {} ms); merged pages save {}.",
+                if status.running { "running" } else { "stopped" },
+                status.pages_to_scan,
+                status.sleep_millisecs,
+                guestdisk::human_size(status.saved)
+            );
+            if let Some(profit) = status.profit {
+                println!("Net of ksmd's own overhead: {}.", guestdisk::human_size(profit));
+            }
+        }
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    }
+    println!("1. Set a VM's merging hint");
+    println!("2. Start ksmd");
+    println!("3. Stop ksmd");
+    println!("4. Tune ksmd scan rate");
+    let choice = prompt("\nSelect an option: ");
+    match choice.as_str() {
+        "1" => {
+            let Some(name) = select_vm(config, "set memory merging for").map(|vm| vm.name.clone()) else { return };
+            let Some(vm) = config.vms.get_mut(&name) else { return };
+            vm.mem_merge = prompt_switch("Allow KSM to merge this VM's memory?", vm.mem_merge);
+            if let Err(e) = save_config(config) {
+                error!("{}", e);
+                return;
+            }
+            println!("Takes effect on the VM's next start.");
+        }
+        "2" | "3" => match ksm::set_running(choice == "2") {
+            Ok(()) => println!("Done."),
+            Err(e) => error!("{}", e),
+        },
+        "4" => {
+            let pages = prompt_or("Pages to scan per wake-up", "100").parse::<u64>();
+            let sleep = prompt_or("Milliseconds between wake-ups", "20").parse::<u64>();
+            let (Ok(pages), Ok(sleep)) = (pages, sleep) else {
+                error!("Invalid number.");
+                return;
+            };
+            if let Err(e) = ksm::tune(pages, sleep) {
+                error!("{}", e);
+            }
+        }
+        _ => println!("{}", t("invalid-choice", &[])),
+    }
+}
+
+fn edit_memory_pressure(config: &mut VMConfig) {
+    match pressure::read_psi() {
+        Ok(psi) => println!("Current memory pressure: {:.1}%", psi),
+        Err(e) => error!("{}", e),
+    }
+    for (name, action) in config.vms.values().filter_map(|vm| vm.memory_pressure.map(|a| (&vm.name, a))) {
+        println!("  {}: {:?}", name, action);
+    }
+    println!("1. Set a VM's action (balloon, pause or none)");
+    println!("2. Thresholds");
+    println!("3. Install and start the watcher service");
+    println!("4. Remove the watcher service");
+    match prompt("\nSelect an option: ").as_str() {
+        "1" => {
+            let Some(name) = select_vm(config, "set the memory pressure action for").map(|vm| vm.name.clone()) else { return };
+            let action = prompt_or("Action (balloon/pause/none)", "none");
+            let action = match action.as_str() {
+                "none" => None,
+                other => match pressure::PressureAction::parse(other) {
+                    Some(action) => Some(action),
+                    None => {
+                        error!("Unknown action '{}'", other);
+                        return;
+                    }
+                },
+            };
+            if let Some(vm) = config.vms.get_mut(&name) {
+                vm.memory_pressure = action;
+            }
+            if let Err(e) = save_config(config) {
+                error!("{}", e);
+                return;
+            }
+            if action == Some(pressure::PressureAction::Balloon) {
+                println!("The balloon device is added on the VM's next start.");
+            }
+        }
+        "2" => {
+            let settings = &config.settings;
+            let threshold = prompt_or("Pressure threshold (% of time stalled)", &settings.pressure_threshold.to_string()).parse::<f64>();
+            let interval = prompt_or("Seconds between checks", &settings.pressure_interval.to_string()).parse::<u64>();
+            let balloon = prompt_or("Shrink ballooned VMs to % of their memory", &settings.balloon_pct.to_string()).parse::<u64>();
+            let (Ok(threshold), Ok(interval), Ok(balloon)) = (threshold, interval, balloon) else {
+                error!("Invalid number.");
+                return;
+            };
+            config.settings.pressure_threshold = threshold;
+            config.settings.pressure_interval = interval;
+            config.settings.balloon_pct = balloon;
+            if let Err(e) = save_config(config) {
+                error!("{}", e);
+            }
+        }
+        "3" => match pressure::install_service() {
+            Ok(()) => println!("Memory pressure watcher running."),
+            Err(e) => error!("Failed to install the watcher: {}", e),
+        },
+        "4" => match pressure::uninstall_service() {
+            Ok(()) => println!("Memory pressure watcher removed."),
+            Err(e) => error!("{}", e),
+        },
+        _ => println!("{}", t("invalid-choice", &[])),
+    }
+}
+
+fn set_host_power(config: &mut VMConfig) {
+    list_defined_vms(config);
+    let spec = prompt("VM name, tag or 'all': ");
+    let names: Vec<String> = update::targets(config, &spec).iter().map(|vm| vm.name.clone()).collect();
+    if names.is_empty() {
+        error!("No VM or tag '{}'.", spec);
+        return;
+    }
+    let profile = optional("Profile while running, e.g. performance (leave empty to leave the host alone): ");
+    for name in &names {
+        if let Some(vm) = config.vms.get_mut(name) {
+            vm.host_power = profile.clone();
+        }
+    }
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("Updated {} VM(s).", names.len());
+    hostpower::update(config, None);
+}
+
+/// Marks or unmarks a VM for autostart, saves, and installs or removes its
+/// systemd unit to match.
+fn set_autostart(config: &mut VMConfig, name: &str, mark: Option<autostart::Autostart>) -> bool {
+    let Some(vm) = config.vms.get_mut(name) else { return false };
+    vm.autostart = mark;
+    let vm = vm.clone();
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return false;
+    }
+    let unit = match &vm.autostart {
+        Some(_) => autostart::install_unit(&vm, &config.settings),
+        None => autostart::uninstall_unit(name),
+    };
+    if let Err(e) = unit {
+        error!("Failed to update the systemd unit of '{}': {}", name, e);
+        return false;
+    }
+    match vm.autostart {
+        Some(_) if autostart::lingering() => println!("VM '{}' will start at boot.", name),
+        Some(_) => println!("VM '{}' will start when you log in; `loginctl enable-linger` makes that boot.", name),
+        None => println!("VM '{}' no longer starts by itself.", name),
+    }
+    true
+}
+
+fn edit_autostart(config: &mut VMConfig) {
+    let settings = &config.settings;
+    println!(
+        "Autostart launches VMs at least {}s apart, at most {} booting at once, each counted as booting for up to {}s.",
+        settings.autostart_stagger,
+        if settings.autostart_max_parallel == 0 { "any number".to_string() } else { settings.autostart_max_parallel.to_string() },
+        settings.autostart_boot_secs
+    );
+    if prompt_or("Change these limits? (y/n)", "n") == "y" {
+        let parse = |msg: &str, current: u64| prompt_or(msg, &current.to_string()).parse::<u64>();
+        let stagger = parse("Seconds between launches", settings.autostart_stagger);
+        let parallel = parse("VMs booting at once (0 = no limit)", settings.autostart_max_parallel as u64);
+        let boot = parse("Seconds a VM counts as booting", settings.autostart_boot_secs);
+        let (Ok(stagger), Ok(parallel), Ok(boot)) = (stagger, parallel, boot) else {
+            error!("Expected whole numbers.");
+            return;
+        };
+        config.settings.autostart_stagger = stagger;
+        config.settings.autostart_max_parallel = parallel as usize;
+        config.settings.autostart_boot_secs = boot;
+    }
+
+    if let Some(vm) = select_vm(config, "configure autostart for") {
+        let name = vm.name.clone();
+        let enabled = prompt_or("Start this VM with your session? (y/n)", if vm.autostart.is_some() { "y" } else { "n" });
+        let mark = if enabled == "y" {
+            let current = vm.autostart.as_ref().map_or(0, |a| a.delay);
+            match prompt_or("Delay in seconds", &current.to_string()).parse() {
+                Ok(delay) => Some(autostart::Autostart { delay }),
+                Err(_) => {
+                    error!("Expected a whole number of seconds.");
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+        let restart = prompt_or(
+            "Restart it when it crashes? Only the daemon does this. (y/n)",
+            if vm.restart_on_crash { "y" } else { "n" },
+        ) == "y";
+        if let Some(vm) = config.vms.get_mut(&name) {
+            vm.restart_on_crash = restart;
+        }
+        set_autostart(config, &name, mark);
+        return;
+    }
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("Autostart settings saved.");
+}
+
+fn edit_notifications(config: &mut VMConfig) {
+    let current = &config.settings.notifications;
+    for hook in &current.webhooks {
+        println!("  webhook ({}): {}", hook.flavor, hook.url);
+    }
+    let desktop = prompt_or("Desktop notifications? (y/n)", if current.desktop { "y" } else { "n" }) == "y";
+    let events = prompt_or(
+        &format!("Events to send, comma separated ({}) or 'all'", notify::EVENT_KINDS.join(", ")),
+        &if current.events.is_empty() { "all".to_string() } else { current.events.join(",") },
+    );
+    let events: Vec<String> = events.split(',').map(str::trim).filter(|e| !e.is_empty() && *e != "all").map(str::to_string).collect();
+    if let Some(unknown) = events.iter().find(|e| !notify::EVENT_KINDS.contains(&e.as_str())) {
+        error!("Unknown event '{}'", unknown);
+        return;
+    }
+    let mut webhooks = current.webhooks.clone();
+    if let Some(url) = optional("Add webhook URL (leave empty to keep the list; 'clear' removes all): ") {
+        if url == "clear" {
+            webhooks.clear();
+        } else {
+            let flavor = prompt_or(&format!("Webhook type ({})", notify::FLAVORS.join(", ")), "generic");
+            if !notify::FLAVORS.contains(&flavor.as_str()) {
+                error!("Unknown webhook type '{}'", flavor);
+                return;
+            }
+            webhooks.push(notify::Webhook { url, flavor });
+        }
+    }
+    config.settings.notifications = notify::Notifications { desktop, webhooks, events };
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("Notification settings saved.");
+}
+
+fn edit_guest_cron(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "schedule commands in").map(|vm| vm.name.clone()) else { return };
+    let Some(vm) = config.vms.get(&name) else { return };
+    for job in &vm.guest_cron {
+        println!("  {}: [{}] {}", job.id, job.schedule, job.command);
+    }
+    if vm.guest_cron.is_empty() {
+        println!("  No scheduled commands.");
+    }
+    let done = match prompt_or("Add or remove a command? (add/remove)", "add").as_str() {
+        "remove" => {
+            let id = prompt("Command id: ");
+            guestcron::remove(config, &name, &id)
+        }
+        _ if !vm.guest_agent => Err(format!("VM '{}' has no guest agent channel; enable it under time synchronization", name)),
+        _ => {
+            let id = prompt("Short id, e.g. updates: ");
+            let schedule = prompt_or("When (systemd calendar, e.g. daily or Mon..Fri 02:30)", "daily");
+            let command = prompt("Command to run in the guest: ");
+            guestcron::add(config, &name, Some(id), &schedule, &command)
+        }
+    };
+    match done {
+        Ok(done) => println!("{}", done),
+        Err(e) => error!("{}", e),
+    }
+}
+
+fn set_display(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "configure the display of").map(|vm| vm.name.clone()) else { return };
+    let Some(vm) = config.vms.get_mut(&name) else { return };
+    let current = vm.display.clone().unwrap_or_default();
+
+    let model = prompt_or(
+        &format!("Graphics card ({}, or 'default')", display::MODELS.join(", ")),
+        current.model.as_deref().unwrap_or("default"),
+    );
+    let model = match model.as_str() {
+        "default" => None,
+        m if display::MODELS.contains(&m) => Some(model),
+        _ => {
+            error!("Unknown graphics card '{}'", model);
+            return;
+        }
+    };
+    let resolution = prompt_or("Resolution, e.g. 2560x1440 (or 'none')", current.resolution.as_deref().unwrap_or("none"));
+    let resolution = match resolution.as_str() {
+        "none" => None,
+        spec => match display::parse_resolution(spec) {
+            Ok((w, h)) => Some(format!("{}x{}", w, h)),
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        },
+    };
+    if model.as_deref() == Some("std") && resolution.is_some() {
+        println!("Note: guests on the std card may only offer their own VESA modes.");
+    }
+    // The card itself is fixed when QEMU creates it; only its mode
+    // follows a new size.
+    let live_size = resolution.as_deref().and_then(|r| display::parse_resolution(r).ok()).filter(|_| model == current.model);
+    let spec = display::DisplaySpec { model, resolution, ..current };
+    vm.display = if spec.is_empty() { None } else { Some(spec) };
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+
+    if vm_running(&name) {
+        match live_size.map(|(w, h)| display::resize_running(&config.vms[&name], w, h)) {
+            Some(Ok(())) => println!("Resized '{}' now and on every start.", name),
+            Some(Err(e)) => println!("Saved for the next start of '{}'; not resized now: {}", name, e),
+            None => println!("Saved; the new display settings apply on the next start of '{}'.", name),
+        }
+    } else {
+        println!("Display settings for '{}' saved.", name);
+    }
+}
+
+fn set_console(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "configure the console of").map(|vm| vm.name.clone()) else { return };
+    let Some(vm) = config.vms.get_mut(&name) else { return };
+    let current = vm.display.clone().unwrap_or_default();
+
+    let vnc = prompt_or("VNC display, e.g. :1 (or 'none')", current.vnc.as_deref().unwrap_or("none"));
+    let vnc = if vnc == "none" { None } else { Some(vnc) };
+    let listen = prompt_or(
+        "Listen address; anything but 127.0.0.1 exposes the console to the network",
+        current.listen.as_deref().unwrap_or("127.0.0.1"),
+    );
+    let listen = if listen == "127.0.0.1" { None } else { Some(listen) };
+    let keymap = prompt_or("Keyboard layout, e.g. de, fr, en-gb (or 'none' for en-us)", current.keymap.as_deref().unwrap_or("none"));
+    let keymap = match keymap.as_str() {
+        "none" => None,
+        k if display::KEYMAPS.contains(&k) => Some(keymap),
+        _ => {
+            error!("Unknown keyboard layout '{}'. Known layouts: {}", keymap, display::KEYMAPS.join(" "));
+            return;
+        }
+    };
+    let delay = current.vnc_key_delay_ms.map(|d| d.to_string()).unwrap_or_else(|| "none".to_string());
+    let delay = prompt_or("Delay between replayed VNC key events in ms (or 'none')", &delay);
+    let vnc_key_delay_ms = match delay.as_str() {
+        "none" => None,
+        d => match d.parse() {
+            Ok(ms) => Some(ms),
+            Err(_) => {
+                error!("Invalid delay '{}'", d);
+                return;
+            }
+        },
+    };
+    if keymap.is_some() && vnc.is_none() {
+        println!("Note: the keyboard layout only affects VNC consoles.");
+    }
+    let spec = display::DisplaySpec { vnc, listen, keymap, vnc_key_delay_ms, ..current };
+    vm.display = if spec.is_empty() { None } else { Some(spec) };
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("Console settings for '{}' saved; they apply on the next start.", name);
+}
+
+fn set_usb(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "configure USB for").map(|vm| vm.name.clone()) else { return };
+    let Some(vm) = config.vms.get_mut(&name) else { return };
+    let model = prompt_or(
+        "USB controller: xhci (USB 3), ehci (USB 2), uhci (USB 1.1), none, or default",
+        vm.usb.as_deref().unwrap_or("default"),
+    );
+    vm.usb = match model.as_str() {
+        "default" => None,
+        m if usb::is_known(m) => Some(model),
+        _ => {
+            error!("Unknown USB controller '{}'", model);
+            return;
+        }
+    };
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("USB settings for '{}' saved; they apply on the next start.", name);
+}
+
+/// Reads `y`/`n`/`default` into an optional switch.
+fn prompt_switch(msg: &str, current: Option<bool>) -> Option<bool> {
+    let current = match current {
+        Some(true) => "y",
+        Some(false) => "n",
+        None => "default",
+    };
+    match prompt_or(&format!("{} (y/n/default)", msg), current).as_str() {
+        "y" => Some(true),
+        "n" => Some(false),
+        _ => None,
+    }
+}
+
+fn set_time_policy(config: &mut VMConfig) {
+    let Some(name) = select_vm(config, "configure time sync for").map(|vm| vm.name.clone()) else { return };
+    let Some(vm) = config.vms.get_mut(&name) else { return };
+    let current = vm.time.clone().unwrap_or_default();
+
+    let rtc_base = prompt_or("RTC base: utc, localtime or default", current.rtc_base.as_deref().unwrap_or("default"));
+    let rtc_base = match rtc_base.as_str() {
+        "utc" | "localtime" => Some(rtc_base),
+        _ => None,
+    };
+    let driftfix = prompt_switch("Replay missed RTC ticks (driftfix=slew)", Some(current.driftfix)) == Some(true);
+    let hpet = prompt_switch("HPET timer", current.hpet);
+    let kvmclock = prompt_switch("kvmclock", current.kvmclock);
+    vm.guest_agent = prompt_switch("Guest agent channel (needed for syncing the clock)", Some(vm.guest_agent)) == Some(true);
+    let sync_on_resume = prompt_switch("Sync guest clock via guest agent after resume", Some(current.sync_on_resume)) == Some(true);
+    if sync_on_resume && !vm.guest_agent {
+        vm.guest_agent = true;
+        println!("Enabled the guest agent channel; install qemu-guest-agent in the guest.");
+    }
+    let time = clock::TimeSpec { rtc_base, driftfix, hpet, kvmclock, sync_on_resume };
+    vm.time = if time.is_empty() { None } else { Some(time) };
+    if let Err(e) = save_config(config) {
+        error!("{}", e);
+        return;
+    }
+    println!("Time settings for '{}' saved; they apply on the next start.", name);
+}
+
+fn clone_vm(config: &mut VMConfig, source: &str, name: &str, thin: bool) -> Result<(), String> {
+    let mut partial = interrupt::Partial::new();
+    let (vm, source) = clone::clone(config, source, name, thin, &mut partial)?;
+    let forwards = vm.nics.iter().any(|nic| nic.backend.forwards().is_some_and(|f| !f.is_empty()));
+    partial.commit(|| {
+        config.vms.insert(source.name.clone(), source);
+        config.vms.insert(vm.name.clone(), vm);
+        save_config(config).inspect_err(|_| {
+            config.vms.remove(name);
+        })
+    })?;
+    println!("VM '{}' created as a {} clone.", name, if thin { "thin" } else { "full" });
+    if forwards {
+        println!("It forwards the same host ports as its source; change them before running both.");
+    }
+    Ok(())
+}
+
+/// Boots a throwaway copy of `base`, runs `script` in it with its output
+/// passed through, and stops it again. Unless `keep`, the copy is never
+/// saved and its files go afterwards; kept, it stays defined and stopped.
+/// Returns the script's exit code.
+fn run_ephemeral(config: &mut VMConfig, base: &str, script: &str, keep: bool, boot_timeout: std::time::Duration, timeout: std::time::Duration) -> Result<i64, String> {
+    if !cli_vm(config, base).guest_agent {
+        return Err(format!("VM '{}' has no guest agent channel, which running the script needs", base));
+    }
+    let name = format!("{}-run{}", base, std::process::id());
+    let mut partial = interrupt::Partial::new();
+    let vm = clone::ephemeral(config, base, &name, &mut partial)?;
+    config.vms.insert(name.clone(), vm.clone());
+    // Launched from here, not through the daemon, which only knows saved VMs.
+    start_vm_with(config, &vm, &cmdline::Boot::configured(&vm, true));
+    let result = match wait_for_pid(&name) {
+        None => Err(format!("the copy '{}' did not start", name)),
+        Some(_) => wait_for_agent(&name, boot_timeout).and_then(|()| {
+            agent::exec_streaming(&name, script, timeout, |data| {
+                let mut out = io::stdout().lock();
+                let _ = out.write_all(data);
+                let _ = out.flush();
+            })
+        }),
+    };
+    if vm_running(&name)
+        && let Err(e) = stop_vm_by_name(config, &name, !keep)
+    {
+        error!("Failed to stop the copy '{}': {}", name, e);
+    }
+    if keep {
+        partial.commit(|| save_config(config)).map_err(|e| e.to_string())?;
+        println!("Kept VM '{}'; `SRQemu delete {}` removes it.", name, name);
+    } else {
+        if let Err(e) = apparmor::remove(&name) {
+            warn!("Failed to remove the AppArmor profile of '{}': {}", name, e);
+        }
+        config.vms.remove(&name);
+    }
+    result
+}
+
+/// Waits until a freshly started guest's agent answers.
+fn wait_for_agent(name: &str, timeout: std::time::Duration) -> Result<(), String> {
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+        match agent::connect(name) {
+            Ok(_) => return Ok(()),
+            Err(e) if std::time::Instant::now() > deadline => return Err(format!("no guest agent within {}s: {}", timeout.as_secs(), e)),
+            Err(_) => std::thread::sleep(std::time::Duration::from_secs(2)),
+        }
+    }
+}
+
+/// Finds a running VM with a guest agent channel, exiting otherwise.
+fn cli_agent_vm<'a>(config: &'a VMConfig, name: &str) -> &'a VMInfo {
+    let vm = cli_vm(config, name);
+    if !vm.guest_agent {
+        error!("VM '{}' has no guest agent channel; enable it under VM settings and restart the VM.", name);
+        std::process::exit(1);
+    }
+    if !vm_running(name) {
+        error!("VM '{}' is not running", name);
+        std::process::exit(1);
+    }
+    vm
+}
+
+/// Finds a VM named on the command line, exiting if there is none.
+fn cli_vm<'a>(config: &'a VMConfig, name: &str) -> &'a VMInfo {
+    config.vms.get(name).unwrap_or_else(|| {
+        error!("{}", t("vm-not-found", &[("name", &name)]));
+        std::process::exit(1);
+    })
+}
+
+/// Like `cli_vm`, for offline disk operations.
+fn cli_stopped_vm<'a>(config: &'a VMConfig, name: &str) -> &'a VMInfo {
+    let vm = cli_vm(config, name);
+    if vm_running(name) {
+        error!("VM '{}' is running; stop it first.", name);
+        std::process::exit(1);
+    }
+    vm
+}
+
+/// Reports what the host offers VMs, then asks for the global settings
+/// and saves them.
+fn setup_wizard(config: &mut VMConfig) -> Result<(), String> {
+    output::heading("Host check");
+    for capability in setup::probe() {
+        println!("{} {}: {}", if capability.found { "[ok]" } else { "[missing]" }, capability.name, capability.detail);
+    }
+    output::heading("Settings");
+    let settings = &config.settings;
+    let answers = setup::Answers {
+        vm_dir: Some(prompt_or("Directory for VMs, images and trash", settings.vm_dir.as_deref().unwrap_or(setup::DEFAULT_VM_DIR))),
+        memory: Some(prompt_or("Memory of a new VM", &settings.default_memory)),
+        threads: Some(prompt_or("CPU threads of a new VM", &settings.default_threads)),
+        disk_size: Some(prompt_or("Disk size of a new VM", &settings.default_disk_size)),
+    };
+    setup::apply(config, answers)?;
+    save_config(config).map_err(|e| e.to_string())?;
+    set_vm_dir(config.settings.vm_dir.clone());
+    println!("Saved to {}; run `SRQemu setup` to change it.", config::active_path().display());
+    Ok(())
+}
+
+fn interactive(config: &mut VMConfig) {
+    loop {
+        output::title(&t("menu-title", &[]));
+        println!("{}", t("menu-create", &[]));
+        println!("{}", t("menu-start", &[]));
+        println!("{}", t("menu-stop", &[]));
+        println!("{}", t("menu-list", &[]));
+        println!("{}", t("menu-delete", &[]));
+        println!("{}", t("menu-restore", &[]));
+        println!("{}", t("menu-disk", &[]));
+        println!("{}", t("menu-network", &[]));
+        println!("{}", t("menu-settings", &[]));
+        println!("{}", t("menu-adopt", &[]));
+        println!("{}", t("menu-import", &[]));
+        println!("{}", t("menu-images", &[]));
+        println!("{}", t("menu-status", &[]));
+        println!("{}", t("menu-clone", &[]));
+        println!("{}", t("menu-exit", &[]));
+
+        match prompt("\nSelect an option: ").as_str() {
+            "1" => create_vm(config),
+            "2" => start_vm(config),
+            "3" => stop_vm(config),
+            "4" => list_defined_vms(config),
+            "5" => delete_vm(config),
+            "6" => restore_deleted_vm(config),
+            "7" => disk_tools_menu(config),
+            "8" => network_menu(config),
+            "9" => vm_settings_menu(config),
+            "10" => adopt_vm(config),
+            "11" => import_vm(config),
+            "12" => images_menu(config),
+            "13" => status::print(config, None, false),
+            "14" => {
+                let Some(source) = select_stopped_vm(config, "clone").map(|vm| vm.name.clone()) else { continue };
+                let name = prompt("Name of the clone: ");
+                let thin = prompt("Thin clone on a shared, frozen base instead of a full copy? (y/N): ").eq_ignore_ascii_case("y");
+                if let Err(e) = clone_vm(config, &source, &name, thin) {
+                    error!("Failed to clone '{}': {}", source, e);
+                }
+            }
+            "15" => break,
+            _ => println!("{}", t("invalid-choice", &[])),
+        }
+    }
+}
+
+fn main() {
+    #[cfg(unix)]
+    if let Some(program) = runner::mocked_program() {
+        std::process::exit(mockqemu::main(&program));
+    }
+    interrupt::install();
+    let cli = cli::Cli::parse();
+    logging::init(cli.verbose, cli.log_json);
+    output::init(cli.plain);
+    let mut config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    i18n::init(config.settings.language.as_deref());
+    // Scripts and other commands keep the defaults; the menu asks first.
+    if matches!(cli.command, None | Some(Command::Interactive)) && setup::first_run() && io::stdin().is_terminal() {
+        println!("Welcome to SRQemu. A few questions before the first VM; Enter keeps the suggestion.");
+        if let Err(e) = setup_wizard(&mut config) {
+            error!("Setup failed, keeping the defaults: {}", e);
+        }
+    }
+    set_vm_dir(config.settings.vm_dir.clone());
+    runner::set_policy(runner::Policy {
+        timeouts: config.settings.command_timeouts.clone(),
+        retries: config.settings.command_retries,
+    });
+    let vm_dir = PathBuf::from(get_vm_folder());
+    if let Err(source) = fs::create_dir_all(&vm_dir) {
+        error!("{}", error::Error::CreateDir { path: vm_dir, source });
+        std::process::exit(1);
+    }
+
+    // Hook entry points run unattended and skip the housekeeping below.
+    match &cli.command {
+        Some(Command::SleepHook { phase, .. }) => {
+            match phase.as_str() {
+                "pre" => hostsleep::pre(&config),
+                "post" => hostsleep::post(&config),
+                _ => error!("usage: SRQemu sleep-hook pre|post [action]"),
+            }
+            return;
+        }
+        Some(Command::GuestRun { vm, job }) => {
+            if let Err(e) = guestcron::run_job(&config, vm, job) {
+                error!("{}", e);
+                notify::send(&config.settings.notifications, notify::Event::JobFailed { vm, job, error: &e });
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    trash::purge_expired(config.settings.trash_days);
+    for name in config.vms.keys() {
+        if !vm_running(name) {
+            // Stopping through SRQemu removes the socket, so one left behind
+            // means QEMU died on its own.
+            if qmp::socket_path(name).exists() {
+                notify::send(&config.settings.notifications, notify::Event::Crashed { vm: name });
+            }
+            qmp::cleanup_runtime(name);
+        }
+    }
+    stats::observe(&config, vm_running);
+    hostpower::update(&config, None);
+
+    let json = cli.json;
+    match cli.command.unwrap_or(Command::Interactive) {
+        Command::Interactive => interactive(&mut config),
+        Command::Create {
+            name,
+            extends,
+            template,
+            memory,
+            disk_size,
+            disk,
+            nic_model,
+            threads,
+            iso,
+            cloud_image,
+            user_data,
+            forward,
+            uefi,
+            arch,
+            guest_agent,
+            recipe,
+            start,
+            headless,
+        } => {
+            let created = match recipe {
+                Some(source) => recipe::load(&source).and_then(|recipe| create_from_recipe(&mut config, &recipe, &name, &source)),
+                None => {
+                let bus_given = disk.as_deref().is_some_and(|d| d.contains("bus="));
+                let disk = match (disk, disk_size) {
+                    (Some(disk), _) => storage::DiskSpec::parse(&disk),
+                    (None, size) => size.as_deref().map(size::disk).transpose().map(|size| storage::DiskSpec { size, ..Default::default() }),
+                };
+                let mut disk = disk.unwrap_or_else(|e| {
+                    error!("{}", e);
+                    std::process::exit(1);
+                });
+                // Cloud images are Linux guests with the virtio drivers built in.
+                let linux_cloud = cloud_image.is_some() && template.is_none();
+                if linux_cloud && !bus_given {
+                    disk.device = Some(storage::DiskDevice { bus: storage::DiskBus::VirtioBlk, iothread: true });
+                }
+                let nic_model = nic_model.or_else(|| linux_cloud.then(|| "virtio".to_string()));
+                let forwards = match forward.iter().map(|f| network::HostForward::parse(f)).collect() {
+                    Ok(forwards) => forwards,
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                };
+                let firmware = uefi.then_some(firmware::Firmware::Uefi);
+                let arch = arch.filter(|a| *a != arch::Arch::X86_64);
+                let template = template.as_deref().map(template::load).transpose().unwrap_or_else(|e| {
+                    error!("{}", e);
+                    std::process::exit(1);
+                });
+                let cloud_init = match (&cloud_image, user_data) {
+                    (None, _) => None,
+                    (Some(_), Some(path)) => match fs::read_to_string(expand_path(&path)) {
+                        Ok(user_data) => Some(cloudinit::CloudInit { user_data, meta_data: None }),
+                        Err(e) => {
+                            error!("Cannot read {}: {}", path, e);
+                            std::process::exit(1);
+                        }
+                    },
+                    (Some(_), None) => match cloudinit::default_user_data(&name) {
+                        Ok(user_data) => Some(cloudinit::CloudInit { user_data, meta_data: None }),
+                        Err(e) => {
+                            error!("{}", e);
+                            std::process::exit(1);
+                        }
+                    },
+                };
+                let spec = NewVm {
+                    name,
+                    extends,
+                    memory,
+                    disk,
+                    threads,
+                    iso: iso.unwrap_or_default(),
+                    forwards,
+                    firmware,
+                    guest_agent: guest_agent.then_some(true),
+                    cloud_image,
+                    cloud_init,
+                    nic_model,
+                    arch,
+                    template: template.unwrap_or_default(),
+                };
+                    define_vm(&mut config, spec)
+                }
+            };
+            match created {
+                Ok(vm) if start && !vm.iso.is_empty() => {
+                    if let Err(e) = first_boot(&config, &vm, headless) {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+                Ok(vm) if start => {
+                    start_vm_common(&config, &vm, headless);
+                    if !vm_running(&vm.name) {
+                        std::process::exit(1);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Failed to create VM: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Start { name, headless, iso, boot_cdrom, display, slot } => {
+            let vm = cli_vm(&config, &name);
+            let slot = slot.map(|s| {
+                slot::Slot::parse(&s).unwrap_or_else(|| {
+                    error!("Unknown slot '{}'; slots are a and b", s);
+                    std::process::exit(1);
+                })
+            });
+            let request = daemon::Request::Start { name: name.clone(), headless, iso: iso.clone(), boot_cdrom, display: display.clone(), slot };
+            match daemon::forward(&request) {
+                Some(true) => {
+                    if let Some(console) = display::recorded(&name) {
+                        println!("Console: {}", console.url());
+                    }
+                }
+                Some(false) => {}
+                None => {
+                    if let Err(e) = start_from_cli(&config, vm, headless, iso, boot_cdrom, display, slot) {
+                        error!("Failed to start VM '{}': {}", name, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            // A failed start has to show as one, e.g. to systemd.
+            if !vm_running(&name) {
+                std::process::exit(1);
+            }
+        }
+        Command::Label { name, color, icon } => {
+            cli_vm(&config, &name);
+            let Some(vm) = config.vms.get_mut(&name) else { return };
+            let changed = color.is_some() || icon.is_some();
+            if let Err(e) = set_label(vm, color.as_deref(), icon.as_deref()).and_then(|()| if changed { save_config(&config).map_err(|e| e.to_string()) } else { Ok(()) }) {
+                error!("Failed to label '{}': {}", name, e);
+                std::process::exit(1);
+            }
+            let vm = &config.vms[&name];
+            println!(
+                "{}: color {}, icon {}",
+                label::paint(vm.color.as_deref(), &name),
+                vm.color.as_deref().unwrap_or("none"),
+                vm.icon.as_deref().unwrap_or("default")
+            );
+            if changed && desktop::entry_path(&name).exists() {
+                println!("Run `SRQemu desktop-entry {}` again to update its launcher.", name);
+            }
+        }
+        Command::Accel { name, accel } => {
+            let vm = cli_vm(&config, &name);
+            let Some(accel) = accel else {
+                println!("'{}' uses {}.", name, arch::describe(vm));
+                return;
+            };
+            match arch::set_accel(&mut config, &name, &accel) {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("Failed to change the accelerator of '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Devices { name, disk_bus, nic_model } => {
+            cli_vm(&config, &name);
+            match set_devices(&mut config, &name, disk_bus.as_deref(), nic_model.as_deref()) {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("Failed to change the devices of '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Stop { name, force } => {
+            let stopped = stop_vm_by_name(&config, &cli_vm(&config, &name).name, force);
+            hostpower::update(&config, Some(&name));
+            if let Err(e) = stopped {
+                error!("Failed to stop VM '{}': {}", name, e);
+                std::process::exit(1);
+            }
+        }
+        Command::Inspect { name } => {
+            let vm = cli_stopped_vm(&config, &name);
+            if let Err(e) = guestdisk::inspect(vm) {
+                error!("Failed to inspect '{}': {}", name, e);
+                std::process::exit(1);
+            }
+        }
+        Command::MountDisk { name, mountpoint, partition, read_only } => {
+            let vm = cli_stopped_vm(&config, &name);
+            if let Err(e) = guestdisk::mount_disk(vm, partition, Path::new(&expand_path(&mountpoint)), read_only) {
+                error!("Failed to mount '{}': {}", name, e);
+                std::process::exit(1);
+            }
+        }
+        Command::UmountDisk { name } => {
+            if let Err(e) = guestdisk::umount_disk(cli_vm(&config, &name)) {
+                error!("Failed to unmount '{}': {}", name, e);
+                std::process::exit(1);
+            }
+        }
+        Command::Disk { action: DiskAction::Cp { source, destination, partition } } => {
+            if let Err(e) = copy_files(&config, &source, &destination, || partition) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Network { action } => {
+            let done = match action {
+                NetworkAction::Bridge { action: BridgeAction::Create { bridge, uplink } } => {
+                    network::bridge_create(&bridge, uplink.as_deref()).map_err(|e| format!("Failed to create bridge {}: {}", bridge, e))
+                }
+                NetworkAction::Bridge { action: BridgeAction::Remove { bridge } } => {
+                    network::bridge_teardown(&bridge).map_err(|e| format!("Failed to remove bridge {}: {}", bridge, e))
+                }
+                NetworkAction::Capture { action: CaptureAction::Start { name, nic, output, rotate_mb, files } } => {
+                    let vm = cli_vm(&config, &name);
+                    if !vm_running(&name) {
+                        error!("VM '{}' is not running.", name);
+                        std::process::exit(1);
+                    }
+                    let out = PathBuf::from(expand_path(&output.unwrap_or_else(|| format!("{}-net{}.pcap", name, nic))));
+                    let rotation = rotate_mb.map(|max_size_mb| capture::Rotation { max_size_mb, files });
+                    capture::start(&config, vm, nic, &out, rotation).map_err(|e| format!("Failed to start capture: {}", e))
+                }
+                NetworkAction::Capture { action: CaptureAction::Stop { name, nic } } => {
+                    capture::stop(cli_vm(&config, &name), nic).map_err(|e| format!("Failed to stop capture: {}", e))
+                }
+            };
+            if let Err(e) = done {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Adopt { process, name } => {
+            let adopted = adopt::find_process(&process).and_then(|pid| {
+                let (vm, notes) = adopt::inspect(pid, name.as_deref(), &config).map_err(|e| format!("Cannot adopt process {}: {}", pid, e))?;
+                for note in &notes {
+                    println!("note: {}", note);
+                }
+                adopt_found(&mut config, pid, vm)
+            });
+            match adopted {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Import { libvirt, connect, vbox, name, disk } => {
+            let (kind, imported) = match (libvirt, vbox) {
+                (Some(source), _) => {
+                    ("libvirt", import::libvirt_xml(&source, &connect).and_then(|xml| import::from_libvirt(&xml, name.as_deref(), &config)))
+                }
+                (None, Some(path)) => ("virtualbox", import::from_vbox(Path::new(&expand_path(&path)), name.as_deref(), &config)),
+                // clap requires one of them.
+                (None, None) => return,
+            };
+            let imported = imported.map_err(|e| format!("Cannot import: {}", e)).and_then(|imported| {
+                print_imported(&imported);
+                finish_import(&mut config, imported, kind, &disk)
+            });
+            match imported {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::ServeDisk { name, readonly, bind, port } => {
+            let vm = cli_vm(&config, &name);
+            let served = if vm_running(&name) {
+                Err(format!("VM '{}' is running; stop it first", name))
+            } else if let Some(mount) = guestdisk::mounted_disk(vm) {
+                Err(format!("the disk is mounted on the host at {}; unmount it first", mount.mountpoint))
+            } else {
+                nbd::serve(&vm.disk_path(), &name, &bind, port, readonly)
+            };
+            if let Err(e) = served {
+                error!("Failed to serve the disk of '{}': {}", name, e);
+                std::process::exit(1);
+            }
+        }
+        Command::RestorePoint { name } => {
+            let vm = cli_vm(&config, &name);
+            match snapshot::restore_points(vm) {
+                Ok(points) if points.is_empty() => println!("'{}' has no restore points; take one with `SRQemu snapshot create`.", name),
+                Ok(points) => {
+                    for point in points {
+                        let kind = if point.live { "live" } else { "disk only" };
+                        let description = if point.description.is_empty() { String::new() } else { format!(": {}", point.description) };
+                        println!("{}  snapshot {} ({}){}", snapshot::format_utc(point.created), point.snapshot, kind, description);
+                    }
+                }
+                Err(e) => {
+                    error!("Cannot read the restore points of '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::RestoreTo { name, timestamp } => {
+            let vm = cli_vm(&config, &name);
+            let Some(when) = snapshot::parse_utc(&timestamp) else {
+                error!("Cannot read '{}' as a time; use YYYY-MM-DD HH:MM:SS (UTC) or seconds since the epoch", timestamp);
+                std::process::exit(1);
+            };
+            let restored = snapshot::point_at(vm, when).and_then(|point| snapshot::restore(vm, &point.snapshot).map(|()| point));
+            match restored {
+                Ok(point) => println!("'{}' is back at snapshot '{}' from {}.", name, point.snapshot, snapshot::format_utc(point.created)),
+                Err(e) => {
+                    error!("Failed to restore '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Suspend { name } => {
+            let vm = cli_vm(&config, &name);
+            match suspend::suspend(&config, vm) {
+                Ok(()) => println!("VM '{}' suspended; `SRQemu restore {}` resumes it.", name, name),
+                Err(e) => {
+                    error!("Failed to suspend '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+            hostpower::update(&config, Some(&name));
+        }
+        Command::Console { name, tmux } => {
+            let names: Vec<String> = match &name {
+                Some(name) => vec![cli_vm(&config, name).name.clone()],
+                None if tmux => {
+                    let mut names: Vec<String> = config.vms.keys().filter(|n| vm_running(n)).cloned().collect();
+                    names.sort();
+                    names
+                }
+                None => {
+                    error!("Name the VM whose console to open, or use --tmux for all running VMs");
+                    std::process::exit(1);
+                }
+            };
+            if let Some(stopped) = names.iter().find(|n| !vm_running(n)) {
+                error!("VM '{}' is not running", stopped);
+                std::process::exit(1);
+            }
+            if names.is_empty() {
+                println!("No VM is running.");
+                return;
+            }
+            let attached = if tmux { serial::open_in_tmux(&names) } else { serial::attach(&names[0]) };
+            if let Err(e) = attached {
+                error!("Failed to open the console: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Open { name } => {
+            let vm = cli_vm(&config, &name);
+            match desktop::open(&config, vm) {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    desktop::report(&format!("Failed to open '{}': {}", name, e));
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Tray => {
+            if let Err(e) = tray::run() {
+                desktop::report(&format!("Failed to show the tray icon: {}", e));
+                std::process::exit(1);
+            }
+        }
+        Command::DesktopEntry { name, output, remove } => {
+            let vm = cli_vm(&config, &name);
+            let done = match remove {
+                true => desktop::remove(&name).map(|path| format!("Removed {}.", path.display())),
+                false => desktop::install(vm, output.map(PathBuf::from))
+                    .map(|path| format!("Wrote {}; '{}' is in the app menu under System.", path.display(), name)),
+            };
+            match done {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("Failed to write the launcher for '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Guest { action } => {
+            let done = match action {
+                GuestAction::Cron { name, schedule, command, id } => {
+                    cli_vm(&config, &name);
+                    guestcron::add(&mut config, &name, id, &schedule, &command)
+                }
+                GuestAction::CronRemove { name, id } => {
+                    cli_vm(&config, &name);
+                    guestcron::remove(&mut config, &name, &id)
+                }
+                GuestAction::ResetPassword { name, user, partition } => {
+                    let vm = cli_stopped_vm(&config, &name);
+                    // Prints what it did, which differs for Windows guests.
+                    if let Err(e) = guestdisk::reset_password(vm, &user, || prompt_secret("New password: "), partition) {
+                        error!("Password reset failed: {}", e);
+                        std::process::exit(1);
+                    }
+                    return;
+                }
+                GuestAction::SyncTime { name } => sync_guest_time(cli_vm(&config, &name)),
+            };
+            match done {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Display { action: DisplayAction::Resize { name, resolution } } => {
+            cli_vm(&config, &name);
+            match display::resize(&mut config, &name, &resolution) {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("Failed to resize the display of '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Usb { action: UsbAction::List } => {
+            let devices = usb::host_devices();
+            if devices.is_empty() {
+                println!("No USB devices besides hubs are plugged in.");
+            }
+            for device in devices {
+                let users: Vec<&str> =
+                    config.vms.values().filter(|vm| vm.usb_devices.iter().any(|d| d.id == device.id)).map(|vm| vm.name.as_str()).collect();
+                let users = if users.is_empty() { String::new() } else { format!(" [{}]", users.join(", ")) };
+                println!("Bus {:03} Device {:03}: {}  {}{}", device.bus, device.address, device.id, device.description, users);
+            }
+        }
+        Command::Usb { action: UsbAction::Attach { name, device } } => {
+            cli_vm(&config, &name);
+            match usb::attach(&mut config, &name, &device) {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("Failed to pass {} through to '{}': {}", device, name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Usb { action: UsbAction::Detach { name, device } } => {
+            cli_vm(&config, &name);
+            match usb::detach(&mut config, &name, &device) {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("Failed to detach {} from '{}': {}", device, name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Vfio { action: VfioAction::Check { name } } => {
+            let vm = cli_vm(&config, &name);
+            let problems = vfio::problems(vm);
+            if vm.pci_devices.is_empty() {
+                println!("'{}' has no PCI devices passed through.", name);
+            } else if problems.is_empty() {
+                println!("This host is ready to pass {} PCI device(s) through to '{}'.", vm.pci_devices.len(), name);
+            } else {
+                error!("'{}' cannot start with its PCI devices:\n  {}", name, problems.join("\n  "));
+                std::process::exit(1);
+            }
+        }
+        Command::Vfio { action } => {
+            let (name, done) = match action {
+                VfioAction::Add { name, address, primary_gpu, romfile } => {
+                    cli_vm(&config, &name);
+                    let device = vfio::parse_address(&address).map(|address| vfio::PciDevice { address, primary_gpu, romfile: romfile.map(|r| expand_path(&r)) });
+                    let done = device.and_then(|device| vfio::add(&mut config, &name, device));
+                    (name, done)
+                }
+                VfioAction::Remove { name, address } => {
+                    cli_vm(&config, &name);
+                    let done = vfio::parse_address(&address).and_then(|address| vfio::remove(&mut config, &name, &address));
+                    (name, done)
+                }
+                VfioAction::Check { .. } => unreachable!(),
+            };
+            match done {
+                Ok(done) => {
+                    println!("{}", done);
+                    for problem in config.vms.get(&name).map(vfio::problems).unwrap_or_default() {
+                        warn!("{}", problem);
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to change the PCI devices of '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Share { action: ShareAction::List { name } } => {
+            let vm = cli_vm(&config, &name);
+            if vm.shares.is_empty() {
+                println!("'{}' has no shared folders.", name);
+            }
+            for share in &vm.shares {
+                let readonly = if share.readonly { ", read-only" } else { "" };
+                println!("{}: {} ({}{})", share.tag, share.path, share.protocol.name(), readonly);
+                println!("  In the guest: {}", share.mount_hint());
+            }
+        }
+        Command::Share { action } => {
+            let (name, done) = match action {
+                ShareAction::Add { name, path, tag, protocol, readonly } => {
+                    cli_vm(&config, &name);
+                    let path = expand_path(&path);
+                    let path = fs::canonicalize(&path).map(|p| p.display().to_string()).unwrap_or(path);
+                    let tag = tag.unwrap_or_else(|| Path::new(&path).file_name().map_or("share".to_string(), |n| n.to_string_lossy().replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_")));
+                    let done = share::Protocol::parse(&protocol)
+                        .ok_or_else(|| format!("unknown protocol '{}'; use virtiofs or 9p", protocol))
+                        .and_then(|protocol| share::add(&mut config, &name, share::Share { tag, path, protocol, readonly }));
+                    (name, done)
+                }
+                ShareAction::Remove { name, tag } => {
+                    cli_vm(&config, &name);
+                    let done = share::remove(&mut config, &name, &tag);
+                    (name, done)
+                }
+                ShareAction::List { .. } => unreachable!(),
+            };
+            match done {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("Failed to change the shared folders of '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Pause { name } => match pause_vm(&cli_vm(&config, &name).name) {
+            Ok(done) => println!("{}", done),
+            Err(e) => {
+                error!("Failed to pause '{}': {}", name, e);
+                std::process::exit(1);
+            }
+        },
+        Command::Resume { name } => match resume_vm(cli_vm(&config, &name)) {
+            Ok(done) => println!("{}", done),
+            Err(e) => {
+                error!("Failed to resume '{}': {}", name, e);
+                std::process::exit(1);
+            }
+        },
+        Command::List if json => {
+            let mut vms: Vec<&VMInfo> = config.vms.values().collect();
+            vms.sort_by(|a, b| a.name.cmp(&b.name));
+            status::print_json(&vms.into_iter().map(status::describe).collect::<Vec<_>>());
+        }
+        Command::List => list_defined_vms(&config),
+        Command::Ports { name } => match &name {
+            Some(name) => print_ports(cli_vm(&config, name)),
+            None => {
+                let mut names: Vec<&String> = config.vms.keys().collect();
+                names.sort();
+                for name in names {
+                    print_ports(&config.vms[name]);
+                }
+            }
+        },
+        Command::Status { name } => {
+            if let Some(name) = &name {
+                cli_vm(&config, name);
+            }
+            status::print(&config, name.as_deref(), json);
+        }
+        Command::Info { name } => {
+            let vm = cli_vm(&config, &name);
+            if json {
+                status::print_json(&status::describe(vm));
+            } else {
+                print_vm_summary(&config, vm);
+                match toml::Value::try_from(vm).map_err(|e| e.to_string()).and_then(|v| toml::to_string_pretty(&v).map_err(|e| e.to_string())) {
+                    Ok(text) => println!("\n{}", text.trim_end()),
+                    Err(e) => error!("cannot show the definition of '{}': {}", name, e),
+                }
+            }
+        }
+        Command::Clone { source, name, thin } => {
+            cli_vm(&config, &source);
+            if let Err(e) = clone_vm(&mut config, &source, &name, thin) {
+                error!("Failed to clone '{}': {}", source, e);
+                std::process::exit(1);
+            }
+        }
+        Command::Cdrom { name, action } => {
+            cli_vm(&config, &name);
+            let iso = match &action {
+                CdromAction::Insert { iso } => Some(iso.as_str()),
+                CdromAction::Eject => None,
+            };
+            match change_cdrom(&mut config, &name, iso) {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("Failed to change the CD of '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Slot { name, action } => {
+            cli_vm(&config, &name);
+            let done = match action {
+                SlotAction::Create => {
+                    let mut partial = interrupt::Partial::new();
+                    slot::create(&config, &name, &mut partial).and_then(|vm| {
+                        partial.commit(|| {
+                            config.vms.insert(name.clone(), vm);
+                            save_config(&config)
+                        })?;
+                        Ok(format!("Slot b of '{}' is a copy of its disk; boot it with `SRQemu start {} --slot b`.", name, name))
+                    })
+                }
+                SlotAction::Promote { slot } => match slot::Slot::parse(&slot) {
+                    Some(slot) => slot::promote(&mut config, &name, slot),
+                    None => Err(format!("unknown slot '{}'; slots are a and b", slot)),
+                },
+                SlotAction::Rollback => slot::rollback(&mut config, &name),
+                SlotAction::Drop => slot::drop_other(&mut config, &name),
+            };
+            match done {
+                Ok(done) => println!("{}", done),
+                Err(e) => {
+                    error!("Failed to change the slots of '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Run { image, cmd, rm, boot_timeout, timeout } => {
+            match run_ephemeral(&mut config, &image, &cmd, !rm, std::time::Duration::from_secs(boot_timeout), std::time::Duration::from_secs(timeout)) {
+                Ok(code) => std::process::exit(code as i32),
+                Err(e) => {
+                    error!("Failed to run the script in a copy of '{}': {}", image, e);
+                    std::process::exit(125);
+                }
+            }
+        }
+        Command::Ip { name, all } => {
+            let vm = cli_agent_vm(&config, &name);
+            match agent::addresses(&vm.name) {
+                Ok(addresses) => {
+                    for address in addresses.iter().filter(|a| all || !a.is_local()) {
+                        if all {
+                            println!("{} {}/{}", address.interface, address.address, address.prefix);
+                        } else {
+                            println!("{}", address.address);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to get the addresses of '{}': {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Exec { name, timeout, command } => {
+            let vm = cli_agent_vm(&config, &name);
+            let args: Vec<&str> = command[1..].iter().map(String::as_str).collect();
+            match agent::exec(&vm.name, &command[0], &args, std::time::Duration::from_secs(timeout)) {
+                Ok(result) => {
+                    print!("{}", result.stdout);
+                    eprint!("{}", result.stderr);
+                    std::process::exit(result.exit_code.clamp(0, 255) as i32);
+                }
+                Err(e) => {
+                    error!("Failed to run {} in '{}': {}", command[0], name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Fsfreeze { name, action } => {
+            let vm = cli_agent_vm(&config, &name);
+            let action = match action {
+                FreezeAction::Freeze => agent::FsFreeze::Freeze,
+                FreezeAction::Thaw => agent::FsFreeze::Thaw,
+                FreezeAction::Status => agent::FsFreeze::Status,
+            };
+            match agent::fsfreeze(&vm.name, action) {
+                Ok(message) => println!("{}", message),
+                Err(e) => {
+                    error!("fsfreeze on '{}' failed: {}", name, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Callbacks { name, allow } => {
+            cli_vm(&config, &name);
+            if !allow.is_empty() {
+                let callbacks: Result<Vec<callback::Callback>, String> = allow
+                    .iter()
+                    .filter(|a| *a != "none")
+                    .map(|a| callback::Callback::parse(a).ok_or_else(|| format!("unknown callback '{}'; known are {}", a, callback::CALLBACKS.join(", "))))
+                    .collect();
+                let callbacks = callbacks.unwrap_or_else(|e| {
+                    error!("{}", e);
+                    std::process::exit(1);
+                });
+                if let Some(vm) = config.vms.get_mut(&name) {
+                    vm.guest_callbacks = callbacks;
+                }
+                if let Err(e) = save_config(&config) {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+                if vm_running(&name) {
+                    println!("The change takes effect when '{}' next starts.", name);
+                }
+            }
+            let allowed: Vec<&str> = config.vms[&name].guest_callbacks.iter().map(|c| c.name()).collect();
+            if allowed.is_empty() {
+                println!("The guest of '{}' may request nothing.", name);
+            } else {
+                println!("The guest of '{}' may request: {}", name, allowed.join(", "));
+                println!("In the guest: echo {} > /dev/virtio-ports/org.srqemu.callback.0", allowed[0]);
+            }
+        }
+        Command::Schedule { action: ScheduleAction::Add { id, target, start, stop } } => {
+            for calendar in start.iter().chain(stop.iter()) {
+                if let Err(e) = guestcron::check_schedule(calendar) {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            if update::targets(&config, &target).is_empty() {
+                warn!("'{}' matches no VM yet.", target);
+            }
+            let previous = config.settings.schedules.insert(id.clone(), schedule::Schedule { target, start, stop });
+            if let Err(e) = save_config(&config) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+            // A replaced schedule may have lost its start or stop time.
+            if previous.is_some()
+                && let Err(e) = schedule::uninstall(&id)
+            {
+                warn!("Failed to remove the old timers of '{}': {}", id, e);
+            }
+            match schedule::install(&id, &config.settings.schedules[&id]) {
+                Ok(()) => println!("Schedule '{}' saved.", id),
+                Err(e) => {
+                    error!("Failed to install the timers of '{}': {}", id, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Schedule { action: ScheduleAction::Remove { id } } => {
+            if config.settings.schedules.remove(&id).is_none() {
+                error!("No schedule '{}'", id);
+                std::process::exit(1);
+            }
+            if let Err(e) = schedule::uninstall(&id) {
+                error!("Failed to remove the timers of '{}': {}", id, e);
+            }
+            if let Err(e) = save_config(&config) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Schedule { action: ScheduleAction::List } => {
+            let mut ids: Vec<&String> = config.settings.schedules.keys().collect();
+            ids.sort();
+            for id in ids {
+                let s = &config.settings.schedules[id];
+                println!("{}: {} start {} stop {}", id, s.target, s.start.as_deref().unwrap_or("-"), s.stop.as_deref().unwrap_or("-"));
+                for vm in update::targets(&config, &s.target).into_iter().filter(|vm| vm_running(&vm.name)) {
+                    match schedule::claimed_by(&vm.name) {
+                        Some(by) if by == *id => println!("    {} running, stops on schedule", vm.name),
+                        _ => println!("    {} running, started by hand", vm.name),
+                    }
+                }
+            }
+        }
+        Command::ScheduleRun { id, phase } => {
+            let Some(phase) = schedule::Phase::parse(&phase) else {
+                error!("usage: SRQemu schedule-run <id> start|stop");
+                std::process::exit(1);
+            };
+            if let Err(e) = schedule::run(&config, &id, phase) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Template { action: TemplateAction::List } => {
+            for (name, source) in template::list() {
+                let description = template::load(&name).map(|t| t.description).unwrap_or_else(|e| e);
+                if output::plain() {
+                    println!("{} ({}): {}", name, source, description);
+                } else {
+                    println!("{:<20} {:<9} {}", name, source, description);
+                }
+            }
+        }
+        Command::Template { action: TemplateAction::Show { name } } => {
+            // Through a Value, which writes plain keys ahead of tables.
+            let text = template::load(&name)
+                .and_then(|t| toml::Value::try_from(&t).and_then(|v| toml::to_string(&v)).map_err(|e| e.to_string()));
+            match text {
+                Ok(text) => print!("{}", text),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Images { action: ImagesAction::Pull { name } } => match images::pull(&name, &config.settings) {
+            Ok(image) => println!("Saved {} as '{}' ({}).", image.file, name, image.trust.describe()),
+            Err(e) => {
+                error!("Failed to pull '{}': {}", name, e);
+                std::process::exit(1);
+            }
+        },
+        Command::Images { action: ImagesAction::List } => print_images(),
+        Command::Images { action: ImagesAction::Catalog } => {
+            for known in images::CATALOG {
+                let kind = match known.kind {
+                    images::Kind::Iso => "installer ISO",
+                    images::Kind::Cloud => "cloud image",
+                };
+                if output::plain() {
+                    println!("{}: {}", known.name, kind);
+                } else {
+                    println!("{:<20} {}", known.name, kind);
+                }
+            }
+        }
+        Command::Delete { name } => {
+            cli_vm(&config, &name);
+            if let Err(e) = delete_vm_by_name(&mut config, &name) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+            println!("`SRQemu restore {}` brings it back; it is purged after {} days.", name, config.settings.trash_days);
+        }
+        Command::Restore { name, headless } if config.vms.contains_key(&name) => {
+            let vm = &config.vms[&name];
+            if !suspend::is_suspended(vm) {
+                error!("VM '{}' is not suspended; `SRQemu start {}` boots it", name, name);
+                std::process::exit(1);
+            }
+            start_vm_common(&config, vm, headless);
+            if !vm_running(&name) {
+                std::process::exit(1);
+            }
+        }
+        Command::Restore { name, .. } => {
+            // Trash entries are listed oldest first.
+            let Some(entry) = trash::list().into_iter().rev().find(|e| e.name == name) else {
+                error!("No deleted VM named '{}'", name);
+                std::process::exit(1);
+            };
+            if let Err(e) = restore_entry(&mut config, &entry) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Resize { name, size, grow, partition } => {
+            let vm = cli_vm(&config, &name);
+            match resize::resize(vm, &size) {
+                Ok(size) => println!("Disk of '{}' is now {}.", vm.name, guestdisk::human_size(size)),
+                Err(e) => {
+                    error!("Failed to resize '{}': {}", vm.name, e);
+                    std::process::exit(1);
+                }
+            }
+            if grow && let Err(e) = grow_guest(vm, partition) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Snapshot { action } => {
+            let (SnapshotAction::List { vm } | SnapshotAction::Create { vm, .. } | SnapshotAction::Restore { vm, .. } | SnapshotAction::Delete { vm, .. }) = &action;
+            cli_vm(&config, vm);
+            if !snapshot_action(&mut config, action) {
+                std::process::exit(1);
+            }
+        }
+        Command::Relocate { vm_dir, move_data } => match relocate::relocate(&mut config, &vm_dir, move_data) {
+            Ok(()) => println!("VMs now live in {}.", get_vm_folder()),
+            Err(e) => {
+                error!("Failed to relocate the VMs: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Command::Setup => {
+            if let Err(e) = setup_wizard(&mut config) {
+                error!("Setup failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::RunProfile { name: None } => {
+            let active = config.settings.run_profile.as_deref().unwrap_or("none");
+            for name in runprofile::names(&config.settings) {
+                println!("{} {}", if name == active { "*" } else { " " }, name);
+            }
+        }
+        Command::RunProfile { name: Some(name) } => {
+            if !set_run_profile(&mut config, &name) {
+                std::process::exit(1);
+            }
+        }
+        Command::Update { target } => {
+            if let Err(e) = run_updates(&config, &target) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Plan { vms } => {
+            let host = plan::Capacity::probe();
+            if vms.is_empty() {
+                plan::report(&config, &host);
+                return;
+            }
+            let mut chosen: Vec<&VMInfo> = Vec::new();
+            for spec in &vms {
+                let targets = update::targets(&config, spec);
+                if targets.is_empty() {
+                    error!("No VM or tag '{}'", spec);
+                    std::process::exit(1);
+                }
+                for vm in targets {
+                    if !chosen.iter().any(|c| c.name == vm.name) {
+                        chosen.push(vm);
+                    }
+                }
+            }
+            if !plan::simulate(&config, &chosen, &host) {
+                std::process::exit(1);
+            }
+        }
+        Command::Stats { enable, disable } => {
+            if enable || disable {
+                config.settings.usage_stats = enable;
+                if let Err(e) = save_config(&config) {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+                stats::observe(&config, vm_running);
+                println!("Usage statistics are {}.", if enable { "on" } else { "off" });
+            } else if !config.settings.usage_stats {
+                println!("Usage statistics are off; turn them on with `SRQemu stats --enable`.");
+            }
+            stats::print(&config);
+        }
+        Command::Autostart { action: None } => autostart::run(&config),
+        Command::Autostart { action: Some(action) } => {
+            let (name, mark) = match action {
+                AutostartAction::Enable { name, delay } => (name, Some(autostart::Autostart { delay })),
+                AutostartAction::Disable { name } => (name, None),
+            };
+            cli_vm(&config, &name);
+            if !set_autostart(&mut config, &name, mark) {
+                std::process::exit(1);
+            }
+        }
+        Command::MemoryWatch => pressure::watch(&config),
+        Command::Daemon { action: None } => {
+            if let Err(e) = daemon::serve() {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Daemon { action: Some(DaemonAction::Status) } => daemon::print_status(),
+        Command::Daemon { action: Some(DaemonAction::Install) } => match daemon::install_service() {
+            Ok(()) => println!("Daemon installed; it starts with your session."),
+            Err(e) => {
+                error!("Failed to install the daemon service: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Command::Daemon { action: Some(DaemonAction::Uninstall) } => {
+            if let Err(e) = daemon::uninstall_service() {
+                error!("Failed to remove the daemon service: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Config { action: ConfigAction::Export { output } } => {
+            let written = dotfiles::export(&config).and_then(|text| match &output {
+                Some(path) => fs::write(path, text).map_err(|e| format!("cannot write {}: {}", path, e)),
+                None => {
+                    print!("{}", text);
+                    Ok(())
+                }
+            });
+            if let Err(e) = written {
+                error!("Failed to export the configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Config { action: ConfigAction::Sync { file } } => match dotfiles::sync(&mut config, &file) {
+            Ok(report) => {
+                for (label, names) in [("Added", &report.added), ("Updated", &report.updated), ("Only on this host", &report.local_only)] {
+                    if !names.is_empty() {
+                        println!("{}: {}", label, names.join(", "));
+                    }
+                }
+                for name in &report.added {
+                    if let Some(vm) = config.vms.get(name)
+                        && !Path::new(&vm.disk_path()).exists()
+                    {
+                        println!("VM '{}' has no disk here yet ({}).", name, vm.disk_path());
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to sync the configuration: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Command::Config { action: ConfigAction::History } => {
+            let checkpoints = history::list();
+            if checkpoints.is_empty() {
+                println!("No configuration checkpoints yet.");
+            }
+            for (i, checkpoint) in checkpoints.iter().enumerate() {
+                println!("{}. before {} ({})", i + 1, checkpoint.reason, checkpoint.age());
+            }
+        }
+        Command::Config { action: ConfigAction::Rollback { number } } => {
+            let Some(checkpoint) = history::list().into_iter().nth(number.saturating_sub(1)) else {
+                error!("No configuration checkpoint {}; see `SRQemu config history`", number);
+                std::process::exit(1);
+            };
+            match history::rollback(&mut config, &checkpoint) {
+                Ok(report) => {
+                    println!("Configuration restored to before {} ({}).", checkpoint.reason, checkpoint.age());
+                    for (label, names) in [("Back", &report.restored), ("Gone", &report.dropped)] {
+                        if !names.is_empty() {
+                            println!("{}: {}", label, names.join(", "));
+                        }
+                    }
+                    for name in &report.restored {
+                        restore_rolled_back(&config, name);
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to roll back the configuration: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::SleepHook { .. } | Command::GuestRun { .. } => unreachable!("handled above"),
+    }
+}
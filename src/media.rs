@@ -0,0 +1,79 @@
+use crate::arch::Arch;
+use crate::config::VMInfo;
+use crate::qmp;
+use serde_json::json;
+
+/// The `-drive` id of the CD drive every VM gets, so media can be changed
+/// while it runs.
+pub const DRIVE_ID: &str = "cd0";
+
+/// What the CD drive holds for one boot, and whether to boot from it.
+#[derive(Debug, Clone, Default)]
+pub struct BootMedia {
+    pub iso: Option<String>,
+    pub boot: bool,
+}
+
+impl BootMedia {
+    /// The VM's stored medium, booting from the disk as usual.
+    pub fn configured(vm: &VMInfo) -> BootMedia {
+        BootMedia { iso: vm.cdrom_path(), boot: false }
+    }
+
+    /// The installation ISO, booted first.
+    pub fn install(vm: &VMInfo) -> BootMedia {
+        BootMedia { iso: Some(vm.iso_path()), boot: true }
+    }
+
+    /// `start --iso` and `--boot-cdrom`: `iso` replaces the stored medium
+    /// for this boot only. Booting from the drive falls back to the stored
+    /// medium, then to the installation ISO.
+    pub fn for_start(vm: &VMInfo, iso: Option<String>, boot: bool) -> Result<BootMedia, String> {
+        let iso = iso.map(|path| crate::expand_path(&path)).or_else(|| vm.cdrom_path());
+        let iso = match iso {
+            None if boot && !vm.iso.is_empty() => Some(vm.iso_path()),
+            None if boot => return Err(format!("VM '{}' has no ISO to boot from; pass --iso", vm.name)),
+            iso => iso,
+        };
+        if let Some(path) = &iso
+            && !std::path::Path::new(path).exists()
+        {
+            return Err(format!("ISO {} does not exist", path));
+        }
+        Ok(BootMedia { iso, boot })
+    }
+}
+
+/// An IDE CD drive at the slot `-cdrom` would use, empty unless a medium
+/// is given. Boards without IDE get a SCSI one instead.
+pub fn launch_args(media: &BootMedia, arch: Arch) -> Vec<String> {
+    let file = media.iso.as_ref().map(|iso| format!(",file={}", iso)).unwrap_or_default();
+    if !arch.is_pc() {
+        let bootindex = if media.boot { ",bootindex=0" } else { "" };
+        return vec![
+            "-drive".to_string(),
+            format!("if=none,media=cdrom,id={}{}", DRIVE_ID, file),
+            "-device".to_string(),
+            "virtio-scsi-pci,id=cdscsi".to_string(),
+            "-device".to_string(),
+            format!("scsi-cd,bus=cdscsi.0,drive={}{}", DRIVE_ID, bootindex),
+        ];
+    }
+    let mut args = vec!["-drive".to_string(), format!("if=ide,index=2,media=cdrom,id={}{}", DRIVE_ID, file)];
+    if media.boot {
+        args.extend(["-boot".to_string(), "order=d".to_string()]);
+    }
+    args
+}
+
+/// Puts `iso` in the drive of a running VM, replacing what is there. An
+/// AppArmor-confined VM can only read the medium it was started with.
+pub fn insert(vm_name: &str, iso: &str) -> Result<(), String> {
+    let arguments = json!({ "device": DRIVE_ID, "filename": iso, "format": "raw" });
+    qmp::command(vm_name, "blockdev-change-medium", Some(arguments)).map(|_| ())
+}
+
+/// Opens the tray of a running VM's drive, even if the guest locked it.
+pub fn eject(vm_name: &str) -> Result<(), String> {
+    qmp::command(vm_name, "eject", Some(json!({ "device": DRIVE_ID, "force": true }))).map(|_| ())
+}
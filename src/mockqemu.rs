@@ -0,0 +1,497 @@
+//! Stand-ins for `qemu-img`, `qemu-nbd` and `qemu-system-*`, run when `runner::Mock`
+//! launches this binary under their names. Images are small JSON files
+//! holding the metadata `qemu-img info` would report, and the "VM" is a
+//! process that writes its pidfile and answers on its QMP socket. Each
+//! VM's command line is recorded in `$SRQEMU_MOCK/<name>.argv`, one
+//! argument per line, and the QMP requests it got in `<name>.qmp`. A
+//! guest agent channel gets a fake qemu-ga: `guest-exec` echoes its
+//! arguments, and `false` fails; without `capture-output` it runs the
+//! command for real, on the host, whose files `guest-file-*` then read. A
+//! callback port sends the host what the test appends to
+//! `<name>.callbacks`, one request per line, and records the answers in
+//! `<name>.answers`.
+//! The serial console prompts for a login and echoes what it is sent.
+//! `qemu-nbd` records its arguments in `qemu-nbd.argv` and exits at once,
+//! as if the export had been interrupted.
+//! `virtiofsd` appends its arguments to `virtiofsd.argv`, creates its
+//! socket and exits.
+//! `SRQEMU_MOCK_SLOW=<subcommand>` makes that `qemu-img` subcommand take a
+//! few seconds, long enough to interrupt.
+//! `SRQEMU_MOCK_NO_KVM` makes SRQemu find no `/dev/kvm`.
+
+use crate::runner::MOCK_ENV;
+use crate::size::{self, Bare};
+use serde_json::{json, Value};
+use std::fs;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Runs the stand-in for `program` and returns its exit code.
+pub fn main(program: &str) -> i32 {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match program {
+        "qemu-img" => qemu_img(&args),
+        "qemu-nbd" => qemu_nbd(&args),
+        "virtiofsd" => virtiofsd(&args),
+        _ => qemu_system(program, &args),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", program, e);
+            1
+        }
+    }
+}
+
+const SLOW_ENV: &str = "SRQEMU_MOCK_SLOW";
+
+/// Flags followed by a value, as opposed to switches like `-U`.
+const IMG_VALUE_FLAGS: &[&str] = &["-f", "-F", "-O", "-b", "-o", "-c", "-a", "-d"];
+
+/// Splits `qemu-img` arguments into flags (with their values) and
+/// positional arguments.
+fn split_args(args: &[String]) -> (Vec<(String, Option<String>)>, Vec<String>) {
+    let mut flags = Vec::new();
+    let mut positional = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with('-') || arg.len() == 1 || arg.starts_with("-0") {
+            positional.push(arg.clone());
+        } else if IMG_VALUE_FLAGS.contains(&arg.as_str()) {
+            flags.push((arg.clone(), iter.next().cloned()));
+        } else {
+            flags.push((arg.clone(), None));
+        }
+    }
+    (flags, positional)
+}
+
+fn flag<'a>(flags: &'a [(String, Option<String>)], name: &str) -> Option<&'a str> {
+    flags.iter().find(|(f, _)| f == name).and_then(|(_, v)| v.as_deref())
+}
+
+fn has_flag(flags: &[(String, Option<String>)], name: &str) -> bool {
+    flags.iter().any(|(f, _)| f == name)
+}
+
+fn read_image(path: &str) -> Result<Value, String> {
+    let data = fs::read(path).map_err(|e| format!("Could not open '{}': {}", path, e))?;
+    // Files the mock did not create (ISOs, downloads) count as raw.
+    Ok(serde_json::from_slice(&data).unwrap_or_else(|_| json!({ "format": "raw", "virtual-size": data.len() })))
+}
+
+fn write_image(path: &str, image: &Value) -> Result<(), String> {
+    fs::write(path, image.to_string()).map_err(|e| format!("Could not create '{}': {}", path, e))
+}
+
+/// `info` output for one image; relative backing files are resolved
+/// against the image's directory, as QEMU does.
+fn info(path: &str) -> Result<Value, String> {
+    let mut image = read_image(path)?;
+    image["filename"] = json!(path);
+    if let Some(backing) = image["backing-filename"].as_str() {
+        let full = Path::new(path).parent().unwrap_or(Path::new("")).join(backing);
+        image["full-backing-filename"] = json!(full.display().to_string());
+    }
+    Ok(image)
+}
+
+fn qemu_img(args: &[String]) -> Result<(), String> {
+    let (command, rest) = args.split_first().ok_or("missing subcommand")?;
+    let (flags, positional) = split_args(rest);
+    if std::env::var(SLOW_ENV).is_ok_and(|slow| slow == *command) {
+        std::thread::sleep(std::time::Duration::from_secs(5));
+    }
+    match command.as_str() {
+        "create" => {
+            let path = positional.first().ok_or("missing filename")?;
+            let mut image = json!({ "format": flag(&flags, "-f").unwrap_or("raw"), "snapshots": [] });
+            let size = match (positional.get(1), flag(&flags, "-b")) {
+                (Some(spec), _) => size::parse(spec, Bare::Bytes)?,
+                (None, Some(backing)) => {
+                    let base = Path::new(path).parent().unwrap_or(Path::new("")).join(backing);
+                    read_image(&base.display().to_string())?["virtual-size"].as_u64().unwrap_or(0)
+                }
+                (None, None) => return Err("missing size".to_string()),
+            };
+            image["virtual-size"] = json!(size);
+            if let Some(backing) = flag(&flags, "-b") {
+                image["backing-filename"] = json!(backing);
+                image["backing-filename-format"] = json!(flag(&flags, "-F").unwrap_or("raw"));
+            }
+            write_image(path, &image)
+        }
+        "info" => {
+            let path = positional.first().ok_or("missing filename")?;
+            let output = if has_flag(&flags, "--backing-chain") {
+                let mut chain = Vec::new();
+                let mut next = Some(path.clone());
+                while let Some(current) = next.take() {
+                    let image = info(&current)?;
+                    next = image["full-backing-filename"].as_str().map(str::to_string);
+                    chain.push(image);
+                }
+                Value::Array(chain)
+            } else {
+                info(path)?
+            };
+            println!("{}", serde_json::to_string_pretty(&output).map_err(|e| e.to_string())?);
+            Ok(())
+        }
+        "convert" => {
+            let [source, target] = positional.as_slice() else {
+                return Err("expected a source and a target".to_string());
+            };
+            let mut image = read_image(source)?;
+            if let Some(map) = image.as_object_mut() {
+                map.remove("backing-filename");
+                map.remove("backing-filename-format");
+            }
+            image["format"] = json!(flag(&flags, "-O").unwrap_or("raw"));
+            image["snapshots"] = json!([]);
+            write_image(target, &image)
+        }
+        "snapshot" => {
+            let path = positional.first().ok_or("missing filename")?;
+            let mut image = read_image(path)?;
+            let mut snapshots = image["snapshots"].as_array().cloned().unwrap_or_default();
+            if let Some(name) = flag(&flags, "-c") {
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                snapshots.push(json!({ "name": name, "id": (snapshots.len() + 1).to_string(), "date-sec": now }));
+            } else if let Some(name) = flag(&flags, "-d") {
+                let before = snapshots.len();
+                snapshots.retain(|s| s["name"] != name);
+                if snapshots.len() == before {
+                    return Err(format!("Could not delete snapshot '{}': snapshot not found", name));
+                }
+            } else if let Some(name) = flag(&flags, "-a") {
+                if !snapshots.iter().any(|s| s["name"] == name) {
+                    return Err(format!("Could not apply snapshot '{}': snapshot not found", name));
+                }
+            } else {
+                for snapshot in &snapshots {
+                    println!("{}", snapshot["name"].as_str().unwrap_or_default());
+                }
+                return Ok(());
+            }
+            image["snapshots"] = Value::Array(snapshots);
+            write_image(path, &image)
+        }
+        "resize" => {
+            let [path, spec] = positional.as_slice() else {
+                return Err("expected a filename and a size".to_string());
+            };
+            let mut image = read_image(path)?;
+            let current = image["virtual-size"].as_u64().unwrap_or(0);
+            let size = match spec.strip_prefix('+') {
+                Some(delta) => current + size::parse(delta, Bare::Bytes)?,
+                None => size::parse(spec, Bare::Bytes)?,
+            };
+            image["virtual-size"] = json!(size);
+            write_image(path, &image)
+        }
+        "rebase" => {
+            let path = positional.first().ok_or("missing filename")?;
+            let mut image = read_image(path)?;
+            image["backing-filename"] = json!(flag(&flags, "-b").ok_or("missing backing file")?);
+            image["backing-filename-format"] = json!(flag(&flags, "-F").unwrap_or("raw"));
+            write_image(path, &image)
+        }
+        other => Err(format!("mock does not support '{}'", other)),
+    }
+}
+
+/// The value of `key=` in a QEMU option string like `file=a.qcow2,if=none`.
+fn qemu_nbd(args: &[String]) -> Result<(), String> {
+    let image = args.last().ok_or("missing filename")?;
+    read_image(image)?;
+    if let Some(dir) = std::env::var_os(MOCK_ENV) {
+        let record = PathBuf::from(dir).join("qemu-nbd.argv");
+        fs::write(&record, args.join("\n") + "\n").map_err(|e| format!("cannot write {}: {}", record.display(), e))?;
+    }
+    Ok(())
+}
+
+fn option<'a>(opts: &'a str, key: &str) -> Option<&'a str> {
+    opts.split(',').find_map(|part| part.strip_prefix(key)?.strip_prefix('='))
+}
+
+fn virtiofsd(args: &[String]) -> Result<(), String> {
+    if let Some(dir) = std::env::var_os(MOCK_ENV) {
+        let record = PathBuf::from(dir).join("virtiofsd.argv");
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&record).map_err(|e| format!("cannot write {}: {}", record.display(), e))?;
+        writeln!(file, "{}", args.join(" ")).map_err(|e| e.to_string())?;
+    }
+    let socket = args.iter().find_map(|a| a.strip_prefix("--socket-path=")).ok_or("missing --socket-path")?;
+    UnixListener::bind(socket).map_err(|e| format!("cannot bind {}: {}", socket, e))?;
+    Ok(())
+}
+
+fn qemu_system(program: &str, args: &[String]) -> Result<(), String> {
+    let value = |name: &str| args.iter().position(|a| a == name).and_then(|i| args.get(i + 1));
+    let name = value("-name").map(String::as_str).unwrap_or("unnamed");
+    let records = std::env::var_os(MOCK_ENV).map(PathBuf::from);
+    if let Some(dir) = &records {
+        let record = dir.join(format!("{}.argv", name));
+        let mut lines = vec![program.to_string()];
+        lines.extend(args.iter().cloned());
+        fs::write(&record, lines.join("\n") + "\n").map_err(|e| format!("cannot write {}: {}", record.display(), e))?;
+    }
+    // Missing disks are the failure QEMU reports most; mimic its message
+    // so startup diagnosis can be exercised.
+    for (i, arg) in args.iter().enumerate() {
+        if arg != "-drive" && arg != "-cdrom" {
+            continue;
+        }
+        let Some(opts) = args.get(i + 1) else { continue };
+        let file = if arg == "-cdrom" { Some(opts.as_str()) } else { option(opts, "file") };
+        if let Some(file) = file
+            && !Path::new(file).exists()
+        {
+            return Err(format!("{} {}: Could not open '{}': No such file or directory", arg, opts, file));
+        }
+    }
+    let pidfile = value("-pidfile").map(PathBuf::from);
+    if let Some(path) = &pidfile {
+        fs::write(path, format!("{}\n", std::process::id())).map_err(|e| format!("cannot write pidfile: {}", e))?;
+    }
+    let socket = value("-qmp").and_then(|spec| spec.strip_prefix("unix:")).map(|spec| PathBuf::from(spec.split(',').next().unwrap_or(spec)));
+    let cleanup = || {
+        for path in pidfile.iter().chain(socket.iter()) {
+            let _ = fs::remove_file(path);
+        }
+    };
+    let Some(socket) = &socket else {
+        // Without a control socket there is nothing to wait for.
+        cleanup();
+        return Ok(());
+    };
+    let _ = fs::remove_file(socket);
+    let listener = UnixListener::bind(socket).map_err(|e| format!("cannot bind {}: {}", socket.display(), e))?;
+    let agent = chardev_path(args, "qga0");
+    if let Some(path) = &agent {
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path).map_err(|e| format!("cannot bind {}: {}", path.display(), e))?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = serve_agent(stream);
+            }
+        });
+    }
+    let callback = chardev_path(args, "srqcb0");
+    if let (Some(path), Some(dir)) = (&callback, &records) {
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path).map_err(|e| format!("cannot bind {}: {}", path.display(), e))?;
+        let (requests, answers) = (dir.join(format!("{}.callbacks", name)), dir.join(format!("{}.answers", name)));
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = serve_callbacks(stream, &requests, &answers);
+            }
+        });
+    }
+    let serial = chardev_path(args, "srqserial0");
+    if let Some(path) = &serial {
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path).map_err(|e| format!("cannot bind {}: {}", path.display(), e))?;
+        let name = name.to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = serve_serial(stream, &name);
+            }
+        });
+    }
+    let log = records.map(|dir| dir.join(format!("{}.qmp", name)));
+    for stream in listener.incoming().flatten() {
+        if serve_qmp(stream, log.as_deref()).unwrap_or(false) {
+            break;
+        }
+    }
+    cleanup();
+    for path in agent.iter().chain(callback.iter()).chain(serial.iter()) {
+        let _ = fs::remove_file(path);
+    }
+    Ok(())
+}
+
+/// Greets a serial console client like a login prompt and echoes each line
+/// back until it hangs up.
+fn serve_serial(stream: UnixStream, name: &str) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    write!(writer, "{} login: ", name)?;
+    for line in BufReader::new(stream).lines() {
+        writeln!(writer, "{}", line?)?;
+    }
+    Ok(())
+}
+
+/// Where the socket chardev with `id` listens.
+fn chardev_path(args: &[String], id: &str) -> Option<PathBuf> {
+    let prefix = format!("socket,id={},path=", id);
+    args.iter().find_map(|a| a.strip_prefix(&prefix)).map(|spec| PathBuf::from(spec.split(',').next().unwrap_or(spec)))
+}
+
+/// Plays the guest on the callback port: passes on each new line of
+/// `requests` and appends the host's answer to `answers`.
+fn serve_callbacks(stream: UnixStream, requests: &Path, answers: &Path) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut sent = 0;
+    loop {
+        let text = fs::read_to_string(requests).unwrap_or_default();
+        for line in text.lines().skip(sent) {
+            writeln!(writer, "{}", line)?;
+            sent += 1;
+            let mut answer = String::new();
+            if reader.read_line(&mut answer)? == 0 {
+                return Ok(());
+            }
+            fs::OpenOptions::new().create(true).append(true).open(answers)?.write_all(answer.as_bytes())?;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
+/// Whether the fake guest's filesystems are frozen.
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+/// Answers one guest agent client; unlike QMP there is no greeting.
+fn serve_agent(stream: UnixStream) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut output = String::new();
+    let mut exit_code = 0;
+    let mut running: HashMap<i64, Child> = HashMap::new();
+    let mut files: HashMap<i64, fs::File> = HashMap::new();
+    for line in BufReader::new(stream).lines() {
+        let request: Value = serde_json::from_str(&line?).unwrap_or_default();
+        let arguments = &request["arguments"];
+        let reply = match request["execute"].as_str().unwrap_or_default() {
+            "guest-sync" => arguments["id"].clone(),
+            "guest-network-get-interfaces" => json!([
+                { "name": "lo", "ip-addresses": [{ "ip-address-type": "ipv4", "ip-address": "127.0.0.1", "prefix": 8 }] },
+                { "name": "eth0", "hardware-address": "52:54:00:12:34:56", "ip-addresses": [
+                    { "ip-address-type": "ipv4", "ip-address": "10.0.2.15", "prefix": 24 },
+                    { "ip-address-type": "ipv6", "ip-address": "fe80::5054:ff:fe12:3456", "prefix": 64 },
+                ] },
+            ]),
+            "guest-exec" => {
+                let path = arguments["path"].as_str().unwrap_or_default();
+                let args: Vec<&str> = arguments["arg"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+                if arguments["capture-output"].as_bool() != Some(true) {
+                    let child = Command::new(path).args(&args).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn()?;
+                    let pid = i64::from(child.id());
+                    running.insert(pid, child);
+                    writeln!(writer, "{}", json!({ "return": { "pid": pid } }))?;
+                    continue;
+                }
+                exit_code = if path == "false" { 1 } else { 0 };
+                output = format!("{} {}\n", path, args.join(" "));
+                json!({ "pid": 4242 })
+            }
+            "guest-exec-status" => match arguments["pid"].as_i64().and_then(|pid| running.get_mut(&pid)) {
+                Some(child) => match child.try_wait()? {
+                    Some(status) => json!({ "exited": true, "exitcode": status.code().unwrap_or(-1) }),
+                    None => json!({ "exited": false }),
+                },
+                None => json!({ "exited": true, "exitcode": exit_code, "out-data": base64_encode(output.as_bytes()) }),
+            },
+            "guest-file-open" => match fs::File::open(arguments["path"].as_str().unwrap_or_default()) {
+                Ok(file) => {
+                    let handle = files.len() as i64 + 1;
+                    files.insert(handle, file);
+                    json!(handle)
+                }
+                Err(e) => {
+                    writeln!(writer, "{}", json!({ "error": { "class": "GenericError", "desc": e.to_string() } }))?;
+                    continue;
+                }
+            },
+            "guest-file-read" => {
+                let mut buf = vec![0; arguments["count"].as_u64().unwrap_or(4096) as usize];
+                let count = match arguments["handle"].as_i64().and_then(|h| files.get_mut(&h)) {
+                    Some(file) => file.read(&mut buf)?,
+                    None => 0,
+                };
+                json!({ "count": count, "buf-b64": base64_encode(&buf[..count]), "eof": count == 0 })
+            }
+            "guest-file-close" => {
+                files.remove(&arguments["handle"].as_i64().unwrap_or_default());
+                json!({})
+            }
+            "guest-fsfreeze-freeze" => {
+                FROZEN.store(true, Ordering::SeqCst);
+                json!(2)
+            }
+            "guest-fsfreeze-thaw" => {
+                FROZEN.store(false, Ordering::SeqCst);
+                json!(2)
+            }
+            "guest-fsfreeze-status" => json!(if FROZEN.load(Ordering::SeqCst) { "frozen" } else { "thawed" }),
+            other => {
+                writeln!(writer, "{}", json!({ "error": { "class": "CommandNotFound", "desc": format!("command {} not found", other) } }))?;
+                continue;
+            }
+        };
+        writeln!(writer, "{}", json!({ "return": reply }))?;
+    }
+    Ok(())
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |acc, (i, b)| acc | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            out.push(if i <= chunk.len() { ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char } else { '=' });
+        }
+    }
+    out
+}
+
+/// Whether the fake VM's CPUs are stopped.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Answers one QMP client, appending its requests to `log`; returns
+/// whether it asked the VM to shut down.
+fn serve_qmp(stream: UnixStream, log: Option<&Path>) -> std::io::Result<bool> {
+    let mut writer = stream.try_clone()?;
+    let greeting = json!({ "QMP": { "version": { "qemu": { "major": 9, "minor": 0, "micro": 0 } }, "capabilities": [] } });
+    writeln!(writer, "{}", greeting)?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if let Some(log) = log {
+            fs::OpenOptions::new().create(true).append(true).open(log)?.write_all(format!("{}\n", line).as_bytes())?;
+        }
+        let request: Value = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(_) => {
+                writeln!(writer, "{}", json!({ "error": { "class": "GenericError", "desc": "invalid JSON" } }))?;
+                continue;
+            }
+        };
+        let reply = match request["execute"].as_str().unwrap_or_default() {
+            "query-status" if PAUSED.load(Ordering::SeqCst) => json!({ "status": "paused", "running": false }),
+            "query-status" => json!({ "status": "running", "running": true }),
+            "stop" | "cont" => {
+                PAUSED.store(request["execute"] == "stop", Ordering::SeqCst);
+                json!({})
+            }
+            "human-monitor-command" => json!(""),
+            _ => json!({}),
+        };
+        let shutdown = matches!(request["execute"].as_str(), Some("system_powerdown" | "quit"));
+        let sent = writeln!(writer, "{}", json!({ "return": reply }));
+        if shutdown {
+            // The client may hang up as soon as it has the reply.
+            let _ = writeln!(writer, "{}", json!({ "event": "SHUTDOWN", "data": { "guest": true } }));
+            return Ok(true);
+        }
+        sent?;
+    }
+    Ok(false)
+}
@@ -0,0 +1,240 @@
+use crate::run;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command as ShellCommand;
+use std::thread;
+use std::time::Duration;
+
+/// Upper bound on /dev/nbdN devices probed, matching the kernel's default
+/// `nbds_max`.
+const MAX_NBD_DEVICES: u32 = 16;
+
+/// A disk image exported through `qemu-nbd`. Disconnects on drop.
+pub struct NbdDevice {
+    pub path: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub path: String,
+    pub size: u64,
+    pub fstype: String,
+    pub label: String,
+}
+
+/// A filesystem mounted from an NBD partition. Unmounts on drop.
+pub struct Mounted {
+    pub dir: PathBuf,
+    remove_dir: bool,
+}
+
+/// Image format as reported by `qemu-img info`, so raw and imported images
+/// are exported correctly instead of being guessed from the extension.
+pub fn image_format(image: &str) -> Result<String, String> {
+    let out = crate::runner::run_read_only(crate::runner::command("qemu-img").args(["info", "--output=json", image]))?;
+    let info: Value = serde_json::from_str(&out).map_err(|e| format!("bad qemu-img output: {}", e))?;
+    info["format"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| "qemu-img did not report a format".to_string())
+}
+
+/// Exports `image` over TCP as `name` until qemu-nbd is interrupted, for
+/// NBD clients such as `qemu-img`, `nbdfuse` or another host's
+/// `nbd-client`. A read-only export may have any number of readers.
+pub fn serve(image: &str, name: &str, bind: &str, port: u16, read_only: bool) -> Result<(), String> {
+    let format = image_format(image)?;
+    let mut cmd = crate::runner::command("qemu-nbd");
+    cmd.args(["--persistent", "--bind", bind, "--port", &port.to_string(), "--export-name", name])
+        .arg(format!("--format={}", format));
+    if read_only {
+        cmd.args(["--read-only", "--shared=0"]);
+    }
+    cmd.arg(image);
+    let host = if bind.contains(':') { format!("[{}]", bind) } else { bind.to_string() };
+    println!("Serving {} as nbd://{}:{}/{}{}; Ctrl-C stops.", image, host, port, name, if read_only { " (read-only)" } else { "" });
+    // Runs in the foreground, without the runner's time limits.
+    let status = cmd.status().map_err(|e| format!("cannot run qemu-nbd: {}", e))?;
+    // Ctrl-C reaches qemu-nbd too; ending there is how exports normally end.
+    if status.success() || status.code().is_none() { Ok(()) } else { Err(format!("qemu-nbd failed ({})", status)) }
+}
+
+fn ensure_module() -> Result<(), String> {
+    if Path::new("/sys/block/nbd0").exists() {
+        return Ok(());
+    }
+    run(ShellCommand::new("modprobe").args(["nbd", "max_part=16"]))?;
+    Ok(())
+}
+
+fn device_in_use(n: u32) -> bool {
+    Path::new(&format!("/sys/block/nbd{}/pid", n)).exists()
+}
+
+fn device_size(n: u32) -> u64 {
+    fs::read_to_string(format!("/sys/block/nbd{}/size", n))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Exports `image` on the first free /dev/nbdN. Another process may take
+/// that device between the check and the connect; qemu-nbd then reports it
+/// busy and the next one is tried.
+pub fn connect(image: &str, read_only: bool) -> Result<NbdDevice, String> {
+    ensure_module()?;
+    let format = image_format(image)?;
+
+    let mut last_error = "no free /dev/nbd device".to_string();
+    for n in (0..MAX_NBD_DEVICES).filter(|n| !device_in_use(*n)) {
+        let path = format!("/dev/nbd{}", n);
+        let mut cmd = ShellCommand::new("qemu-nbd");
+        cmd.arg(format!("--connect={}", path))
+            .arg(format!("--format={}", format));
+        if read_only {
+            cmd.arg("--read-only");
+        }
+        cmd.arg(image);
+        if let Err(e) = run(&mut cmd) {
+            if e.contains("busy") || device_in_use(n) {
+                last_error = e;
+                continue;
+            }
+            return Err(e);
+        }
+        let device = NbdDevice { path };
+
+        // qemu-nbd returns before the kernel has scanned the partition table.
+        for _ in 0..50 {
+            if device_size(n) > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        if device_size(n) == 0 {
+            return Err(format!("{} did not come up with {} after 5 seconds", device.path, image));
+        }
+        crate::runner::best_effort(ShellCommand::new("udevadm").arg("settle"));
+        return Ok(device);
+    }
+    Err(last_error)
+}
+
+impl NbdDevice {
+    pub fn partitions(&self) -> Result<Vec<Partition>, String> {
+        let out = crate::runner::run_read_only(ShellCommand::new("lsblk").args([
+            "-J", "-b", "-o", "PATH,SIZE,FSTYPE,LABEL,TYPE", &self.path,
+        ]))?;
+        let tree: Value = serde_json::from_str(&out).map_err(|e| format!("bad lsblk output: {}", e))?;
+        let mut parts = Vec::new();
+        if let Some(devices) = tree["blockdevices"].as_array() {
+            for dev in devices {
+                collect_partitions(dev, &mut parts);
+            }
+        }
+        Ok(parts)
+    }
+
+    /// Keeps the connection alive past this handle, returning the device path
+    /// so it can be disconnected later.
+    pub fn persist(self) -> String {
+        let path = self.path.clone();
+        std::mem::forget(self);
+        path
+    }
+}
+
+pub fn disconnect(device: &str) -> Result<(), String> {
+    run(ShellCommand::new("qemu-nbd").arg("--disconnect").arg(device))?;
+    Ok(())
+}
+
+impl Drop for NbdDevice {
+    fn drop(&mut self) {
+        let _ = disconnect(&self.path);
+    }
+}
+
+fn collect_partitions(dev: &Value, out: &mut Vec<Partition>) {
+    if let Some(children) = dev["children"].as_array()
+        && !children.is_empty()
+    {
+        for child in children {
+            collect_partitions(child, out);
+        }
+        return;
+    }
+    let text = |key: &str| dev[key].as_str().unwrap_or("").to_string();
+    // Older lsblk versions emit sizes as strings even with -J.
+    let size = dev["size"]
+        .as_u64()
+        .or_else(|| dev["size"].as_str().and_then(|s| s.parse().ok()))
+        .unwrap_or(0);
+    out.push(Partition {
+        path: text("path"),
+        size,
+        fstype: text("fstype"),
+        label: text("label"),
+    });
+}
+
+impl Partition {
+    /// Whether it makes sense to try mounting this partition.
+    pub fn mountable(&self) -> bool {
+        !self.fstype.is_empty()
+            && !matches!(self.fstype.as_str(), "swap" | "LVM2_member" | "crypto_LUKS" | "linux_raid_member")
+    }
+}
+
+/// Mount options that avoid replaying journals, so a read-only look at an
+/// uncleanly shut down guest never modifies the image.
+fn read_only_options(fstype: &str) -> &'static str {
+    match fstype {
+        "ext3" | "ext4" => "ro,noload",
+        "xfs" => "ro,norecovery",
+        _ => "ro",
+    }
+}
+
+pub fn mount(part: &Partition, dir: &Path, read_only: bool) -> Result<Mounted, String> {
+    let remove_dir = !dir.exists();
+    fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let mut cmd = ShellCommand::new("mount");
+    if read_only {
+        cmd.arg("-o").arg(read_only_options(&part.fstype));
+    }
+    if let Err(e) = run(cmd.arg(&part.path).arg(dir)) {
+        if remove_dir {
+            let _ = fs::remove_dir(dir);
+        }
+        return Err(e);
+    }
+    Ok(Mounted { dir: dir.to_path_buf(), remove_dir })
+}
+
+/// A fresh scratch directory for a temporary mount.
+pub fn scratch_dir(purpose: &str, index: usize) -> PathBuf {
+    std::env::temp_dir().join(format!("srqemu-{}-{}-{}", purpose, std::process::id(), index))
+}
+
+impl Mounted {
+    /// Leaves the filesystem mounted after this handle goes away.
+    pub fn persist(self) {
+        std::mem::forget(self);
+    }
+}
+
+pub fn unmount(dir: &Path) -> Result<(), String> {
+    run(ShellCommand::new("umount").arg(dir))?;
+    Ok(())
+}
+
+impl Drop for Mounted {
+    fn drop(&mut self) {
+        let _ = unmount(&self.dir);
+        if self.remove_dir {
+            let _ = fs::remove_dir(&self.dir);
+        }
+    }
+}
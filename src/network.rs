@@ -0,0 +1,1113 @@
+use crate::config::{VMConfig, VMInfo};
+use crate::run;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Command as ShellCommand;
+use std::time::SystemTime;
+use tracing::{debug, warn};
+
+/// ACL read by qemu-bridge-helper; a bridge must be listed here before
+/// unprivileged QEMU processes may attach taps to it.
+const BRIDGE_ACL: &str = "/etc/qemu/bridge.conf";
+
+/// Longest managed network name that still fits the 15-character Linux
+/// interface limit once prefixed (`srq-`, `wg-`, `vx-`).
+pub const MAX_NETWORK_NAME: usize = 11;
+
+/// VXLAN rides inside WireGuard, so the bridge port loses both overheads:
+/// 1420 (wg default) - 50 (vxlan) = 1370.
+const WIREGUARD_VXLAN_MTU: u32 = 1370;
+
+/// First UDP port handed out to multicast-socket isolated networks.
+const ISOLATED_BASE_PORT: u16 = 12000;
+const ISOLATED_MCAST_GROUP: &str = "230.0.0.1";
+
+/// A named network managed by SRQemu: either a host bridge that VM NICs
+/// attach to (optionally stretched to other hosts over WireGuard), or an
+/// isolated VM-to-VM segment with no host interface at all.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NetworkDef {
+    /// Empty for isolated networks.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub bridge: String,
+    /// Host address on the bridge, e.g. `10.10.0.1/24`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Host IPv6 address on the bridge, e.g. `fd10:10::1/64`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address6: Option<String>,
+    /// Masquerade the network's subnets out of the host's other interfaces.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub nat: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dhcp: Option<Dhcp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wireguard: Option<WireGuardLink>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub isolated: Option<IsolatedLink>,
+}
+
+/// Addressing for guests on a managed network, served by a dnsmasq bound to
+/// its bridge.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Dhcp {
+    /// IPv4 pool as `start,end`, e.g. `10.10.0.100,10.10.0.200`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
+    /// IPv6 pool as interface ids on the bridge prefix, e.g. `::100,::1ff`,
+    /// handed out by stateful DHCPv6. Without it guests on a network with an
+    /// `address6` get SLAAC from router advertisements.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range6: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub leases: Vec<StaticLease>,
+}
+
+/// Fixed addresses for one guest NIC, matched by MAC.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StaticLease {
+    pub mac: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv4: Option<String>,
+    /// Needs a `range6`; SLAAC addresses cannot be pinned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<String>,
+}
+
+/// Transport for an isolated network; frames only ever reach other VMs.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum IsolatedLink {
+    /// UDP multicast bound to loopback: every VM joined to the group sees the
+    /// others' frames. Needs nothing installed on the host.
+    Socket { mcast: String },
+    /// A `vde_switch` started by SRQemu on demand; `switch` is its socket
+    /// directory.
+    Vde { switch: String },
+}
+
+/// Joins a managed network with the same network on other SRQemu hosts. The
+/// bridge is extended with a VXLAN device whose traffic goes through the
+/// WireGuard tunnel, so guests on every host share one flat L2 segment.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WireGuardLink {
+    pub interface: String,
+    /// Path of this host's private key; kept out of the config file.
+    pub private_key_file: String,
+    pub listen_port: u16,
+    /// This host's tunnel address, e.g. `10.200.0.1/24`.
+    pub tunnel_address: String,
+    pub vni: u32,
+    #[serde(default)]
+    pub peers: Vec<WireGuardPeer>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WireGuardPeer {
+    pub public_key: String,
+    /// `host:port` of the peer; leave unset for peers that dial in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// The peer's tunnel address, used as its VXLAN remote.
+    pub tunnel_ip: String,
+}
+
+/// Where a VM network card is plugged in.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NetBackend {
+    /// QEMU user-mode (slirp) networking.
+    User {
+        /// Guest IPv6 prefix, e.g. `fd00:1::/64`; QEMU's default is
+        /// `fec0::/64`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ipv6_net: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        hostfwd: Vec<HostForward>,
+    },
+    /// A passt process SRQemu starts next to QEMU: unprivileged like
+    /// user-mode, but the guest shares the host's addresses and gets far
+    /// better throughput. Needs QEMU 7.2 or newer.
+    Passt {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        forwards: Vec<HostForward>,
+    },
+    /// An existing host bridge, via qemu-bridge-helper.
+    Bridge { bridge: String },
+    /// A managed network from `[networks]`.
+    Network { network: String },
+}
+
+/// A user-mode port forward from the host into the guest.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HostForward {
+    #[serde(default = "default_proto")]
+    pub proto: String,
+    /// Host address to listen on; unset means every IPv4 address. Use `::`
+    /// to listen on IPv6, which needs a QEMU whose slirp accepts bracketed
+    /// IPv6 forward addresses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_addr: Option<String>,
+    pub host_port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_addr: Option<String>,
+    pub guest_port: u16,
+}
+
+pub fn default_proto() -> String {
+    "tcp".to_string()
+}
+
+/// Splits `addr:port`, `[v6addr]:port` or `port`.
+fn split_addr_port(spec: &str) -> Result<(Option<String>, u16), String> {
+    let (addr, port) = match spec.rsplit_once(':') {
+        Some((addr, port)) => (addr.trim_start_matches('[').trim_end_matches(']'), port),
+        None => ("", spec),
+    };
+    let port = port.parse().map_err(|_| format!("invalid port in '{}'", spec))?;
+    Ok(((!addr.is_empty()).then(|| addr.to_string()), port))
+}
+
+/// Parses a comma-separated list of forwards, as typed at prompts.
+pub fn parse_forwards(specs: &str) -> Result<Vec<HostForward>, String> {
+    specs.split(',').map(str::trim).filter(|s| !s.is_empty()).map(HostForward::parse).collect()
+}
+
+/// Brackets IPv6 literals the way QEMU expects in hostfwd rules.
+fn fwd_addr(addr: &Option<String>) -> String {
+    match addr {
+        Some(a) if a.contains(':') => format!("[{}]", a),
+        Some(a) => a.clone(),
+        None => String::new(),
+    }
+}
+
+impl HostForward {
+    /// Parses QEMU's own syntax: `[tcp|udp:][hostaddr:]hostport-[guestaddr:]guestport`,
+    /// with IPv6 addresses in brackets, e.g. `tcp:[::]:2222-:22`. `2222->22`
+    /// and `2222→22` are accepted as shorthand.
+    pub fn parse(spec: &str) -> Result<HostForward, String> {
+        let spec = spec.replace("->", "-").replace('→', "-");
+        let spec = spec.as_str();
+        let (proto, rest) = match spec.split_once(':') {
+            Some((p @ ("tcp" | "udp"), rest)) => (p.to_string(), rest),
+            _ => (default_proto(), spec),
+        };
+        let (host, guest) = rest
+            .split_once('-')
+            .ok_or_else(|| format!("expected host-guest in '{}'", spec))?;
+        let (host_addr, host_port) = split_addr_port(host)?;
+        let (guest_addr, guest_port) = split_addr_port(guest)?;
+        Ok(HostForward { proto, host_addr, host_port, guest_addr, guest_port })
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{}:{}:{}-{}:{}",
+            self.proto,
+            fwd_addr(&self.host_addr),
+            self.host_port,
+            fwd_addr(&self.guest_addr),
+            self.guest_port
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NicSpec {
+    #[serde(flatten)]
+    pub backend: NetBackend,
+    pub mac: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impairment: Option<Impairment>,
+    /// Paravirtual NIC; without it the guest gets an emulated e1000, which
+    /// every OS can drive but which is far slower.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virtio: Option<VirtioNet>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct VirtioNet {
+    /// Move the data path of bridged NICs into the host kernel's vhost-net.
+    #[serde(default = "default_true")]
+    pub vhost: bool,
+    /// Queue pairs for bridged NICs; unset gives one per vCPU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queues: Option<u32>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// NIC models as templates, recipes and `--nic-model` name them.
+pub const NIC_MODELS: [&str; 2] = ["virtio", "e1000"];
+
+/// The `virtio` setting of a NIC of `model`, with vhost on and one queue
+/// pair per vCPU.
+pub fn parse_nic_model(model: &str) -> Result<Option<VirtioNet>, String> {
+    match model {
+        "virtio" | "virtio-net" => Ok(Some(VirtioNet { vhost: true, queues: None })),
+        "e1000" => Ok(None),
+        _ => Err(format!("unknown NIC model '{}'; use {}", model, NIC_MODELS.join(" or "))),
+    }
+}
+
+impl VirtioNet {
+    pub fn describe(&self) -> String {
+        let queues = self.queues.map_or("queues per vCPU".to_string(), |q| format!("{} queue(s)", q));
+        format!("virtio, {}{}", queues, if self.vhost { ", vhost" } else { "" })
+    }
+}
+
+/// Persistent tap for a bridged virtio NIC. qemu-bridge-helper cannot open
+/// multiqueue taps, so these are created up front; the MAC keeps the name
+/// unique and within the 15-character limit.
+fn virtio_tap(nic: &NicSpec) -> String {
+    format!("vt{}", nic.mac.replace(':', ""))
+}
+
+/// Creates the multiqueue taps of a VM's bridged virtio NICs, owned by the
+/// invoking user so QEMU can open them unprivileged.
+fn ensure_taps(config: &VMConfig, vm: &VMInfo) -> Result<(), String> {
+    for nic in vm.nics.iter().filter(|n| n.virtio.is_some()) {
+        let Some(bridge) = nic_bridge(config, nic) else { continue };
+        let tap = virtio_tap(nic);
+        if !link_exists(&tap) {
+            let uid = run(ShellCommand::new("id").arg("-u"))?;
+            run(ShellCommand::new("ip").args(["tuntap", "add", "dev", &tap, "mode", "tap", "multi_queue", "user", uid.trim()]))?;
+        }
+        run(ShellCommand::new("ip").args(["link", "set", &tap, "master", bridge, "up"]))?;
+    }
+    Ok(())
+}
+
+/// vhost=on makes QEMU fail outright when it cannot open the device, so it
+/// is only asked for when that will work.
+pub fn vhost_usable() -> bool {
+    fs::OpenOptions::new().read(true).write(true).open("/dev/vhost-net").is_ok()
+}
+
+/// netem settings for a NIC. They shape the tap's egress, i.e. traffic
+/// travelling towards the guest; impair both ends of a link for symmetric
+/// behaviour.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Impairment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_ms: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter_ms: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loss_pct: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reorder_pct: Option<f32>,
+}
+
+impl Impairment {
+    pub fn is_empty(&self) -> bool {
+        *self == Impairment::default()
+    }
+
+    /// Arguments after `tc qdisc replace dev <tap> root netem`.
+    fn netem_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        // netem only reorders delayed packets, so reordering implies a delay.
+        let delay = self.delay_ms.or(self.reorder_pct.map(|_| 10));
+        if let Some(delay) = delay {
+            args.push("delay".to_string());
+            args.push(format!("{}ms", delay));
+            if let Some(jitter) = self.jitter_ms {
+                args.push(format!("{}ms", jitter));
+            }
+        }
+        if let Some(loss) = self.loss_pct {
+            args.push("loss".to_string());
+            args.push(format!("{}%", loss));
+        }
+        if let Some(reorder) = self.reorder_pct {
+            args.push("reorder".to_string());
+            args.push(format!("{}%", reorder));
+        }
+        args
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(d) = self.delay_ms {
+            parts.push(format!("delay {}ms", d));
+        }
+        if let Some(j) = self.jitter_ms {
+            parts.push(format!("jitter {}ms", j));
+        }
+        if let Some(l) = self.loss_pct {
+            parts.push(format!("loss {}%", l));
+        }
+        if let Some(r) = self.reorder_pct {
+            parts.push(format!("reorder {}%", r));
+        }
+        parts.join(", ")
+    }
+}
+
+/// Tap interfaces held by a QEMU process, in the order its netdevs opened
+/// them. Read from the `iff:` line the kernel prints for tun file
+/// descriptors.
+pub fn process_taps(pid: u32) -> Vec<String> {
+    let mut fds: Vec<(u32, String)> = Vec::new();
+    let Ok(entries) = fs::read_dir(format!("/proc/{}/fdinfo", pid)) else {
+        return Vec::new();
+    };
+    for entry in entries.flatten() {
+        let Ok(fd) = entry.file_name().to_string_lossy().parse::<u32>() else { continue };
+        if let Ok(info) = fs::read_to_string(entry.path())
+            && let Some(tap) = info.lines().find_map(|l| l.strip_prefix("iff:"))
+        {
+            fds.push((fd, tap.trim().to_string()));
+        }
+    }
+    fds.sort();
+    // Multiqueue taps hold one descriptor per queue.
+    let mut taps: Vec<String> = Vec::new();
+    for (_, tap) in fds {
+        if !taps.contains(&tap) {
+            taps.push(tap);
+        }
+    }
+    taps
+}
+
+/// Applies (or clears) each NIC's impairment on a running VM's taps.
+/// Returns how many NICs were shaped.
+pub fn apply_impairments(config: &VMConfig, vm: &VMInfo, pid: u32) -> Result<usize, String> {
+    let taps = process_taps(pid);
+    let mut tap_iter = taps.iter();
+    let mut shaped = 0;
+    for (i, nic) in vm.nics.iter().enumerate() {
+        // Only bridge-backed NICs own a tap; others have nothing to shape.
+        if nic_bridge(config, nic).is_none() {
+            if nic.impairment.as_ref().is_some_and(|imp| !imp.is_empty()) {
+                warn!("NIC {} of '{}' has no tap device; impairments need a bridged NIC.", i, vm.name);
+            }
+            continue;
+        }
+        let Some(tap) = tap_iter.next() else {
+            return Err(format!("could not find the tap device of NIC {}", i));
+        };
+        match &nic.impairment {
+            Some(imp) if !imp.is_empty() => {
+                let mut cmd = ShellCommand::new("tc");
+                cmd.args(["qdisc", "replace", "dev", tap, "root", "netem"]).args(imp.netem_args());
+                run(&mut cmd)?;
+                shaped += 1;
+            }
+            // Removing a qdisc that is not there fails harmlessly.
+            _ => {
+                if let Err(e) = run(ShellCommand::new("tc").args(["qdisc", "del", "dev", tap, "root"])) {
+                    debug!("no qdisc to remove on {}: {}", tap, e);
+                }
+            }
+        }
+    }
+    Ok(shaped)
+}
+
+impl NetBackend {
+    /// Parses `user`, `passt`, `bridge:<name>` or a managed network name.
+    pub fn parse(spec: &str, config: &VMConfig) -> Result<NetBackend, String> {
+        if spec.is_empty() || spec == "user" {
+            return Ok(NetBackend::User { ipv6_net: None, hostfwd: Vec::new() });
+        }
+        if spec == "passt" {
+            return Ok(NetBackend::Passt { forwards: Vec::new() });
+        }
+        if let Some(bridge) = spec.strip_prefix("bridge:") {
+            return Ok(NetBackend::Bridge { bridge: bridge.to_string() });
+        }
+        if config.networks.contains_key(spec) {
+            return Ok(NetBackend::Network { network: spec.to_string() });
+        }
+        Err(format!("unknown network '{}'", spec))
+    }
+
+    /// Port forwards of the backends that have them.
+    pub fn forwards(&self) -> Option<&Vec<HostForward>> {
+        match self {
+            NetBackend::User { hostfwd, .. } => Some(hostfwd),
+            NetBackend::Passt { forwards } => Some(forwards),
+            NetBackend::Bridge { .. } | NetBackend::Network { .. } => None,
+        }
+    }
+
+    pub fn forwards_mut(&mut self) -> Option<&mut Vec<HostForward>> {
+        match self {
+            NetBackend::User { hostfwd, .. } => Some(hostfwd),
+            NetBackend::Passt { forwards } => Some(forwards),
+            NetBackend::Bridge { .. } | NetBackend::Network { .. } => None,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            NetBackend::User { ipv6_net, hostfwd } => {
+                let mut out = "user".to_string();
+                if let Some(net) = ipv6_net {
+                    out.push_str(&format!(", ipv6 {}", net));
+                }
+                for fwd in hostfwd {
+                    out.push_str(&format!(", fwd {}", fwd.describe()));
+                }
+                out
+            }
+            NetBackend::Passt { forwards } => {
+                let mut out = "passt".to_string();
+                for fwd in forwards {
+                    out.push_str(&format!(", fwd {}", fwd.describe()));
+                }
+                out
+            }
+            NetBackend::Bridge { bridge } => format!("bridge:{}", bridge),
+            NetBackend::Network { network } => format!("network:{}", network),
+        }
+    }
+}
+
+fn passt_socket(vm_name: &str, nic: usize) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join(format!("passt-net{}.sock", nic))
+}
+
+fn passt_pidfile(vm_name: &str, nic: usize) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join(format!("passt-net{}.pid", nic))
+}
+
+/// passt's `-t`/`-u` spec, `[addr/]host_port:guest_port`. The guest has a
+/// single address, so a guest address in the forward is meaningless.
+fn passt_forward(fwd: &HostForward) -> Vec<String> {
+    let flag = if fwd.proto == "udp" { "-u" } else { "-t" };
+    let addr = fwd.host_addr.as_ref().map(|a| format!("{}/", a)).unwrap_or_default();
+    vec![flag.to_string(), format!("{}{}:{}", addr, fwd.host_port, fwd.guest_port)]
+}
+
+/// Starts a passt for every passt NIC of the VM. Each serves one QEMU
+/// connection and exits with it; a leftover one from an earlier run that
+/// never connected is killed first.
+fn start_passt(vm: &VMInfo) -> Result<(), String> {
+    for (i, nic) in vm.nics.iter().enumerate() {
+        let NetBackend::Passt { forwards } = &nic.backend else { continue };
+        let pidfile = passt_pidfile(&vm.name, i);
+        if let Some(pid) = fs::read_to_string(&pidfile).ok().and_then(|p| p.trim().parse::<u32>().ok()) {
+            // A stale pidfile names a process that is long gone.
+            if let Err(e) = run(ShellCommand::new("kill").arg(pid.to_string())) {
+                debug!("old passt {} already gone: {}", pid, e);
+            }
+        }
+        let socket = passt_socket(&vm.name, i);
+        let _ = fs::remove_file(&socket);
+        let mut cmd = ShellCommand::new("passt");
+        cmd.args(["--one-off", "--socket"]).arg(&socket).arg("--pid").arg(&pidfile);
+        for fwd in forwards {
+            if fwd.guest_addr.is_some() {
+                warn!("VM '{}': passt ignores the guest address of forward {}", vm.name, fwd.describe());
+            }
+            cmd.args(passt_forward(fwd));
+        }
+        run(&mut cmd).map_err(|e| format!("cannot start passt for NIC {} (is passt installed?): {}", i, e))?;
+    }
+    Ok(())
+}
+
+/// Locally administered QEMU-range MAC that stays stable once stored.
+pub fn generate_mac(vm_name: &str, index: usize) -> String {
+    let mut hasher = DefaultHasher::new();
+    vm_name.hash(&mut hasher);
+    index.hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+    let h = hasher.finish().to_be_bytes();
+    format!("52:54:00:{:02x}:{:02x}:{:02x}", h[0], h[1], h[2])
+}
+
+/// Host bridge a NIC's frames cross, if any; user-mode and isolated NICs
+/// never touch a host interface.
+pub fn nic_bridge<'a>(config: &'a VMConfig, nic: &'a NicSpec) -> Option<&'a str> {
+    match &nic.backend {
+        NetBackend::User { .. } | NetBackend::Passt { .. } => None,
+        NetBackend::Bridge { bridge } => Some(bridge),
+        NetBackend::Network { network } => config
+            .networks
+            .get(network)
+            .filter(|def| def.isolated.is_none())
+            .map(|def| def.bridge.as_str()),
+    }
+}
+
+/// `-netdev`/`-device` pairs for a VM's configured NICs. VMs without NICs
+/// keep QEMU's implicit default network.
+/// `vhost_net` is whether `/dev/vhost-net` can be opened.
+pub fn nic_args(config: &VMConfig, vm: &VMInfo, vhost_net: bool) -> Vec<String> {
+    let mut args = Vec::new();
+    let vcpus = vm.threads.parse::<u32>().unwrap_or(1).max(1);
+    for (i, nic) in vm.nics.iter().enumerate() {
+        let id = format!("net{}", i);
+        if let Some(virtio) = &nic.virtio
+            && nic_bridge(config, nic).is_some()
+        {
+            let queues = virtio.queues.unwrap_or(vcpus).max(1);
+            let mut netdev = format!("tap,id={},ifname={},script=no,downscript=no", id, virtio_tap(nic));
+            if queues > 1 {
+                netdev.push_str(&format!(",queues={}", queues));
+            }
+            if virtio.vhost {
+                if vhost_net {
+                    netdev.push_str(",vhost=on");
+                } else {
+                    warn!("VM '{}': /dev/vhost-net is not accessible, NIC {} runs without vhost", vm.name, i);
+                }
+            }
+            let mut device = format!("virtio-net-pci,netdev={},mac={}", id, nic.mac);
+            if queues > 1 {
+                // One vector per queue in each direction, plus config and control.
+                device.push_str(&format!(",mq=on,vectors={}", 2 * queues + 2));
+            }
+            args.extend(["-netdev".to_string(), netdev, "-device".to_string(), device]);
+            continue;
+        }
+        let netdev = match &nic.backend {
+            NetBackend::User { ipv6_net, hostfwd } => {
+                let mut netdev = format!("user,id={}", id);
+                if let Some(net) = ipv6_net {
+                    netdev.push_str(&format!(",ipv6-net={}", net));
+                }
+                for fwd in hostfwd {
+                    netdev.push_str(&format!(",hostfwd={}", fwd.describe()));
+                }
+                netdev
+            }
+            NetBackend::Passt { .. } => format!(
+                "stream,id={},server=off,addr.type=unix,addr.path={}",
+                id,
+                passt_socket(&vm.name, i).display()
+            ),
+            NetBackend::Bridge { bridge } => format!("bridge,id={},br={}", id, bridge),
+            NetBackend::Network { network } => match config.networks.get(network) {
+                Some(NetworkDef { isolated: Some(IsolatedLink::Socket { mcast }), .. }) => {
+                    format!("socket,id={},mcast={},localaddr=127.0.0.1", id, mcast)
+                }
+                Some(NetworkDef { isolated: Some(IsolatedLink::Vde { switch }), .. }) => {
+                    format!("vde,id={},sock={}", id, switch)
+                }
+                Some(def) => format!("bridge,id={},br={}", id, def.bridge),
+                None => {
+                    warn!("VM '{}': network '{}' is not defined, NIC {} skipped", vm.name, network, i);
+                    continue;
+                }
+            },
+        };
+        args.push("-netdev".to_string());
+        args.push(netdev);
+        args.push("-device".to_string());
+        let model = if nic.virtio.is_some() { "virtio-net-pci" } else { "e1000" };
+        args.push(format!("{},netdev={},mac={}", model, id, nic.mac));
+    }
+    args
+}
+
+fn network_manager_running() -> bool {
+    run(ShellCommand::new("nmcli").args(["-t", "-f", "RUNNING", "general"]))
+        .map(|out| out.trim() == "running")
+        .unwrap_or(false)
+}
+
+fn nm_connection_exists(name: &str) -> bool {
+    run(ShellCommand::new("nmcli").args(["-t", "-f", "NAME", "connection", "show"]))
+        .map(|out| out.lines().any(|l| l == name))
+        .unwrap_or(false)
+}
+
+pub fn link_exists(name: &str) -> bool {
+    Path::new("/sys/class/net").join(name).exists()
+}
+
+fn allow_bridge(bridge: &str) -> Result<(), String> {
+    let line = format!("allow {}", bridge);
+    let existing = fs::read_to_string(BRIDGE_ACL).unwrap_or_default();
+    if existing.lines().any(|l| l.trim() == line) {
+        return Ok(());
+    }
+    if let Some(dir) = Path::new(BRIDGE_ACL).parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    }
+    let mut content = existing;
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(&line);
+    content.push('\n');
+    fs::write(BRIDGE_ACL, content).map_err(|e| format!("cannot update {}: {}", BRIDGE_ACL, e))
+}
+
+fn disallow_bridge(bridge: &str) {
+    let line = format!("allow {}", bridge);
+    if let Ok(existing) = fs::read_to_string(BRIDGE_ACL) {
+        let kept: Vec<&str> = existing.lines().filter(|l| l.trim() != line).collect();
+        let mut content = kept.join("\n");
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        let _ = fs::write(BRIDGE_ACL, content);
+    }
+}
+
+/// Creates a Linux bridge for bridged VMs, optionally enslaving a physical
+/// uplink. Uses NetworkManager when it manages the host so the bridge
+/// survives reboots and NM does not fight the change; plain `ip` otherwise.
+pub fn bridge_create(bridge: &str, uplink: Option<&str>) -> Result<(), String> {
+    if link_exists(bridge) {
+        return Err(format!("interface {} already exists", bridge));
+    }
+    if let Some(uplink) = uplink
+        && !link_exists(uplink)
+    {
+        return Err(format!("uplink {} does not exist", uplink));
+    }
+
+    if network_manager_running() {
+        run(ShellCommand::new("nmcli").args([
+            "connection", "add", "type", "bridge", "ifname", bridge, "con-name", bridge,
+            "bridge.stp", "no",
+        ]))?;
+        if let Some(uplink) = uplink {
+            let slave = format!("{}-port-{}", bridge, uplink);
+            run(ShellCommand::new("nmcli").args([
+                "connection", "add", "type", "bridge-slave", "ifname", uplink, "master", bridge,
+                "con-name", &slave,
+            ]))?;
+        }
+        run(ShellCommand::new("nmcli").args(["connection", "up", bridge]))?;
+    } else {
+        run(ShellCommand::new("ip").args(["link", "add", "name", bridge, "type", "bridge"]))?;
+        if let Some(uplink) = uplink {
+            run(ShellCommand::new("ip").args(["link", "set", uplink, "master", bridge]))?;
+        }
+        run(ShellCommand::new("ip").args(["link", "set", bridge, "up"]))?;
+        if uplink.is_some() {
+            println!("Note: host addresses on the uplink now belong on {}.", bridge);
+            println!("Re-run DHCP (e.g. 'dhclient {}') or move static addresses to keep connectivity.", bridge);
+        }
+    }
+
+    allow_bridge(bridge)?;
+    println!("Bridge {} is ready for bridged VMs.", bridge);
+    Ok(())
+}
+
+pub fn bridge_teardown(bridge: &str) -> Result<(), String> {
+    if network_manager_running() && nm_connection_exists(bridge) {
+        let prefix = format!("{}-port-", bridge);
+        let slaves: Vec<String> = run(ShellCommand::new("nmcli").args(["-t", "-f", "NAME", "connection", "show"]))?
+            .lines()
+            .filter(|l| l.starts_with(&prefix))
+            .map(str::to_string)
+            .collect();
+        for slave in slaves {
+            run(ShellCommand::new("nmcli").args(["connection", "delete", &slave]))?;
+        }
+        run(ShellCommand::new("nmcli").args(["connection", "delete", bridge]))?;
+    } else {
+        if !link_exists(bridge) {
+            return Err(format!("bridge {} does not exist", bridge));
+        }
+        // Release ports first so uplinks get their own link state back.
+        if let Ok(ports) = fs::read_dir(Path::new("/sys/class/net").join(bridge).join("brif")) {
+            for port in ports.flatten() {
+                let port = port.file_name().to_string_lossy().to_string();
+                run(ShellCommand::new("ip").args(["link", "set", &port, "nomaster"]))?;
+            }
+        }
+        run(ShellCommand::new("ip").args(["link", "delete", bridge, "type", "bridge"]))?;
+    }
+    disallow_bridge(bridge);
+    println!("Bridge {} removed.", bridge);
+    Ok(())
+}
+
+/// Directory holding per-network state that must not live in the config,
+/// such as WireGuard private keys.
+fn network_state_dir(name: &str) -> PathBuf {
+    PathBuf::from(crate::get_vm_folder()).join(".networks").join(name)
+}
+
+/// Registers a bridge network; the caller fills in NAT and DHCP on the
+/// returned definition.
+pub fn network_create<'a>(config: &'a mut VMConfig, name: &str) -> Result<&'a mut NetworkDef, String> {
+    validate_network_name(config, name)?;
+    let def = NetworkDef { bridge: format!("srq-{}", name), ..Default::default() };
+    Ok(config.networks.entry(name.to_string()).or_insert(def))
+}
+
+fn validate_network_name(config: &VMConfig, name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NETWORK_NAME || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(format!("network names must be 1-{} letters, digits or '-'", MAX_NETWORK_NAME));
+    }
+    if config.networks.contains_key(name) {
+        return Err(format!("network '{}' already exists", name));
+    }
+    Ok(())
+}
+
+/// Creates a VM-only network. `vde` selects a vde_switch, anything else a
+/// multicast socket on its own UDP port.
+pub fn isolated_create(config: &mut VMConfig, name: &str, vde: bool) -> Result<(), String> {
+    validate_network_name(config, name)?;
+    let link = if vde {
+        IsolatedLink::Vde { switch: network_state_dir(name).join("vde.ctl").display().to_string() }
+    } else {
+        let used: Vec<String> = config
+            .networks
+            .values()
+            .filter_map(|d| match &d.isolated {
+                Some(IsolatedLink::Socket { mcast }) => Some(mcast.clone()),
+                _ => None,
+            })
+            .collect();
+        let port = (ISOLATED_BASE_PORT..)
+            .find(|p| !used.contains(&format!("{}:{}", ISOLATED_MCAST_GROUP, p)))
+            .ok_or("no free multicast port")?;
+        IsolatedLink::Socket { mcast: format!("{}:{}", ISOLATED_MCAST_GROUP, port) }
+    };
+    config.networks.insert(
+        name.to_string(),
+        NetworkDef { isolated: Some(link), ..Default::default() },
+    );
+    Ok(())
+}
+
+impl NetworkDef {
+    pub fn is_up(&self) -> bool {
+        match &self.isolated {
+            Some(IsolatedLink::Socket { .. }) => true,
+            Some(IsolatedLink::Vde { switch }) => Path::new(switch).join("ctl").exists(),
+            None => link_exists(&self.bridge),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match &self.isolated {
+            Some(IsolatedLink::Socket { mcast }) => format!("isolated, multicast {}", mcast),
+            Some(IsolatedLink::Vde { switch }) => format!("isolated, vde_switch {}", switch),
+            None => {
+                let addresses: Vec<&str> = self.address.iter().chain(self.address6.iter()).map(String::as_str).collect();
+                let mut out = format!(
+                    "bridge {}, {}",
+                    self.bridge,
+                    if addresses.is_empty() { "no host address".to_string() } else { addresses.join(" ") }
+                );
+                if self.nat {
+                    out.push_str(", NAT");
+                }
+                if let Some(dhcp) = &self.dhcp {
+                    out.push_str(&format!(", DHCP with {} static lease(s)", dhcp.leases.len()));
+                }
+                out
+            }
+        }
+    }
+
+    /// Network prefixes of the host addresses, e.g. `10.10.0.0/24`.
+    fn subnets(&self) -> Vec<String> {
+        self.address.iter().chain(self.address6.iter()).filter_map(|a| subnet(a)).collect()
+    }
+}
+
+fn subnet(address: &str) -> Option<String> {
+    let (ip, len) = address.split_once('/')?;
+    let len: u32 = len.parse().ok()?;
+    let network = match ip.parse::<IpAddr>().ok()? {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - len.min(32)).unwrap_or(0);
+            IpAddr::from((u32::from(v4) & mask).to_be_bytes())
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - len.min(128)).unwrap_or(0);
+            IpAddr::from((u128::from(v6) & mask).to_be_bytes())
+        }
+    };
+    Some(format!("{}/{}", network, len))
+}
+
+fn dnsmasq_pidfile(name: &str) -> PathBuf {
+    network_state_dir(name).join("dnsmasq.pid")
+}
+
+fn kill_pidfile(pidfile: &Path) -> Result<(), String> {
+    if let Ok(pid) = fs::read_to_string(pidfile) {
+        let _ = fs::remove_file(pidfile);
+        run(ShellCommand::new("kill").arg(pid.trim()))?;
+    }
+    Ok(())
+}
+
+/// (Re)starts the network's dnsmasq so lease changes take effect.
+pub fn dhcp_restart(name: &str, def: &NetworkDef) -> Result<(), String> {
+    let Some(dhcp) = &def.dhcp else {
+        return Ok(());
+    };
+    let pidfile = dnsmasq_pidfile(name);
+    kill_pidfile(&pidfile)?;
+    fs::create_dir_all(network_state_dir(name)).map_err(|e| e.to_string())?;
+
+    let mut cmd = ShellCommand::new("dnsmasq");
+    cmd.args(["--strict-order", "--bind-interfaces", "--except-interface=lo"])
+        .arg(format!("--interface={}", def.bridge))
+        .arg(format!("--pid-file={}", pidfile.display()))
+        .arg(format!("--dhcp-leasefile={}", network_state_dir(name).join("dnsmasq.leases").display()));
+    if let Some(range) = &dhcp.range {
+        cmd.arg(format!("--dhcp-range={},12h", range));
+    }
+    if def.address6.is_some() {
+        // The constructor takes the prefix from the bridge's own address.
+        let range6 = match &dhcp.range6 {
+            Some(range6) => format!("{},constructor:{},ra-stateful,64,12h", range6, def.bridge),
+            None => format!("::,constructor:{},ra-stateless", def.bridge),
+        };
+        cmd.arg(format!("--dhcp-range={}", range6)).arg("--enable-ra");
+    }
+    for lease in &dhcp.leases {
+        let mut host = lease.mac.clone();
+        if let Some(ip) = &lease.ipv4 {
+            host.push_str(&format!(",{}", ip));
+        }
+        if let Some(ip) = &lease.ipv6 {
+            host.push_str(&format!(",[{}]", ip));
+        }
+        cmd.arg(format!("--dhcp-host={}", host));
+    }
+    run(&mut cmd)?;
+    Ok(())
+}
+
+/// Links the host's IPv6 default routes go out of.
+fn ipv6_uplinks() -> Vec<String> {
+    let routes = run(ShellCommand::new("ip").args(["-6", "route", "show", "default"])).unwrap_or_default();
+    let mut uplinks: Vec<String> = routes
+        .lines()
+        .filter_map(|route| {
+            let mut words = route.split_whitespace();
+            words.find(|w| *w == "dev").and(words.next()).map(str::to_string)
+        })
+        .collect();
+    uplinks.sort();
+    uplinks.dedup();
+    uplinks
+}
+
+/// Routing between the bridge and the uplink needs forwarding on; with
+/// IPv6 forwarding the host only keeps taking router advertisements if
+/// `accept_ra` is 2, so uplinks that take them get 2 first and the host
+/// keeps its own default route.
+fn enable_forwarding(ipv6: bool) -> Result<(), String> {
+    fs::write("/proc/sys/net/ipv4/ip_forward", "1").map_err(|e| format!("cannot enable IPv4 forwarding: {}", e))?;
+    if ipv6 {
+        for uplink in ipv6_uplinks() {
+            let accept_ra = format!("/proc/sys/net/ipv6/conf/{}/accept_ra", uplink);
+            // 0 means the host was set up without advertisements.
+            if fs::read_to_string(&accept_ra).is_ok_and(|value| value.trim() == "1") {
+                fs::write(&accept_ra, "2").map_err(|e| format!("cannot keep router advertisements on {}: {}", uplink, e))?;
+            }
+        }
+        fs::write("/proc/sys/net/ipv6/conf/all/forwarding", "1")
+            .map_err(|e| format!("cannot enable IPv6 forwarding: {}", e))?;
+    }
+    Ok(())
+}
+
+fn vde_pidfile(name: &str) -> PathBuf {
+    network_state_dir(name).join("vde.pid")
+}
+
+pub fn network_up(name: &str, def: &NetworkDef) -> Result<(), String> {
+    match &def.isolated {
+        Some(IsolatedLink::Socket { .. }) => {
+            println!("Network '{}' needs no host setup.", name);
+            return Ok(());
+        }
+        Some(IsolatedLink::Vde { switch }) => {
+            if !def.is_up() {
+                fs::create_dir_all(network_state_dir(name)).map_err(|e| e.to_string())?;
+                let pidfile = vde_pidfile(name).display().to_string();
+                run(ShellCommand::new("vde_switch").args(["-d", "-s", switch, "-p", &pidfile]))?;
+            }
+            println!("Network '{}' is up on vde_switch {}.", name, switch);
+            return Ok(());
+        }
+        None => {}
+    }
+    if !link_exists(&def.bridge) {
+        run(ShellCommand::new("ip").args(["link", "add", "name", &def.bridge, "type", "bridge"]))?;
+        if let Some(address) = &def.address {
+            run(ShellCommand::new("ip").args(["addr", "add", address, "dev", &def.bridge]))?;
+        }
+        if let Some(address) = &def.address6 {
+            // No DAD: the bridge is the only holder of the address.
+            run(ShellCommand::new("ip").args(["-6", "addr", "add", address, "dev", &def.bridge, "nodad"]))?;
+        }
+        run(ShellCommand::new("ip").args(["link", "set", &def.bridge, "up"]))?;
+    }
+    allow_bridge(&def.bridge)?;
+    if def.nat {
+        enable_forwarding(def.address6.is_some())?;
+        crate::firewall::nat_up(name, &def.bridge, &def.subnets())?;
+    }
+    dhcp_restart(name, def)?;
+    if let Some(wg) = &def.wireguard {
+        wireguard_up(name, def, wg)?;
+    }
+    println!("Network '{}' is up on {}.", name, def.bridge);
+    Ok(())
+}
+
+pub fn network_down(name: &str, def: &NetworkDef) -> Result<(), String> {
+    match &def.isolated {
+        Some(IsolatedLink::Socket { .. }) => return Ok(()),
+        Some(IsolatedLink::Vde { .. }) => {
+            kill_pidfile(&vde_pidfile(name))?;
+            println!("Network '{}' is down.", name);
+            return Ok(());
+        }
+        None => {}
+    }
+    kill_pidfile(&dnsmasq_pidfile(name))?;
+    crate::firewall::nat_down(name)?;
+    if def.wireguard.is_some() {
+        for link in [format!("vx-{}", name), format!("wg-{}", name)] {
+            if link_exists(&link) {
+                run(ShellCommand::new("ip").args(["link", "delete", &link]))?;
+            }
+        }
+    }
+    if link_exists(&def.bridge) {
+        run(ShellCommand::new("ip").args(["link", "delete", &def.bridge, "type", "bridge"]))?;
+    }
+    disallow_bridge(&def.bridge);
+    println!("Network '{}' is down.", name);
+    Ok(())
+}
+
+/// Brings up every managed network a VM's NICs use, so starting the VM does
+/// not fail on a missing bridge after a host reboot.
+pub fn ensure_networks(config: &VMConfig, vm: &VMInfo) -> Result<(), String> {
+    for nic in &vm.nics {
+        if let NetBackend::Network { network } = &nic.backend
+            && let Some(def) = config.networks.get(network)
+            && !def.is_up()
+        {
+            network_up(network, def)?;
+        }
+    }
+    ensure_taps(config, vm)?;
+    start_passt(vm)
+}
+
+/// Creates (once) this host's WireGuard key for a network and returns the
+/// public half to hand to peers.
+pub fn wireguard_init(name: &str, listen_port: u16, tunnel_address: &str, vni: u32) -> Result<(WireGuardLink, String), String> {
+    let dir = network_state_dir(name);
+    fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let key_file = dir.join("wg.key");
+    if !key_file.exists() {
+        let key = run(ShellCommand::new("wg").arg("genkey"))?;
+        fs::write(&key_file, key.trim()).map_err(|e| format!("cannot write key: {}", e))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600));
+        }
+    }
+    let public_key = wireguard_public_key(&key_file)?;
+    let link = WireGuardLink {
+        interface: format!("wg-{}", name),
+        private_key_file: key_file.display().to_string(),
+        listen_port,
+        tunnel_address: tunnel_address.to_string(),
+        vni,
+        peers: Vec::new(),
+    };
+    Ok((link, public_key))
+}
+
+/// The public half of the key in `key_file`: 32 bytes in base64, as `wg`
+/// writes keys.
+pub fn wireguard_public_key(key_file: &Path) -> Result<String, String> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let private = fs::read(key_file).map_err(|e| format!("cannot read {}: {}", key_file.display(), e))?;
+    let mut child = ShellCommand::new("wg")
+        .arg("pubkey")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run wg: {}", e))?;
+    child
+        .stdin
+        .take()
+        .ok_or("wg stdin unavailable")?
+        .write_all(&private)
+        .map_err(|e| e.to_string())?;
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("wg pubkey rejected {}: {}", key_file.display(), String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    let public = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if public.len() != 44 || !public.ends_with('=') {
+        return Err(format!("wg pubkey gave '{}' for {}, not a WireGuard key", public, key_file.display()));
+    }
+    Ok(public)
+}
+
+fn wireguard_up(name: &str, def: &NetworkDef, wg: &WireGuardLink) -> Result<(), String> {
+    if !link_exists(&wg.interface) {
+        run(ShellCommand::new("ip").args(["link", "add", &wg.interface, "type", "wireguard"]))?;
+        run(ShellCommand::new("ip").args(["addr", "add", &wg.tunnel_address, "dev", &wg.interface]))?;
+    }
+    let port = wg.listen_port.to_string();
+    run(ShellCommand::new("wg").args([
+        "set", &wg.interface, "listen-port", &port, "private-key", &wg.private_key_file,
+    ]))?;
+    for peer in &wg.peers {
+        let allowed = format!("{}/32", peer.tunnel_ip);
+        let mut cmd = ShellCommand::new("wg");
+        cmd.args(["set", &wg.interface, "peer", &peer.public_key, "allowed-ips", &allowed, "persistent-keepalive", "25"]);
+        if let Some(endpoint) = &peer.endpoint {
+            cmd.args(["endpoint", endpoint]);
+        }
+        run(&mut cmd)?;
+    }
+    run(ShellCommand::new("ip").args(["link", "set", &wg.interface, "up"]))?;
+
+    let vxlan = format!("vx-{}", name);
+    if !link_exists(&vxlan) {
+        let local = wg.tunnel_address.split('/').next().unwrap_or(&wg.tunnel_address);
+        let vni = wg.vni.to_string();
+        run(ShellCommand::new("ip").args([
+            "link", "add", &vxlan, "type", "vxlan", "id", &vni, "dstport", "4789", "local", local,
+            "dev", &wg.interface, "nolearning",
+        ]))?;
+        // Head-end replication: flood BUM traffic to every peer.
+        for peer in &wg.peers {
+            run(ShellCommand::new("bridge").args([
+                "fdb", "append", "00:00:00:00:00:00", "dev", &vxlan, "dst", &peer.tunnel_ip,
+            ]))?;
+        }
+        let mtu = WIREGUARD_VXLAN_MTU.to_string();
+        run(ShellCommand::new("ip").args(["link", "set", &vxlan, "mtu", &mtu, "master", &def.bridge, "up"]))?;
+        println!(
+            "Guests on '{}' should use an MTU of {} to cross the WireGuard link.",
+            name, WIREGUARD_VXLAN_MTU
+        );
+    }
+    Ok(())
+}
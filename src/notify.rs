@@ -0,0 +1,104 @@
+use crate::run;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::process::Command as ShellCommand;
+use tracing::error;
+
+/// Where notifications go and which events are worth one.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Notifications {
+    /// Pop up a desktop notification through `notify-send`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub desktop: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<Webhook>,
+    /// Event kinds to send (see `Event::kind`); empty means all of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub url: String,
+    /// `slack`, `discord` or `generic`; decides the JSON body.
+    #[serde(default = "default_flavor")]
+    pub flavor: String,
+}
+
+fn default_flavor() -> String {
+    "generic".to_string()
+}
+
+pub const FLAVORS: [&str; 3] = ["generic", "slack", "discord"];
+
+/// Something that happened to a VM that its owner may want to hear about.
+pub enum Event<'a> {
+    /// QEMU went away without SRQemu stopping it.
+    Crashed { vm: &'a str },
+    DiskNearlyFull { vm: &'a str, mountpoint: &'a str, used_pct: u64 },
+    JobFailed { vm: &'a str, job: &'a str, error: &'a str },
+    UpdateFinished { vm: &'a str, ok: bool },
+}
+
+impl Event<'_> {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Crashed { .. } => "crashed",
+            Event::DiskNearlyFull { .. } => "disk-full",
+            Event::JobFailed { .. } => "job-failed",
+            Event::UpdateFinished { .. } => "update-finished",
+        }
+    }
+
+    fn vm(&self) -> &str {
+        match self {
+            Event::Crashed { vm } | Event::DiskNearlyFull { vm, .. } | Event::JobFailed { vm, .. } | Event::UpdateFinished { vm, .. } => vm,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Event::Crashed { vm } => format!("VM '{}' stopped unexpectedly", vm),
+            Event::DiskNearlyFull { vm, mountpoint, used_pct } => format!("VM '{}': {} is {}% full", vm, mountpoint, used_pct),
+            Event::JobFailed { vm, job, error } => format!("VM '{}': scheduled command '{}' failed: {}", vm, job, error),
+            Event::UpdateFinished { vm, ok: true } => format!("VM '{}': guest packages updated", vm),
+            Event::UpdateFinished { vm, ok: false } => format!("VM '{}': guest package update failed", vm),
+        }
+    }
+}
+
+pub const EVENT_KINDS: [&str; 4] = ["crashed", "disk-full", "job-failed", "update-finished"];
+
+/// Sends an event to every configured target. Failures are reported but
+/// never interrupt what raised the event.
+pub fn send(config: &Notifications, event: Event) {
+    if !config.events.is_empty() && !config.events.iter().any(|e| e == event.kind()) {
+        return;
+    }
+    let message = event.message();
+    if config.desktop
+        && let Err(e) = run(ShellCommand::new("notify-send").args(["-a", "SRQemu", "SRQemu", &message]))
+    {
+        error!("Desktop notification failed: {}", e);
+    }
+    for hook in &config.webhooks {
+        let body = match hook.flavor.as_str() {
+            "slack" => json!({ "text": message }),
+            "discord" => json!({ "content": message }),
+            _ => json!({ "event": event.kind(), "vm": event.vm(), "message": message }),
+        };
+        let result = run(ShellCommand::new("curl").args([
+            "-fsS",
+            "--max-time",
+            "10",
+            "-H",
+            "Content-Type: application/json",
+            "-d",
+            &body.to_string(),
+            &hook.url,
+        ]));
+        if let Err(e) = result {
+            error!("Webhook {} failed: {}", hook.url, e);
+        }
+    }
+}
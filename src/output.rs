@@ -0,0 +1,36 @@
+//! How results reach the terminal. `--plain`, or a terminal announcing
+//! itself as `dumb`, asks for output screen readers and dumb terminals
+//! handle well: no colors, progress bars or charts, and instead of
+//! aligned tables one line per item with each value named.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+/// Settles the mode for this process, from `--plain` and `TERM`.
+pub fn init(plain: bool) {
+    let dumb = std::env::var("TERM").is_ok_and(|term| term == "dumb");
+    PLAIN.store(plain || dumb, Ordering::Relaxed);
+}
+
+pub fn plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
+
+/// A table row as one line: the subject, then each named value, skipping
+/// those with nothing to say.
+pub fn line<N: AsRef<str>>(subject: &str, values: &[(N, String)]) -> String {
+    let values: Vec<String> =
+        values.iter().filter(|(_, value)| !value.is_empty() && value != "-").map(|(name, value)| format!("{} {}", name.as_ref(), value)).collect();
+    if values.is_empty() { subject.to_string() } else { format!("{}: {}", subject, values.join(", ")) }
+}
+
+/// The title of the main menu.
+pub fn title(text: &str) {
+    if plain() { println!("\n{}", text) } else { println!("\n=== {} ===", text) }
+}
+
+/// The title of a submenu.
+pub fn heading(text: &str) {
+    if plain() { println!("\n{}", text) } else { println!("\n--- {} ---", text) }
+}
@@ -0,0 +1,52 @@
+use crate::adopt;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where QEMU writes its pid for VMs SRQemu launched.
+pub fn path(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("qemu.pid")
+}
+
+pub fn launch_args(vm_name: &str) -> Vec<String> {
+    vec!["-pidfile".to_string(), path(vm_name).display().to_string()]
+}
+
+/// The pid QEMU recorded, if that process is still the QEMU that wrote it.
+/// A pid reused after a crash fails the command line check, and the stale
+/// file is removed.
+pub fn read(vm_name: &str) -> Option<u32> {
+    let file = path(vm_name);
+    let pid: u32 = fs::read_to_string(&file).ok()?.trim().parse().ok()?;
+    // Windows has no cheap look at another process's command line, so
+    // there the program it runs has to be QEMU.
+    #[cfg(windows)]
+    let ours = crate::process::image(pid).is_some_and(|image| {
+        image.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+            let name = name.to_ascii_lowercase();
+            name.starts_with("qemu-system-") && name.ends_with(".exe")
+        })
+    });
+    #[cfg(unix)]
+    let ours = adopt::cmdline(pid).is_some_and(|args| adopt::is_qemu(&args) && args.contains(&file.display().to_string()));
+    if ours {
+        Some(pid)
+    } else {
+        let _ = fs::remove_file(&file);
+        None
+    }
+}
+
+/// VMs launched before SRQemu passed `-pidfile` are found by their QMP
+/// socket, whose path is unique to the VM folder.
+pub fn find_by_socket(vm_name: &str) -> Option<u32> {
+    let socket = crate::qmp::socket_path(vm_name).display().to_string();
+    fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+        let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+        let args = adopt::cmdline(pid)?;
+        (adopt::is_qemu(&args) && !args.iter().any(|a| a == "-pidfile") && args.iter().any(|a| a.contains(&socket))).then_some(pid)
+    })
+}
+
+pub fn remove(vm_name: &str) {
+    let _ = fs::remove_file(path(vm_name));
+}
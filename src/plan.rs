@@ -0,0 +1,181 @@
+//! Capacity planning: how the configured VMs compare with what the host
+//! has, and whether a set of them fits alongside what already runs.
+
+use crate::config::{VMConfig, VMInfo};
+use crate::guestdisk::{self, human_size};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// Memory QEMU itself needs per VM beyond the guest's RAM, roughly.
+const QEMU_OVERHEAD: u64 = 256 << 20;
+/// vCPUs per host thread above which guests start waiting for CPU time.
+const CPU_RATIO_WARN: f64 = 4.0;
+/// Memory the host keeps for itself when judging whether VMs fit.
+const HOST_RESERVE: u64 = 1 << 30;
+
+/// What the host has, read from /proc and the VM directory's filesystem.
+pub struct Capacity {
+    pub memory: u64,
+    pub memory_available: u64,
+    pub cpus: usize,
+    pub disk: u64,
+    pub disk_free: u64,
+}
+
+impl Capacity {
+    pub fn probe() -> Capacity {
+        let meminfo = fs::read_to_string("/proc/meminfo").unwrap_or_default();
+        let field = |key: &str| {
+            meminfo
+                .lines()
+                .find_map(|l| l.strip_prefix(key))
+                .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+                .unwrap_or(0)
+                * 1024
+        };
+        let (disk, disk_free) = guestdisk::fs_usage(Path::new(&crate::get_vm_folder())).unwrap_or((0, 0));
+        Capacity {
+            memory: field("MemTotal:"),
+            memory_available: field("MemAvailable:"),
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            disk,
+            disk_free,
+        }
+    }
+}
+
+/// What one VM asks for.
+pub struct Demand {
+    pub name: String,
+    pub memory: u64,
+    pub vcpus: usize,
+    /// Size the guest sees.
+    pub disk: u64,
+    /// Bytes the image takes on the host now.
+    pub disk_used: u64,
+    pub running: bool,
+}
+
+impl Demand {
+    pub fn of(vm: &VMInfo) -> Demand {
+        let (disk, disk_used) = image_sizes(&vm.disk_path()).unwrap_or((0, 0));
+        Demand {
+            name: vm.name.clone(),
+            memory: crate::size::memory_bytes(&vm.memory).unwrap_or(0),
+            vcpus: vm.threads.parse().unwrap_or(1),
+            disk,
+            disk_used,
+            running: crate::vm_running(&vm.name),
+        }
+    }
+
+    /// Bytes the image can still grow by.
+    fn disk_growth(&self) -> u64 {
+        self.disk.saturating_sub(self.disk_used)
+    }
+}
+
+/// Virtual and allocated size of a disk image, also while a VM has it open.
+fn image_sizes(disk: &str) -> Option<(u64, u64)> {
+    let out = crate::runner::run_read_only(crate::runner::command("qemu-img").args(["info", "--output=json", "-U", disk])).ok()?;
+    let info: Value = serde_json::from_str(&out).ok()?;
+    Some((info["virtual-size"].as_u64()?, info["actual-size"].as_u64().unwrap_or(0)))
+}
+
+fn ratio(demand: u64, capacity: u64) -> f64 {
+    if capacity == 0 { 0.0 } else { demand as f64 / capacity as f64 }
+}
+
+/// Prints configured totals against the host with their overcommit
+/// ratios, and what stands out.
+pub fn report(config: &VMConfig, host: &Capacity) {
+    let mut vms: Vec<&VMInfo> = config.vms.values().collect();
+    vms.sort_by(|a, b| a.name.cmp(&b.name));
+    let demands: Vec<Demand> = vms.into_iter().map(Demand::of).collect();
+    let memory: u64 = demands.iter().map(|d| d.memory).sum();
+    let vcpus: usize = demands.iter().map(|d| d.vcpus).sum();
+    let disk: u64 = demands.iter().map(|d| d.disk).sum();
+    let growth: u64 = demands.iter().map(Demand::disk_growth).sum();
+
+    let rows = [
+        ("memory", human_size(memory), human_size(host.memory), ratio(memory, host.memory)),
+        ("vCPUs", vcpus.to_string(), host.cpus.to_string(), ratio(vcpus as u64, host.cpus as u64)),
+        ("disk", human_size(disk), human_size(host.disk), ratio(disk, host.disk)),
+    ];
+    if crate::output::plain() {
+        for (what, configured, available, ratio) in rows {
+            println!("{}", crate::output::line(what, &[("configured", configured), ("host", available), ("ratio", format!("{:.2}x", ratio))]));
+        }
+    } else {
+        println!("{:<8} {:>12} {:>12} {:>8}", "", "CONFIGURED", "HOST", "RATIO");
+        for (what, configured, available, ratio) in rows {
+            println!("{:<8} {:>12} {:>12} {:>7.2}x", what, configured, available, ratio);
+        }
+    }
+
+    let mut notes = Vec::new();
+    if memory > host.memory {
+        notes.push(format!("Memory is overcommitted: all VMs at once need {} more than the host has.", human_size(memory - host.memory)));
+    }
+    if ratio(vcpus as u64, host.cpus as u64) > CPU_RATIO_WARN {
+        notes.push(format!("More than {} vCPUs per host thread; busy guests will wait for CPU time.", CPU_RATIO_WARN));
+    }
+    if growth > host.disk_free {
+        notes.push(format!(
+            "Disks can still grow by {} but only {} is free; a full host disk pauses the VMs writing to it.",
+            human_size(growth),
+            human_size(host.disk_free)
+        ));
+    }
+    if let Some(largest) = demands.iter().max_by_key(|d| d.memory).filter(|d| d.memory + HOST_RESERVE > host.memory) {
+        notes.push(format!("'{}' alone asks for more memory than the host can spare.", largest.name));
+    }
+    println!();
+    if notes.is_empty() {
+        println!("Everything configured fits on this host at once.");
+    }
+    for note in notes {
+        println!("! {}", note);
+    }
+}
+
+/// Whether `vms` can start together next to what runs now, explaining
+/// why not. Memory decides; CPU and disk only warn.
+pub fn simulate(config: &VMConfig, vms: &[&VMInfo], host: &Capacity) -> bool {
+    let demands: Vec<Demand> = vms.iter().map(|vm| Demand::of(vm)).collect();
+    let starting: Vec<&Demand> = demands.iter().filter(|d| !d.running).collect();
+    for demand in demands.iter().filter(|d| d.running) {
+        println!("'{}' already runs.", demand.name);
+    }
+    if starting.is_empty() {
+        println!("Nothing to start.");
+        return true;
+    }
+    let memory: u64 = starting.iter().map(|d| d.memory + QEMU_OVERHEAD).sum();
+    let spare = host.memory_available.saturating_sub(HOST_RESERVE);
+    println!(
+        "Starting {} needs about {} of memory; {} is available after keeping {} for the host.",
+        starting.iter().map(|d| format!("'{}'", d.name)).collect::<Vec<_>>().join(", "),
+        human_size(memory),
+        human_size(spare),
+        human_size(HOST_RESERVE)
+    );
+
+    let running: usize = config.vms.values().filter(|vm| crate::vm_running(&vm.name)).map(|vm| vm.threads.parse::<usize>().unwrap_or(1)).sum();
+    let vcpus = starting.iter().map(|d| d.vcpus).sum::<usize>() + running;
+    if ratio(vcpus as u64, host.cpus as u64) > CPU_RATIO_WARN {
+        println!("! {} vCPUs would share {} host threads.", vcpus, host.cpus);
+    }
+    let growth: u64 = starting.iter().map(|d| d.disk_growth()).sum();
+    if growth > host.disk_free {
+        println!("! Their disks can grow by {}, more than the {} free.", human_size(growth), human_size(host.disk_free));
+    }
+
+    if memory > spare {
+        println!("No: {} short of memory.", human_size(memory - spare));
+        return false;
+    }
+    println!("Yes, with {} to spare.", human_size(spare - memory));
+    true
+}
@@ -0,0 +1,188 @@
+use crate::config::{Settings, VMConfig, VMInfo};
+use crate::{guestcron, qmp};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+use tracing::error;
+
+const PSI_FILE: &str = "/proc/pressure/memory";
+const SERVICE: &str = "srqemu-memory-watch.service";
+
+/// What to do to a low-priority VM when host memory runs short. VMs without
+/// one are never touched.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PressureAction {
+    /// Shrink the guest through virtio-balloon; it keeps running.
+    Balloon,
+    /// Stop the vCPUs. Guest memory stays allocated but becomes cold, so
+    /// the kernel can swap it out instead of OOM-killing a QEMU.
+    Pause,
+}
+
+impl PressureAction {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "balloon" => Some(PressureAction::Balloon),
+            "pause" => Some(PressureAction::Pause),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PressureAction::Balloon => "balloon",
+            PressureAction::Pause => "pause",
+        }
+    }
+}
+
+/// Records that the watcher relieved a VM, holding the action taken, so a
+/// watcher that was restarted can still give the memory back.
+pub fn relieved_file(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("pressure-relieved")
+}
+
+/// Adds the balloon device for VMs that may be shrunk.
+pub fn launch_args(vm: &VMInfo) -> Vec<String> {
+    match vm.memory_pressure {
+        Some(PressureAction::Balloon) => vec!["-device".to_string(), "virtio-balloon-pci,id=balloon0".to_string()],
+        _ => Vec::new(),
+    }
+}
+
+/// The `full avg10` figure: share of the last ten seconds in which every
+/// non-idle task was stalled on memory.
+pub fn read_psi() -> Result<f64, String> {
+    let text = fs::read_to_string(PSI_FILE).map_err(|e| format!("cannot read {} (kernel without PSI?): {}", PSI_FILE, e))?;
+    text.lines()
+        .find(|l| l.starts_with("full "))
+        .and_then(|l| l.split_whitespace().find_map(|f| f.strip_prefix("avg10=")))
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| format!("unexpected format in {}", PSI_FILE))
+}
+
+fn relieve(vm: &VMInfo, action: PressureAction, settings: &Settings) -> Result<(), String> {
+    match action {
+        PressureAction::Balloon => {
+            let full = crate::size::memory_bytes(&vm.memory)?;
+            let target = full / 100 * crate::runprofile::balloon_pct(settings).clamp(1, 100);
+            qmp::command(&vm.name, "balloon", Some(json!({ "value": target }))).map(|_| ())
+        }
+        PressureAction::Pause => qmp::command(&vm.name, "stop", None).map(|_| ()),
+    }
+}
+
+fn restore(vm: &VMInfo, action: PressureAction) -> Result<(), String> {
+    match action {
+        PressureAction::Balloon => {
+            let full = crate::size::memory_bytes(&vm.memory)?;
+            qmp::command(&vm.name, "balloon", Some(json!({ "value": full })))?;
+        }
+        PressureAction::Pause => {
+            qmp::command(&vm.name, "cont", None)?;
+            crate::after_resume(vm);
+        }
+    }
+    let _ = fs::remove_file(relieved_file(&vm.name));
+    Ok(())
+}
+
+/// Gives back what an earlier watcher took and never returned, e.g.
+/// because it was stopped or crashed while pressure was high.
+fn restore_leftovers(config: &VMConfig) {
+    for vm in config.vms.values() {
+        let file = relieved_file(&vm.name);
+        let Some(action) = fs::read_to_string(&file).ok().and_then(|text| PressureAction::parse(text.trim())) else {
+            continue;
+        };
+        if !crate::vm_running(&vm.name) {
+            let _ = fs::remove_file(&file);
+            continue;
+        }
+        match restore(vm, action) {
+            Ok(()) => println!("VM '{}' restored after an earlier watcher relieved it.", vm.name),
+            Err(e) => error!("Failed to restore VM '{}': {}", vm.name, e),
+        }
+    }
+}
+
+/// Watches host memory pressure until killed. Above the threshold one more
+/// low-priority VM is relieved per interval, ballooned ones before paused
+/// ones; once pressure falls below half the threshold they are given their
+/// memory back one at a time, last relieved first.
+pub fn watch(config: &VMConfig) {
+    restore_leftovers(config);
+    let settings = &config.settings;
+    let interval = Duration::from_secs(settings.pressure_interval.max(1));
+    let mut candidates: Vec<(&VMInfo, PressureAction)> =
+        config.vms.values().filter_map(|vm| vm.memory_pressure.map(|a| (vm, a))).collect();
+    candidates.sort_by_key(|(vm, action)| (*action == PressureAction::Pause, vm.name.clone()));
+    let mut relieved: Vec<(&VMInfo, PressureAction)> = Vec::new();
+
+    println!(
+        "Watching memory pressure (threshold {:.1}%, {} VM(s) eligible).",
+        settings.pressure_threshold,
+        candidates.len()
+    );
+    loop {
+        let psi = match read_psi() {
+            Ok(psi) => psi,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        if psi >= settings.pressure_threshold {
+            let next = candidates
+                .iter()
+                .find(|(vm, _)| !relieved.iter().any(|(r, _)| r.name == vm.name) && crate::vm_running(&vm.name));
+            if let Some(&(vm, action)) = next {
+                match relieve(vm, action, settings) {
+                    Ok(()) => {
+                        println!("Memory pressure {:.1}%: {:?} applied to VM '{}'.", psi, action, vm.name);
+                        if let Err(e) = fs::write(relieved_file(&vm.name), action.name()) {
+                            error!("Cannot record that VM '{}' was relieved: {}", vm.name, e);
+                        }
+                        relieved.push((vm, action));
+                    }
+                    Err(e) => error!("Failed to relieve VM '{}': {}", vm.name, e),
+                }
+            }
+        } else if psi < settings.pressure_threshold / 2.0
+            && let Some((vm, action)) = relieved.pop()
+            && crate::vm_running(&vm.name)
+        {
+            match restore(vm, action) {
+                Ok(()) => println!("Memory pressure {:.1}%: VM '{}' restored.", psi, vm.name),
+                Err(e) => error!("Failed to restore VM '{}': {}", vm.name, e),
+            }
+        }
+        sleep(interval);
+    }
+}
+
+/// Runs the watcher as a systemd user service that starts with the session.
+pub fn install_service() -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("cannot locate this binary: {}", e))?;
+    let unit = format!(
+        "[Unit]\nDescription=SRQemu memory pressure watcher\n\n[Service]\nExecStart={} memory-watch\nRestart=on-failure\n\n[Install]\nWantedBy=default.target\n",
+        exe.display()
+    );
+    let dir = guestcron::unit_dir()?;
+    fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    fs::write(dir.join(SERVICE), unit).map_err(|e| e.to_string())?;
+    guestcron::systemctl(&["daemon-reload"])?;
+    // Restart so a running watcher picks up changed policies.
+    guestcron::systemctl(&["enable", SERVICE])?;
+    guestcron::systemctl(&["restart", SERVICE])
+}
+
+pub fn uninstall_service() -> Result<(), String> {
+    let _ = guestcron::systemctl(&["disable", "--now", SERVICE]);
+    let _ = fs::remove_file(guestcron::unit_dir()?.join(SERVICE));
+    guestcron::systemctl(&["daemon-reload"])
+}
@@ -0,0 +1,105 @@
+//! Launching, finding and ending QEMU and its helpers, per host platform.
+//! On Unix a launched process gets its own process group, so Ctrl-C in
+//! the terminal does not reach it, and is ended with SIGKILL. On Windows
+//! it gets a new process group through `CREATE_NEW_PROCESS_GROUP`, is
+//! checked and ended through the process APIs, and QEMU is found in
+//! Program Files, where its installer puts it without touching PATH.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Detaches `cmd` from the terminal's process group before it is spawned.
+#[cfg(unix)]
+pub fn detach(cmd: &mut Command) -> &mut Command {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0)
+}
+
+#[cfg(windows)]
+pub fn detach(cmd: &mut Command) -> &mut Command {
+    use std::os::windows::process::CommandExt;
+    use windows_sys::Win32::System::Threading::CREATE_NEW_PROCESS_GROUP;
+    cmd.creation_flags(CREATE_NEW_PROCESS_GROUP)
+}
+
+/// The program to run for `name`: the name itself, for a PATH lookup,
+/// unless Windows has QEMU installed where its installer puts it.
+pub fn program(name: &str) -> PathBuf {
+    #[cfg(windows)]
+    if name.starts_with("qemu-") {
+        let roots = ["ProgramFiles", "ProgramW6432", "ProgramFiles(x86)"].iter().filter_map(std::env::var_os);
+        if let Some(found) = roots.map(|root| PathBuf::from(root).join("qemu").join(format!("{}.exe", name))).find(|path| path.is_file()) {
+            return found;
+        }
+    }
+    PathBuf::from(name)
+}
+
+/// Whether a process with this pid exists.
+#[cfg(unix)]
+pub fn alive(pid: u32) -> bool {
+    std::path::Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+#[cfg(windows)]
+pub fn alive(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION};
+    // SAFETY: the handle is checked before use and closed after it.
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            return false;
+        }
+        let mut code = 0u32;
+        let running = GetExitCodeProcess(handle, &mut code) != 0 && code == STILL_ACTIVE as u32;
+        CloseHandle(handle);
+        running
+    }
+}
+
+/// The full path of the program the process runs, for telling a VM's
+/// QEMU from whatever got its pid after a reboot.
+#[cfg(windows)]
+pub fn image(pid: u32) -> Option<PathBuf> {
+    use std::os::windows::ffi::OsStringExt;
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::Threading::{OpenProcess, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION, QueryFullProcessImageNameW};
+    let mut name = [0u16; 1024];
+    let mut len = name.len() as u32;
+    // SAFETY: the handle is checked before use and closed after it; `len`
+    // holds the buffer's size going in and the name's length coming out.
+    let found = unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            return None;
+        }
+        let found = QueryFullProcessImageNameW(handle, PROCESS_NAME_WIN32, name.as_mut_ptr(), &mut len) != 0;
+        CloseHandle(handle);
+        found
+    };
+    found.then(|| PathBuf::from(std::ffi::OsString::from_wide(&name[..len as usize])))
+}
+
+/// Ends the process at once, without giving it a chance to clean up.
+#[cfg(unix)]
+pub fn kill(pid: u32) -> Result<(), String> {
+    crate::run(Command::new("kill").arg("-9").arg(pid.to_string())).map(|_| ())
+}
+
+#[cfg(windows)]
+pub fn kill(pid: u32) -> Result<(), String> {
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::Threading::{OpenProcess, PROCESS_TERMINATE, TerminateProcess};
+    // SAFETY: the handle is checked before use and closed after it.
+    unsafe {
+        let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
+        if handle.is_null() {
+            return Err(format!("cannot open process {}: {}", pid, std::io::Error::last_os_error()));
+        }
+        let ended = TerminateProcess(handle, 1) != 0;
+        let error = std::io::Error::last_os_error();
+        CloseHandle(handle);
+        if ended { Ok(()) } else { Err(format!("cannot end process {}: {}", pid, error)) }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::config::VMInfo;
+use crate::run;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::process::Command as ShellCommand;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A read-only image a VM depends on (its ISO or a backing file of its
+/// disk), as it was when the VM started relying on it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ImageRecord {
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+    /// Modification time in seconds; a cheap first check before rehashing
+    /// multi-GB files on every start.
+    pub mtime: u64,
+    pub registered: u64,
+    /// Where the image came from, e.g. `created`, `imported from libvirt`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub source: String,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn size_and_mtime(path: &str) -> Result<(u64, u64), String> {
+    let meta = fs::metadata(path).map_err(|e| format!("cannot stat {}: {}", path, e))?;
+    let mtime = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()).unwrap_or(0);
+    Ok((meta.len(), mtime))
+}
+
+pub fn sha256(path: &str) -> Result<String, String> {
+    let out = run(ShellCommand::new("sha256sum").arg(path))?;
+    out.split_whitespace()
+        .next()
+        .map(str::to_string)
+        .ok_or_else(|| format!("sha256sum printed nothing for {}", path))
+}
+
+/// Images below the VM's own disk in its qcow2 backing chain.
+fn backing_files(disk: &str) -> Vec<String> {
+    let Ok(out) = crate::runner::run_read_only(crate::runner::command("qemu-img").args(["info", "--backing-chain", "--output=json", "-U", disk])) else {
+        return Vec::new();
+    };
+    let chain: Vec<Value> = serde_json::from_str(&out).unwrap_or_default();
+    chain.iter().skip(1).filter_map(|i| i["filename"].as_str().map(str::to_string)).collect()
+}
+
+/// The files a VM reads but should never change: its ISO and the backing
+/// files of its disk.
+pub fn tracked_images(vm: &VMInfo) -> Vec<String> {
+    let mut images = Vec::new();
+    if !vm.iso.is_empty() {
+        images.push(vm.iso_path());
+    }
+    if !vm.disk.is_empty() {
+        images.extend(backing_files(&vm.disk_path()));
+    }
+    images
+}
+
+/// Hashes every tracked image that is not registered yet (or whose record
+/// is being replaced) and returns how many were recorded.
+pub fn register(vm: &mut VMInfo, source: &str, replace: bool) -> Result<usize, String> {
+    let mut recorded = 0;
+    for path in tracked_images(vm) {
+        if !replace && vm.images.iter().any(|r| r.path == path) {
+            continue;
+        }
+        println!("Hashing {}...", path);
+        let (size, mtime) = size_and_mtime(&path)?;
+        let record = ImageRecord { sha256: sha256(&path)?, size, mtime, registered: now_secs(), source: source.to_string(), path };
+        vm.images.retain(|r| r.path != record.path);
+        vm.images.push(record);
+        recorded += 1;
+    }
+    Ok(recorded)
+}
+
+/// Checks every registered image, rehashing only those whose size or
+/// modification time moved. Returns one warning per changed or missing
+/// image, plus one for each image in use that was never registered.
+pub fn verify(vm: &VMInfo) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for record in &vm.images {
+        let Ok((size, mtime)) = size_and_mtime(&record.path) else {
+            warnings.push(format!("{} is missing", record.path));
+            continue;
+        };
+        if size == record.size && mtime == record.mtime {
+            continue;
+        }
+        match sha256(&record.path) {
+            Ok(hash) if hash == record.sha256 => {}
+            Ok(hash) => warnings.push(format!(
+                "{} changed on disk since registration (sha256 {} instead of {})",
+                record.path, hash, record.sha256
+            )),
+            Err(e) => warnings.push(format!("cannot verify {}: {}", record.path, e)),
+        }
+    }
+    for path in tracked_images(vm) {
+        if !vm.images.iter().any(|r| r.path == path) {
+            warnings.push(format!("{} is not registered; its contents are unverified", path));
+        }
+    }
+    warnings
+}
@@ -0,0 +1,197 @@
+use crate::ipc::{self, Stream};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long to wait for QEMU to answer a single command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection attempts while a just-started QEMU is still creating its
+/// socket.
+const CONNECT_ATTEMPTS: u32 = 5;
+
+/// A QMP session on a VM's control socket, past capability negotiation.
+pub struct Qmp {
+    reader: BufReader<Stream>,
+    writer: Stream,
+    /// Set once the socket failed; the session cannot be used again.
+    broken: bool,
+}
+
+pub fn socket_path(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("qmp.sock")
+}
+
+/// Arguments that expose the QMP socket when launching a VM.
+pub fn launch_args(vm_name: &str) -> Vec<String> {
+    vec![
+        "-qmp".to_string(),
+        ipc::qmp(&socket_path(vm_name)),
+    ]
+}
+
+/// Opens a JSON-lines session on `path`. Shared with the guest agent,
+/// which speaks the same framing.
+pub fn open(path: &Path) -> Result<Qmp, String> {
+    let stream = ipc::connect(path)
+        .map_err(|e| format!("cannot connect to {}: {}", path.display(), e))?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT)).map_err(|e| e.to_string())?;
+    let writer = stream.try_clone().map_err(|e| e.to_string())?;
+    Ok(Qmp { reader: BufReader::new(stream), writer, broken: false })
+}
+
+/// The QMP socket of the VM's QEMU process: ours if SRQemu launched it,
+/// else whatever its command line serves, for a QEMU adopted or
+/// restarted by hand. Such a QEMU leaves our old socket file behind with
+/// no listener.
+fn control_socket(vm_name: &str) -> Option<PathBuf> {
+    let pid = crate::vm_pid(vm_name)?;
+    let ours = socket_path(vm_name);
+    // Windows has no /proc; the port follows from the path, so
+    // connecting tells whether QEMU serves it.
+    if cfg!(windows) {
+        return Some(ours);
+    }
+    let args = crate::adopt::cmdline(pid)?;
+    if args.iter().any(|arg| arg.contains(&*ours.to_string_lossy())) {
+        return Some(ours);
+    }
+    let _ = fs::remove_file(&ours);
+    let theirs = crate::adopt::qmp_socket(&args)?;
+    if theirs.is_relative() {
+        return fs::read_link(format!("/proc/{}/cwd", pid)).ok().map(|cwd| cwd.join(theirs));
+    }
+    Some(theirs)
+}
+
+/// Removes sockets and state files a VM leaves behind when QEMU exits
+/// without SRQemu noticing, so they are not mistaken for live ones.
+pub fn cleanup_runtime(vm_name: &str) {
+    for path in [socket_path(vm_name), crate::agent::socket_path(vm_name), crate::callback::socket_path(vm_name), crate::serial::socket_path(vm_name)] {
+        let _ = fs::remove_file(path);
+    }
+    crate::capture::clear_stale(vm_name);
+    crate::display::clear(vm_name);
+    let _ = fs::remove_file(crate::pressure::relieved_file(vm_name));
+}
+
+pub fn connect(vm_name: &str) -> Result<Qmp, String> {
+    let mut last_error = String::new();
+    for attempt in 0..CONNECT_ATTEMPTS {
+        if attempt > 0 {
+            std::thread::sleep(Duration::from_millis(200 * attempt as u64));
+        }
+        if !crate::vm_running(vm_name) {
+            cleanup_runtime(vm_name);
+            return Err(format!("VM '{}' is not running", vm_name));
+        }
+        let Some(path) = control_socket(vm_name) else {
+            return Err(format!(
+                "VM '{}' runs without a QMP socket SRQemu can reach; start it with `-qmp unix:<path>,server=on,wait=off` or from SRQemu",
+                vm_name
+            ));
+        };
+        let mut qmp = match open(&path) {
+            Ok(qmp) => qmp,
+            Err(e) => {
+                last_error = e;
+                continue;
+            }
+        };
+        // The server greets first, then refuses everything until
+        // capabilities are negotiated.
+        qmp.read_message()?;
+        qmp.execute("qmp_capabilities", None)?;
+        return Ok(qmp);
+    }
+    Err(last_error)
+}
+
+/// Runs one command on a fresh session, reconnecting once if the socket
+/// drops mid-command. Only for commands that are safe to repeat.
+pub fn command(vm_name: &str, command: &str, arguments: Option<Value>) -> Result<Value, String> {
+    let mut session = connect(vm_name)?;
+    match session.execute(command, arguments.clone()) {
+        Err(_) if session.broken => connect(vm_name)?.execute(command, arguments),
+        result => result,
+    }
+}
+
+/// QEMU's run state, e.g. `running`, `paused` or `suspended`.
+pub fn run_state(vm_name: &str) -> Result<String, String> {
+    let status = command(vm_name, "query-status", None)?;
+    status.get("status").and_then(Value::as_str).map(str::to_string).ok_or_else(|| "query-status returned no state".to_string())
+}
+
+/// Whether the VM's CPUs are stopped, by `pause`, host sleep or the memory
+/// watcher.
+pub fn paused(vm_name: &str) -> bool {
+    run_state(vm_name).is_ok_and(|state| state == "paused")
+}
+
+/// Presses the virtual power button and waits up to `timeout` for the guest
+/// to shut down and QEMU to exit. Returns whether it did.
+pub fn powerdown(vm_name: &str, timeout: Duration) -> Result<bool, String> {
+    command(vm_name, "system_powerdown", None)?;
+    let started = std::time::Instant::now();
+    while started.elapsed() < timeout {
+        if !crate::vm_running(vm_name) {
+            return Ok(true);
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    Ok(false)
+}
+
+impl Qmp {
+    fn read_message(&mut self) -> Result<Value, String> {
+        let mut line = String::new();
+        let n = match self.reader.read_line(&mut line) {
+            Ok(n) => n,
+            Err(e) => {
+                self.broken = true;
+                return Err(format!("QMP read failed: {}", e));
+            }
+        };
+        if n == 0 {
+            self.broken = true;
+            return Err("QMP connection closed".to_string());
+        }
+        serde_json::from_str(&line).map_err(|e| format!("bad QMP message: {}", e))
+    }
+
+    /// Allows slow commands (e.g. saving guest RAM) more than the default
+    /// reply timeout.
+    pub fn set_reply_timeout(&mut self, timeout: Duration) -> Result<(), String> {
+        self.reader.get_ref().set_read_timeout(Some(timeout)).map_err(|e| e.to_string())
+    }
+
+    /// Runs a command and returns its `return` value, skipping any
+    /// asynchronous events that arrive in between.
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, String> {
+        let mut request = json!({ "execute": command });
+        if let Some(args) = arguments {
+            request["arguments"] = args;
+        }
+        tracing::debug!("QMP request: {}", request);
+        if let Err(e) = writeln!(self.writer, "{}", request) {
+            self.broken = true;
+            return Err(format!("QMP write failed: {}", e));
+        }
+        loop {
+            let message = self.read_message()?;
+            if let Some(ret) = message.get("return") {
+                return Ok(ret.clone());
+            }
+            if let Some(err) = message.get("error") {
+                return Err(format!(
+                    "{}: {}",
+                    command,
+                    err["desc"].as_str().unwrap_or("unknown QMP error")
+                ));
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use crate::clock::TimeSpec;
+use crate::cloudinit::{self, CloudInit};
+use crate::config::{VMConfig, VMInfo};
+use crate::display::DisplaySpec;
+use crate::images::{self, ChecksumSource};
+use crate::network::{self, NetBackend, NicSpec};
+use crate::{provenance, run};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::process::Command as ShellCommand;
+
+/// A shareable, diskless VM description: which base image to download,
+/// how to configure the machine around it and what cloud-init should do
+/// on first boot. Stored as TOML.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Recipe {
+    /// Suggested VM name.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    pub base: BaseImage,
+    #[serde(default)]
+    pub vm: RecipeVm,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloud_init: Option<CloudInit>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BaseImage {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+    /// Pins the exact image; creation fails if the download differs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Published checksum file and its detached signature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksums: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Size of the VM's disk on top of the base image, e.g. `20G`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_size: Option<String>,
+}
+
+/// Machine settings; NICs are given as `user`, `passt`, `bridge:<name>` or a
+/// managed network name, and get fresh MACs on every instantiation.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RecipeVm {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threads: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nics: Vec<String>,
+    /// Model of those NICs, `virtio` or `e1000`; unset means e1000, which
+    /// needs no guest drivers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nic_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<DisplaySpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usb: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<TimeSpec>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub guest_agent: bool,
+}
+
+/// Reads a recipe from a file or an http(s) URL.
+pub fn load(source: &str) -> Result<Recipe, String> {
+    let text = if source.starts_with("http://") || source.starts_with("https://") {
+        run(ShellCommand::new("curl").args(["-fsSL", source]))?
+    } else {
+        fs::read_to_string(crate::expand_path(source)).map_err(|e| format!("cannot read {}: {}", source, e))?
+    };
+    toml::from_str(&text).map_err(|e| format!("invalid recipe: {}", e))
+}
+
+/// The cached copy of the base image, downloading it if needed.
+fn base_image(base: &BaseImage, config: &VMConfig) -> Result<String, String> {
+    let cached = images::list().into_iter().find(|i| i.url == base.url && Path::new(&i.file).exists());
+    let image = match cached {
+        Some(image) => image,
+        None => {
+            let mut urls = vec![base.url.clone()];
+            urls.extend(base.mirrors.iter().cloned());
+            let checksums = base.checksums.clone().map(|url| ChecksumSource { url, signature_url: base.signature.clone() });
+            images::fetch(&urls, checksums.as_ref(), &config.settings)?
+        }
+    };
+    if let Some(expected) = &base.sha256
+        && !expected.eq_ignore_ascii_case(&image.sha256)
+    {
+        return Err(format!("base image {} has sha256 {}, the recipe pins {}", image.file, image.sha256, expected));
+    }
+    Ok(image.file)
+}
+
+/// Creates a VM named `name` from a recipe: a qcow2 overlay on the cached
+/// base image plus, if the recipe has one, a cloud-init seed.
+pub fn instantiate(recipe: &Recipe, name: &str, source: &str, config: &VMConfig) -> Result<VMInfo, String> {
+    if config.vms.contains_key(name) {
+        return Err(format!("a VM named '{}' already exists", name));
+    }
+    let memory = crate::size::memory(recipe.vm.memory.as_deref().unwrap_or("2G"))?;
+    let disk_size = recipe.base.disk_size.as_deref().map(crate::size::disk).transpose()?;
+    let base = base_image(&recipe.base, config)?;
+    let vm_dir = crate::vm_folder(name);
+    fs::create_dir_all(&vm_dir).map_err(|e| format!("cannot create {}: {}", vm_dir, e))?;
+    let disk = format!("{}/{}.qcow2", vm_dir, name);
+    let format = crate::nbd::image_format(&base)?;
+    let mut cmd = crate::runner::command("qemu-img");
+    cmd.args(["create", "-f", "qcow2", "-b", &base, "-F", &format, &disk]);
+    cmd.args(disk_size);
+    run(&mut cmd)?;
+
+    let spec = &recipe.vm;
+    let mut vm = VMInfo {
+        name: name.to_string(),
+        memory,
+        cpu: spec.cpu.clone().unwrap_or_else(|| "host".to_string()),
+        threads: spec.threads.clone().unwrap_or_else(|| "1".to_string()),
+        disk: crate::relative_to_folder(name, &disk),
+        display: spec.display.clone(),
+        usb: spec.usb.clone(),
+        time: spec.time.clone(),
+        guest_agent: spec.guest_agent,
+        ..Default::default()
+    };
+    let virtio = spec.nic_model.as_deref().map(network::parse_nic_model).transpose()?.flatten();
+    for (i, nic) in spec.nics.iter().enumerate() {
+        let backend = NetBackend::parse(nic, config)?;
+        vm.nics.push(NicSpec { backend, mac: network::generate_mac(name, i), impairment: None, virtio: virtio.clone() });
+    }
+    if let Some(data) = &recipe.cloud_init {
+        vm.seed = Some(crate::relative_to_folder(name, &cloudinit::create_seed(name, data)?));
+    }
+    provenance::register(&mut vm, &format!("recipe {}", source), false)?;
+    Ok(vm)
+}
+
+/// Describes an existing VM as a recipe. Only VMs whose disk sits on a
+/// downloaded base image can be shared this way.
+pub fn export(vm: &VMInfo) -> Result<Recipe, String> {
+    let backing = provenance::tracked_images(vm)
+        .into_iter()
+        .find(|path| *path != vm.iso_path())
+        .ok_or_else(|| format!("the disk of '{}' has no base image", vm.name))?;
+    let image = images::list()
+        .into_iter()
+        .find(|i| i.file == backing)
+        .ok_or_else(|| format!("base image {} was not downloaded through SRQemu, so it has no URL", backing))?;
+    let cloud_init = match &vm.seed {
+        Some(_) => {
+            let dir = Path::new(&crate::vm_folder(&vm.name)).join("cloud-init");
+            let user_data = fs::read_to_string(dir.join("user-data")).map_err(|e| format!("cannot read user-data: {}", e))?;
+            Some(CloudInit { user_data, meta_data: None })
+        }
+        None => None,
+    };
+    let nics = vm
+        .nics
+        .iter()
+        .map(|nic| match &nic.backend {
+            NetBackend::User { .. } => "user".to_string(),
+            NetBackend::Passt { .. } => "passt".to_string(),
+            NetBackend::Bridge { bridge } => format!("bridge:{}", bridge),
+            NetBackend::Network { network } => network.clone(),
+        })
+        .collect();
+    Ok(Recipe {
+        name: vm.name.clone(),
+        base: BaseImage { url: image.url, sha256: Some(image.sha256), ..Default::default() },
+        vm: RecipeVm {
+            memory: Some(vm.memory.clone()),
+            cpu: Some(vm.cpu.clone()),
+            threads: Some(vm.threads.clone()),
+            nics,
+            nic_model: vm.nics.iter().any(|nic| nic.virtio.is_some()).then(|| "virtio".to_string()),
+            display: vm.display.clone(),
+            usb: vm.usb.clone(),
+            time: vm.time.clone(),
+            guest_agent: vm.guest_agent,
+        },
+        cloud_init,
+    })
+}
@@ -0,0 +1,122 @@
+use crate::config::{self, VMConfig, VMInfo};
+use crate::{history, images, run};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command as ShellCommand;
+use tracing::error;
+
+/// A finished step, undone in reverse order when a later one fails.
+enum Step {
+    Moved { from: PathBuf, to: PathBuf },
+    Rebased { disk: String, backing: String, format: String },
+}
+
+/// `path` under `new` if it lies under `old`.
+fn rebase_path(path: &str, old: &Path, new: &Path) -> Option<String> {
+    let path = crate::expand_path(path);
+    let rest = Path::new(&path).strip_prefix(old).ok()?;
+    Some(new.join(rest).display().to_string())
+}
+
+/// `mv` rather than `fs::rename`, so whole folders can cross filesystems.
+fn move_path(from: &Path, to: &Path) -> Result<(), String> {
+    run(ShellCommand::new("mv").arg("-n").arg(from).arg(to)).map(|_| ())
+}
+
+fn backing_file(disk: &str) -> Option<(String, String)> {
+    let out = crate::runner::run_read_only(crate::runner::command("qemu-img").args(["info", "--output=json", "-U", disk])).ok()?;
+    let info: Value = serde_json::from_str(&out).ok()?;
+    let backing = info["backing-filename"].as_str()?.to_string();
+    let format = info["backing-filename-format"].as_str().unwrap_or("qcow2").to_string();
+    Some((backing, format))
+}
+
+/// Points a disk at a backing file without touching its data.
+fn set_backing(disk: &str, backing: &str, format: &str) -> Result<(), String> {
+    run(crate::runner::command("qemu-img").args(["rebase", "-u", "-F", format, "-b", backing, disk])).map(|_| ())
+}
+
+fn undo(steps: Vec<Step>) {
+    for step in steps.into_iter().rev() {
+        let result = match &step {
+            Step::Moved { from, to } => move_path(to, from),
+            Step::Rebased { disk, backing, format } => set_backing(disk, backing, format),
+        };
+        if let Err(e) = result {
+            error!("Rollback step failed, fix it by hand: {}", e);
+        }
+    }
+}
+
+fn rewrite_paths(vm: &mut VMInfo, old: &Path, new: &Path) {
+    for path in [&mut vm.disk, &mut vm.iso].into_iter().chain(vm.seed.as_mut()).chain(vm.cdrom.as_mut()) {
+        if let Some(moved) = rebase_path(path, old, new) {
+            *path = moved;
+        }
+    }
+    for record in vm.images.iter_mut() {
+        if let Some(moved) = rebase_path(&record.path, old, new) {
+            record.path = moved;
+        }
+    }
+}
+
+/// Moves the whole VM directory (VM folders, images, trash and state
+/// files) to `target`, or with `move_data` unset switches to a copy the
+/// user already made there. Backing file references and stored paths are
+/// rewritten; any failure puts everything back as it was.
+pub fn relocate(config: &mut VMConfig, target: &str, move_data: bool) -> Result<(), String> {
+    let old = PathBuf::from(crate::get_vm_folder());
+    let new = PathBuf::from(crate::expand_path(target));
+    if !new.is_absolute() {
+        return Err(format!("{} is not an absolute path", target));
+    }
+    if new == old || new.starts_with(&old) {
+        return Err(format!("{} is inside the current VM directory {}", new.display(), old.display()));
+    }
+    if let Some(name) = config.vms.keys().find(|name| crate::vm_running(name)) {
+        return Err(format!("VM '{}' is running; stop every VM first", name));
+    }
+    history::checkpoint(&config.settings, "relocate")?;
+    fs::create_dir_all(&new).map_err(|e| format!("cannot create {}: {}", new.display(), e))?;
+
+    let mut steps = Vec::new();
+    if move_data {
+        let entries = fs::read_dir(&old).map_err(|e| format!("cannot read {}: {}", old.display(), e))?;
+        for entry in entries.flatten() {
+            let to = new.join(entry.file_name());
+            let moved = if to.exists() { Err(format!("{} already exists", to.display())) } else { move_path(&entry.path(), &to) };
+            if let Err(e) = moved {
+                undo(steps);
+                return Err(e);
+            }
+            steps.push(Step::Moved { from: entry.path(), to });
+        }
+    } else if let Some(name) = config.vms.keys().find(|name| !new.join(name).is_dir()) {
+        return Err(format!("{} has no folder for VM '{}'; copy the data first or use --move", new.display(), name));
+    }
+
+    let mut vms = config.vms.clone();
+    crate::set_vm_dir(Some(new.display().to_string()));
+    for vm in vms.values_mut() {
+        rewrite_paths(vm, &old, &new);
+        let disk = vm.disk_path();
+        let Some((backing, format)) = backing_file(&disk) else { continue };
+        let Some(moved) = rebase_path(&backing, &old, &new) else { continue };
+        if let Err(e) = set_backing(&disk, &moved, &format) {
+            crate::set_vm_dir(config.settings.vm_dir.clone());
+            undo(steps);
+            return Err(format!("cannot update the backing file of '{}': {}", vm.name, e));
+        }
+        steps.push(Step::Rebased { disk, backing, format });
+    }
+    if let Err(e) = images::relocate_index(&old, &new) {
+        error!("Failed to update the image index: {}", e);
+    }
+
+    config.vms = vms;
+    config.settings.vm_dir = Some(new.display().to_string());
+    config::save_config(config)?;
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use crate::config::VMInfo;
+use crate::guestdisk;
+use crate::size::{self, Bare};
+use crate::{agent, nbd, qmp, run};
+use serde_json::{json, Value};
+use std::process::Command as ShellCommand;
+use std::time::Duration;
+
+/// Grows the partition holding `/` to the end of its disk and then the
+/// filesystem on it. Runs inside the guest through the agent.
+const GROW_ROOT_SCRIPT: &str = r#"set -e
+src=$(findmnt -no SOURCE /)
+fstype=$(findmnt -no FSTYPE /)
+part=$(basename "$(readlink -f "$src")")
+if [ ! -e "/sys/class/block/$part/partition" ]; then
+    echo "/ is on $src, not a plain partition (LVM or RAID?)" >&2
+    exit 2
+fi
+disk=$(lsblk -no PKNAME "/dev/$part" | head -n1)
+num=$(cat "/sys/class/block/$part/partition")
+growpart "/dev/$disk" "$num" || [ $? -eq 1 ]
+case "$fstype" in
+    ext2|ext3|ext4) resize2fs "$src" ;;
+    xfs) xfs_growfs / ;;
+    btrfs) btrfs filesystem resize max / ;;
+    *) echo "cannot grow $fstype filesystems" >&2; exit 2 ;;
+esac
+"#;
+
+/// `+10G` grows by that much; `40G` sets the new size. Shrinking is
+/// refused, since it cuts off whatever the guest stored at the end.
+fn target_size(spec: &str, current: u64) -> Result<u64, String> {
+    let size = match spec.trim().strip_prefix('+') {
+        Some(delta) => current.checked_add(size::parse(delta, Bare::Bytes)?).ok_or_else(|| format!("size '{}' is too large", spec))?,
+        None => size::parse(spec, Bare::Bytes)?,
+    };
+    match size {
+        size if size < current => Err(format!("{} is smaller than the disk ({}); shrinking is not supported", spec, guestdisk::human_size(current))),
+        size => Ok(size),
+    }
+}
+
+/// The running VM's block device whose image is `disk`, with its current
+/// virtual size.
+fn running_device(vm: &VMInfo, disk: &str) -> Result<(String, u64), String> {
+    let blocks = qmp::command(&vm.name, "query-block", None)?;
+    blocks
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|b| b["inserted"]["file"].as_str() == Some(disk))
+        .and_then(|b| Some((b["device"].as_str()?.to_string(), b["inserted"]["image"]["virtual-size"].as_u64()?)))
+        .ok_or_else(|| format!("VM '{}' has no block device for {}", vm.name, disk))
+}
+
+pub fn offline_size(disk: &str) -> Result<u64, String> {
+    let out = crate::runner::run_read_only(crate::runner::command("qemu-img").args(["info", "--output=json", disk]))?;
+    let info: Value = serde_json::from_str(&out).map_err(|e| format!("bad qemu-img output: {}", e))?;
+    info["virtual-size"].as_u64().ok_or_else(|| "qemu-img did not report a size".to_string())
+}
+
+/// Grows a VM's disk image, through QMP while it runs and with qemu-img
+/// otherwise. Returns the new size in bytes.
+pub fn resize(vm: &VMInfo, spec: &str) -> Result<u64, String> {
+    let disk = vm.disk_path();
+    if crate::vm_running(&vm.name) {
+        let (device, current) = running_device(vm, &disk)?;
+        let size = target_size(spec, current)?;
+        qmp::command(&vm.name, "block_resize", Some(json!({ "device": device, "size": size })))?;
+        Ok(size)
+    } else {
+        let size = target_size(spec, offline_size(&disk)?)?;
+        run(crate::runner::command("qemu-img").args(["resize", &disk, &size.to_string()]))?;
+        Ok(size)
+    }
+}
+
+/// Grows the guest's root partition and filesystem from inside the running
+/// guest.
+pub fn grow_guest_online(vm: &VMInfo) -> Result<String, String> {
+    let result = agent::exec(&vm.name, "/bin/sh", &["-c", GROW_ROOT_SCRIPT], Duration::from_secs(300))?;
+    if result.exit_code != 0 {
+        return Err(format!("growing inside the guest failed: {}", result.stderr.trim()));
+    }
+    Ok(result.stdout)
+}
+
+/// Grows a partition of a stopped VM's disk and its filesystem through
+/// qemu-nbd. `partition` is 1-based; unset picks the largest mountable one.
+pub fn grow_guest_offline(vm: &VMInfo, partition: Option<usize>) -> Result<(), String> {
+    if let Some(mount) = guestdisk::mounted_disk(vm) {
+        return Err(format!("disk is mounted at {}; unmount it first", mount.mountpoint));
+    }
+    let device = nbd::connect(&vm.disk_path(), false)?;
+    let partitions = device.partitions()?;
+    let index = match partition {
+        Some(n) => n,
+        None => guestdisk::largest_mountable(&partitions).ok_or("no mountable partition found")?,
+    };
+    let part = index
+        .checked_sub(1)
+        .and_then(|i| partitions.get(i))
+        .ok_or_else(|| format!("partition {} does not exist ({} found)", index, partitions.len()))?;
+    let number = part
+        .path
+        .strip_prefix(&format!("{}p", device.path))
+        .ok_or_else(|| format!("{} is not a partition of {}", part.path, device.path))?;
+
+    // growpart exits 1 when the partition already fills the disk.
+    let status = ShellCommand::new("growpart").args([&device.path, number]).status();
+    match status.map(|s| s.code()) {
+        Ok(Some(0 | 1)) => {}
+        Ok(_) => return Err(format!("growpart failed on {}", part.path)),
+        Err(e) => return Err(format!("failed to run growpart (install cloud-guest-utils): {}", e)),
+    }
+    crate::runner::best_effort(ShellCommand::new("udevadm").arg("settle"));
+
+    match part.fstype.as_str() {
+        "ext2" | "ext3" | "ext4" => {
+            // resize2fs insists on a freshly checked filesystem; e2fsck exits
+            // 1 after fixing things and 2 when only a reboot is missing,
+            // which is fine here.
+            match ShellCommand::new("e2fsck").args(["-f", "-y", &part.path]).status().map(|s| s.code()) {
+                Ok(Some(0..=2)) => {}
+                Ok(_) => return Err(format!("e2fsck found errors on {} it could not fix", part.path)),
+                Err(e) => return Err(format!("failed to run e2fsck: {}", e)),
+            }
+            run(ShellCommand::new("resize2fs").arg(&part.path))?;
+        }
+        "ntfs" => {
+            run(ShellCommand::new("ntfsresize").args(["-f", "-P", &part.path]))?;
+        }
+        "xfs" | "btrfs" => {
+            let mounted = nbd::mount(part, &nbd::scratch_dir("grow", index), false)?;
+            let mut cmd = if part.fstype == "xfs" {
+                let mut cmd = ShellCommand::new("xfs_growfs");
+                cmd.arg(&mounted.dir);
+                cmd
+            } else {
+                let mut cmd = ShellCommand::new("btrfs");
+                cmd.args(["filesystem", "resize", "max"]).arg(&mounted.dir);
+                cmd
+            };
+            run(&mut cmd)?;
+        }
+        other => return Err(format!("cannot grow {} filesystems", if other.is_empty() { "unknown" } else { other })),
+    }
+    println!("Grew {} ({}) to fill the disk.", part.path, part.fstype);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn growing_past_the_largest_size_is_an_error() {
+        assert_eq!(target_size("+1G", 1 << 30), Ok(2 << 30));
+        assert_eq!(target_size("+1G", u64::MAX - 1), Err("size '+1G' is too large".to_string()));
+        assert!(target_size("1G", 2 << 30).unwrap_err().contains("shrinking"));
+    }
+}
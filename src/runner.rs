@@ -0,0 +1,244 @@
+use crate::error::Error;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::process::{Command as ShellCommand, Output, Stdio};
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Directory for the mock backend's records; setting it switches SRQemu
+/// to the mock for this process and everything it launches.
+pub const MOCK_ENV: &str = "SRQEMU_MOCK";
+
+/// Makes the mock backend find no `/dev/kvm`.
+pub const NO_KVM_ENV: &str = "SRQEMU_MOCK_NO_KVM";
+
+/// Creates the commands for QEMU and its tools, so the real programs can
+/// be swapped for stand-ins.
+pub trait Runner: Send + Sync {
+    /// A command for `program`, ready for arguments and spawning.
+    fn command(&self, program: &str) -> ShellCommand;
+}
+
+/// Runs the installed programs.
+pub struct System;
+
+impl Runner for System {
+    fn command(&self, program: &str) -> ShellCommand {
+        ShellCommand::new(crate::process::program(program))
+    }
+}
+
+/// Runs this binary in place of `qemu-img`, `qemu-nbd`, `qemu-system-*`
+/// and `virtiofsd` (see `mockqemu`), so the create, start and stop flows
+/// work without a hypervisor. Other programs run as usual. Unix only, as
+/// the mock's QEMU speaks over Unix sockets; Windows ignores `SRQEMU_MOCK`.
+#[cfg(unix)]
+pub struct Mock {
+    exe: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl Runner for Mock {
+    fn command(&self, program: &str) -> ShellCommand {
+        use std::os::unix::process::CommandExt;
+        if !is_mocked(program) {
+            return ShellCommand::new(program);
+        }
+        let mut cmd = ShellCommand::new(&self.exe);
+        // Keeps the name, so the mock passes the `/proc` checks for QEMU.
+        cmd.arg0(program);
+        cmd
+    }
+}
+
+#[cfg(unix)]
+fn is_mocked(program: &str) -> bool {
+    program == "qemu-img" || program == "qemu-nbd" || program.starts_with("qemu-system-") || program.rsplit('/').next() == Some("virtiofsd")
+}
+
+fn current() -> &'static dyn Runner {
+    static RUNNER: OnceLock<Box<dyn Runner>> = OnceLock::new();
+    RUNNER
+        .get_or_init(|| match (std::env::var_os(MOCK_ENV), std::env::current_exe()) {
+            #[cfg(unix)]
+            (Some(_), Ok(exe)) => Box::new(Mock { exe }),
+            _ => Box::new(System),
+        })
+        .as_ref()
+}
+
+pub fn command(program: &str) -> ShellCommand {
+    current().command(program)
+}
+
+/// The program this process stands in for when the mock launched it.
+#[cfg(unix)]
+pub fn mocked_program() -> Option<String> {
+    std::env::var_os(MOCK_ENV)?;
+    let arg0 = std::env::args().next()?;
+    let program = arg0.rsplit('/').next().unwrap_or(&arg0).to_string();
+    is_mocked(&program).then_some(program)
+}
+
+/// Seconds a command may run before it is killed, by `<program>
+/// <subcommand>`, `<program>` or `default`; 0 means no limit. Copies,
+/// downloads and filesystem checks take as long as the data needs.
+const DEFAULT_TIMEOUTS: &[(&str, u64)] = &[
+    ("default", 120),
+    ("qemu-img convert", 0),
+    ("qemu-img rebase", 0),
+    ("qemu-img commit", 0),
+    ("curl", 0),
+    ("cp", 0),
+    ("mv", 0),
+    ("sha256sum", 0),
+    ("e2fsck", 0),
+    ("resize2fs", 0),
+    ("ntfsresize", 0),
+    ("btrfs", 0),
+    ("xfs_growfs", 0),
+];
+
+/// How external commands are bounded, from `[settings]`.
+pub struct Policy {
+    /// Overrides of `DEFAULT_TIMEOUTS`, keyed the same way.
+    pub timeouts: HashMap<String, u64>,
+    /// Further attempts for commands that only read state.
+    pub retries: u32,
+}
+
+static POLICY: RwLock<Option<Policy>> = RwLock::new(None);
+
+pub fn set_policy(policy: Policy) {
+    *POLICY.write().unwrap_or_else(|e| e.into_inner()) = Some(policy);
+}
+
+/// The timeout for `cmd` and the key it was found under.
+fn timeout_for(cmd: &ShellCommand) -> (Option<Duration>, String) {
+    let program = cmd.get_program().to_string_lossy();
+    let program = program.rsplit('/').next().unwrap_or(&program).to_string();
+    let keys = match cmd.get_args().next() {
+        Some(sub) => vec![format!("{} {}", program, sub.to_string_lossy()), program, "default".to_string()],
+        None => vec![program, "default".to_string()],
+    };
+    let policy = POLICY.read().unwrap_or_else(|e| e.into_inner());
+    let configured = policy.as_ref().map(|p| &p.timeouts);
+    for key in keys {
+        let secs = configured.and_then(|t| t.get(&key)).copied().or_else(|| DEFAULT_TIMEOUTS.iter().find(|(k, _)| *k == key).map(|(_, s)| *s));
+        if let Some(secs) = secs {
+            return ((secs > 0).then(|| Duration::from_secs(secs)), key);
+        }
+    }
+    (None, "default".to_string())
+}
+
+/// Collects a pipe on a thread, so a chatty command cannot block on a
+/// full pipe while it is being waited for.
+fn collect(pipe: Option<impl Read + Send + 'static>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut data = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut data);
+        }
+        data
+    })
+}
+
+/// `cmd.output()`, killing the command once `timeout` has passed; `None`
+/// then.
+fn output_within(cmd: &mut ShellCommand, timeout: Option<Duration>) -> io::Result<Option<Output>> {
+    let mut child = cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = collect(child.stdout.take());
+    let stderr = collect(child.stderr.take());
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if timeout.is_some_and(|t| started.elapsed() > t) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        // Quick commands are noticed quickly, long ones polled less often.
+        std::thread::sleep((started.elapsed() / 4).clamp(Duration::from_millis(1), Duration::from_millis(50)));
+    };
+    Ok(Some(Output { status, stdout: stdout.join().unwrap_or_default(), stderr: stderr.join().unwrap_or_default() }))
+}
+
+/// Runs a command to completion within its timeout, returning stdout or a
+/// message with stderr.
+pub fn run(cmd: &mut ShellCommand) -> Result<String, String> {
+    Ok(run_checked(cmd)?)
+}
+
+fn run_checked(cmd: &mut ShellCommand) -> Result<String, Error> {
+    let program = cmd.get_program().to_string_lossy().to_string();
+    debug!("running {} {}", program, cmd.get_args().map(|a| a.to_string_lossy()).collect::<Vec<_>>().join(" "));
+    let (timeout, key) = timeout_for(cmd);
+    let output = output_within(cmd, timeout).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::ToolMissing { program: program.clone() },
+        _ => Error::ToolFailed { program: program.clone(), message: e.to_string() },
+    })?;
+    let Some(output) = output else {
+        return Err(Error::ToolTimedOut { program, secs: timeout.map_or(0, |t| t.as_secs()), key });
+    };
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(Error::ToolFailed { program, message });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// `run` for commands that only read state, tried again after a short
+/// pause when they fail: an image briefly locked by a starting VM or a
+/// device still settling.
+pub fn run_read_only(cmd: &mut ShellCommand) -> Result<String, String> {
+    let retries = POLICY.read().unwrap_or_else(|e| e.into_inner()).as_ref().map_or(2, |p| p.retries);
+    let mut attempt = 0;
+    loop {
+        match run_checked(cmd) {
+            Err(Error::ToolMissing { program }) => return Err(Error::ToolMissing { program }.into()),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                debug!("retrying ({}/{}) after: {}", attempt, retries, e);
+                std::thread::sleep(Duration::from_millis(500 * attempt as u64));
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Runs a command whose failure does not stop the operation, such as
+/// cleanup, logging what went wrong instead of dropping it.
+pub fn best_effort(cmd: &mut ShellCommand) {
+    if let Err(e) = run(cmd) {
+        warn!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_stop_commands_and_name_their_key() {
+        let timeouts = HashMap::from([("sleep".to_string(), 1), ("qemu-img info".to_string(), 7)]);
+        set_policy(Policy { timeouts, retries: 0 });
+
+        let (timeout, key) = timeout_for(ShellCommand::new("/usr/bin/qemu-img").args(["info", "disk.qcow2"]));
+        assert_eq!((timeout, key.as_str()), (Some(Duration::from_secs(7)), "qemu-img info"));
+        let (timeout, key) = timeout_for(ShellCommand::new("qemu-img").args(["convert", "a", "b"]));
+        assert_eq!((timeout, key.as_str()), (None, "qemu-img convert"));
+        let (timeout, key) = timeout_for(ShellCommand::new("lsblk").arg("-J"));
+        assert_eq!((timeout, key.as_str()), (Some(Duration::from_secs(120)), "default"));
+
+        let started = Instant::now();
+        match run_checked(ShellCommand::new("sleep").arg("5")) {
+            Err(Error::ToolTimedOut { secs: 1, key, .. }) => assert_eq!(key, "sleep"),
+            other => panic!("expected a timeout, got {:?}", other.map_err(|e| e.to_string())),
+        }
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+}
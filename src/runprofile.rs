@@ -0,0 +1,106 @@
+use crate::config::{Settings, VMInfo};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use tracing::warn;
+
+const HUGEPAGES_MOUNT: &str = "/dev/hugepages";
+
+/// A bundle of host-dependent settings switched together, e.g. for running
+/// docked versus on battery. Unset fields leave each VM's own settings.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RunProfile {
+    /// Host power profile (or cpufreq governor) while any VM runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_power: Option<String>,
+    /// Back guest RAM with preallocated hugepages from `/dev/hugepages`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hugepages: Option<bool>,
+    /// Overrides the IOThread choice of VMs on a virtio disk bus.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iothread: Option<bool>,
+    /// Overrides `balloon_pct` for the memory watcher.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balloon_pct: Option<u64>,
+}
+
+fn builtin(name: &str) -> Option<RunProfile> {
+    match name {
+        "performance" => Some(RunProfile {
+            host_power: Some("performance".to_string()),
+            hugepages: Some(true),
+            iothread: Some(true),
+            balloon_pct: Some(75),
+        }),
+        "battery" => Some(RunProfile {
+            host_power: Some("power-saver".to_string()),
+            hugepages: Some(false),
+            iothread: Some(false),
+            balloon_pct: Some(30),
+        }),
+        _ => None,
+    }
+}
+
+/// Built-in and user-defined profile names; a user-defined profile of the
+/// same name replaces the built-in one.
+pub fn names(settings: &Settings) -> Vec<String> {
+    let mut names: Vec<String> = ["performance", "battery"].iter().map(|s| s.to_string()).collect();
+    names.extend(settings.run_profiles.keys().filter(|k| builtin(k).is_none()).cloned());
+    names.sort();
+    names
+}
+
+pub fn get(settings: &Settings, name: &str) -> Option<RunProfile> {
+    settings.run_profiles.get(name).cloned().or_else(|| builtin(name))
+}
+
+fn active(settings: &Settings) -> RunProfile {
+    settings.run_profile.as_deref().and_then(|name| get(settings, name)).unwrap_or_default()
+}
+
+/// The VM as it should run under the active profile.
+pub fn apply(settings: &Settings, vm: &VMInfo) -> VMInfo {
+    let profile = active(settings);
+    let mut vm = vm.clone();
+    if profile.host_power.is_some() {
+        vm.host_power = profile.host_power;
+    }
+    if let (Some(iothread), Some(device)) = (profile.iothread, vm.disk_device.as_mut()) {
+        device.iothread = iothread;
+    }
+    vm
+}
+
+pub fn balloon_pct(settings: &Settings) -> u64 {
+    active(settings).balloon_pct.unwrap_or(settings.balloon_pct)
+}
+
+/// Free hugepage memory in bytes, from /proc/meminfo.
+pub fn hugepages_free() -> u64 {
+    let Ok(meminfo) = fs::read_to_string("/proc/meminfo") else {
+        return 0;
+    };
+    let field = |key: &str| {
+        meminfo
+            .lines()
+            .find_map(|l| l.strip_prefix(key))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+    field("HugePages_Free:") * field("Hugepagesize:") * 1024
+}
+
+/// `free_hugepages` is `hugepages_free()` at launch, in bytes.
+pub fn launch_args(settings: &Settings, vm: &VMInfo, free_hugepages: u64) -> Vec<String> {
+    if active(settings).hugepages != Some(true) {
+        return Vec::new();
+    }
+    let needed = crate::size::memory_bytes(&vm.memory).unwrap_or(u64::MAX);
+    if free_hugepages < needed {
+        // QEMU would refuse to start rather than fall back to normal pages.
+        warn!("VM '{}': not enough free hugepages for {}; using normal pages.", vm.name, vm.memory);
+        return Vec::new();
+    }
+    vec!["-mem-path".to_string(), HUGEPAGES_MOUNT.to_string(), "-mem-prealloc".to_string()]
+}
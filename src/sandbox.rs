@@ -0,0 +1,103 @@
+use crate::config::{VMConfig, VMInfo};
+use crate::network;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+/// Restrictions QEMU applies to itself once it has set up its devices.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Hardening {
+    /// seccomp filter (`-sandbox on`) blocking obsolete syscalls, process
+    /// spawning, privilege changes and scheduler/resource tweaks.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub seccomp: bool,
+    /// Unprivileged user QEMU switches to; only works when SRQemu runs as
+    /// root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runas: Option<String>,
+    /// Directory QEMU chroots into after opening its disks and sockets;
+    /// root only, like `runas`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chroot: Option<String>,
+    /// Confine QEMU to its own files with a generated AppArmor profile.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub apparmor: bool,
+}
+
+impl Hardening {
+    pub fn is_empty(&self) -> bool {
+        *self == Hardening::default()
+    }
+}
+
+/// qemu-bridge-helper is spawned by QEMU, so bridged NICs that use it
+/// rule out denying `spawn`.
+fn needs_spawn(config: &VMConfig, vm: &VMInfo) -> bool {
+    vm.nics.iter().any(|nic| nic.virtio.is_none() && network::nic_bridge(config, nic).is_some())
+}
+
+pub fn is_root() -> bool {
+    crate::run(std::process::Command::new("id").arg("-u")).is_ok_and(|uid| uid.trim() == "0")
+}
+
+fn seccomp_options(config: &VMConfig, vm: &VMInfo) -> String {
+    let hardening = vm.hardening.as_ref();
+    let mut opts = vec!["on", "obsolete=deny", "resourcecontrol=deny"];
+    // Dropping to `runas` is itself a privilege change.
+    if hardening.is_none_or(|h| h.runas.is_none() && h.chroot.is_none()) {
+        opts.push("elevateprivileges=deny");
+    }
+    if !needs_spawn(config, vm) {
+        opts.push("spawn=deny");
+    }
+    opts.join(",")
+}
+
+pub fn launch_args(config: &VMConfig, vm: &VMInfo, root: bool) -> Vec<String> {
+    let Some(hardening) = &vm.hardening else {
+        return Vec::new();
+    };
+    let mut args = Vec::new();
+    if hardening.seccomp {
+        args.extend(["-sandbox".to_string(), seccomp_options(config, vm)]);
+    }
+    if (hardening.runas.is_some() || hardening.chroot.is_some()) && !root {
+        error!("VM '{}': runas/chroot need SRQemu to run as root; starting without them.", vm.name);
+        return args;
+    }
+    if let Some(user) = &hardening.runas {
+        args.extend(["-runas".to_string(), user.clone()]);
+    }
+    // `-chroot` is gone since QEMU 9.0; `-run-with` replaced it.
+    if let Some(dir) = &hardening.chroot {
+        args.extend(["-run-with".to_string(), format!("chroot={}", dir)]);
+    }
+    args
+}
+
+/// One line per restriction in effect, for listings.
+pub fn describe(config: &VMConfig, vm: &VMInfo) -> Vec<String> {
+    let Some(hardening) = &vm.hardening else {
+        return Vec::new();
+    };
+    let mut lines = Vec::new();
+    if hardening.seccomp {
+        let mut denied = vec!["obsolete syscalls", "scheduler and resource changes"];
+        if hardening.runas.is_none() && hardening.chroot.is_none() {
+            denied.push("privilege changes");
+        }
+        if !needs_spawn(config, vm) {
+            denied.push("spawning processes");
+        }
+        lines.push(format!("seccomp sandbox denies {}", denied.join(", ")));
+    }
+    if let Some(user) = &hardening.runas {
+        lines.push(format!("runs as user '{}'", user));
+    }
+    if let Some(dir) = &hardening.chroot {
+        lines.push(format!("confined to {}", dir));
+    }
+    if hardening.apparmor {
+        lines.push(format!("AppArmor profile {} limits it to its own files", crate::apparmor::profile_name(&vm.name)));
+    }
+    lines
+}
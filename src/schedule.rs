@@ -0,0 +1,179 @@
+//! Starting and stopping groups of VMs by time of day, through systemd user
+//! timers. A schedule only stops the VMs it started itself, so a VM someone
+//! started by hand keeps running past the stop time.
+
+use crate::config::{VMConfig, VMInfo};
+use crate::guestcron;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use tracing::warn;
+
+/// When a group of VMs comes up and goes down.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Schedule {
+    /// VM name, tag, or `all`.
+    pub target: String,
+    /// systemd calendar expression, e.g. `Mon..Fri 08:00`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Start,
+    Stop,
+}
+
+impl Phase {
+    pub fn parse(s: &str) -> Option<Phase> {
+        match s {
+            "start" => Some(Phase::Start),
+            "stop" => Some(Phase::Stop),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Start => "start",
+            Phase::Stop => "stop",
+        }
+    }
+}
+
+fn unit_name(id: &str, phase: Phase) -> String {
+    format!("srqemu-schedule-{}-{}", id, phase.name())
+}
+
+/// VMs a schedule started and may stop again, by schedule id; kept next to
+/// the manager log.
+fn claims_file() -> PathBuf {
+    crate::logging::log_file().with_file_name("scheduled.json")
+}
+
+fn claims() -> BTreeMap<String, String> {
+    fs::read_to_string(claims_file()).ok().and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default()
+}
+
+fn save_claims(claims: &BTreeMap<String, String>) {
+    let path = claims_file();
+    let written = serde_json::to_string_pretty(claims).map_err(|e| e.to_string()).and_then(|json| {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        fs::write(&path, json).map_err(|e| e.to_string())
+    });
+    if let Err(e) = written {
+        warn!("cannot write {}: {}", path.display(), e);
+    }
+}
+
+/// The schedule that started `vm_name` and will stop it, if any.
+pub fn claimed_by(vm_name: &str) -> Option<String> {
+    claims().remove(vm_name)
+}
+
+/// Called for every start; one not made by a schedule takes the VM out of
+/// scheduled stops.
+pub fn release(vm_name: &str) {
+    let mut claims = claims();
+    if claims.remove(vm_name).is_some() {
+        save_claims(&claims);
+    }
+}
+
+/// Writes and enables the timers for a schedule's start and stop times.
+pub fn install(id: &str, schedule: &Schedule) -> Result<(), String> {
+    let dir = guestcron::unit_dir()?;
+    fs::create_dir_all(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let exe = std::env::current_exe().map_err(|e| format!("cannot locate this binary: {}", e))?;
+    let mut timers = Vec::new();
+    for (phase, calendar) in [(Phase::Start, &schedule.start), (Phase::Stop, &schedule.stop)] {
+        let Some(calendar) = calendar else { continue };
+        let unit = unit_name(id, phase);
+        let service = format!(
+            "[Unit]\nDescription=SRQemu schedule '{}': {} {}\n\n[Service]\nType=oneshot\nExecStart={} schedule-run {} {}\n",
+            id,
+            phase.name(),
+            schedule.target,
+            exe.display(),
+            id,
+            phase.name()
+        );
+        let timer = format!(
+            "[Unit]\nDescription=Schedule for {}\n\n[Timer]\nOnCalendar={}\n\n[Install]\nWantedBy=timers.target\n",
+            unit, calendar
+        );
+        fs::write(dir.join(format!("{}.service", unit)), service).map_err(|e| e.to_string())?;
+        fs::write(dir.join(format!("{}.timer", unit)), timer).map_err(|e| e.to_string())?;
+        timers.push(format!("{}.timer", unit));
+    }
+    guestcron::systemctl(&["daemon-reload"])?;
+    for timer in timers {
+        guestcron::systemctl(&["enable", "--now", &timer])?;
+    }
+    Ok(())
+}
+
+pub fn uninstall(id: &str) -> Result<(), String> {
+    let dir = guestcron::unit_dir()?;
+    for phase in [Phase::Start, Phase::Stop] {
+        let unit = unit_name(id, phase);
+        let _ = guestcron::systemctl(&["disable", "--now", &format!("{}.timer", unit)]);
+        for ext in ["timer", "service"] {
+            let _ = fs::remove_file(dir.join(format!("{}.{}", unit, ext)));
+        }
+    }
+    guestcron::systemctl(&["daemon-reload"])
+}
+
+/// Entry point for the timers. Starting claims the VMs that were not
+/// running yet; stopping only touches VMs this schedule claimed.
+pub fn run(config: &VMConfig, id: &str, phase: Phase) -> Result<(), String> {
+    let schedule = config.settings.schedules.get(id).ok_or_else(|| format!("no schedule '{}'", id))?;
+    let vms: Vec<&VMInfo> = crate::update::targets(config, &schedule.target);
+    if vms.is_empty() {
+        return Err(format!("schedule '{}' matches no VM ('{}')", id, schedule.target));
+    }
+    let mut failed = Vec::new();
+    match phase {
+        Phase::Start => {
+            for vm in vms.into_iter().filter(|vm| !crate::vm_running(&vm.name)) {
+                crate::start_vm_common(config, vm, true);
+                if crate::wait_for_pid(&vm.name).is_some() {
+                    let mut claims = claims();
+                    claims.insert(vm.name.clone(), id.to_string());
+                    save_claims(&claims);
+                } else {
+                    failed.push(vm.name.clone());
+                }
+            }
+        }
+        Phase::Stop => {
+            for vm in vms {
+                if claimed_by(&vm.name).as_deref() != Some(id) {
+                    if crate::vm_running(&vm.name) {
+                        println!("Leaving '{}' running: it was started by hand.", vm.name);
+                    }
+                    continue;
+                }
+                if crate::vm_running(&vm.name)
+                    && let Err(e) = crate::stop_vm_by_name(config, &vm.name, false)
+                {
+                    warn!("Failed to stop VM '{}': {}", vm.name, e);
+                }
+                if crate::vm_running(&vm.name) {
+                    failed.push(vm.name.clone());
+                } else {
+                    release(&vm.name);
+                }
+            }
+            crate::hostpower::update(config, None);
+        }
+    }
+    if failed.is_empty() { Ok(()) } else { Err(format!("could not {} {}", phase.name(), failed.join(", "))) }
+}
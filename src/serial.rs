@@ -0,0 +1,142 @@
+//! The first serial port of every VM, on a socket in its folder, and the
+//! `console` command attaching a terminal to it: directly, or in a window
+//! of an `srqemu` tmux session with one window per VM.
+
+use std::io::{self, IsTerminal, Read, Write};
+use std::net::Shutdown;
+use std::path::PathBuf;
+use std::process::{Command as ShellCommand, Stdio};
+use std::time::Duration;
+
+/// Ctrl-], as in telnet and `virsh console`.
+const DETACH: u8 = 0x1d;
+
+/// tmux session holding the consoles SRQemu opened.
+const TMUX_SESSION: &str = "srqemu";
+
+/// How long piped input waits for the guest's last output.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub fn socket_path(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("serial.sock")
+}
+
+/// Arguments putting COM1 on the console socket instead of QEMU's default
+/// backend; the guest sees the same port.
+pub fn launch_args(vm_name: &str) -> Vec<String> {
+    vec![
+        "-chardev".to_string(),
+        format!("socket,id=srqserial0,{}", crate::ipc::chardev(&socket_path(vm_name))),
+        "-serial".to_string(),
+        "chardev:srqserial0".to_string(),
+    ]
+}
+
+/// Puts the terminal into raw mode and returns its previous settings, or
+/// None when stdin is not a terminal.
+fn raw_terminal() -> Option<String> {
+    if !io::stdin().is_terminal() {
+        return None;
+    }
+    let saved = ShellCommand::new("stty").arg("-g").stdin(Stdio::inherit()).stderr(Stdio::null()).output().ok()?;
+    if !saved.status.success() {
+        return None;
+    }
+    ShellCommand::new("stty").args(["raw", "-echo"]).stdin(Stdio::inherit()).status().ok()?;
+    Some(String::from_utf8_lossy(&saved.stdout).trim().to_string())
+}
+
+fn restore_terminal(saved: &str) {
+    let _ = ShellCommand::new("stty").arg(saved).stdin(Stdio::inherit()).status();
+}
+
+/// Connects the terminal to the VM's serial console until Ctrl-] or, for
+/// piped input, until the input runs out and the guest falls silent.
+pub fn attach(vm_name: &str) -> Result<(), String> {
+    let path = socket_path(vm_name);
+    let stream = crate::ipc::connect(&path)
+        .map_err(|e| format!("cannot connect to {}: {} (a VM started by an older SRQemu has no console socket until it restarts)", path.display(), e))?;
+    let mut reader = stream.try_clone().map_err(|e| e.to_string())?;
+    let saved = raw_terminal();
+    if saved.is_some() {
+        eprint!("Connected to the serial console of '{}'; Ctrl-] detaches.\r\n", vm_name);
+    }
+    let output = std::thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        let mut out = io::stdout();
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 || out.write_all(&buf[..n]).and_then(|()| out.flush()).is_err() {
+                break;
+            }
+        }
+    });
+    let mut writer = stream;
+    let mut input = io::stdin().lock();
+    let mut buf = [0u8; 1024];
+    let detached = loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) | Err(_) => break false,
+            Ok(n) => n,
+        };
+        let end = buf[..n].iter().position(|&b| b == DETACH);
+        if writer.write_all(&buf[..end.unwrap_or(n)]).is_err() || end.is_some() {
+            break true;
+        }
+    };
+    if let Some(saved) = &saved {
+        restore_terminal(saved);
+        eprintln!();
+    }
+    if !detached {
+        // The socket option is shared with the reading clone.
+        let _ = writer.shutdown(Shutdown::Write);
+        let _ = writer.set_read_timeout(Some(DRAIN_TIMEOUT));
+        let _ = output.join();
+    }
+    Ok(())
+}
+
+fn tmux(args: &[&str]) -> Result<bool, String> {
+    let status = ShellCommand::new("tmux").args(args).stdout(Stdio::null()).stderr(Stdio::null()).status();
+    match status {
+        Ok(status) => Ok(status.success()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(crate::error::Error::ToolMissing { program: "tmux".to_string() }.to_string()),
+        Err(e) => Err(format!("cannot run tmux: {}", e)),
+    }
+}
+
+/// Opens a window per VM in the `srqemu` tmux session, reusing windows
+/// already open, and switches to it; from inside tmux the current client
+/// switches instead of nesting.
+pub fn open_in_tmux(vm_names: &[String]) -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("cannot locate this binary: {}", e))?;
+    let exe = exe.display().to_string();
+    let mut session = tmux(&["has-session", "-t", &format!("={}", TMUX_SESSION)])?;
+    for name in vm_names {
+        let window = format!("={}:={}", TMUX_SESSION, name);
+        if session && tmux(&["list-panes", "-t", &window])? {
+            continue;
+        }
+        let created = if session {
+            tmux(&["new-window", "-d", "-t", &format!("={}:", TMUX_SESSION), "-n", name, &exe, "console", name])?
+        } else {
+            tmux(&["new-session", "-d", "-s", TMUX_SESSION, "-n", name, &exe, "console", name])?
+        };
+        if !created {
+            return Err(format!("tmux could not open a window for '{}'", name));
+        }
+        session = true;
+    }
+    if let Some(first) = vm_names.first() {
+        let _ = tmux(&["select-window", "-t", &format!("={}:={}", TMUX_SESSION, first)]);
+    }
+    if std::env::var_os("TMUX").is_some() {
+        tmux(&["switch-client", "-t", &format!("={}", TMUX_SESSION)])?;
+    } else if io::stdin().is_terminal() {
+        // Attaching takes over this terminal until the user detaches.
+        ShellCommand::new("tmux").args(["attach-session", "-t", &format!("={}", TMUX_SESSION)]).status().map_err(|e| format!("cannot run tmux: {}", e))?;
+    } else {
+        println!("Consoles are open in tmux; `tmux attach -t {}` shows them.", TMUX_SESSION);
+    }
+    Ok(())
+}
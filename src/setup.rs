@@ -0,0 +1,126 @@
+//! First-run setup: what this host offers VMs, checked once instead of
+//! assumed, and the settings asked for up front — where VMs live and what
+//! a new VM gets when nothing else says. Runs on the first interactive
+//! launch and again with `SRQemu setup`.
+
+use crate::arch::{self, Arch};
+use crate::config::{VMConfig, VMInfo};
+use crate::firmware::{self, Firmware};
+use std::path::{Path, PathBuf};
+
+/// Where VM folders live without a `vm_dir` setting.
+pub const DEFAULT_VM_DIR: &str = "~/vms";
+
+/// Where distributions install qemu-bridge-helper, which is not on PATH.
+const BRIDGE_HELPERS: [&str; 2] = ["/usr/lib/qemu/qemu-bridge-helper", "/usr/libexec/qemu-bridge-helper"];
+
+/// One thing VMs may need from the host, and what its absence means.
+pub struct Capability {
+    pub name: &'static str,
+    /// What was found, or why it is missing and what that costs.
+    pub detail: String,
+    pub found: bool,
+}
+
+fn on_path(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).map(|dir| dir.join(program)).find(|candidate| candidate.is_file())
+}
+
+/// Whether `path` runs as its owner, as the bridge helper must.
+#[cfg(unix)]
+fn setuid(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().is_ok_and(|m| m.permissions().mode() & 0o4000 != 0)
+}
+
+#[cfg(windows)]
+fn setuid(_path: &Path) -> bool {
+    false
+}
+
+fn capability(name: &'static str, found: Result<String, String>) -> Capability {
+    match found {
+        Ok(detail) => Capability { name, detail, found: true },
+        Err(detail) => Capability { name, detail, found: false },
+    }
+}
+
+/// The host's capabilities, for guests of its own architecture.
+pub fn probe() -> Vec<Capability> {
+    let host = Arch::host().unwrap_or(Arch::X86_64);
+    let qemu = host.qemu_binary();
+    let probe_vm = VMInfo { arch: Some(host), firmware: Some(Firmware::Uefi), ..Default::default() };
+    let bridge_helper = BRIDGE_HELPERS.iter().map(Path::new).find(|path| path.exists());
+    vec![
+        capability("QEMU", on_path(&qemu).map(|path| path.display().to_string()).ok_or(format!("{} is not installed; no VM can start", qemu))),
+        capability(
+            "KVM",
+            match arch::kvm_unavailable() {
+                None => Ok("usable".to_string()),
+                Some(reason) => Err(format!("{}; VMs will be emulated with TCG, many times slower", reason)),
+            },
+        ),
+        capability(
+            "UEFI firmware",
+            firmware::installed_code(&probe_vm).map(str::to_string).ok_or("not installed; VMs can only boot with BIOS".to_string()),
+        ),
+        capability(
+            "swtpm",
+            on_path("swtpm").map(|path| path.display().to_string()).ok_or("not installed; guests get no software TPM".to_string()),
+        ),
+        capability(
+            "Bridge helper",
+            match bridge_helper {
+                // Unprivileged QEMU only reaches a bridge through the setuid helper.
+                Some(path) if setuid(path) => Ok(path.display().to_string()),
+                Some(path) => Err(format!("{} is not setuid root; bridged NICs need SRQemu run as root", path.display())),
+                None => Err("qemu-bridge-helper is not installed; bridged NICs need SRQemu run as root".to_string()),
+            },
+        ),
+    ]
+}
+
+/// Whether SRQemu has never saved its settings here.
+pub fn first_run() -> bool {
+    !crate::config::active_path().exists()
+}
+
+/// Answers to the setup questions; None keeps the current value.
+#[derive(Default)]
+pub struct Answers {
+    pub vm_dir: Option<String>,
+    pub memory: Option<String>,
+    pub threads: Option<String>,
+    pub disk_size: Option<String>,
+}
+
+/// Checks the answers and stores them in the settings; the caller saves.
+/// The VM directory only changes while there are no VMs, which
+/// `relocate` moves instead.
+pub fn apply(config: &mut VMConfig, answers: Answers) -> Result<(), String> {
+    let current = config.settings.vm_dir.as_deref().unwrap_or(DEFAULT_VM_DIR);
+    if let Some(dir) = answers.vm_dir.filter(|dir| dir != current) {
+        if !config.vms.is_empty() {
+            return Err(format!("there are VMs already; move them to {} with `SRQemu relocate --vm-dir {} --move`", dir, dir));
+        }
+        let path = crate::expand_path(&dir);
+        std::fs::create_dir_all(&path).map_err(|e| format!("cannot create {}: {}", path, e))?;
+        config.settings.vm_dir = Some(dir);
+    }
+    let settings = &mut config.settings;
+    if let Some(memory) = answers.memory {
+        settings.default_memory = crate::size::memory(&memory)?;
+    }
+    if let Some(threads) = answers.threads {
+        match threads.parse::<u32>() {
+            Ok(n) if n > 0 => settings.default_threads = threads,
+            _ => return Err(format!("'{}' is not a number of CPU threads", threads)),
+        }
+    }
+    if let Some(disk_size) = answers.disk_size {
+        crate::size::disk(&disk_size)?;
+        settings.default_disk_size = disk_size;
+    }
+    Ok(())
+}
@@ -0,0 +1,202 @@
+//! Host folders shared with a guest. virtiofs is fast and keeps file
+//! semantics, but each share needs a virtiofsd serving it and the guest's
+//! memory shared with that daemon; 9p is built into QEMU and older guest
+//! kernels, at a fraction of the speed.
+
+use crate::config::{self, VMConfig, VMInfo};
+use crate::{error, runner, size};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+/// Where distributions install virtiofsd, which is rarely on PATH.
+const VIRTIOFSD: [&str; 3] = ["/usr/libexec/virtiofsd", "/usr/lib/qemu/virtiofsd", "/usr/lib/virtiofsd"];
+
+/// How long virtiofsd gets to create its socket.
+const SOCKET_WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum Protocol {
+    #[default]
+    #[serde(rename = "virtiofs")]
+    Virtiofs,
+    #[serde(rename = "9p")]
+    NineP,
+}
+
+impl Protocol {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "virtiofs" => Some(Protocol::Virtiofs),
+            "9p" => Some(Protocol::NineP),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Virtiofs => "virtiofs",
+            Protocol::NineP => "9p",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Share {
+    /// Name the guest mounts the folder by.
+    pub tag: String,
+    pub path: String,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub readonly: bool,
+}
+
+impl Share {
+    /// The guest command mounting the share at `/mnt/<tag>`.
+    pub fn mount_hint(&self) -> String {
+        let options = match (self.protocol, self.readonly) {
+            (Protocol::Virtiofs, false) => String::new(),
+            (Protocol::Virtiofs, true) => " -o ro".to_string(),
+            (Protocol::NineP, readonly) => format!(" -o trans=virtio,version=9p2000.L,msize=512000{}", if readonly { ",ro" } else { "" }),
+        };
+        format!("mkdir -p /mnt/{tag} && mount -t {}{} {tag} /mnt/{tag}", self.protocol.name(), options, tag = self.tag)
+    }
+}
+
+/// Tags end up in QEMU option strings and socket names.
+fn check_tag(tag: &str) -> Result<(), String> {
+    let valid = !tag.is_empty() && tag.len() <= 31 && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid { Ok(()) } else { Err(format!("invalid share tag '{}'; use up to 31 letters, digits, - and _", tag)) }
+}
+
+/// Shares `share.path` with the VM from its next start.
+pub fn add(config: &mut VMConfig, name: &str, share: Share) -> Result<String, String> {
+    check_tag(&share.tag)?;
+    if !Path::new(&share.path).is_dir() {
+        return Err(format!("{} is not a directory", share.path));
+    }
+    // QEMU would read a comma as the end of the option.
+    if share.path.contains(',') {
+        return Err(format!("{} contains a comma, which QEMU cannot take in a shared path", share.path));
+    }
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    if vm.shares.iter().any(|s| s.tag == share.tag) {
+        return Err(format!("'{}' already has a share tagged '{}'", name, share.tag));
+    }
+    let done = format!(
+        "{} is shared with '{}' as '{}' ({}) from its next start. Mount it in the guest with:\n  {}",
+        share.path,
+        name,
+        share.tag,
+        share.protocol.name(),
+        share.mount_hint()
+    );
+    vm.shares.push(share);
+    config::save_config(config).map_err(|e| e.to_string())?;
+    Ok(done)
+}
+
+pub fn remove(config: &mut VMConfig, name: &str, tag: &str) -> Result<String, String> {
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    let Some(position) = vm.shares.iter().position(|s| s.tag == tag) else {
+        return Err(format!("'{}' has no share tagged '{}'", name, tag));
+    };
+    let share = vm.shares.remove(position);
+    config::save_config(config).map_err(|e| e.to_string())?;
+    Ok(format!("{} is no longer shared with '{}' from its next start.", share.path, name))
+}
+
+fn socket_path(vm_name: &str, tag: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join(format!("virtiofs-{}.sock", tag))
+}
+
+/// Memory arguments for a VM with virtiofs shares, whose memory virtiofsd
+/// maps: a shared memfd backend, on hugepages when the run profile got
+/// them. None when the VM's memory needs no sharing.
+pub fn memory_args(vm: &VMInfo, hugepages: bool) -> Option<Vec<String>> {
+    if !vm.shares.iter().any(|s| s.protocol == Protocol::Virtiofs) {
+        return None;
+    }
+    let memory = size::memory(&vm.memory).unwrap_or_else(|_| vm.memory.clone());
+    let hugetlb = if hugepages { ",hugetlb=on" } else { "" };
+    Some(vec![
+        "-object".to_string(),
+        format!("memory-backend-memfd,id=srqmem,size={},share=on{}", memory, hugetlb),
+        "-numa".to_string(),
+        "node,memdev=srqmem".to_string(),
+    ])
+}
+
+/// Arguments for the VM's shares; virtiofs ones connect to the sockets
+/// `start_daemons` serves.
+pub fn launch_args(vm: &VMInfo) -> Vec<String> {
+    let mut args = Vec::new();
+    for (i, share) in vm.shares.iter().enumerate() {
+        match share.protocol {
+            Protocol::Virtiofs => args.extend([
+                "-chardev".to_string(),
+                format!("socket,id=srqfs{},path={}", i, socket_path(&vm.name, &share.tag).display()),
+                "-device".to_string(),
+                format!("vhost-user-fs-pci,chardev=srqfs{},tag={}", i, share.tag),
+            ]),
+            Protocol::NineP => {
+                let ro = if share.readonly { ",readonly=on" } else { "" };
+                args.extend([
+                    "-virtfs".to_string(),
+                    format!("local,path={},mount_tag={},security_model=mapped-xattr,id=srqfs{}{}", share.path, share.tag, i, ro),
+                ]);
+            }
+        }
+    }
+    args
+}
+
+fn virtiofsd() -> String {
+    VIRTIOFSD.iter().find(|path| Path::new(path).exists()).map_or("virtiofsd".to_string(), |path| path.to_string())
+}
+
+/// Starts a virtiofsd for each virtiofs share and waits for its socket.
+/// Each serves one QEMU connection and exits with it.
+pub fn start_daemons(vm: &VMInfo) -> Result<(), String> {
+    for share in vm.shares.iter().filter(|s| s.protocol == Protocol::Virtiofs) {
+        if !Path::new(&share.path).is_dir() {
+            return Err(format!("shared folder {} is gone", share.path));
+        }
+        let socket = socket_path(&vm.name, &share.tag);
+        let _ = fs::remove_file(&socket);
+        let log = PathBuf::from(crate::vm_folder(&vm.name)).join(format!("virtiofsd-{}.log", share.tag));
+        let stderr = fs::File::create(&log).map(Stdio::from).unwrap_or_else(|_| Stdio::null());
+        let mut cmd = runner::command(&virtiofsd());
+        cmd.arg(format!("--socket-path={}", socket.display())).arg(format!("--shared-dir={}", share.path)).arg("--cache=auto");
+        if share.readonly {
+            cmd.arg("--readonly");
+        }
+        // Its namespace sandbox needs root.
+        if !crate::sandbox::is_root() {
+            cmd.arg("--sandbox=none");
+        }
+        crate::process::detach(cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(stderr));
+        let mut child = cmd.spawn().map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => error::Error::ToolMissing { program: "virtiofsd".to_string() }.to_string(),
+            _ => format!("cannot run virtiofsd: {}", e),
+        })?;
+        let deadline = Instant::now() + SOCKET_WAIT;
+        while !socket.exists() {
+            if let Ok(Some(status)) = child.try_wait() {
+                return Err(format!("virtiofsd for share '{}' exited ({}); see {}", share.tag, status, log.display()));
+            }
+            if Instant::now() > deadline {
+                let _ = child.kill();
+                return Err(format!("virtiofsd for share '{}' did not start; see {}", share.tag, log.display()));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        // Reap it when it exits while this process still runs.
+        std::thread::spawn(move || child.wait());
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+/// What a number without a unit counts: QEMU reads `-m 2048` as MiB, but
+/// `qemu-img` reads a disk size of `2048` as bytes.
+#[derive(Debug, Clone, Copy)]
+pub enum Bare {
+    Bytes,
+    Mebibytes,
+}
+
+const MIB: u64 = 1 << 20;
+
+/// Bytes per unit. Single letters and `KiB`-style units are powers of
+/// 1024, as in QEMU; `KB`-style units are powers of 1000. `o` (octet) is
+/// accepted in place of `B`, e.g. `Go` or `Mio`.
+fn multiplier(unit: &str, bare: Bare) -> Option<u64> {
+    let unit = unit.to_lowercase();
+    let unit = unit.strip_suffix('o').map(|u| format!("{}b", u)).unwrap_or(unit);
+    let (prefix, decimal) = match unit.as_str() {
+        "" => {
+            return Some(match bare {
+                Bare::Bytes => 1,
+                Bare::Mebibytes => MIB,
+            });
+        }
+        "b" | "byte" | "bytes" => return Some(1),
+        u if u.len() == 1 => (u, false),
+        u if u.len() == 3 && u.ends_with("ib") => (&u[..1], false),
+        u if u.len() == 2 && u.ends_with('b') => (&u[..1], true),
+        _ => return None,
+    };
+    let power = "kmgtp".find(prefix)? as u32 + 1;
+    Some(if decimal { 1000u64.pow(power) } else { 1024u64.pow(power) })
+}
+
+/// Parses sizes like `4G`, `1.5G`, `1,5 GiB`, `512MiB`, `2GB` or `8Go` into
+/// bytes, rounding fractions up to whole bytes.
+pub fn parse(spec: &str, bare: Bare) -> Result<u64, String> {
+    let invalid = || format!("invalid size '{}'", spec);
+    let compact: String = spec.chars().filter(|c| !c.is_whitespace() && *c != '_').collect();
+    let split = compact.find(|c: char| !c.is_ascii_digit() && c != '.' && c != ',').unwrap_or(compact.len());
+    let (number, unit) = compact.split_at(split);
+    let mult = multiplier(unit, bare).ok_or_else(invalid)?;
+    // Either separator is a decimal point; with both, one is grouping
+    // digits and which one depends on the locale.
+    let mut parts = number.split(['.', ',']);
+    let whole = parts.next().unwrap_or_default();
+    let fraction = parts.next().unwrap_or_default();
+    if parts.next().is_some() || (whole.is_empty() && fraction.is_empty()) {
+        return Err(invalid());
+    }
+    let whole: u128 = if whole.is_empty() { 0 } else { whole.parse().map_err(|_| invalid())? };
+    let scale = 10u128.checked_pow(fraction.len() as u32).ok_or_else(invalid)?;
+    let fraction: u128 = if fraction.is_empty() { 0 } else { fraction.parse().map_err(|_| invalid())? };
+    let bytes = whole * mult as u128 + (fraction * mult as u128).div_ceil(scale);
+    match u64::try_from(bytes) {
+        Ok(0) => Err(format!("size '{}' is zero", spec)),
+        Ok(bytes) => Ok(bytes),
+        Err(_) => Err(format!("size '{}' is too large", spec)),
+    }
+}
+
+/// `bytes` in the largest binary unit that holds it exactly, as QEMU and
+/// `qemu-img` accept it.
+pub fn to_qemu(bytes: u64) -> String {
+    ["T", "G", "M", "K"]
+        .iter()
+        .zip([40, 30, 20, 10])
+        .find(|(_, shift)| bytes.is_multiple_of(1 << shift))
+        .map(|(unit, shift)| format!("{}{}", bytes >> shift, unit))
+        .unwrap_or_else(|| format!("{}B", bytes))
+}
+
+pub fn memory_bytes(spec: &str) -> Result<u64, String> {
+    parse(spec, Bare::Mebibytes)
+}
+
+/// A hint for a bare number that was probably meant in GiB.
+fn suggest_gib(spec: &str) -> String {
+    let spec = spec.trim();
+    if spec.chars().all(|c| c.is_ascii_digit()) { format!("; did you mean {}G?", spec) } else { String::new() }
+}
+
+/// Guest memory in QEMU's notation, rounded up to whole MiB.
+pub fn memory(spec: &str) -> Result<String, String> {
+    let bytes = memory_bytes(spec)?.div_ceil(MIB) * MIB;
+    if bytes < 16 * MIB {
+        return Err(format!("{} of memory is too little to boot{}", to_qemu(bytes), suggest_gib(spec)));
+    }
+    Ok(to_qemu(bytes))
+}
+
+/// A disk size in `qemu-img`'s notation, rounded up to whole sectors.
+pub fn disk(spec: &str) -> Result<String, String> {
+    let bytes = parse(spec, Bare::Bytes)?;
+    if bytes < MIB {
+        return Err(format!("a {} byte disk is too small{}", bytes, suggest_gib(spec)));
+    }
+    Ok(to_qemu(bytes.div_ceil(512) * 512))
+}
@@ -0,0 +1,131 @@
+//! A/B system disks, so an OS upgrade can be tried on a copy and kept or
+//! thrown away without a backup. `disk` always holds the default slot; the
+//! other slot's image and snapshots are kept aside and swapped in when it
+//! is promoted.
+
+use crate::config::{self, VMConfig, VMInfo};
+use crate::interrupt::Partial;
+use crate::run;
+use crate::snapshot::Snapshot;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn parse(s: &str) -> Option<Slot> {
+        match s {
+            "a" | "A" => Some(Slot::A),
+            "b" | "B" => Some(Slot::B),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Slot::A => "a",
+            Slot::B => "b",
+        }
+    }
+
+    fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Slots {
+    /// The slot `disk` holds, which boots unless `start --slot` says
+    /// otherwise.
+    pub default: Slot,
+    /// Image of the other slot, relative to the VM folder when inside it.
+    pub other: String,
+    /// Internal snapshots of the other slot's image.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub other_snapshots: Vec<Snapshot>,
+}
+
+/// The VM as it boots from `slot`.
+pub fn for_boot(vm: &VMInfo, slot: Slot) -> Result<VMInfo, String> {
+    let Some(slots) = &vm.slots else {
+        return match slot {
+            Slot::A => Ok(vm.clone()),
+            Slot::B => Err(format!("'{}' has no slot b; create it with `SRQemu slot {} create`", vm.name, vm.name)),
+        };
+    };
+    if slot == slots.default {
+        return Ok(vm.clone());
+    }
+    Ok(VMInfo { disk: slots.other.clone(), snapshots: slots.other_snapshots.clone(), ..vm.clone() })
+}
+
+/// Copies the stopped VM's disk into slot b. The copy is tracked in
+/// `partial`.
+pub fn create(config: &VMConfig, name: &str, partial: &mut Partial) -> Result<VMInfo, String> {
+    let vm = config.vms.get(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    if vm.slots.is_some() {
+        return Err(format!("'{}' already has two slots; drop the other one first", name));
+    }
+    if crate::vm_running(name) {
+        return Err(format!("VM '{}' is running; stop it first", name));
+    }
+    let disk = format!("{}/{}-b.qcow2", crate::vm_folder(name), name);
+    partial.track(&disk);
+    println!("Copying {} to {}...", vm.disk_path(), disk);
+    run(crate::runner::command("qemu-img").args(["convert", "-O", "qcow2"]).arg(vm.disk_path()).arg(&disk))?;
+    Ok(VMInfo { slots: Some(Slots { default: Slot::A, other: crate::relative_to_folder(name, &disk), other_snapshots: Vec::new() }), ..vm.clone() })
+}
+
+/// Makes `slot` the one that boots by default. Nothing is copied, so this
+/// also works while the VM runs from either slot.
+pub fn promote(config: &mut VMConfig, name: &str, slot: Slot) -> Result<String, String> {
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    let Some(slots) = &mut vm.slots else {
+        return Err(format!("'{}' has only one slot", name));
+    };
+    if slots.default == slot {
+        return Ok(format!("Slot {} already is the default of '{}'.", slot.name(), name));
+    }
+    std::mem::swap(&mut vm.disk, &mut slots.other);
+    std::mem::swap(&mut vm.snapshots, &mut slots.other_snapshots);
+    slots.default = slot;
+    config::save_config(config).map_err(|e| e.to_string())?;
+    let when = if crate::vm_running(name) { "when it next starts" } else { "from now on" };
+    Ok(format!("'{}' boots from slot {} {}; slot {} is kept for a rollback.", name, slot.name(), when, slot.other().name()))
+}
+
+/// Goes back to the slot that was the default before the last promotion.
+pub fn rollback(config: &mut VMConfig, name: &str) -> Result<String, String> {
+    let vm = config.vms.get(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    let slot = vm.slots.as_ref().map(|s| s.default.other()).ok_or_else(|| format!("'{}' has only one slot", name))?;
+    promote(config, name, slot)
+}
+
+/// Deletes the slot that is not the default, leaving the VM with one disk.
+pub fn drop_other(config: &mut VMConfig, name: &str) -> Result<String, String> {
+    if crate::vm_running(name) {
+        return Err(format!("VM '{}' is running, maybe from the other slot; stop it first", name));
+    }
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    let slots = vm.slots.take().ok_or_else(|| format!("'{}' has only one slot", name))?;
+    let path = crate::resolve_path(name, &slots.other);
+    config::save_config(config).map_err(|e| e.to_string())?;
+    if let Err(e) = fs::remove_file(&path) {
+        tracing::warn!("cannot remove {}: {}", path, e);
+    }
+    Ok(format!("Dropped slot {} of '{}'; it runs from slot {} only.", slots.default.other().name(), name, slots.default.name()))
+}
+
+/// One line for listings, e.g. `a (default), b at /vms/web/web-b.qcow2`.
+pub fn describe(vm: &VMInfo) -> Option<String> {
+    let slots = vm.slots.as_ref()?;
+    Some(format!("{} (default), {} at {}", slots.default.name(), slots.default.other().name(), crate::resolve_path(&vm.name, &slots.other)))
+}
@@ -0,0 +1,247 @@
+use crate::config::VMInfo;
+use crate::firmware::Firmware;
+use crate::{qmp, run};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Saving or loading guest RAM takes a while for large VMs.
+const LIVE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// An internal qcow2 snapshot SRQemu took, as recorded in the config.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub created: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// Taken while the VM ran, so it also holds RAM and device state and
+    /// resumes where it left off.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub live: bool,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn age(created: u64) -> String {
+    match now_secs().saturating_sub(created) {
+        s if s < 3600 => format!("{} min ago", s / 60),
+        s if s < 86400 => format!("{} h ago", s / 3600),
+        s => format!("{} days ago", s / 86400),
+    }
+}
+
+/// Names end up in monitor command lines, so keep them to one plain word.
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)) {
+        return Err(format!("invalid snapshot name '{}'; use letters, digits, '.', '_' and '-'", name));
+    }
+    Ok(())
+}
+
+/// Runs a monitor command that prints nothing on success, like `savevm`.
+pub fn monitor(vm: &VMInfo, command_line: &str) -> Result<(), String> {
+    let mut session = qmp::connect(&vm.name)?;
+    session.set_reply_timeout(LIVE_TIMEOUT)?;
+    let out = session.execute("human-monitor-command", Some(json!({ "command-line": command_line })))?;
+    match out.as_str().map(str::trim) {
+        Some("") | None => Ok(()),
+        Some(message) => Err(message.to_string()),
+    }
+}
+
+fn qemu_img(vm: &VMInfo, flag: &str, name: &str) -> Result<(), String> {
+    run(crate::runner::command("qemu-img").args(["snapshot", flag, name]).arg(vm.disk_path())).map(|_| ())
+}
+
+/// Snapshots present in the disk image with when they were taken,
+/// including ones taken outside SRQemu.
+fn on_disk_dated(vm: &VMInfo) -> Result<Vec<(String, u64)>, String> {
+    // -U reads the image even while the running VM holds its lock.
+    let out = crate::runner::run_read_only(crate::runner::command("qemu-img").args(["info", "--output=json", "-U"]).arg(vm.disk_path()))?;
+    let info: Value = serde_json::from_str(&out).map_err(|e| format!("bad qemu-img output: {}", e))?;
+    Ok(info["snapshots"]
+        .as_array()
+        .map(|list| list.iter().filter_map(|s| Some((s["name"].as_str()?.to_string(), s["date-sec"].as_u64().unwrap_or(0)))).collect())
+        .unwrap_or_default())
+}
+
+/// Snapshot names present in the disk image, including ones taken outside
+/// SRQemu.
+pub fn on_disk(vm: &VMInfo) -> Result<Vec<String>, String> {
+    Ok(on_disk_dated(vm)?.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Takes a snapshot: with `savevm` when the VM runs, otherwise of the disk
+/// alone with `qemu-img`.
+pub fn create(vm: &mut VMInfo, name: &str, description: &str) -> Result<(), String> {
+    check_name(name)?;
+    if vm.snapshots.iter().any(|s| s.name == name) || on_disk(vm)?.iter().any(|s| s == name) {
+        return Err(format!("snapshot '{}' already exists", name));
+    }
+    let live = crate::vm_running(&vm.name);
+    if live && vm.firmware == Some(Firmware::Uefi) {
+        // savevm refuses writable drives that are not qcow2.
+        return Err("the raw UEFI NVRAM rules out live snapshots; stop the VM to snapshot its disk".to_string());
+    }
+    if live {
+        monitor(vm, &format!("savevm {}", name))?;
+    } else {
+        qemu_img(vm, "-c", name)?;
+    }
+    vm.snapshots.push(Snapshot { name: name.to_string(), created: now_secs(), description: description.to_string(), live });
+    Ok(())
+}
+
+/// Reverts the VM to a snapshot. A running VM can only return to a live
+/// snapshot; a stopped one can return to any, but only its disk state.
+pub fn restore(vm: &VMInfo, name: &str) -> Result<(), String> {
+    check_name(name)?;
+    if !on_disk(vm)?.iter().any(|s| s == name) {
+        return Err(format!("the disk of '{}' has no snapshot '{}'", vm.name, name));
+    }
+    if crate::vm_running(&vm.name) {
+        if vm.snapshots.iter().any(|s| s.name == name && !s.live) {
+            return Err(format!("'{}' holds no RAM state; stop the VM to restore it", name));
+        }
+        monitor(vm, &format!("loadvm {}", name))
+    } else {
+        qemu_img(vm, "-a", name)
+    }
+}
+
+pub fn delete(vm: &mut VMInfo, name: &str) -> Result<(), String> {
+    check_name(name)?;
+    if on_disk(vm)?.iter().any(|s| s == name) {
+        if crate::vm_running(&vm.name) {
+            monitor(vm, &format!("delvm {}", name))?;
+        } else {
+            qemu_img(vm, "-d", name)?;
+        }
+    }
+    vm.snapshots.retain(|s| s.name != name);
+    Ok(())
+}
+
+/// A state the VM can be taken back to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestorePoint {
+    pub created: u64,
+    pub snapshot: String,
+    pub live: bool,
+    pub description: String,
+}
+
+/// Every snapshot on the disk, oldest first; recorded ones keep their
+/// description, others are dated by the image.
+pub fn restore_points(vm: &VMInfo) -> Result<Vec<RestorePoint>, String> {
+    let mut points: Vec<RestorePoint> = on_disk_dated(vm)?
+        .into_iter()
+        .filter(|(name, _)| name != crate::suspend::SNAPSHOT)
+        .map(|(name, date)| match vm.snapshots.iter().find(|s| s.name == name) {
+            Some(s) => RestorePoint { created: s.created, snapshot: name, live: s.live, description: s.description.clone() },
+            None => RestorePoint { created: date, snapshot: name, live: false, description: "not taken by SRQemu".to_string() },
+        })
+        .collect();
+    points.sort_by_key(|p| p.created);
+    Ok(points)
+}
+
+/// The latest restore point taken at or before `when`.
+pub fn point_at(vm: &VMInfo, when: u64) -> Result<RestorePoint, String> {
+    restore_points(vm)?
+        .into_iter()
+        .rfind(|p| p.created <= when)
+        .ok_or_else(|| format!("'{}' has no restore point from {} or earlier", vm.name, format_utc(when)))
+}
+
+/// `YYYY-MM-DD HH:MM:SS UTC`, the form `parse_utc` reads back.
+pub fn format_utc(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let t = secs % 86400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, t / 3600, t % 3600 / 60, t % 60)
+}
+
+/// Reads seconds since the epoch or a UTC `YYYY-MM-DD[ HH:MM[:SS]]`; a
+/// missing time of day means the end of that day.
+pub fn parse_utc(s: &str) -> Option<u64> {
+    let s = s.trim().trim_end_matches("UTC").trim();
+    if let Ok(secs) = s.parse() {
+        return Some(secs);
+    }
+    let (date, time) = s.split_once([' ', 'T']).map_or((s, None), |(d, t)| (d, Some(t)));
+    let mut ymd = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (ymd.next()??, ymd.next()??, ymd.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let seconds = match time {
+        None => 86399,
+        Some(time) => {
+            let parts: Option<Vec<i64>> = time.split(':').map(|p| p.parse().ok()).collect();
+            match parts?.as_slice() {
+                [h, m] if *h < 24 && *m < 60 => h * 3600 + m * 60,
+                [h, m, s] if *h < 24 && *m < 60 && *s < 60 => h * 3600 + m * 60 + s,
+                _ => return None,
+            }
+        }
+    };
+    // Days since the epoch from a civil date, the inverse of `format_utc`.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    u64::try_from(days * 86400 + seconds).ok()
+}
+
+/// One line per snapshot, recorded or found on the disk.
+pub fn describe(vm: &VMInfo) -> Result<Vec<String>, String> {
+    let present = on_disk(vm)?;
+    let mut lines: Vec<String> = vm
+        .snapshots
+        .iter()
+        .map(|s| {
+            let kind = if s.live { "live" } else { "disk only" };
+            let missing = if present.contains(&s.name) { "" } else { " [missing from disk]" };
+            let description = if s.description.is_empty() { String::new() } else { format!(": {}", s.description) };
+            format!("{} ({}, {}){}{}", s.name, kind, age(s.created), description, missing)
+        })
+        .collect();
+    for name in present.iter().filter(|n| *n != crate::suspend::SNAPSHOT && !vm.snapshots.iter().any(|s| &s.name == *n)) {
+        lines.push(format!("{} (not taken by SRQemu)", name));
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_times_round_trip() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(1_709_210_096), "2024-02-29 12:34:56 UTC");
+        for secs in [0, 951_782_400, 1_709_210_096, 4_102_444_799] {
+            assert_eq!(parse_utc(&format_utc(secs)), Some(secs));
+        }
+        assert_eq!(parse_utc("2024-02-29 12:34"), Some(1_709_210_040));
+        assert_eq!(parse_utc("2024-02-29"), Some(1_709_251_199));
+        assert_eq!(parse_utc("1709210096"), Some(1_709_210_096));
+        assert_eq!(parse_utc("2024-13-01"), None);
+        assert_eq!(parse_utc("yesterday"), None);
+    }
+}
@@ -0,0 +1,265 @@
+//! Opt-in usage statistics for capacity planning: starts and uptime per VM
+//! and daily samples of how much disk each VM takes. Kept next to the
+//! manager log and never sent anywhere.
+
+use crate::config::{Settings, VMConfig, VMInfo};
+use crate::guestdisk::human_size;
+use crate::status::format_uptime;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Disk samples kept per VM, one per day.
+const MAX_SAMPLES: usize = 365;
+/// Width of the longest uptime bar.
+const BAR_WIDTH: usize = 30;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Usage {
+    #[serde(default)]
+    vms: BTreeMap<String, VmUsage>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct VmUsage {
+    starts: u64,
+    /// Seconds of finished runs.
+    uptime_secs: u64,
+    /// Start of the current run, in seconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    running_since: Option<u64>,
+    /// Last time SRQemu saw the current run; a VM that stops on its own is
+    /// counted up to here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_seen: Option<u64>,
+    #[serde(default)]
+    disk: Vec<DiskSample>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiskSample {
+    /// Days since the epoch.
+    day: u64,
+    /// Bytes allocated on the host, which is what fills the disk.
+    bytes: u64,
+}
+
+impl VmUsage {
+    fn close_run(&mut self, until: u64) {
+        if let Some(since) = self.running_since.take() {
+            self.uptime_secs += until.saturating_sub(since);
+        }
+        self.last_seen = None;
+    }
+
+    /// Finished runs plus the current one so far.
+    fn uptime(&self, now: u64) -> u64 {
+        self.uptime_secs + self.running_since.map_or(0, |since| now.saturating_sub(since))
+    }
+
+    /// Records the disk's size for today, replacing an earlier sample of the
+    /// same day. Returns whether anything changed.
+    fn sample_disk(&mut self, vm: &VMInfo, now: u64) -> bool {
+        let Ok(meta) = fs::metadata(vm.disk_path()) else {
+            return false;
+        };
+        // Allocated rather than apparent size, which a sparse qcow2 overstates;
+        // Windows only tells the latter.
+        #[cfg(unix)]
+        let bytes = std::os::unix::fs::MetadataExt::blocks(&meta) * 512;
+        #[cfg(windows)]
+        let bytes = meta.len();
+        let sample = DiskSample { day: now / 86400, bytes };
+        match self.disk.last_mut() {
+            Some(last) if last.day == sample.day && last.bytes == sample.bytes => return false,
+            Some(last) if last.day == sample.day => *last = sample,
+            _ => self.disk.push(sample),
+        }
+        if self.disk.len() > MAX_SAMPLES {
+            self.disk.drain(..self.disk.len() - MAX_SAMPLES);
+        }
+        true
+    }
+}
+
+fn stats_file() -> PathBuf {
+    crate::logging::log_file().with_file_name("stats.json")
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn load() -> Usage {
+    fs::read_to_string(stats_file()).ok().and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default()
+}
+
+fn save(usage: &Usage) {
+    let path = stats_file();
+    let written = serde_json::to_string_pretty(usage)
+        .map_err(|e| e.to_string())
+        .and_then(|json| {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+            }
+            fs::write(&path, json).map_err(|e| e.to_string())
+        });
+    if let Err(e) = written {
+        warn!("cannot write usage statistics to {}: {}", path.display(), e);
+    }
+}
+
+/// Counts a start of `vm` that just came up.
+pub fn started(settings: &Settings, vm: &VMInfo) {
+    if !settings.usage_stats {
+        return;
+    }
+    let now = now();
+    let mut usage = load();
+    let entry = usage.vms.entry(vm.name.clone()).or_default();
+    let last_seen = entry.last_seen.unwrap_or(now);
+    entry.close_run(last_seen);
+    entry.starts += 1;
+    entry.running_since = Some(now);
+    entry.last_seen = Some(now);
+    entry.sample_disk(vm, now);
+    save(&usage);
+}
+
+/// Ends the current run of a VM that SRQemu just stopped.
+pub fn stopped(settings: &Settings, name: &str) {
+    if !settings.usage_stats {
+        return;
+    }
+    let mut usage = load();
+    if let Some(entry) = usage.vms.get_mut(name).filter(|e| e.running_since.is_some()) {
+        entry.close_run(now());
+        save(&usage);
+    }
+}
+
+/// Brings the statistics up to date with what runs now: runs of VMs that
+/// went away are closed and each disk is sampled once a day. `running`
+/// tells whether a VM runs.
+pub fn observe(config: &VMConfig, running: impl Fn(&str) -> bool) {
+    if !config.settings.usage_stats {
+        return;
+    }
+    let now = now();
+    let mut usage = load();
+    let mut changed = false;
+    for vm in config.vms.values() {
+        let entry = usage.vms.entry(vm.name.clone()).or_default();
+        match (running(&vm.name), entry.running_since) {
+            (true, Some(_)) => {
+                // Once a minute is plenty for an estimate of when it stopped.
+                if entry.last_seen.is_none_or(|seen| now >= seen + 60) {
+                    entry.last_seen = Some(now);
+                    changed = true;
+                }
+            }
+            // Started before statistics were turned on.
+            (true, None) => {
+                entry.running_since = Some(now);
+                entry.last_seen = Some(now);
+                changed = true;
+            }
+            (false, Some(since)) => {
+                entry.close_run(entry.last_seen.unwrap_or(since));
+                changed = true;
+            }
+            (false, None) => {}
+        }
+        changed |= entry.sample_disk(vm, now);
+    }
+    if changed {
+        save(&usage);
+    }
+}
+
+fn bar(value: u64, max: u64) -> String {
+    let len = if max == 0 { 0 } else { (value as f64 / max as f64 * BAR_WIDTH as f64).round() as usize };
+    format!("{:<width$}", "█".repeat(len.max(usize::from(value > 0))), width = BAR_WIDTH)
+}
+
+/// One character per sample, scaled between the smallest and largest.
+fn sparkline(values: &[u64]) -> String {
+    let (Some(min), Some(max)) = (values.iter().min(), values.iter().max()) else {
+        return String::new();
+    };
+    values
+        .iter()
+        .map(|v| match max - min {
+            0 => SPARKS[0],
+            range => SPARKS[((v - min) as f64 / range as f64 * (SPARKS.len() - 1) as f64).round() as usize],
+        })
+        .collect()
+}
+
+fn print_uptimes(rows: &[(String, u64, u64)]) {
+    let max = rows.iter().map(|(_, secs, _)| *secs).max().unwrap_or(0);
+    for (label, secs, starts) in rows {
+        if crate::output::plain() {
+            println!("  {}: up {}, {} start(s)", label, format_uptime(Duration::from_secs(*secs)), starts);
+            continue;
+        }
+        println!("  {:<20} {} {:>9}  {} start(s)", label, bar(*secs, max), format_uptime(Duration::from_secs(*secs)), starts);
+    }
+}
+
+/// Charts uptime per VM and tag and the disk growth of each VM.
+pub fn print(config: &VMConfig) {
+    let usage = load();
+    let now = now();
+    let mut names: Vec<&String> = config.vms.keys().collect();
+    names.sort();
+    let recorded: Vec<(&VMInfo, &VmUsage)> = names.iter().filter_map(|n| Some((&config.vms[*n], usage.vms.get(*n)?))).collect();
+    if recorded.is_empty() {
+        println!("Nothing recorded yet.");
+        return;
+    }
+
+    println!("Uptime per VM:");
+    let rows: Vec<(String, u64, u64)> = recorded.iter().map(|(vm, u)| (vm.name.clone(), u.uptime(now), u.starts)).collect();
+    print_uptimes(&rows);
+
+    let mut tags: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    for (vm, u) in &recorded {
+        for tag in &vm.tags {
+            let total = tags.entry(tag.as_str()).or_default();
+            total.0 += u.uptime(now);
+            total.1 += u.starts;
+        }
+    }
+    if !tags.is_empty() {
+        println!("\nUptime per tag:");
+        let rows: Vec<(String, u64, u64)> = tags.iter().map(|(tag, (secs, starts))| (tag.to_string(), *secs, *starts)).collect();
+        print_uptimes(&rows);
+    }
+
+    println!("\nDisk use on the host, one sample per day:");
+    for (vm, u) in &recorded {
+        let (Some(first), Some(last)) = (u.disk.first(), u.disk.last()) else {
+            continue;
+        };
+        let bytes: Vec<u64> = u.disk.iter().rev().take(BAR_WIDTH).rev().map(|s| s.bytes).collect();
+        let days = last.day - first.day;
+        if crate::output::plain() {
+            println!("  {}: {} -> {} over {} day(s)", vm.name, human_size(first.bytes), human_size(last.bytes), days);
+            continue;
+        }
+        println!(
+            "  {:<20} {:<width$} {} -> {} over {} day(s)",
+            vm.name,
+            sparkline(&bytes),
+            human_size(first.bytes),
+            human_size(last.bytes),
+            days,
+            width = BAR_WIDTH
+        );
+    }
+}
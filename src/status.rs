@@ -0,0 +1,216 @@
+use crate::config::{VMConfig, VMInfo};
+use crate::guestdisk::human_size;
+use crate::i18n::t;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::process::Command as ShellCommand;
+use std::time::{Duration, Instant};
+
+/// How long CPU time is sampled for `status`.
+const CPU_SAMPLE: Duration = Duration::from_millis(500);
+
+/// A QEMU process as seen through /proc.
+pub struct ProcStats {
+    pub pid: u32,
+    pub uptime: Duration,
+    pub rss_bytes: u64,
+    /// User plus system time, in clock ticks.
+    cpu_ticks: u64,
+}
+
+fn clock_ticks() -> u64 {
+    crate::run(ShellCommand::new("getconf").arg("CLK_TCK")).ok().and_then(|t| t.trim().parse().ok()).unwrap_or(100)
+}
+
+fn host_uptime() -> Option<f64> {
+    fs::read_to_string("/proc/uptime").ok()?.split_whitespace().next()?.parse().ok()
+}
+
+pub fn read(pid: u32) -> Option<ProcStats> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name is parenthesized and may contain spaces; fields are
+    // counted from the state right after it (field 3 in proc(5)).
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok());
+    let ticks = clock_ticks();
+    let started = field(22)? as f64 / ticks as f64;
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let rss_kb: u64 = status.lines().find_map(|l| l.strip_prefix("VmRSS:"))?.trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(ProcStats {
+        pid,
+        uptime: Duration::from_secs_f64((host_uptime()? - started).max(0.0)),
+        rss_bytes: rss_kb * 1024,
+        cpu_ticks: field(14)? + field(15)?,
+    })
+}
+
+/// CPU use between two readings, where 100% is one host core.
+fn cpu_percent(before: &ProcStats, after: &ProcStats, elapsed: Duration) -> f64 {
+    let busy = after.cpu_ticks.saturating_sub(before.cpu_ticks) as f64 / clock_ticks() as f64;
+    busy / elapsed.as_secs_f64() * 100.0
+}
+
+pub fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    match (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60) {
+        (0, 0, m) => format!("{}m {}s", m, secs % 60),
+        (0, h, m) => format!("{}h {}m", h, m),
+        (d, h, _) => format!("{}d {}h", d, h),
+    }
+}
+
+/// One-line state for listings.
+pub fn summary(vm: &VMInfo) -> String {
+    match crate::vm_pid(&vm.name).and_then(read) {
+        Some(s) => {
+            let console = crate::display::recorded(&vm.name).map(|c| format!(", console {}", c.url())).unwrap_or_default();
+            let state = t(if crate::qmp::paused(&vm.name) { "state-paused" } else { "state-running" }, &[]);
+            let uptime = format_uptime(s.uptime);
+            let summary = t("state-summary", &[("state", &state), ("pid", &s.pid), ("uptime", &uptime), ("rss", &human_size(s.rss_bytes))]);
+            format!("{}{}", summary, console)
+        }
+        None => t("state-stopped", &[]),
+    }
+}
+
+/// The state of one VM; what `status` and `--json` report.
+#[derive(Serialize)]
+pub struct VmStatus {
+    pub name: String,
+    pub running: bool,
+    /// Running, but with its CPUs stopped.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rss_bytes: Option<u64>,
+    /// Only sampled by `status`; 100 is one host core.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_percent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub console: Option<String>,
+}
+
+impl VmStatus {
+    fn new(name: &str, stats: Option<&ProcStats>, cpu_percent: Option<f64>) -> VmStatus {
+        VmStatus {
+            name: name.to_string(),
+            running: stats.is_some(),
+            paused: stats.is_some() && crate::qmp::paused(name),
+            pid: stats.map(|s| s.pid),
+            uptime_secs: stats.map(|s| s.uptime.as_secs()),
+            rss_bytes: stats.map(|s| s.rss_bytes),
+            cpu_percent,
+            console: stats.and_then(|_| crate::display::recorded(name)).map(|c| c.url()),
+        }
+    }
+
+    /// The state now, without CPU use, which takes a sampling interval.
+    pub fn of(name: &str) -> VmStatus {
+        VmStatus::new(name, crate::vm_pid(name).and_then(read).as_ref(), None)
+    }
+}
+
+/// The VMs (or just `only`), sorted, with CPU use sampled over a short
+/// interval.
+pub fn collect(config: &VMConfig, only: Option<&str>) -> Vec<VmStatus> {
+    let mut names: Vec<&String> = config.vms.keys().filter(|n| only.is_none_or(|o| o == n.as_str())).collect();
+    names.sort();
+    let before: Vec<Option<ProcStats>> = names.iter().map(|n| crate::vm_pid(n).and_then(read)).collect();
+    let start = Instant::now();
+    if before.iter().any(Option::is_some) {
+        std::thread::sleep(CPU_SAMPLE);
+    }
+    let elapsed = start.elapsed();
+    names
+        .iter()
+        .zip(before)
+        .map(|(name, before)| {
+            let after = before.as_ref().and_then(|b| read(b.pid));
+            let cpu = before.as_ref().zip(after.as_ref()).map(|(b, a)| cpu_percent(b, a, elapsed));
+            VmStatus::new(name, after.as_ref(), cpu)
+        })
+        .collect()
+}
+
+/// A VM's definition with its disk path and state, for `list` and `info`
+/// as JSON.
+pub fn describe(vm: &VMInfo) -> Value {
+    let mut value = serde_json::to_value(vm).unwrap_or_else(|_| json!({ "name": vm.name }));
+    value["disk_path"] = json!(vm.disk_path());
+    value["status"] = serde_json::to_value(VmStatus::of(&vm.name)).unwrap_or_default();
+    value
+}
+
+pub fn print_json(value: &impl Serialize) {
+    match serde_json::to_string_pretty(value) {
+        Ok(text) => println!("{}", text),
+        Err(e) => tracing::error!("cannot encode JSON: {}", e),
+    }
+}
+
+/// The name column, in the VM's color.
+fn name(config: &VMConfig, name: &str) -> String {
+    crate::label::paint(config.vms.get(name).and_then(|vm| vm.color.as_deref()), &format!("{:<20}", name))
+}
+
+/// Prints a table of the VMs (or just `only`), or the same as JSON.
+pub fn print(config: &VMConfig, only: Option<&str>, as_json: bool) {
+    let rows = collect(config, only);
+    if as_json {
+        print_json(&rows);
+        return;
+    }
+    let heading = |key: &str| t(key, &[]);
+    if crate::output::plain() {
+        for row in &rows {
+            let state = t(if !row.running { "state-stopped" } else if row.paused { "state-paused" } else { "state-running" }, &[]);
+            let values = [
+                (heading("status-state").to_lowercase(), state),
+                (heading("status-pid"), row.pid.map(|pid| pid.to_string()).unwrap_or_default()),
+                (heading("status-uptime").to_lowercase(), row.uptime_secs.map(|s| format_uptime(Duration::from_secs(s))).unwrap_or_default()),
+                (heading("status-rss"), row.rss_bytes.map(human_size).unwrap_or_default()),
+                (heading("status-cpu"), row.cpu_percent.map(|cpu| format!("{:.1}%", cpu)).unwrap_or_default()),
+            ];
+            println!("{}", crate::output::line(&row.name, &values));
+        }
+    } else {
+        print_table(config, &rows);
+    }
+    for row in &rows {
+        if let Some(console) = &row.console {
+            println!("{}", t("console-of", &[("name", &row.name), ("url", console)]));
+        }
+    }
+}
+
+fn print_table(config: &VMConfig, rows: &[VmStatus]) {
+    let heading = |key: &str| t(key, &[]);
+    println!(
+        "{:<20} {:<8} {:>8} {:>10} {:>9} {:>6}",
+        heading("status-name"),
+        heading("status-state"),
+        heading("status-pid"),
+        heading("status-uptime"),
+        heading("status-rss"),
+        heading("status-cpu")
+    );
+    for row in rows {
+        match (row.pid, row.uptime_secs, row.rss_bytes) {
+            (Some(pid), Some(uptime), Some(rss)) => println!(
+                "{} {:<8} {:>8} {:>10} {:>9} {:>5.1}%",
+                name(config, &row.name),
+                t(if row.paused { "state-paused" } else { "state-running" }, &[]),
+                pid,
+                format_uptime(Duration::from_secs(uptime)),
+                human_size(rss),
+                row.cpu_percent.unwrap_or(0.0)
+            ),
+            _ => println!("{} {:<8} {:>8} {:>10} {:>9} {:>6}", name(config, &row.name), t("state-stopped", &[]), "-", "-", "-", "-"),
+        }
+    }
+}
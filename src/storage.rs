@@ -0,0 +1,270 @@
+use crate::config::VMInfo;
+use crate::runner::run_read_only;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::process::Command as ShellCommand;
+
+/// QEMU `cache=` modes. `none` and `directsync` open the image with
+/// O_DIRECT.
+pub const CACHE_MODES: &[&str] = &["writeback", "writethrough", "none", "directsync", "unsafe"];
+
+/// Controller for the VM's disk. Without one the disk sits on QEMU's
+/// default (emulated IDE) bus, which needs no guest drivers.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum DiskBus {
+    #[serde(rename = "virtio-blk")]
+    VirtioBlk,
+    /// Supports discard/TRIM and more devices per controller.
+    #[serde(rename = "virtio-scsi")]
+    VirtioScsi,
+    /// Emulated NVMe controller; guests drive it with their stock NVMe
+    /// driver, but it cannot use an IOThread.
+    #[serde(rename = "nvme")]
+    Nvme,
+    /// Emulated AHCI controller; faster than IDE and driven by every OS
+    /// since Windows Vista without extra drivers.
+    #[serde(rename = "sata")]
+    Sata,
+}
+
+impl DiskBus {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "virtio-blk" => Some(DiskBus::VirtioBlk),
+            "virtio-scsi" => Some(DiskBus::VirtioScsi),
+            "nvme" => Some(DiskBus::Nvme),
+            "sata" => Some(DiskBus::Sata),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DiskBus::VirtioBlk => "virtio-blk",
+            DiskBus::VirtioScsi => "virtio-scsi",
+            DiskBus::Nvme => "nvme",
+            DiskBus::Sata => "sata",
+        }
+    }
+
+    /// Only the virtio controllers can run from an IOThread.
+    pub fn takes_iothread(self) -> bool {
+        matches!(self, DiskBus::VirtioBlk | DiskBus::VirtioScsi)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DiskDevice {
+    pub bus: DiskBus,
+    /// Serve the disk from its own IOThread instead of QEMU's main loop, so
+    /// heavy I/O does not stall the rest of the VM.
+    #[serde(default = "default_iothread")]
+    pub iothread: bool,
+}
+
+fn default_iothread() -> bool {
+    true
+}
+
+/// A new VM's disk in the compact form of `--disk`, e.g.
+/// `size=40G,bus=nvme,cache=none,pool=ssd`. A leading value without a key
+/// is the size.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiskSpec {
+    /// In `qemu-img` notation.
+    pub size: Option<String>,
+    pub device: Option<DiskDevice>,
+    pub cache: Option<String>,
+    /// Name of a directory under `settings.pools`.
+    pub pool: Option<String>,
+}
+
+const DISK_KEYS: &[&str] = &["size", "bus", "cache", "iothread", "pool"];
+
+fn parse_switch(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "yes" | "true" => Ok(true),
+        "off" | "no" | "false" => Ok(false),
+        _ => Err(format!("{} takes on or off, not '{}'", key, value)),
+    }
+}
+
+impl DiskSpec {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut disk = DiskSpec::default();
+        let mut bus = None;
+        let mut iothread = None;
+        let mut seen = Vec::new();
+        for (i, field) in spec.split(',').map(str::trim).filter(|f| !f.is_empty()).enumerate() {
+            let (key, value) = match field.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None if i == 0 => ("size", field),
+                None => return Err(format!("'{}' in disk spec needs a key, e.g. size={}", field, field)),
+            };
+            if !DISK_KEYS.contains(&key) {
+                return Err(format!("unknown disk option '{}'; known: {}", key, DISK_KEYS.join(", ")));
+            }
+            if seen.contains(&key) {
+                return Err(format!("disk option '{}' given twice", key));
+            }
+            seen.push(key);
+            match key {
+                "size" => disk.size = Some(crate::size::disk(value)?),
+                "bus" => {
+                    bus = match value {
+                        "ide" => None,
+                        "virtio" => Some(DiskBus::VirtioBlk),
+                        "scsi" => Some(DiskBus::VirtioScsi),
+                        other => Some(DiskBus::parse(other).ok_or_else(|| {
+                            format!("unknown disk bus '{}'; use ide, sata, virtio-blk, virtio-scsi or nvme", other)
+                        })?),
+                    }
+                }
+                "cache" if CACHE_MODES.contains(&value) => disk.cache = Some(value.to_string()),
+                "cache" => return Err(format!("unknown cache mode '{}'; use one of {}", value, CACHE_MODES.join(", "))),
+                "iothread" => iothread = Some(parse_switch(key, value)?),
+                _ => disk.pool = Some(value.to_string()),
+            }
+        }
+        disk.device = match (bus, iothread) {
+            (Some(bus), Some(true)) if !bus.takes_iothread() => return Err(format!("{} disks cannot use an IOThread", bus.name())),
+            (Some(bus), iothread) => Some(DiskDevice { bus, iothread: iothread.unwrap_or(bus.takes_iothread()) }),
+            (None, Some(true)) => return Err("iothread needs a virtio bus".to_string()),
+            (None, _) => None,
+        };
+        Ok(disk)
+    }
+}
+
+/// The filesystem an image really lives on, after following symlinks.
+pub struct DiskLocation {
+    pub real_path: String,
+    pub fs_type: String,
+    pub symlinked: bool,
+}
+
+impl DiskLocation {
+    pub fn is_network(&self) -> bool {
+        matches!(self.fs_type.as_str(), "nfs" | "nfs4" | "cifs" | "smb3" | "9p" | "glusterfs" | "ceph" | "fuse.sshfs")
+    }
+
+    /// SMB and sshfs do not carry the byte-range locks QEMU uses to keep a
+    /// second QEMU off the image; taking them fails or hangs there.
+    fn breaks_locking(&self) -> bool {
+        matches!(self.fs_type.as_str(), "cifs" | "smb3" | "fuse.sshfs")
+    }
+
+    /// Filesystems that reject O_DIRECT, so `cache=none` cannot open the
+    /// image.
+    fn lacks_direct_io(&self) -> bool {
+        self.fs_type == "tmpfs" || self.fs_type.starts_with("fuse") || matches!(self.fs_type.as_str(), "cifs" | "smb3")
+    }
+}
+
+pub fn locate(path: &str) -> Option<DiskLocation> {
+    let real = fs::canonicalize(path).ok()?;
+    let symlinked = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink());
+    let fs_type = run_read_only(ShellCommand::new("findmnt").args(["-n", "-o", "FSTYPE", "-T"]).arg(&real)).ok()?;
+    Some(DiskLocation { real_path: real.display().to_string(), fs_type: fs_type.trim().to_string(), symlinked })
+}
+
+/// The configured cache mode, or the one that suits the filesystem: on NFS
+/// host-side caching only delays writes the server should see on flush.
+fn cache_mode(vm: &VMInfo, location: Option<&DiskLocation>) -> Option<String> {
+    match (&vm.disk_cache, location) {
+        (Some(cache), _) => Some(cache.clone()),
+        (None, Some(l)) if l.fs_type.starts_with("nfs") => Some("none".to_string()),
+        _ => None,
+    }
+}
+
+/// Caching and locking options for the image's filesystem.
+fn file_options(vm: &VMInfo, location: Option<&DiskLocation>) -> String {
+    let mut opts = String::new();
+    if let Some(cache) = cache_mode(vm, location) {
+        opts.push_str(&format!(",cache={}", cache));
+    }
+    if location.is_some_and(|l| l.breaks_locking()) {
+        opts.push_str(",file.locking=off");
+    }
+    opts
+}
+
+/// Problems with where the disk lives, for warnings before start.
+pub fn warnings(vm: &VMInfo) -> Vec<String> {
+    let Some(location) = locate(&vm.disk_path()) else {
+        return Vec::new();
+    };
+    let mut warnings = Vec::new();
+    let cache = cache_mode(vm, Some(&location)).unwrap_or_else(|| "writeback".to_string());
+    let cache = cache.as_str();
+    if matches!(cache, "none" | "directsync") && location.lacks_direct_io() {
+        warnings.push(format!("cache={} needs O_DIRECT, which {} does not support; the disk will fail to open", cache, location.fs_type));
+    }
+    if location.is_network() && cache == "unsafe" {
+        warnings.push(format!("cache=unsafe on {} loses data if the host or the server goes away", location.fs_type));
+    }
+    if location.breaks_locking() {
+        warnings.push(format!("image locking is off on {}; nothing stops a second QEMU from opening the disk", location.fs_type));
+    }
+    warnings
+}
+
+/// One line about where the disk lives when that is not a plain local
+/// file, for listings.
+pub fn describe_location(vm: &VMInfo) -> Option<String> {
+    let location = locate(&vm.disk_path())?;
+    if !location.symlinked && !location.is_network() {
+        return None;
+    }
+    Some(format!("{} on {}", location.real_path, location.fs_type))
+}
+
+/// A serial made of the VM name's letters and digits, within NVMe's 20
+/// characters.
+fn nvme_serial(vm_name: &str) -> String {
+    let serial: String = vm_name.chars().filter(char::is_ascii_alphanumeric).take(20).collect();
+    if serial.is_empty() { "disk0".to_string() } else { serial }
+}
+
+/// `-drive` (and, on a virtio bus, `-device`/`-object`) arguments for the
+/// VM's disk, which `location` says lives where.
+pub fn drive_args(vm: &VMInfo, location: Option<&DiskLocation>) -> Vec<String> {
+    let Some(device) = &vm.disk_device else {
+        return vec!["-drive".to_string(), format!("file={},format=qcow2{}", vm.disk_path(), file_options(vm, location))];
+    };
+    let mut args = Vec::new();
+    let iothread = if device.iothread && device.bus.takes_iothread() {
+        args.extend(["-object".to_string(), "iothread,id=iothread0".to_string()]);
+        ",iothread=iothread0"
+    } else {
+        ""
+    };
+    args.extend(["-drive".to_string(), format!("file={},format=qcow2,if=none,id=disk0{}", vm.disk_path(), file_options(vm, location))]);
+    match device.bus {
+        DiskBus::VirtioBlk => {
+            args.extend(["-device".to_string(), format!("virtio-blk-pci,drive=disk0{}", iothread)]);
+        }
+        DiskBus::VirtioScsi => {
+            args.extend([
+                "-device".to_string(),
+                format!("virtio-scsi-pci,id=scsi0{}", iothread),
+                "-device".to_string(),
+                "scsi-hd,drive=disk0,bus=scsi0.0".to_string(),
+            ]);
+        }
+        DiskBus::Nvme => {
+            // The controller needs a serial; guests see it as the disk's.
+            args.extend(["-device".to_string(), format!("nvme,drive=disk0,serial={}", nvme_serial(&vm.name))]);
+        }
+        DiskBus::Sata => {
+            args.extend([
+                "-device".to_string(),
+                "ahci,id=ahci0".to_string(),
+                "-device".to_string(),
+                "ide-hd,drive=disk0,bus=ahci0.0".to_string(),
+            ]);
+        }
+    }
+    args
+}
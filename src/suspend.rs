@@ -0,0 +1,70 @@
+//! Saving a running VM's whole state and resuming it later, e.g. across a
+//! host reboot. The state goes into an internal snapshot of the disk with
+//! `savevm`, so it works for hardened VMs that may not spawn processes; a
+//! marker in the VM folder makes the next start load it instead of
+//! booting.
+
+use crate::config::{VMConfig, VMInfo};
+use crate::firmware::Firmware;
+use crate::{qmp, snapshot};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Internal snapshot holding the suspended state; not listed as one of the
+/// VM's snapshots.
+pub const SNAPSHOT: &str = "srqemu-suspend";
+
+/// How long QEMU gets to exit once the state is saved.
+const QUIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Holds the disk the state was saved on, so booting the other A/B slot
+/// does not try to load it.
+fn marker(vm_name: &str) -> PathBuf {
+    PathBuf::from(crate::vm_folder(vm_name)).join("suspended")
+}
+
+/// Whether the VM's next start resumes a suspended state.
+pub fn is_suspended(vm: &VMInfo) -> bool {
+    fs::read_to_string(marker(&vm.name)).is_ok_and(|disk| disk.trim() == vm.disk_path())
+}
+
+/// Saves the running VM's RAM, devices and disk state and stops it.
+pub fn suspend(config: &VMConfig, vm: &VMInfo) -> Result<(), String> {
+    if !crate::vm_running(&vm.name) {
+        return Err(format!("VM '{}' is not running", vm.name));
+    }
+    if vm.firmware == Some(Firmware::Uefi) {
+        return Err("the raw UEFI NVRAM rules out saving the VM's state".to_string());
+    }
+    // Left behind by a resume that could not delete it.
+    let _ = snapshot::monitor(vm, &format!("delvm {}", SNAPSHOT));
+    snapshot::monitor(vm, &format!("savevm {}", SNAPSHOT))?;
+    fs::write(marker(&vm.name), vm.disk_path()).map_err(|e| format!("cannot write {}: {}", marker(&vm.name).display(), e))?;
+    crate::daemon::expect_exit(&vm.name);
+    // QEMU exits before it can answer.
+    let _ = qmp::command(&vm.name, "quit", None);
+    let started = std::time::Instant::now();
+    while crate::vm_running(&vm.name) {
+        if started.elapsed() > QUIT_TIMEOUT {
+            crate::daemon::cancel_expected_exit(&vm.name);
+            return Err(format!("the state is saved, but QEMU did not exit; `SRQemu stop {} --force` ends it", vm.name));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    if let Err(e) = crate::firewall::remove(&vm.name) {
+        tracing::error!("Failed to remove firewall rules for '{}': {}", vm.name, e);
+    }
+    qmp::cleanup_runtime(&vm.name);
+    crate::stats::stopped(&config.settings, &vm.name);
+    Ok(())
+}
+
+/// Called once a suspended VM runs again: the state is used up, and
+/// loading it a second time would roll the disk back.
+pub fn resumed(vm: &VMInfo) {
+    let _ = fs::remove_file(marker(&vm.name));
+    if let Err(e) = snapshot::monitor(vm, &format!("delvm {}", SNAPSHOT)) {
+        tracing::warn!("VM '{}': cannot delete the snapshot '{}' of its suspended state: {}", vm.name, SNAPSHOT, e);
+    }
+}
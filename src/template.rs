@@ -0,0 +1,126 @@
+//! Starting points for new VMs. Unlike a profile, a template only fills in
+//! the answers at creation; the VM keeps no link to it afterwards.
+
+use crate::config;
+use crate::firmware::Firmware;
+use crate::recipe::RecipeVm;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Template {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// Memory, CPU, NICs, display, USB, clock and guest agent, as in a
+    /// recipe.
+    #[serde(flatten)]
+    pub vm: RecipeVm,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<Firmware>,
+    /// Disk in `--disk` notation, e.g. `40G,bus=nvme`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk: Option<String>,
+}
+
+const BUILT_IN: &[(&str, &str)] = &[
+    (
+        "linux-server",
+        r#"
+description = "Headless Linux with virtio disk and NIC and guest agent"
+memory = "2G"
+threads = "2"
+disk = "20G,bus=virtio-blk"
+nics = ["user"]
+nic_model = "virtio"
+guest_agent = true
+"#,
+    ),
+    (
+        "linux-desktop",
+        r#"
+description = "Linux desktop with a virtio GPU and USB tablet"
+memory = "4G"
+threads = "4"
+disk = "40G,bus=virtio-blk"
+nics = ["user"]
+nic_model = "virtio"
+usb = "xhci"
+guest_agent = true
+display = { model = "virtio", resolution = "1920x1080" }
+"#,
+    ),
+    (
+        "windows-desktop",
+        r#"
+description = "Windows 10/11 on UEFI with devices it drives out of the box"
+memory = "8G"
+threads = "4"
+firmware = "uefi"
+disk = "64G,bus=nvme"
+nics = ["user"]
+usb = "xhci"
+display = { model = "std", resolution = "1920x1080" }
+time = { rtc_base = "localtime", driftfix = true }
+"#,
+    ),
+];
+
+/// User templates, `<name>.toml` next to the config file.
+pub fn templates_dir() -> PathBuf {
+    let active = config::active_path();
+    active.parent().map(|dir| dir.join("templates")).unwrap_or_else(|| PathBuf::from("templates"))
+}
+
+fn parse(name: &str, text: &str) -> Result<Template, String> {
+    toml::from_str(text).map_err(|e| format!("invalid template '{}': {}", name, e))
+}
+
+/// A template by name; a user template replaces a built-in one of the same
+/// name.
+pub fn load(name: &str) -> Result<Template, String> {
+    let path = templates_dir().join(format!("{}.toml", name));
+    if let Ok(text) = fs::read_to_string(&path) {
+        return parse(name, &text);
+    }
+    match BUILT_IN.iter().find(|(n, _)| *n == name) {
+        Some((_, text)) => parse(name, text),
+        None => Err(format!("no template '{}'; see `SRQemu template list`", name)),
+    }
+}
+
+/// Names of all templates with where they come from, sorted.
+pub fn list() -> Vec<(String, &'static str)> {
+    let mut names: Vec<(String, &'static str)> = Vec::new();
+    for file in fs::read_dir(templates_dir()).into_iter().flatten().flatten() {
+        let path = file.path();
+        if path.extension().is_some_and(|x| x == "toml")
+            && let Some(name) = path.file_stem()
+        {
+            names.push((name.to_string_lossy().to_string(), "user"));
+        }
+    }
+    for (name, _) in BUILT_IN {
+        if !names.iter().any(|(n, _)| n == name) {
+            names.push((name.to_string(), "built-in"));
+        }
+    }
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_templates_are_valid() {
+        for (name, text) in BUILT_IN {
+            let template = parse(name, text).unwrap();
+            assert!(!template.description.is_empty(), "{} has no description", name);
+            crate::storage::DiskSpec::parse(template.disk.as_deref().unwrap()).unwrap();
+            crate::size::memory(template.vm.memory.as_deref().unwrap()).unwrap();
+            template.vm.nic_model.as_deref().map(crate::network::parse_nic_model).transpose().unwrap();
+        }
+    }
+}
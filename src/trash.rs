@@ -0,0 +1,159 @@
+use crate::config::{VMConfig, VMInfo};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// Metadata written into each trash entry so it can be restored.
+const RECORD_FILE: &str = "trashed-vm.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TrashRecord {
+    vm: VMInfo,
+    original_dir: String,
+    /// Set when the disk lived outside the VM folder and was moved alongside
+    /// it; holds the file name inside the trash entry.
+    moved_disk: Option<String>,
+}
+
+pub struct TrashEntry {
+    pub dir: PathBuf,
+    pub name: String,
+    pub deleted_at: SystemTime,
+    record: TrashRecord,
+}
+
+fn trash_dir() -> PathBuf {
+    PathBuf::from(crate::get_vm_folder()).join(".trash")
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Renames, falling back to copy + delete for files on another filesystem.
+fn move_file(src: &Path, dst: &Path) -> Result<(), String> {
+    if fs::rename(src, dst).is_ok() {
+        return Ok(());
+    }
+    fs::copy(src, dst).map_err(|e| format!("cannot move {}: {}", src.display(), e))?;
+    fs::remove_file(src).map_err(|e| format!("cannot remove {}: {}", src.display(), e))
+}
+
+/// Moves a VM's folder (and its disk, if stored elsewhere) into
+/// `~/vms/.trash/<timestamp>-<name>`.
+pub fn move_to_trash(vm: &VMInfo) -> Result<PathBuf, String> {
+    let entry = trash_dir().join(format!("{}-{}", now_secs(), vm.name));
+    fs::create_dir_all(trash_dir()).map_err(|e| format!("cannot create trash: {}", e))?;
+
+    let vm_dir = PathBuf::from(crate::vm_folder(&vm.name));
+    if vm_dir.exists() {
+        fs::rename(&vm_dir, &entry).map_err(|e| format!("cannot move {}: {}", vm_dir.display(), e))?;
+    } else {
+        fs::create_dir_all(&entry).map_err(|e| format!("cannot create {}: {}", entry.display(), e))?;
+    }
+
+    let disk = PathBuf::from(vm.disk_path());
+    let mut moved_disk = None;
+    if !disk.starts_with(&vm_dir) && disk.exists() {
+        let file_name = disk.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        move_file(&disk, &entry.join(&file_name))?;
+        moved_disk = Some(file_name);
+    }
+
+    let record = TrashRecord {
+        vm: vm.clone(),
+        original_dir: vm_dir.display().to_string(),
+        moved_disk,
+    };
+    let json = serde_json::to_string_pretty(&record).map_err(|e| e.to_string())?;
+    fs::write(entry.join(RECORD_FILE), json).map_err(|e| format!("cannot write trash record: {}", e))?;
+    Ok(entry)
+}
+
+pub fn list() -> Vec<TrashEntry> {
+    let Ok(dirs) = fs::read_dir(trash_dir()) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<TrashEntry> = dirs
+        .flatten()
+        .filter_map(|d| {
+            let dir = d.path();
+            let file_name = d.file_name().to_string_lossy().to_string();
+            let (secs, name) = file_name.split_once('-')?;
+            let secs: u64 = secs.parse().ok()?;
+            let record: TrashRecord = serde_json::from_str(&fs::read_to_string(dir.join(RECORD_FILE)).ok()?).ok()?;
+            Some(TrashEntry {
+                dir,
+                name: name.to_string(),
+                deleted_at: UNIX_EPOCH + Duration::from_secs(secs),
+                record,
+            })
+        })
+        .collect();
+    entries.sort_by_key(|e| e.deleted_at);
+    entries
+}
+
+impl TrashEntry {
+    pub fn age(&self) -> String {
+        let secs = SystemTime::now().duration_since(self.deleted_at).map(|d| d.as_secs()).unwrap_or(0);
+        match secs {
+            s if s < 3600 => format!("{} min ago", s / 60),
+            s if s < 86400 => format!("{} h ago", s / 3600),
+            s => format!("{} days ago", s / 86400),
+        }
+    }
+}
+
+/// Moves a trashed VM back into place and re-registers it.
+pub fn restore(entry: &TrashEntry, config: &mut VMConfig) -> Result<(), String> {
+    let vm = &entry.record.vm;
+    if config.vms.contains_key(&vm.name) {
+        return Err(format!("a VM named '{}' already exists", vm.name));
+    }
+    put_back(entry)?;
+    config.vms.insert(vm.name.clone(), vm.clone());
+    Ok(())
+}
+
+/// Moves a trashed VM's files back into place without touching the
+/// config, for a definition that came back some other way.
+pub fn put_back(entry: &TrashEntry) -> Result<(), String> {
+    let vm = &entry.record.vm;
+    // The current VM folder rather than `original_dir`, which is stale once
+    // the VMs were relocated.
+    let vm_dir = PathBuf::from(crate::vm_folder(&vm.name));
+    if vm_dir.exists() {
+        return Err(format!("{} already exists", vm_dir.display()));
+    }
+
+    if let Some(file_name) = &entry.record.moved_disk {
+        let disk = PathBuf::from(vm.disk_path());
+        if disk.exists() {
+            return Err(format!("{} already exists", disk.display()));
+        }
+        move_file(&entry.dir.join(file_name), &disk)?;
+    }
+    let _ = fs::remove_file(entry.dir.join(RECORD_FILE));
+    fs::rename(&entry.dir, &vm_dir).map_err(|e| format!("cannot restore {}: {}", vm_dir.display(), e))
+}
+
+/// Permanently removes trash entries older than `days`.
+pub fn purge_expired(days: u64) {
+    let max_age = Duration::from_secs(days * 86400);
+    for entry in list() {
+        let expired = SystemTime::now()
+            .duration_since(entry.deleted_at)
+            .map(|age| age > max_age)
+            .unwrap_or(false);
+        if !expired {
+            continue;
+        }
+        match fs::remove_dir_all(&entry.dir) {
+            Ok(()) => println!("Purged deleted VM '{}' ({})", entry.name, entry.age()),
+            Err(e) => error!("Failed to purge {}: {}", entry.dir.display(), e),
+        }
+    }
+}
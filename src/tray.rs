@@ -0,0 +1,109 @@
+//! A tray icon listing the VMs with start, stop and console actions. It
+//! polls the daemon for their states and draws the icon with `yad
+//! --notification`, so SRQemu itself links no GUI toolkit.
+
+use crate::daemon::{self, VmState};
+use std::io::{self, Write};
+use std::process::{Command as ShellCommand, Stdio};
+use std::time::Duration;
+use tracing::warn;
+
+/// How often the daemon is asked for the VMs' states.
+const REFRESH: Duration = Duration::from_secs(5);
+
+/// Quotes an argument for the GLib parser yad splits menu commands with.
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// yad's `menu:` line: the actions that fit each VM's state, then Quit.
+fn menu(exe: &str, vms: &[VmState]) -> String {
+    let exe = quote(exe);
+    let mut items = Vec::new();
+    for vm in vms {
+        // yad splits items at `|` and fields at `!`, with no way to escape.
+        if vm.name.contains(['|', '!']) {
+            continue;
+        }
+        let name = quote(&vm.name);
+        // A third field is the item's icon.
+        let icon = vm.icon.as_deref().filter(|icon| !icon.contains(['|', '!'])).map(|icon| format!("!{}", icon)).unwrap_or_default();
+        if !vm.running {
+            items.push(format!("Start {}!{} open {}{}", vm.name, exe, name, icon));
+            continue;
+        }
+        if vm.paused {
+            items.push(format!("Resume {}!{} resume {}{}", vm.name, exe, name, icon));
+        }
+        items.push(format!("Console of {}!{} open {}{}", vm.name, exe, name, icon));
+        items.push(format!("Stop {}!{} stop {}{}", vm.name, exe, name, icon));
+    }
+    items.push("Quit!quit".to_string());
+    format!("menu:{}", items.join("|"))
+}
+
+fn tooltip(vms: &[VmState]) -> String {
+    let running: Vec<&str> = vms.iter().filter(|vm| vm.running).map(|vm| vm.name.as_str()).collect();
+    if running.is_empty() { "tooltip:SRQemu: no VMs running".to_string() } else { format!("tooltip:SRQemu: {} running", running.join(", ")) }
+}
+
+/// Shows the tray icon until it is quit from its menu.
+pub fn run() -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("cannot locate this binary: {}", e))?;
+    let exe = exe.display().to_string();
+    if daemon::vms().is_none() {
+        return Err("no daemon is running; start it with `SRQemu daemon` or install it with `SRQemu daemon install`".to_string());
+    }
+    let icon = crate::desktop::install_icon()?;
+    let mut yad = ShellCommand::new("yad")
+        .args(["--notification", "--listen", "--command=menu", "--text=SRQemu"])
+        .arg(format!("--image={}", icon.display()))
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => crate::error::Error::ToolMissing { program: "yad".to_string() }.to_string(),
+            _ => format!("cannot run yad: {}", e),
+        })?;
+    let Some(mut input) = yad.stdin.take() else {
+        return Err("yad has no input".to_string());
+    };
+    let mut shown = String::new();
+    loop {
+        if yad.try_wait().map_err(|e| e.to_string())?.is_some() {
+            return Ok(());
+        }
+        let state = match daemon::vms() {
+            Some(Ok(vms)) => format!("{}\n{}\n", tooltip(&vms), menu(&exe, &vms)),
+            Some(Err(e)) => {
+                warn!("The daemon did not list the VMs: {}", e);
+                shown.clone()
+            }
+            None => "tooltip:SRQemu: the daemon is not running\nmenu:Quit!quit\n".to_string(),
+        };
+        if state != shown {
+            // A write fails once yad is gone; the next round notices.
+            let _ = input.write_all(state.as_bytes()).and_then(|()| input.flush());
+            shown = state;
+        }
+        std::thread::sleep(REFRESH);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn menu_offers_what_fits_each_state() {
+        let vms = [
+            VmState { name: "db".to_string(), running: false, paused: false, icon: Some("/icons/db.svg".to_string()) },
+            VmState { name: "it's".to_string(), running: true, paused: true, icon: None },
+            VmState { name: "a|b".to_string(), running: false, paused: false, icon: None },
+        ];
+        assert_eq!(
+            menu("/bin/SRQemu", &vms),
+            "menu:Start db!'/bin/SRQemu' open 'db'!/icons/db.svg|Resume it's!'/bin/SRQemu' resume 'it'\\''s'|Console of it's!'/bin/SRQemu' open 'it'\\''s'|Stop it's!'/bin/SRQemu' stop 'it'\\''s'|Quit!quit"
+        );
+        assert_eq!(tooltip(&vms), "tooltip:SRQemu: it's running");
+    }
+}
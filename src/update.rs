@@ -0,0 +1,84 @@
+use crate::config::{VMConfig, VMInfo};
+use crate::{agent, guestcron};
+use std::fs;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Package upgrades can take a while on guests that were off for months.
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(3 * 3600);
+
+/// Upgrades every package with whichever manager the guest has.
+const UPDATE_SCRIPT: &str = r#"if command -v apt-get >/dev/null; then
+    export DEBIAN_FRONTEND=noninteractive
+    apt-get update && apt-get -y upgrade
+elif command -v dnf >/dev/null; then
+    dnf -y upgrade
+elif command -v yum >/dev/null; then
+    yum -y update
+elif command -v zypper >/dev/null; then
+    zypper --non-interactive update
+elif command -v pacman >/dev/null; then
+    pacman -Syu --noconfirm
+elif command -v apk >/dev/null; then
+    apk update && apk upgrade
+else
+    echo "no supported package manager found" >&2
+    exit 3
+fi
+"#;
+
+pub enum Outcome {
+    Updated,
+    Failed(String),
+    Skipped(String),
+}
+
+pub struct UpdateReport {
+    pub vm: String,
+    pub outcome: Outcome,
+    pub elapsed: Duration,
+    /// Full output, also kept in the VM's log folder.
+    pub log: Option<String>,
+}
+
+/// VMs named by `spec`: a VM name, a tag, or `all`.
+pub fn targets<'a>(config: &'a VMConfig, spec: &str) -> Vec<&'a VMInfo> {
+    let mut vms: Vec<&VMInfo> = match config.vms.get(spec) {
+        Some(vm) => vec![vm],
+        None => config.vms.values().filter(|vm| spec == "all" || vm.tags.iter().any(|t| t == spec)).collect(),
+    };
+    vms.sort_by(|a, b| a.name.cmp(&b.name));
+    vms
+}
+
+fn update_one(vm: &VMInfo) -> UpdateReport {
+    let started = Instant::now();
+    let report = |outcome, log| UpdateReport { vm: vm.name.clone(), outcome, elapsed: started.elapsed(), log };
+    if !crate::vm_running(&vm.name) {
+        return report(Outcome::Skipped("not running".to_string()), None);
+    }
+    if !vm.guest_agent {
+        return report(Outcome::Skipped("no guest agent channel".to_string()), None);
+    }
+    let result = match agent::exec(&vm.name, "/bin/sh", &["-c", UPDATE_SCRIPT], UPDATE_TIMEOUT) {
+        Ok(result) => result,
+        Err(e) => return report(Outcome::Failed(e), None),
+    };
+    let stamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let log_path = guestcron::log_dir(&vm.name).join(format!("update-{}.log", stamp));
+    let written = fs::create_dir_all(guestcron::log_dir(&vm.name))
+        .and_then(|_| fs::write(&log_path, format!("{}{}", result.stdout, result.stderr)));
+    let log = written.ok().map(|_| log_path.display().to_string());
+    match result.exit_code {
+        0 => report(Outcome::Updated, log),
+        3 => report(Outcome::Skipped("no supported package manager".to_string()), log),
+        code => report(Outcome::Failed(format!("exit {}: {}", code, result.stderr.lines().last().unwrap_or(""))), log),
+    }
+}
+
+/// Updates the given VMs in parallel, one thread per guest.
+pub fn update(vms: &[&VMInfo]) -> Vec<UpdateReport> {
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = vms.iter().map(|vm| scope.spawn(move || update_one(vm))).collect();
+        handles.into_iter().filter_map(|h| h.join().ok()).collect()
+    })
+}
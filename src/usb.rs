@@ -0,0 +1,204 @@
+use crate::config::{self, VMConfig, VMInfo};
+use crate::qmp;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+use tracing::warn;
+
+/// Controller models a VM can get, with the QEMU device behind each.
+/// `xhci` serves USB 1-3 devices; older guests without an xHCI driver
+/// need `ehci` (USB 2) or `uhci` (USB 1.1).
+pub const CONTROLLERS: [(&str, &str); 3] = [("xhci", "qemu-xhci"), ("ehci", "usb-ehci"), ("uhci", "piix3-usb-uhci")];
+
+pub fn is_known(model: &str) -> bool {
+    model == "none" || CONTROLLERS.iter().any(|(name, _)| *name == model)
+}
+
+/// Arguments for the VM's USB controller, `usb` as its bus id, and the
+/// host devices passed through. VMs with no choice keep QEMU's machine
+/// default; `none` means no USB at all.
+pub fn launch_args(vm: &VMInfo) -> Vec<String> {
+    let mut args = match &vm.usb {
+        None => Vec::new(),
+        Some(model) => match CONTROLLERS.iter().find(|(name, _)| name == model) {
+            Some((_, device)) => vec!["-device".to_string(), format!("{},id=usb", device)],
+            None => {
+                if model != "none" {
+                    warn!("VM '{}': unknown USB controller '{}', none added", vm.name, model);
+                }
+                Vec::new()
+            }
+        },
+    };
+    args.extend(passthrough_args(vm));
+    args
+}
+
+/// Where the kernel lists USB devices.
+const SYSFS_DEVICES: &str = "/sys/bus/usb/devices";
+
+/// A host USB device handed to the guest. It is matched by its IDs, so it
+/// follows the device across ports and replugs.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UsbDevice {
+    /// `vendor:product` in hex, as lsusb shows it, e.g. `046d:c52b`.
+    pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+}
+
+impl UsbDevice {
+    fn ids(&self) -> Option<(u16, u16)> {
+        let (vendor, product) = self.id.split_once(':')?;
+        Some((u16::from_str_radix(vendor, 16).ok()?, u16::from_str_radix(product, 16).ok()?))
+    }
+
+    /// Device id in QEMU, for unplugging it again.
+    fn qdev_id(&self) -> String {
+        format!("usbhost-{}", self.id.replace(':', "-"))
+    }
+}
+
+/// Reads `vendor:product` in hex into the form stored in the config.
+pub fn parse_id(s: &str) -> Result<String, String> {
+    let valid = |part: &str| part.len() == 4 && part.chars().all(|c| c.is_ascii_hexdigit());
+    match s.trim().split_once(':') {
+        Some((vendor, product)) if valid(vendor) && valid(product) => Ok(format!("{}:{}", vendor, product).to_ascii_lowercase()),
+        _ => Err(format!("invalid USB device '{}'; use vendor:product in hex as `SRQemu usb list` shows, e.g. 046d:c52b", s)),
+    }
+}
+
+/// A USB device plugged into the host.
+pub struct HostDevice {
+    pub id: String,
+    pub bus: u32,
+    pub address: u32,
+    pub description: String,
+}
+
+impl HostDevice {
+    fn node(&self) -> PathBuf {
+        PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", self.bus, self.address))
+    }
+}
+
+/// USB devices plugged into the host, hubs left out, in bus order.
+pub fn host_devices() -> Vec<HostDevice> {
+    let mut devices: Vec<HostDevice> = fs::read_dir(SYSFS_DEVICES)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let dir = entry.path();
+            let attr = |name: &str| fs::read_to_string(dir.join(name)).ok().map(|s| s.trim().to_string());
+            if attr("bDeviceClass").as_deref() == Some("09") {
+                return None;
+            }
+            let description = [attr("manufacturer"), attr("product")].into_iter().flatten().collect::<Vec<_>>().join(" ");
+            Some(HostDevice {
+                id: format!("{}:{}", attr("idVendor")?, attr("idProduct")?),
+                bus: attr("busnum")?.parse().ok()?,
+                address: attr("devnum")?.parse().ok()?,
+                description,
+            })
+        })
+        .collect();
+    devices.sort_by_key(|d| (d.bus, d.address));
+    devices
+}
+
+/// Arguments for the devices passed through, after the controller's. A VM
+/// without a controller of its own gets an xHCI one for them.
+fn passthrough_args(vm: &VMInfo) -> Vec<String> {
+    if vm.usb_devices.is_empty() {
+        return Vec::new();
+    }
+    if vm.usb.as_deref() == Some("none") {
+        warn!("VM '{}' has no USB controller; its USB devices are left out", vm.name);
+        return Vec::new();
+    }
+    let mut args = Vec::new();
+    if vm.usb.is_none() {
+        args.extend(["-device".to_string(), "qemu-xhci,id=usb".to_string()]);
+    }
+    for device in &vm.usb_devices {
+        let Some((vendor, product)) = device.ids() else {
+            warn!("VM '{}': invalid USB device '{}' left out", vm.name, device.id);
+            continue;
+        };
+        args.extend([
+            "-device".to_string(),
+            format!("usb-host,bus=usb.0,vendorid={:#06x},productid={:#06x},id={}", vendor, product, device.qdev_id()),
+        ]);
+    }
+    args
+}
+
+/// Warns when QEMU, running as this user, could not open a plugged-in
+/// device.
+fn check_access(id: &str) {
+    for device in host_devices().into_iter().filter(|d| d.id == id) {
+        let node = device.node();
+        if let Err(e) = fs::OpenOptions::new().write(true).open(&node)
+            && e.kind() == std::io::ErrorKind::PermissionDenied
+        {
+            warn!(
+                "{} is not writable for this user; add a udev rule such as SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{}\", MODE=\"0666\"",
+                node.display(),
+                id.split(':').next().unwrap_or_default()
+            );
+        }
+    }
+}
+
+/// Passes a host device through to the VM on every start and, if it runs,
+/// right away.
+pub fn attach(config: &mut VMConfig, name: &str, id: &str) -> Result<String, String> {
+    let id = parse_id(id)?;
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    if vm.usb.as_deref() == Some("none") {
+        return Err(format!("'{}' has no USB controller; choose one under VM settings first", name));
+    }
+    if vm.usb_devices.iter().any(|d| d.id == id) {
+        return Err(format!("{} is already passed through to '{}'", id, name));
+    }
+    let description = host_devices().into_iter().find(|d| d.id == id).map(|d| d.description).unwrap_or_default();
+    if description.is_empty() {
+        warn!("{} is not plugged in; '{}' gets it once it is", id, name);
+    }
+    let device = UsbDevice { id: id.clone(), description };
+    vm.usb_devices.push(device.clone());
+    let running = crate::vm_running(name);
+    let plugged = running.then(|| plug(name, &device));
+    config::save_config(config).map_err(|e| e.to_string())?;
+    check_access(&id);
+    match plugged {
+        None => Ok(format!("{} is passed through to '{}' from its next start.", id, name)),
+        Some(Ok(())) => Ok(format!("{} is passed through to '{}' now and on every start.", id, name)),
+        Some(Err(e)) => Err(format!("{} is passed through to '{}' from its next start, but not now: {}", id, name, e)),
+    }
+}
+
+fn plug(vm_name: &str, device: &UsbDevice) -> Result<(), String> {
+    let (vendor, product) = device.ids().ok_or_else(|| format!("invalid USB device '{}'", device.id))?;
+    let arguments = json!({ "driver": "usb-host", "bus": "usb.0", "vendorid": vendor, "productid": product, "id": device.qdev_id() });
+    qmp::command(vm_name, "device_add", Some(arguments))
+        .map(|_| ())
+        .map_err(|e| format!("{} (a VM started without a USB controller gets one when it restarts)", e))
+}
+
+/// Takes a device away from the VM, live if it runs.
+pub fn detach(config: &mut VMConfig, name: &str, id: &str) -> Result<String, String> {
+    let id = parse_id(id)?;
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    let Some(position) = vm.usb_devices.iter().position(|d| d.id == id) else {
+        return Err(format!("{} is not passed through to '{}'", id, name));
+    };
+    let device = vm.usb_devices.remove(position);
+    config::save_config(config).map_err(|e| e.to_string())?;
+    if crate::vm_running(name) {
+        qmp::command(name, "device_del", Some(json!({ "id": device.qdev_id() })))?;
+    }
+    Ok(format!("{} is no longer passed through to '{}'.", id, name))
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command as ShellCommand, Stdio};
+use std::sync::OnceLock;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Overrides the keyring and the prompt, for scripts and timers.
+const PASSPHRASE_ENV: &str = "SRQEMU_PASSPHRASE";
+/// Attribute the passphrase is stored under in the Secret Service keyring.
+const KEYRING_ATTR: [&str; 2] = ["application", "srqemu"];
+
+static PASSPHRASE: OnceLock<String> = OnceLock::new();
+
+/// Starts every encrypted file: the HMAC-SHA256 tag over the openssl
+/// output follows it, then that output.
+const MAGIC: &[u8] = b"SRQemu-HMAC1\n";
+const TAG_LEN: usize = 32;
+/// Where openssl puts its salt: after its "Salted__" header.
+const SALT: std::ops::Range<usize> = 8..16;
+/// Keeps the MAC key apart from the key openssl derives from the same
+/// passphrase and salt.
+const MAC_CONTEXT: &[u8] = b"srqemu-config-mac";
+
+/// Where the encrypted config lives, next to the plain one confy manages.
+/// Its presence is what turns encryption on.
+pub fn encrypted_path(plain: &str) -> Option<PathBuf> {
+    let path = confy::get_configuration_file_path(plain, None).ok()?;
+    Some(path.with_extension("toml.enc"))
+}
+
+pub fn enabled(plain: &str) -> bool {
+    encrypted_path(plain).is_some_and(|p| p.exists())
+}
+
+fn keyring_lookup() -> Option<String> {
+    let out = ShellCommand::new("secret-tool").arg("lookup").args(KEYRING_ATTR).output().ok()?;
+    let secret = String::from_utf8(out.stdout).ok()?;
+    (out.status.success() && !secret.is_empty()).then_some(secret)
+}
+
+/// Saves the passphrase in the desktop keyring so it is not asked for on
+/// every run.
+pub fn keyring_store(passphrase: &str) -> Result<(), String> {
+    let mut child = ShellCommand::new("secret-tool")
+        .args(["store", "--label=SRQemu configuration"])
+        .args(KEYRING_ATTR)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("cannot run secret-tool: {}", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(passphrase.as_bytes());
+    }
+    match child.wait() {
+        Ok(status) if status.success() => Ok(()),
+        _ => Err("secret-tool could not store the passphrase".to_string()),
+    }
+}
+
+pub fn keyring_clear() {
+    crate::runner::best_effort(ShellCommand::new("secret-tool").arg("clear").args(KEYRING_ATTR));
+}
+
+/// The passphrase from the environment, the keyring or, failing both, the
+/// terminal. Asked for at most once per run.
+fn passphrase() -> &'static str {
+    PASSPHRASE.get_or_init(|| {
+        std::env::var(PASSPHRASE_ENV)
+            .ok()
+            .or_else(keyring_lookup)
+            .unwrap_or_else(|| crate::prompt_secret("Configuration passphrase: "))
+    })
+}
+
+/// Uses `passphrase` for the rest of this run, e.g. right after it was
+/// chosen.
+pub fn set_passphrase(passphrase: String) {
+    let _ = PASSPHRASE.set(passphrase);
+}
+
+/// Pipes `input` through `openssl enc`; the passphrase goes through the
+/// child's environment, never its command line.
+fn openssl(decrypt: bool, input: &[u8]) -> Result<Vec<u8>, String> {
+    let mut cmd = ShellCommand::new("openssl");
+    cmd.args(["enc", "-aes-256-cbc", "-pbkdf2", "-iter", "200000", "-salt", "-pass", "env:SRQEMU_KEY"]);
+    if decrypt {
+        cmd.arg("-d");
+    }
+    let mut child = cmd
+        .env("SRQEMU_KEY", passphrase())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("cannot run openssl: {}", e))?;
+    let mut stdin = child.stdin.take().ok_or("cannot write to openssl")?;
+    let data = input.to_vec();
+    // Written from a thread so a large config cannot deadlock on full pipes.
+    let writer = std::thread::spawn(move || stdin.write_all(&data));
+    let out = child.wait_with_output().map_err(|e| e.to_string())?;
+    let _ = writer.join();
+    if !out.status.success() {
+        return Err(if decrypt { "wrong passphrase or damaged file".to_string() } else { String::from_utf8_lossy(&out.stderr).trim().to_string() });
+    }
+    Ok(out.stdout)
+}
+
+/// The MAC over `ciphertext`, keyed by the passphrase and openssl's salt.
+fn mac(ciphertext: &[u8]) -> Result<Hmac<Sha256>, String> {
+    let salt = ciphertext.get(SALT).ok_or("encrypted file is truncated")?;
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase().as_bytes(), &[MAC_CONTEXT, salt].concat(), 200000, &mut key);
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).map_err(|e| e.to_string())?;
+    mac.update(ciphertext);
+    Ok(mac)
+}
+
+/// AES-CBC alone lets whoever can write the file flip bits in the
+/// config, so the tag is checked before anything is decrypted.
+fn open(data: &[u8]) -> Result<Vec<u8>, String> {
+    let sealed = data.strip_prefix(MAGIC).ok_or("not an encrypted SRQemu config, or one from before configs were authenticated")?;
+    if sealed.len() < TAG_LEN {
+        return Err("encrypted file is truncated".to_string());
+    }
+    let (tag, ciphertext) = sealed.split_at(TAG_LEN);
+    mac(ciphertext)?.verify_slice(tag).map_err(|_| "wrong passphrase or damaged file")?;
+    openssl(true, ciphertext)
+}
+
+fn seal(contents: &[u8]) -> Result<Vec<u8>, String> {
+    let ciphertext = openssl(false, contents)?;
+    let tag = mac(&ciphertext)?.finalize().into_bytes();
+    Ok([MAGIC, &tag, &ciphertext].concat())
+}
+
+pub fn read(plain: &str) -> Result<String, String> {
+    let path = encrypted_path(plain).ok_or("cannot locate the config directory")?;
+    let data = fs::read(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    String::from_utf8(open(&data)?).map_err(|_| "decrypted config is not text".to_string())
+}
+
+pub fn write(plain: &str, contents: &str) -> Result<(), String> {
+    let path = encrypted_path(plain).ok_or("cannot locate the config directory")?;
+    write_to(&path, contents.as_bytes())
+}
+
+/// Encrypts `contents` into `path`, e.g. a copy of the config kept
+/// elsewhere.
+pub fn write_to(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let data = seal(contents)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    // Write next to the target first so a failure never leaves half a file.
+    let tmp = path.with_extension("enc.tmp");
+    fs::write(&tmp, data).map_err(|e| format!("cannot write {}: {}", tmp.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600));
+    }
+    fs::rename(&tmp, path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tampered_files_are_refused_before_decrypting() {
+        set_passphrase("correct horse".to_string());
+        let sealed = seal(b"[vms]\n").unwrap();
+        assert_eq!(open(&sealed).unwrap(), b"[vms]\n");
+        let mut flipped = sealed.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(open(&flipped).unwrap_err(), "wrong passphrase or damaged file");
+        assert!(open(&sealed[MAGIC.len()..]).is_err());
+    }
+}
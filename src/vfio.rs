@@ -0,0 +1,282 @@
+//! PCI passthrough with VFIO: devices such as a GPU handed to one VM. The
+//! host needs the IOMMU on and the devices bound to vfio-pci; `problems`
+//! says what is missing before QEMU fails with far less helpful errors.
+
+use crate::config::{self, VMConfig, VMInfo};
+use crate::size;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PciDevice {
+    /// Host address as `lspci -D` shows it, e.g. `0000:01:00.0`.
+    pub address: String,
+    /// The guest's primary display: legacy VGA goes to this card and QEMU's
+    /// emulated one is left out.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub primary_gpu: bool,
+    /// ROM image shown to the guest instead of the card's own, for cards
+    /// whose ROM is unusable once the host booted from them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub romfile: Option<String>,
+}
+
+impl PciDevice {
+    /// `0000:01:00`, shared by the functions of one card.
+    fn slot(&self) -> &str {
+        self.address.split_once('.').map_or(&self.address, |(slot, _)| slot)
+    }
+
+    fn function(&self) -> &str {
+        self.address.split_once('.').map_or("0", |(_, function)| function)
+    }
+}
+
+/// Reads a PCI address, with or without its domain, into the full form
+/// stored in the config.
+pub fn parse_address(s: &str) -> Result<String, String> {
+    let s = s.trim().to_ascii_lowercase();
+    let full = if s.matches(':').count() == 1 { format!("0000:{}", s) } else { s.clone() };
+    let hex = |part: &str, len: usize| part.len() == len && part.chars().all(|c| c.is_ascii_hexdigit());
+    let valid = match full.split(':').collect::<Vec<_>>()[..] {
+        [domain, bus, rest] => match rest.split_once('.') {
+            Some((slot, function)) => hex(domain, 4) && hex(bus, 2) && hex(slot, 2) && matches!(function.as_bytes(), [b'0'..=b'7']),
+            None => false,
+        },
+        _ => false,
+    };
+    if valid { Ok(full) } else { Err(format!("invalid PCI address '{}'; use the form `lspci -D` shows, e.g. 0000:01:00.0", s)) }
+}
+
+/// Passes a device through from the VM's next start; `problems` tells
+/// whether the host is ready for it.
+pub fn add(config: &mut VMConfig, name: &str, device: PciDevice) -> Result<String, String> {
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    if vm.pci_devices.iter().any(|d| d.address == device.address) {
+        return Err(format!("{} is already passed through to '{}'", device.address, name));
+    }
+    if device.primary_gpu && vm.pci_devices.iter().any(|d| d.primary_gpu) {
+        return Err(format!("'{}' already has a primary GPU", name));
+    }
+    let address = device.address.clone();
+    vm.pci_devices.push(device);
+    config::save_config(config).map_err(|e| e.to_string())?;
+    Ok(format!("{} is passed through to '{}' from its next start.", address, name))
+}
+
+pub fn remove(config: &mut VMConfig, name: &str, address: &str) -> Result<String, String> {
+    let vm = config.vms.get_mut(name).ok_or_else(|| format!("VM '{}' not found", name))?;
+    let Some(position) = vm.pci_devices.iter().position(|d| d.address == address) else {
+        return Err(format!("{} is not passed through to '{}'", address, name));
+    };
+    vm.pci_devices.remove(position);
+    config::save_config(config).map_err(|e| e.to_string())?;
+    Ok(format!("{} is no longer passed through to '{}' from its next start.", address, name))
+}
+
+/// Arguments for the passed-through devices: a q35 machine, as PCIe
+/// devices expect, and a root port per card. The functions of one card
+/// share its port as on the host, so drivers pairing a GPU with its audio
+/// function find both.
+pub fn launch_args(vm: &VMInfo) -> Vec<String> {
+    if vm.pci_devices.is_empty() {
+        return Vec::new();
+    }
+    let mut args = vec!["-machine".to_string(), "q35,kernel-irqchip=on".to_string()];
+    if vm.pci_devices.iter().any(|d| d.primary_gpu) {
+        args.extend(["-vga".to_string(), "none".to_string()]);
+    }
+    let mut slots: Vec<&str> = Vec::new();
+    for device in &vm.pci_devices {
+        if !slots.contains(&device.slot()) {
+            slots.push(device.slot());
+        }
+    }
+    for (i, slot) in slots.iter().enumerate() {
+        let port = format!("vfio{}", i);
+        args.extend(["-device".to_string(), format!("pcie-root-port,id={},chassis={}", port, i + 1)]);
+        let functions: Vec<&PciDevice> = vm.pci_devices.iter().filter(|d| d.slot() == *slot).collect();
+        for device in &functions {
+            let function = if functions.len() > 1 { device.function() } else { "0" };
+            let mut arg = format!("vfio-pci,host={},bus={},addr=00.{}", device.address, port, function);
+            if functions.len() > 1 && function == "0" {
+                arg.push_str(",multifunction=on");
+            }
+            if device.primary_gpu {
+                arg.push_str(",x-vga=on");
+            }
+            if let Some(rom) = &device.romfile {
+                arg.push_str(&format!(",romfile={}", rom));
+            }
+            args.extend(["-device".to_string(), arg]);
+        }
+    }
+    args
+}
+
+/// The soft limit on locked memory of this process, which QEMU inherits;
+/// None when unlimited.
+fn memlock_limit() -> Option<u64> {
+    let limits = fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits.lines().find(|l| l.starts_with("Max locked memory"))?;
+    line["Max locked memory".len()..].split_whitespace().next()?.parse().ok()
+}
+
+fn driver(device: &Path) -> Option<String> {
+    Some(fs::read_link(device.join("driver")).ok()?.file_name()?.to_string_lossy().into_owned())
+}
+
+fn attr(device: &Path, name: &str) -> String {
+    fs::read_to_string(device.join(name)).map(|s| s.trim().trim_start_matches("0x").to_string()).unwrap_or_default()
+}
+
+/// Why the VM's devices cannot be passed through on this host, one line
+/// each with what to do about it; empty when they can.
+pub fn problems(vm: &VMInfo) -> Vec<String> {
+    if vm.pci_devices.is_empty() {
+        return Vec::new();
+    }
+    if !crate::arch::of(vm).is_pc() {
+        return vec![format!("passthrough sets up a q35 PC, which {} guests are not; remove the devices with `SRQemu vfio remove`", crate::arch::of(vm).name())];
+    }
+    // Root may lock any amount of memory.
+    let memlock = if crate::sandbox::is_root() { None } else { memlock_limit() };
+    problems_at(Path::new("/"), vm, memlock)
+}
+
+fn problems_at(root: &Path, vm: &VMInfo, memlock: Option<u64>) -> Vec<String> {
+    let groups = root.join("sys/kernel/iommu_groups");
+    if fs::read_dir(&groups).map_or(true, |mut entries| entries.next().is_none()) {
+        return vec![
+            "the IOMMU is off: enable VT-d (Intel) or AMD-Vi (AMD) in the firmware settings and boot with intel_iommu=on or amd_iommu=on".to_string(),
+        ];
+    }
+    let mut problems = Vec::new();
+    let mut report = |line: String| {
+        if !problems.contains(&line) {
+            problems.push(line);
+        }
+    };
+    for device in &vm.pci_devices {
+        let address = &device.address;
+        let dir = root.join("sys/bus/pci/devices").join(address);
+        if !dir.exists() {
+            report(format!("{}: no such PCI device; `lspci -D` lists the host's devices", address));
+            continue;
+        }
+        match driver(&dir) {
+            Some(driver) if driver == "vfio-pci" => {}
+            driver => report(format!(
+                "{} is bound to {} instead of vfio-pci: boot with vfio-pci.ids={}:{}, or run `echo vfio-pci > /sys/bus/pci/devices/{}/driver_override` and rebind it",
+                address,
+                driver.unwrap_or_else(|| "no driver".to_string()),
+                attr(&dir, "vendor"),
+                attr(&dir, "device"),
+                address
+            )),
+        }
+        let Some(group) = fs::read_link(dir.join("iommu_group")).ok().and_then(|g| g.file_name().map(|n| n.to_string_lossy().into_owned())) else {
+            report(format!("{} is in no IOMMU group; the IOMMU does not cover it", address));
+            continue;
+        };
+        let members = fs::read_dir(groups.join(&group).join("devices")).into_iter().flatten().flatten();
+        for member in members {
+            let name = member.file_name().to_string_lossy().into_owned();
+            let path = root.join("sys/bus/pci/devices").join(&name);
+            // Bridges stay with the host; everything else must let go.
+            if name == *address || attr(&path, "class").starts_with("0604") {
+                continue;
+            }
+            if let Some(other) = driver(&path).filter(|d| d != "vfio-pci") {
+                report(format!(
+                    "{} shares IOMMU group {} with {} (driver {}): bind that to vfio-pci too, or move the card to a slot with a group of its own",
+                    address, group, name, other
+                ));
+            }
+        }
+        let node = root.join("dev/vfio").join(&group);
+        match fs::OpenOptions::new().read(true).write(true).open(&node) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                report(format!("{} is missing: load the vfio-pci module and bind {} to it", node.display(), address))
+            }
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => report(format!(
+                "{} is not writable for this user: grant it with a udev rule such as SUBSYSTEM==\"vfio\", OWNER=\"{}\"",
+                node.display(),
+                std::env::var("USER").unwrap_or_else(|_| "you".to_string())
+            )),
+            Err(e) if e.raw_os_error() == Some(16) => report(format!("IOMMU group {} of {} is in use, by another VM or program", group, address)),
+            Err(e) => report(format!("cannot open {}: {}", node.display(), e)),
+        }
+        if let Some(rom) = &device.romfile
+            && !Path::new(rom).exists()
+        {
+            report(format!("ROM file {} of {} does not exist", rom, address));
+        }
+    }
+    let memory = size::memory_bytes(&vm.memory).unwrap_or(0);
+    if let Some(limit) = memlock
+        && limit < memory
+    {
+        report(format!(
+            "VFIO locks all {} of guest RAM, but only {} may be locked: raise the limit with `ulimit -l`, LimitMEMLOCK= for the daemon, or /etc/security/limits.conf",
+            size::to_qemu(memory),
+            size::to_qemu(limit)
+        ));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_get_their_domain() {
+        assert_eq!(parse_address("01:00.0").unwrap(), "0000:01:00.0");
+        assert_eq!(parse_address("0000:0A:1f.7").unwrap(), "0000:0a:1f.7");
+        assert!(parse_address("01:00.8").is_err());
+        assert!(parse_address("gpu").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn checks_find_unbound_group_members() {
+        use std::os::unix::fs::symlink;
+        let root = std::env::temp_dir().join(format!("srqemu-vfio-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let devices = root.join("sys/bus/pci/devices");
+        let drivers = root.join("sys/bus/pci/drivers");
+        let group = root.join("sys/kernel/iommu_groups/7");
+        fs::create_dir_all(group.join("devices")).unwrap();
+        fs::create_dir_all(drivers.join("vfio-pci")).unwrap();
+        fs::create_dir_all(drivers.join("snd_hda_intel")).unwrap();
+        fs::create_dir_all(root.join("dev/vfio")).unwrap();
+        fs::write(root.join("dev/vfio/7"), "").unwrap();
+        for (address, driver, class) in [("0000:01:00.0", "vfio-pci", "0x030000"), ("0000:01:00.1", "snd_hda_intel", "0x040300")] {
+            let dir = devices.join(address);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("class"), class).unwrap();
+            fs::write(dir.join("vendor"), "0x10de\n").unwrap();
+            fs::write(dir.join("device"), "0x1b80\n").unwrap();
+            symlink(drivers.join(driver), dir.join("driver")).unwrap();
+            symlink(&group, dir.join("iommu_group")).unwrap();
+            symlink(&dir, group.join("devices").join(address)).unwrap();
+        }
+        let mut vm: VMInfo = toml::from_str("name = \"gpu\"\nmemory = \"8G\"\ncpu = \"host\"\nthreads = \"4\"\ndisk = \"gpu.qcow2\"\niso = \"\"\n").unwrap();
+        vm.pci_devices = vec![PciDevice { address: "0000:01:00.0".to_string(), primary_gpu: true, romfile: None }];
+
+        let problems = problems_at(&root, &vm, Some(64 << 20));
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("shares IOMMU group 7 with 0000:01:00.1 (driver snd_hda_intel)"), "{:?}", problems);
+        assert!(problems[1].contains("ulimit -l"), "{:?}", problems);
+
+        vm.pci_devices.push(PciDevice { address: "0000:01:00.1".to_string(), primary_gpu: false, romfile: None });
+        let problems = problems_at(&root, &vm, None);
+        assert!(problems.iter().any(|p| p.contains("0000:01:00.1 is bound to snd_hda_intel instead of vfio-pci: boot with vfio-pci.ids=10de:1b80")), "{:?}", problems);
+        fs::remove_dir_all(&root).unwrap();
+        assert!(problems_at(&root, &vm, None)[0].contains("IOMMU is off"));
+    }
+}
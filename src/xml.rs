@@ -0,0 +1,108 @@
+/// Just enough XML for the machine descriptions SRQemu imports (libvirt
+/// domains, VirtualBox `.vbox` files): elements, attributes and text. No
+/// namespaces, DTDs or CDATA.
+#[derive(Debug, Default)]
+pub struct Element {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+
+    /// Follows a `/`-separated path of child names.
+    pub fn find(&self, path: &str) -> Option<&Element> {
+        path.split('/').try_fold(self, |e, name| e.child(name))
+    }
+
+    /// Every element below this one with the given name, depth first.
+    pub fn descendants<'a>(&'a self, name: &'a str) -> Vec<&'a Element> {
+        let mut found = Vec::new();
+        for child in &self.children {
+            if child.name == name {
+                found.push(child);
+            }
+            found.extend(child.descendants(name));
+        }
+        found
+    }
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Parses `name attr="v" attr2='v2'` from inside a start tag.
+fn parse_tag(tag: &str) -> Result<Element, String> {
+    let tag = tag.trim();
+    let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+    let mut element = Element { name: tag[..name_end].to_string(), ..Default::default() };
+    let mut rest = tag[name_end..].trim_start();
+    while !rest.is_empty() {
+        let (key, after) = rest.split_once('=').ok_or_else(|| format!("bad attribute in <{}>", tag))?;
+        let after = after.trim_start();
+        let quote = after.chars().next().filter(|c| *c == '"' || *c == '\'');
+        let quote = quote.ok_or_else(|| format!("unquoted attribute in <{}>", tag))?;
+        let value_end = after[1..].find(quote).ok_or_else(|| format!("unterminated attribute in <{}>", tag))?;
+        element.attrs.push((key.trim().to_string(), unescape(&after[1..1 + value_end])));
+        rest = after[value_end + 2..].trim_start();
+    }
+    Ok(element)
+}
+
+/// Parses a document and returns its root element.
+pub fn parse(doc: &str) -> Result<Element, String> {
+    let mut stack: Vec<Element> = vec![Element::default()];
+    let mut rest = doc;
+    while let Some(open) = rest.find('<') {
+        let text = rest[..open].trim();
+        if !text.is_empty()
+            && let Some(top) = stack.last_mut()
+        {
+            top.text.push_str(&unescape(text));
+        }
+        rest = &rest[open..];
+        let skip_to = |rest: &str, end: &str| rest.find(end).map(|i| i + end.len()).ok_or("unterminated markup".to_string());
+        if rest.starts_with("<!--") {
+            rest = &rest[skip_to(rest, "-->")?..];
+            continue;
+        }
+        if rest.starts_with("<?") || rest.starts_with("<!") {
+            rest = &rest[skip_to(rest, ">")?..];
+            continue;
+        }
+        let close = rest.find('>').ok_or("unterminated tag")?;
+        let tag = &rest[1..close];
+        rest = &rest[close + 1..];
+        if let Some(name) = tag.strip_prefix('/') {
+            let element = stack.pop().filter(|e| e.name == name.trim() && !stack.is_empty());
+            let element = element.ok_or_else(|| format!("unexpected </{}>", name.trim()))?;
+            stack.last_mut().ok_or("unbalanced document")?.children.push(element);
+        } else if let Some(tag) = tag.strip_suffix('/') {
+            let element = parse_tag(tag)?;
+            stack.last_mut().ok_or("unbalanced document")?.children.push(element);
+        } else {
+            stack.push(parse_tag(tag)?);
+        }
+    }
+    if stack.len() != 1 {
+        return Err(format!("unclosed <{}>", stack.last().map(|e| e.name.as_str()).unwrap_or_default()));
+    }
+    stack.pop().and_then(|doc| doc.children.into_iter().next()).ok_or_else(|| "empty document".to_string())
+}
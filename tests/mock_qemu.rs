@@ -0,0 +1,1216 @@
+//! Drives the binary through the create, start, stop and delete flows
+//! with the mock QEMU backend (`SRQEMU_MOCK`), in a throwaway home.
+//! The mock only exists on Unix.
+#![cfg(unix)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+struct Sandbox {
+    home: PathBuf,
+}
+
+impl Sandbox {
+    fn new(test: &str) -> Sandbox {
+        let home = std::env::temp_dir().join(format!("srqemu-test-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(home.join("mock")).unwrap();
+        Sandbox { home }
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_SRQemu"));
+        cmd.args(args)
+            .env("HOME", &self.home)
+            .env("XDG_CONFIG_HOME", self.home.join(".config"))
+            .env("XDG_STATE_HOME", self.home.join(".state"))
+            .env("XDG_DATA_HOME", self.home.join(".local/share"))
+            .env("SRQEMU_MOCK", self.home.join("mock"))
+            // Output checks expect English whatever the host's locale.
+            .env("SRQEMU_LANG", "en");
+        cmd
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    /// Runs a command that must succeed and returns its stdout.
+    fn ok(&self, args: &[&str]) -> String {
+        let out = self.run(args);
+        assert!(out.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&out.stderr));
+        String::from_utf8_lossy(&out.stdout).into_owned()
+    }
+
+    fn vm_dir(&self, name: &str) -> PathBuf {
+        self.home.join("vms").join(name)
+    }
+
+    /// The command line the mock QEMU was started with.
+    fn argv(&self, name: &str) -> Vec<String> {
+        let text = fs::read_to_string(self.home.join("mock").join(format!("{}.argv", name))).unwrap();
+        text.lines().map(str::to_string).collect()
+    }
+
+    /// Runs `SRQemu daemon` until the returned guard is dropped.
+    fn daemon(&self) -> KillOnDrop {
+        let daemon = self.command(&["daemon"]).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap();
+        let socket = self.home.join(".state/qemuctl/daemon.sock");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !socket.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        KillOnDrop(daemon)
+    }
+
+    fn config(&self) -> String {
+        fs::read_to_string(self.home.join(".config/qemuctl/default-config.toml")).unwrap()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        // Mock VMs left running by a failed test would outlive it.
+        for entry in fs::read_dir(self.home.join("vms")).into_iter().flatten().flatten() {
+            if let Ok(pid) = fs::read_to_string(entry.path().join("qemu.pid")) {
+                let _ = Command::new("kill").arg(pid.trim()).status();
+            }
+        }
+        let _ = fs::remove_dir_all(&self.home);
+    }
+}
+
+/// A background SRQemu that must not outlive a failed test.
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn has_pair(argv: &[String], flag: &str, value: &str) -> bool {
+    argv.windows(2).any(|w| w[0] == flag && w[1] == value)
+}
+
+fn image(path: &Path) -> serde_json::Value {
+    serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+}
+
+#[test]
+fn create_writes_config_and_disk() {
+    let sandbox = Sandbox::new("create");
+    sandbox.ok(&["create", "web", "--memory", "1.5G", "--disk", "20G,bus=nvme"]);
+
+    let config = sandbox.config();
+    assert!(config.contains("[vms.web]"), "{}", config);
+    assert!(config.contains("1536M"), "{}", config);
+    let disk = image(&sandbox.vm_dir("web").join("web.qcow2"));
+    assert_eq!(disk["format"], "qcow2");
+    assert_eq!(disk["virtual-size"], 20u64 << 30);
+
+    let out = sandbox.run(&["create", "web"]);
+    assert!(!out.status.success(), "creating a duplicate VM succeeded");
+}
+
+#[test]
+fn start_builds_command_line_and_stop_shuts_down() {
+    let sandbox = Sandbox::new("start-stop");
+    sandbox.ok(&["create", "web", "--memory", "2G", "--disk", "8G,bus=nvme", "--forward", "2222->22"]);
+    sandbox.ok(&["start", "web", "--headless"]);
+
+    let argv = sandbox.argv("web");
+    let dir = sandbox.vm_dir("web");
+    assert_eq!(argv[0], "qemu-system-x86_64");
+    assert!(has_pair(&argv, "-name", "web"));
+    assert!(has_pair(&argv, "-m", "2G"));
+    assert!(has_pair(&argv, "-display", "none"));
+    assert!(has_pair(&argv, "-pidfile", &dir.join("qemu.pid").display().to_string()));
+    assert!(has_pair(&argv, "-qmp", &format!("unix:{},server=on,wait=off", dir.join("qmp.sock").display())));
+    assert!(argv.iter().any(|a| a.starts_with(&format!("file={},format=qcow2", dir.join("web.qcow2").display()))));
+    assert!(argv.iter().any(|a| a.starts_with("nvme,drive=disk0")));
+    assert!(argv.iter().any(|a| a.contains("hostfwd=tcp::2222-:22")));
+
+    let status = sandbox.ok(&["status", "web"]);
+    assert!(status.contains("running"), "{}", status);
+
+    let stop = sandbox.ok(&["stop", "web"]);
+    assert!(stop.contains("shut down"), "{}", stop);
+    assert!(!dir.join("qemu.pid").exists());
+    assert!(!dir.join("qmp.sock").exists());
+    let status = sandbox.ok(&["status", "web"]);
+    assert!(status.contains("stopped"), "{}", status);
+}
+
+#[test]
+fn start_failure_is_explained() {
+    let sandbox = Sandbox::new("start-failure");
+    sandbox.ok(&["create", "web"]);
+    fs::remove_file(sandbox.vm_dir("web").join("web.qcow2")).unwrap();
+
+    let out = sandbox.run(&["start", "web", "--headless"]);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("exited right after starting"), "{}", stderr);
+    assert!(stderr.contains("A file the VM refers to is missing"), "{}", stderr);
+}
+
+#[test]
+fn delete_moves_to_trash_and_restore_brings_back() {
+    let sandbox = Sandbox::new("delete");
+    sandbox.ok(&["create", "web"]);
+    let out = sandbox.ok(&["delete", "web"]);
+    assert!(out.contains("`SRQemu restore web` brings it back"), "{}", out);
+    assert!(!sandbox.vm_dir("web").exists());
+    assert!(!sandbox.config().contains("[vms.web]"));
+
+    sandbox.ok(&["restore", "web"]);
+    assert!(sandbox.vm_dir("web").join("web.qcow2").exists());
+    assert!(sandbox.config().contains("[vms.web]"));
+}
+
+#[test]
+fn thin_clone_shares_a_frozen_base() {
+    let sandbox = Sandbox::new("clone");
+    sandbox.ok(&["create", "base", "--disk-size", "4G"]);
+    sandbox.ok(&["clone", "base", "copy", "--thin"]);
+
+    let source = image(&sandbox.vm_dir("base").join("base.qcow2"));
+    let clone = image(&sandbox.vm_dir("copy").join("copy.qcow2"));
+    assert_eq!(source["backing-filename"], clone["backing-filename"]);
+    assert_eq!(clone["virtual-size"], 4u64 << 30);
+    let base = PathBuf::from(clone["backing-filename"].as_str().unwrap());
+    assert!(base.starts_with(sandbox.home.join("vms/images/clones")), "{}", base.display());
+}
+
+#[test]
+fn cdrom_changes_stored_and_live_media() {
+    let sandbox = Sandbox::new("cdrom");
+    let installer = sandbox.home.join("installer.iso");
+    let rescue = sandbox.home.join("rescue.iso");
+    fs::write(&installer, "").unwrap();
+    fs::write(&rescue, "").unwrap();
+    sandbox.ok(&["create", "web"]);
+
+    let out = sandbox.run(&["start", "web", "--headless", "--boot-cdrom"]);
+    assert!(!out.status.success(), "booting from an empty drive succeeded");
+
+    sandbox.ok(&["cdrom", "web", "insert", installer.to_str().unwrap()]);
+    assert!(sandbox.config().contains(&format!("cdrom = '{}'", installer.display())));
+
+    sandbox.ok(&["start", "web", "--headless", "--iso", rescue.to_str().unwrap(), "--boot-cdrom"]);
+    let argv = sandbox.argv("web");
+    assert!(has_pair(&argv, "-drive", &format!("if=ide,index=2,media=cdrom,id=cd0,file={}", rescue.display())));
+    assert!(has_pair(&argv, "-boot", "order=d"));
+
+    sandbox.ok(&["cdrom", "web", "eject"]);
+    let qmp = fs::read_to_string(sandbox.home.join("mock/web.qmp")).unwrap();
+    assert!(qmp.contains(r#""execute":"eject""#), "{}", qmp);
+    // A live change leaves the stored medium alone.
+    assert!(sandbox.config().contains("cdrom ="));
+
+    sandbox.ok(&["stop", "web"]);
+    sandbox.ok(&["cdrom", "web", "eject"]);
+    assert!(!sandbox.config().contains("cdrom ="));
+}
+
+#[test]
+fn display_option_opens_remote_console() {
+    let sandbox = Sandbox::new("display");
+    sandbox.ok(&["create", "web"]);
+
+    let out = sandbox.ok(&["start", "web", "--headless", "--display", "spice:5930"]);
+    assert!(out.contains("spice://127.0.0.1:5930"), "{}", out);
+    let argv = sandbox.argv("web");
+    assert!(has_pair(&argv, "-spice", "port=5930,addr=127.0.0.1,disable-ticketing=on"));
+    let status = sandbox.ok(&["status", "web"]);
+    assert!(status.contains("spice://127.0.0.1:5930"), "{}", status);
+    sandbox.ok(&["stop", "web"]);
+    assert!(!sandbox.vm_dir("web").join("console.json").exists());
+
+    sandbox.ok(&["start", "web", "--headless", "--display", "vnc:5905"]);
+    assert!(has_pair(&sandbox.argv("web"), "-vnc", "127.0.0.1:5"));
+    sandbox.ok(&["stop", "web"]);
+
+    let out = sandbox.run(&["start", "web", "--display", "vnc:22"]);
+    assert!(!out.status.success(), "a VNC port below 5900 was accepted");
+}
+
+#[test]
+fn rollback_undoes_a_delete() {
+    let sandbox = Sandbox::new("rollback");
+    sandbox.ok(&["create", "web"]);
+    sandbox.ok(&["delete", "web"]);
+    sandbox.ok(&["create", "db"]);
+
+    let history = sandbox.ok(&["config", "history"]);
+    assert!(history.contains("1. before delete-web"), "{}", history);
+
+    let out = sandbox.ok(&["config", "rollback"]);
+    assert!(out.contains("Back: web"), "{}", out);
+    assert!(out.contains("Gone: db"), "{}", out);
+    assert!(sandbox.config().contains("[vms.web]"));
+    assert!(!sandbox.config().contains("[vms.db]"));
+    assert!(sandbox.vm_dir("web").join("web.qcow2").exists());
+
+    // The rollback is itself undoable.
+    let history = sandbox.ok(&["config", "history"]);
+    assert!(history.contains("1. before rollback"), "{}", history);
+    sandbox.ok(&["config", "rollback"]);
+    assert!(sandbox.config().contains("[vms.db]"));
+}
+
+#[test]
+fn interrupted_create_leaves_nothing_behind() {
+    let sandbox = Sandbox::new("interrupt");
+    sandbox.ok(&["create", "db"]);
+    let mut create = Command::new(env!("CARGO_BIN_EXE_SRQemu"))
+        .args(["create", "web"])
+        .env("HOME", &sandbox.home)
+        .env("XDG_CONFIG_HOME", sandbox.home.join(".config"))
+        .env("XDG_STATE_HOME", sandbox.home.join(".state"))
+        .env("SRQEMU_MOCK", sandbox.home.join("mock"))
+        .env("SRQEMU_MOCK_SLOW", "create")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    while !sandbox.vm_dir("web").exists() {
+        assert!(started.elapsed() < Duration::from_secs(5), "create never made the VM folder");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    Command::new("kill").args(["-INT", &create.id().to_string()]).status().unwrap();
+
+    let status = create.wait().unwrap();
+    assert_eq!(status.code(), Some(130));
+    assert!(!sandbox.vm_dir("web").exists());
+    assert!(!sandbox.config().contains("[vms.web]"));
+    assert!(sandbox.config().contains("[vms.db]"));
+}
+
+#[test]
+fn guest_agent_reports_addresses_and_runs_commands() {
+    let sandbox = Sandbox::new("agent");
+    sandbox.ok(&["create", "web", "--guest-agent"]);
+    sandbox.ok(&["create", "plain"]);
+    sandbox.ok(&["start", "web", "--headless"]);
+    assert!(sandbox.argv("web").iter().any(|a| a.starts_with("virtserialport,chardev=qga0")));
+
+    assert_eq!(sandbox.ok(&["ip", "web"]), "10.0.2.15\n");
+    let all = sandbox.ok(&["ip", "web", "--all"]);
+    assert!(all.contains("lo 127.0.0.1/8"), "{}", all);
+    assert!(all.contains("eth0 fe80::5054:ff:fe12:3456/64"), "{}", all);
+
+    assert_eq!(sandbox.ok(&["exec", "web", "--", "uname", "-r"]), "uname -r\n");
+    let out = sandbox.run(&["exec", "web", "false"]);
+    assert_eq!(out.status.code(), Some(1));
+
+    sandbox.ok(&["fsfreeze", "web", "freeze"]);
+    assert_eq!(sandbox.ok(&["fsfreeze", "web", "status"]), "frozen\n");
+    sandbox.ok(&["fsfreeze", "web", "thaw"]);
+    assert_eq!(sandbox.ok(&["fsfreeze", "web", "status"]), "thawed\n");
+    sandbox.ok(&["stop", "web"]);
+
+    let out = sandbox.run(&["ip", "plain"]);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("no guest agent channel"), "{}", stderr);
+}
+
+#[test]
+fn cloud_image_gets_a_seed() {
+    let sandbox = Sandbox::new("cloud-image");
+    let cloud = sandbox.home.join("noble.img");
+    let user_data = sandbox.home.join("user-data.yaml");
+    fs::write(&cloud, "not really an image").unwrap();
+    fs::write(&user_data, "#cloud-config\npackages: [nginx]\n").unwrap();
+    sandbox.ok(&["create", "web", "--cloud-image", cloud.to_str().unwrap(), "--user-data", user_data.to_str().unwrap()]);
+
+    let dir = sandbox.vm_dir("web");
+    let disk = image(&dir.join("web.qcow2"));
+    assert_eq!(disk["format"], "qcow2");
+    assert_eq!(disk["virtual-size"], 10u64 << 30);
+    let seed = fs::read(dir.join("seed.iso")).unwrap();
+    assert_eq!(&seed[16 * 2048 + 40..16 * 2048 + 46], b"cidata");
+    assert!(sandbox.config().contains("seed = "), "{}", sandbox.config());
+
+    sandbox.ok(&["start", "web", "--headless"]);
+    let argv = sandbox.argv("web");
+    assert!(has_pair(&argv, "-drive", &format!("file={},media=cdrom,readonly=on", dir.join("seed.iso").display())));
+    sandbox.ok(&["stop", "web"]);
+
+    // Without user data the host's SSH keys go in.
+    fs::create_dir_all(sandbox.home.join(".ssh")).unwrap();
+    fs::write(sandbox.home.join(".ssh/id_ed25519.pub"), "ssh-ed25519 AAAA me@host\n").unwrap();
+    sandbox.ok(&["create", "db", "--cloud-image", cloud.to_str().unwrap()]);
+    let user_data = fs::read_to_string(sandbox.vm_dir("db").join("cloud-init/user-data")).unwrap();
+    assert!(user_data.contains("hostname: db"), "{}", user_data);
+    assert!(user_data.contains("  - ssh-ed25519 AAAA me@host"), "{}", user_data);
+}
+
+#[test]
+fn device_models_default_to_virtio_for_linux_and_can_be_changed() {
+    let sandbox = Sandbox::new("devices");
+    let cloud = sandbox.home.join("noble.img");
+    fs::write(&cloud, "not really an image").unwrap();
+    fs::create_dir_all(sandbox.home.join(".ssh")).unwrap();
+    fs::write(sandbox.home.join(".ssh/id_ed25519.pub"), "ssh-ed25519 AAAA me@host\n").unwrap();
+    sandbox.ok(&["create", "web", "--cloud-image", cloud.to_str().unwrap(), "--forward", "2222->22"]);
+    sandbox.ok(&["start", "web", "--headless"]);
+    let argv = sandbox.argv("web");
+    assert!(has_pair(&argv, "-device", "virtio-blk-pci,drive=disk0,iothread=iothread0"), "{:?}", argv);
+    assert!(argv.iter().any(|a| a.starts_with("virtio-net-pci,netdev=net0,")), "{:?}", argv);
+    sandbox.ok(&["stop", "web"]);
+
+    assert!(!sandbox.run(&["devices", "web", "--disk-bus", "floppy"]).status.success(), "an unknown bus was accepted");
+    let changed = sandbox.ok(&["devices", "web", "--disk-bus", "sata", "--nic-model", "e1000"]);
+    assert!(changed.contains("disk on sata and NICs e1000"), "{}", changed);
+    sandbox.ok(&["start", "web", "--headless"]);
+    let argv = sandbox.argv("web");
+    assert!(has_pair(&argv, "-device", "ahci,id=ahci0") && has_pair(&argv, "-device", "ide-hd,drive=disk0,bus=ahci0.0"), "{:?}", argv);
+    assert!(!argv.iter().any(|a| a.starts_with("iothread")), "{:?}", argv);
+    assert!(argv.iter().any(|a| a.starts_with("e1000,netdev=net0,")), "{:?}", argv);
+    sandbox.ok(&["stop", "web"]);
+
+    sandbox.ok(&["create", "srv", "--template", "linux-server"]);
+    let shown = sandbox.ok(&["devices", "srv"]);
+    assert!(shown.contains("disk on virtio-blk and NICs virtio"), "{}", shown);
+    sandbox.ok(&["create", "win", "--template", "windows-desktop"]);
+    let shown = sandbox.ok(&["devices", "win"]);
+    assert!(shown.contains("disk on nvme and NICs e1000"), "{}", shown);
+}
+
+#[test]
+fn pulled_images_are_used_by_alias() {
+    let sandbox = Sandbox::new("image-alias");
+    fs::create_dir_all(sandbox.home.join(".ssh")).unwrap();
+    fs::write(sandbox.home.join(".ssh/id_ed25519.pub"), "ssh-ed25519 AAAA me@host\n").unwrap();
+    let out = sandbox.run(&["create", "web", "--cloud-image", "ubuntu-24.04-cloud"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("images pull ubuntu-24.04-cloud"), "{}", String::from_utf8_lossy(&out.stderr));
+    let out = sandbox.run(&["create", "web", "--iso", "ubuntu-24.04-cloud"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("pass it as --cloud-image"), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(!sandbox.vm_dir("web").exists());
+
+    // What `images pull ubuntu-24.04-cloud` leaves behind.
+    let images = sandbox.home.join("vms/images");
+    let cloud = images.join("ubuntu-24.04-server-cloudimg-amd64.img");
+    fs::create_dir_all(&images).unwrap();
+    fs::write(&cloud, "not really an image").unwrap();
+    let index = serde_json::json!([{
+        "file": cloud,
+        "url": "https://cloud-images.ubuntu.com/releases/noble/release/ubuntu-24.04-server-cloudimg-amd64.img",
+        "sha256": "0".repeat(64),
+        "trust": "signed",
+        "alias": "ubuntu-24.04-cloud",
+    }]);
+    fs::write(images.join("index.json"), index.to_string()).unwrap();
+
+    assert!(sandbox.ok(&["images", "list"]).contains("as 'ubuntu-24.04-cloud'"));
+    sandbox.ok(&["create", "web", "--cloud-image", "ubuntu-24.04-cloud"]);
+    assert_eq!(image(&sandbox.vm_dir("web").join("web.qcow2"))["format"], "qcow2");
+}
+
+#[test]
+fn stats_count_starts_once_enabled() {
+    let sandbox = Sandbox::new("stats");
+    sandbox.ok(&["create", "web"]);
+    sandbox.ok(&["start", "web", "--headless"]);
+    sandbox.ok(&["stop", "web"]);
+    assert!(sandbox.ok(&["stats"]).contains("Usage statistics are off"));
+    assert!(!sandbox.home.join(".state/qemuctl/stats.json").exists());
+
+    sandbox.ok(&["stats", "--enable"]);
+    sandbox.ok(&["start", "web", "--headless"]);
+    sandbox.ok(&["stop", "web"]);
+    let stats = sandbox.ok(&["stats"]);
+    assert!(stats.contains("1 start(s)"), "{}", stats);
+    assert!(stats.contains("Disk use on the host"), "{}", stats);
+}
+
+#[test]
+fn plan_refuses_what_cannot_fit() {
+    let sandbox = Sandbox::new("plan");
+    sandbox.ok(&["create", "tiny", "--memory", "64M"]);
+    sandbox.ok(&["create", "huge", "--memory", "64T"]);
+    let report = sandbox.ok(&["plan"]);
+    assert!(report.contains("'huge' alone asks for more memory"), "{}", report);
+
+    assert!(sandbox.ok(&["plan", "tiny"]).contains("Yes"));
+    let out = sandbox.run(&["plan", "tiny", "huge"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("short of memory"));
+}
+
+#[test]
+fn template_fills_in_what_was_not_given() {
+    let sandbox = Sandbox::new("template");
+    sandbox.ok(&["create", "win", "--template", "windows-desktop", "--memory", "6G", "--forward", "3389->3389"]);
+    let config = sandbox.config();
+    for line in ["memory = '6G'", "firmware = 'uefi'", "bus = 'nvme'", "rtc_base = 'localtime'", "host_port = 3389"] {
+        assert!(config.contains(line), "{} missing from\n{}", line, config);
+    }
+    assert!(!config.contains("extends"), "{}", config);
+
+    let out = sandbox.run(&["create", "web", "--template", "nope"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("no template 'nope'"));
+}
+
+#[test]
+fn autostart_enable_writes_a_unit_that_uses_start() {
+    let sandbox = Sandbox::new("autostart-unit");
+    sandbox.ok(&["create", "web"]);
+    // Enabling needs a systemd user manager, which the sandbox may lack;
+    // the files and the mark are written either way.
+    sandbox.run(&["autostart", "enable", "web", "--delay", "10"]);
+    let units = sandbox.home.join(".config/systemd/user");
+    let unit = fs::read_to_string(units.join("srqemu@.service")).unwrap();
+    assert!(unit.contains(" start %I --headless\n"), "{}", unit);
+    assert!(unit.contains(" stop %I\n"), "{}", unit);
+    let delay = fs::read_to_string(units.join("srqemu@web.service.d/delay.conf")).unwrap();
+    assert!(delay.contains("ExecStartPre=/bin/sleep 10"), "{}", delay);
+    assert!(sandbox.config().contains("[vms.web.autostart]"), "{}", sandbox.config());
+
+    sandbox.run(&["autostart", "disable", "web"]);
+    assert!(!units.join("srqemu@web.service.d").exists());
+    assert!(!sandbox.config().contains("autostart]"), "{}", sandbox.config());
+}
+
+#[test]
+fn scheduled_stop_leaves_hand_started_vms_running() {
+    let sandbox = Sandbox::new("schedule");
+    sandbox.ok(&["create", "a"]);
+    sandbox.ok(&["create", "b"]);
+    // As with autostart, the timers may not be enabled in the sandbox.
+    sandbox.run(&["schedule", "add", "farm", "all", "--start", "Mon..Fri 08:00", "--stop", "Mon..Fri 20:00"]);
+    let units = sandbox.home.join(".config/systemd/user");
+    let timer = fs::read_to_string(units.join("srqemu-schedule-farm-stop.timer")).unwrap();
+    assert!(timer.contains("OnCalendar=Mon..Fri 20:00"), "{}", timer);
+    let service = fs::read_to_string(units.join("srqemu-schedule-farm-stop.service")).unwrap();
+    assert!(service.contains(" schedule-run farm stop\n"), "{}", service);
+
+    sandbox.ok(&["schedule-run", "farm", "start"]);
+    assert!(sandbox.vm_dir("a").join("qemu.pid").exists());
+    sandbox.ok(&["stop", "b"]);
+    sandbox.ok(&["start", "b", "--headless"]);
+
+    let out = sandbox.ok(&["schedule-run", "farm", "stop"]);
+    assert!(out.contains("Leaving 'b' running"), "{}", out);
+    assert!(sandbox.ok(&["status", "a"]).contains("stopped"));
+    assert!(sandbox.ok(&["status", "b"]).contains("running"));
+
+    sandbox.run(&["schedule", "remove", "farm"]);
+    assert!(!units.join("srqemu-schedule-farm-stop.timer").exists());
+    assert!(!sandbox.config().contains("farm"), "{}", sandbox.config());
+}
+
+#[test]
+fn daemon_supervises_and_restarts_crashed_vms() {
+    let sandbox = Sandbox::new("daemon");
+    sandbox.ok(&["create", "web"]);
+    let config = sandbox.config().replace("[vms.web]\n", "[vms.web]\nrestart_on_crash = true\n");
+    fs::write(sandbox.home.join(".config/qemuctl/default-config.toml"), config).unwrap();
+
+    let _daemon = sandbox.daemon();
+    // A client that never sends its request holds up no one else.
+    let _silent = std::os::unix::net::UnixStream::connect(sandbox.home.join(".state/qemuctl/daemon.sock")).unwrap();
+
+    let out = sandbox.ok(&["start", "web", "--headless"]);
+    assert!(out.contains("started by the daemon"), "{}", out);
+    let status = sandbox.ok(&["daemon", "status"]);
+    assert!(status.contains("  web (pid "), "{}", status);
+
+    let pidfile = sandbox.vm_dir("web").join("qemu.pid");
+    let pid = fs::read_to_string(&pidfile).unwrap();
+    Command::new("kill").args(["-9", pid.trim()]).status().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let mut restarted = false;
+    while !restarted && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(250));
+        restarted = fs::read_to_string(&pidfile).is_ok_and(|p| p != pid && !p.is_empty());
+    }
+    assert!(restarted, "the daemon did not restart the crashed VM");
+
+    let out = sandbox.ok(&["stop", "web"]);
+    assert!(out.contains("VM 'web' stopped."), "{}", out);
+    assert!(sandbox.ok(&["status", "web"]).contains("stopped"));
+    // The daemon reaps QEMU shortly after it is gone.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut status = String::new();
+    while !status.contains("supervises no VMs") && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(100));
+        status = sandbox.ok(&["daemon", "status"]);
+    }
+    assert!(status.contains("supervises no VMs"), "{}", status);
+}
+
+#[test]
+fn guest_callbacks_follow_the_allowlist() {
+    let sandbox = Sandbox::new("callbacks");
+    sandbox.ok(&["create", "ci"]);
+    let out = sandbox.ok(&["callbacks", "ci", "snapshot,shutdown"]);
+    assert!(out.contains("may request: snapshot, shutdown"), "{}", out);
+    let _daemon = sandbox.daemon();
+    sandbox.ok(&["start", "ci", "--headless"]);
+    assert!(sandbox.argv("ci").iter().any(|a| a == "virtserialport,chardev=srqcb0,name=org.srqemu.callback.0"));
+
+    let requests = sandbox.home.join("mock/ci.callbacks");
+    let answers = sandbox.home.join("mock/ci.answers");
+    let answered = |n: usize| {
+        let deadline = Instant::now() + Duration::from_secs(15);
+        loop {
+            let text = fs::read_to_string(&answers).unwrap_or_default();
+            if text.lines().count() >= n || Instant::now() > deadline {
+                return text;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    };
+    fs::write(&requests, "reboot\nsnapshot before-job\n").unwrap();
+    let text = answered(2);
+    assert!(text.starts_with("error: unknown request 'reboot'"), "{}", text);
+    assert!(text.contains("ok snapshot before-job"), "{}", text);
+    assert!(sandbox.config().contains("before-job"), "{}", sandbox.config());
+
+    fs::write(&requests, "reboot\nsnapshot before-job\nshutdown\n").unwrap();
+    assert!(answered(3).ends_with("ok shutdown\n"));
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while sandbox.ok(&["status", "ci"]).contains("running") && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(250));
+    }
+    assert!(sandbox.ok(&["status", "ci"]).contains("stopped"));
+}
+
+#[test]
+fn run_streams_a_script_and_removes_the_copy() {
+    let sandbox = Sandbox::new("run");
+    sandbox.ok(&["create", "base", "--guest-agent"]);
+    let before = sandbox.config();
+
+    let out = sandbox.run(&["run", "--rm", "--image", "base", "--cmd", "echo from the guest; echo oops >&2; exit 3"]);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert_eq!(out.status.code(), Some(3), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(stdout.contains("from the guest\noops\n"), "{}", stdout);
+    assert_eq!(sandbox.config(), before);
+    let left: Vec<_> = fs::read_dir(sandbox.home.join("vms")).unwrap().flatten().map(|e| e.file_name()).collect();
+    assert!(!left.iter().any(|n| n.to_string_lossy().starts_with("base-run")), "{:?}", left);
+
+    let out = sandbox.ok(&["run", "--image", "base", "--cmd", "true"]);
+    assert!(out.contains("Kept VM 'base-run"), "{}", out);
+    assert!(sandbox.config().contains("ephemeral"), "{}", sandbox.config());
+}
+
+#[test]
+fn json_output_is_machine_readable() {
+    let sandbox = Sandbox::new("json");
+    sandbox.ok(&["create", "web", "--memory", "2G"]);
+    sandbox.ok(&["create", "db"]);
+    sandbox.ok(&["start", "web", "--headless"]);
+
+    let list: serde_json::Value = serde_json::from_str(&sandbox.ok(&["list", "--json"])).unwrap();
+    let names: Vec<&str> = list.as_array().unwrap().iter().map(|vm| vm["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["db", "web"]);
+    assert_eq!(list[1]["memory"], "2G");
+    assert_eq!(list[1]["status"]["running"], true);
+
+    let status: serde_json::Value = serde_json::from_str(&sandbox.ok(&["--json", "status"])).unwrap();
+    assert_eq!(status[0]["running"], false);
+    assert!(status[1]["pid"].as_u64().is_some(), "{}", status);
+    assert!(status[1]["cpu_percent"].as_f64().is_some(), "{}", status);
+
+    let info: serde_json::Value = serde_json::from_str(&sandbox.ok(&["info", "db", "--json"])).unwrap();
+    assert_eq!(info["name"], "db");
+    assert_eq!(info["status"]["running"], false);
+    assert!(info["disk_path"].as_str().unwrap().ends_with("db.qcow2"), "{}", info);
+
+    let text = sandbox.ok(&["info", "web"]);
+    assert!(text.contains("State: running"), "{}", text);
+    assert!(text.contains("memory = '2G'"), "{}", text);
+    sandbox.ok(&["stop", "web"]);
+}
+
+#[test]
+fn slots_boot_promote_and_roll_back() {
+    let sandbox = Sandbox::new("slots");
+    sandbox.ok(&["create", "web", "--disk-size", "4G"]);
+    let out = sandbox.run(&["start", "web", "--headless", "--slot", "b"]);
+    assert!(!out.status.success(), "booting a missing slot succeeded");
+
+    sandbox.ok(&["slot", "web", "create"]);
+    let copy = sandbox.vm_dir("web").join("web-b.qcow2");
+    assert_eq!(image(&copy)["virtual-size"], 4u64 << 30);
+    let drive = |disk: &str| format!("file={},format=qcow2", sandbox.vm_dir("web").join(disk).display());
+
+    sandbox.ok(&["start", "web", "--headless", "--slot", "b"]);
+    assert!(sandbox.argv("web").iter().any(|a| a.starts_with(&drive("web-b.qcow2"))));
+    sandbox.ok(&["stop", "web"]);
+    assert!(sandbox.config().contains("disk = 'web.qcow2'"), "{}", sandbox.config());
+
+    sandbox.ok(&["slot", "web", "promote", "b"]);
+    sandbox.ok(&["start", "web", "--headless"]);
+    assert!(sandbox.argv("web").iter().any(|a| a.starts_with(&drive("web-b.qcow2"))));
+    sandbox.ok(&["stop", "web"]);
+
+    sandbox.ok(&["slot", "web", "rollback"]);
+    assert!(sandbox.config().contains("disk = 'web.qcow2'"), "{}", sandbox.config());
+    sandbox.ok(&["slot", "web", "drop"]);
+    assert!(!copy.exists());
+    assert!(!sandbox.config().contains("slots"), "{}", sandbox.config());
+}
+
+#[test]
+fn pause_and_resume_show_in_status() {
+    let sandbox = Sandbox::new("pause");
+    sandbox.ok(&["create", "web"]);
+    assert!(!sandbox.run(&["pause", "web"]).status.success(), "pausing a stopped VM succeeded");
+    sandbox.ok(&["start", "web", "--headless"]);
+
+    let paused = sandbox.ok(&["pause", "web"]);
+    assert!(paused.contains("paused"), "{}", paused);
+    let status = sandbox.ok(&["status", "web"]);
+    assert!(status.contains("paused"), "{}", status);
+    let status: serde_json::Value = serde_json::from_str(&sandbox.ok(&["status", "web", "--json"])).unwrap();
+    assert_eq!(status[0]["paused"], true);
+    let list = sandbox.ok(&["list"]);
+    assert!(list.contains("State: paused"), "{}", list);
+
+    sandbox.ok(&["resume", "web"]);
+    let status = sandbox.ok(&["status", "web"]);
+    assert!(status.contains("running"), "{}", status);
+    let qmp = fs::read_to_string(sandbox.home.join("mock/web.qmp")).unwrap();
+    assert!(qmp.contains("\"stop\"") && qmp.contains("\"cont\""), "{}", qmp);
+
+    sandbox.ok(&["pause", "web"]);
+    let stop = sandbox.ok(&["stop", "web"]);
+    assert!(stop.contains("shut down"), "{}", stop);
+}
+
+#[test]
+fn desktop_entry_starts_the_vm_or_opens_its_console() {
+    use std::os::unix::fs::PermissionsExt;
+    let sandbox = Sandbox::new("desktop");
+    sandbox.ok(&["create", "web"]);
+    let written = sandbox.ok(&["desktop-entry", "web"]);
+    assert!(written.contains("srqemu-web.desktop"), "{}", written);
+    let path = sandbox.home.join(".local/share/applications/srqemu-web.desktop");
+    let entry = fs::read_to_string(&path).unwrap();
+    assert!(entry.starts_with("[Desktop Entry]\n") && entry.contains("Name=web\n") && entry.contains(" open web\n"), "{}", entry);
+    assert!(sandbox.home.join(".local/share/icons/hicolor/scalable/apps/srqemu.svg").exists());
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o111, 0o111);
+
+    sandbox.ok(&["open", "web"]);
+    assert!(!sandbox.argv("web").contains(&"none".to_string()), "{:?}", sandbox.argv("web"));
+    let again = sandbox.ok(&["open", "web"]);
+    assert!(again.contains("already running"), "{}", again);
+    sandbox.ok(&["stop", "web"]);
+
+    // A stand-in viewer records the console it was pointed at.
+    let bin = sandbox.home.join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    let viewer = bin.join("remote-viewer");
+    fs::write(&viewer, format!("#!/bin/sh\necho \"$@\" > {}\n", sandbox.home.join("viewer.url").display())).unwrap();
+    fs::set_permissions(&viewer, fs::Permissions::from_mode(0o755)).unwrap();
+    sandbox.ok(&["start", "web", "--headless", "--display", "vnc"]);
+    let path_var = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+    let out = sandbox.command(&["open", "web"]).env("PATH", path_var).output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !sandbox.home.join("viewer.url").exists() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let url = fs::read_to_string(sandbox.home.join("viewer.url")).unwrap();
+    assert!(url.starts_with("vnc://127.0.0.1:59"), "{}", url);
+    sandbox.ok(&["stop", "web"]);
+
+    sandbox.ok(&["desktop-entry", "web", "--remove"]);
+    assert!(!path.exists());
+}
+
+#[test]
+fn labels_color_listings_and_launchers() {
+    let sandbox = Sandbox::new("label");
+    sandbox.ok(&["create", "web"]);
+    assert!(!sandbox.run(&["label", "web", "--color", "mauve"]).status.success(), "an unknown color was accepted");
+    assert!(!sandbox.run(&["label", "web", "--icon", "/no/such/icon.png"]).status.success(), "a missing icon file was accepted");
+    sandbox.ok(&["label", "web", "--color", "Red", "--icon", "network-server"]);
+    let config = sandbox.config();
+    assert!(config.contains("color = 'red'") || config.contains("color = \"red\""), "{}", config);
+    assert!(config.contains("network-server"), "{}", config);
+    let shown = sandbox.ok(&["label", "web"]);
+    assert!(shown.contains("color red, icon network-server"), "{}", shown);
+
+    let entry_path = sandbox.home.join(".local/share/applications/srqemu-web.desktop");
+    sandbox.ok(&["desktop-entry", "web"]);
+    assert!(fs::read_to_string(&entry_path).unwrap().contains("Icon=network-server\n"));
+
+    // Without an icon of its own, the launcher's icon takes the color.
+    sandbox.ok(&["label", "web", "--icon", "none"]);
+    sandbox.ok(&["desktop-entry", "web"]);
+    let entry = fs::read_to_string(&entry_path).unwrap();
+    let icon = entry.lines().find_map(|line| line.strip_prefix("Icon=")).unwrap();
+    assert!(icon.ends_with("srqemu-web.svg"), "{}", entry);
+    assert!(fs::read_to_string(icon).unwrap().contains("#e01b24"));
+    sandbox.ok(&["desktop-entry", "web", "--remove"]);
+    assert!(!Path::new(icon).exists());
+
+    sandbox.ok(&["label", "web", "--color", "none"]);
+    assert!(!sandbox.config().contains("color"), "{}", sandbox.config());
+}
+
+#[test]
+fn tray_menu_follows_the_daemon() {
+    use std::os::unix::fs::PermissionsExt;
+    let sandbox = Sandbox::new("tray");
+    sandbox.ok(&["create", "web"]);
+    sandbox.ok(&["create", "db"]);
+    assert!(!sandbox.run(&["tray"]).status.success(), "the tray ran without a daemon");
+
+    // A stand-in for yad records what it was told to show.
+    let bin = sandbox.home.join("bin");
+    fs::create_dir_all(&bin).unwrap();
+    let yad = bin.join("yad");
+    fs::write(&yad, format!("#!/bin/sh\ncat > {}\n", sandbox.home.join("yad.in").display())).unwrap();
+    fs::set_permissions(&yad, fs::Permissions::from_mode(0o755)).unwrap();
+    let _daemon = sandbox.daemon();
+    sandbox.ok(&["start", "web", "--headless"]);
+    let path_var = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+    let _tray = KillOnDrop(sandbox.command(&["tray"]).env("PATH", path_var).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap());
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut shown = String::new();
+    while !shown.contains("Quit!quit") && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(100));
+        shown = fs::read_to_string(sandbox.home.join("yad.in")).unwrap_or_default();
+    }
+    assert!(shown.contains("tooltip:SRQemu: web running"), "{}", shown);
+    assert!(shown.contains("|Stop web!") && shown.contains(" stop 'web'"), "{}", shown);
+    assert!(shown.contains("Console of web!") && shown.contains("Start db!"), "{}", shown);
+    sandbox.ok(&["stop", "web"]);
+}
+
+#[test]
+fn vfio_devices_are_checked_before_start() {
+    let sandbox = Sandbox::new("vfio");
+    sandbox.ok(&["create", "web"]);
+    assert!(!sandbox.run(&["vfio", "add", "web", "gpu"]).status.success(), "a malformed PCI address was accepted");
+    let added = sandbox.ok(&["vfio", "add", "web", "FF:1F.7", "--primary-gpu"]);
+    assert!(added.contains("0000:ff:1f.7 is passed through"), "{}", added);
+    assert!(sandbox.config().contains("0000:ff:1f.7"), "{}", sandbox.config());
+
+    // No host has this device, whether or not its IOMMU is on.
+    let out = sandbox.run(&["vfio", "check", "web"]);
+    assert!(!out.status.success(), "checking a missing device succeeded");
+    let out = sandbox.run(&["start", "web", "--headless"]);
+    assert!(!out.status.success(), "a VM with a missing PCI device started");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Cannot pass PCI devices through"), "{}", String::from_utf8_lossy(&out.stderr));
+
+    sandbox.ok(&["vfio", "remove", "web", "0000:ff:1f.7"]);
+    assert!(!sandbox.config().contains("pci_devices"), "{}", sandbox.config());
+    sandbox.ok(&["start", "web", "--headless"]);
+    sandbox.ok(&["stop", "web"]);
+}
+
+#[test]
+fn shared_folders_are_served_from_the_next_start() {
+    let sandbox = Sandbox::new("share");
+    sandbox.ok(&["create", "web"]);
+    let folder = sandbox.home.join("project");
+    fs::create_dir_all(&folder).unwrap();
+    assert!(!sandbox.run(&["share", "add", "web", "/no/such/folder"]).status.success(), "a missing folder was shared");
+    let added = sandbox.ok(&["share", "add", "web", folder.to_str().unwrap()]);
+    assert!(added.contains("mount -t virtiofs project /mnt/project"), "{}", added);
+    let added = sandbox.ok(&["share", "add", "web", folder.to_str().unwrap(), "--tag", "ro", "--protocol", "9p", "--readonly"]);
+    assert!(added.contains("mount -t 9p -o trans=virtio"), "{}", added);
+    assert!(!sandbox.run(&["share", "add", "web", folder.to_str().unwrap(), "--tag", "ro"]).status.success(), "a tag was used twice");
+    assert!(!sandbox.run(&["share", "add", "web", folder.to_str().unwrap(), "--protocol", "nfs"]).status.success(), "an unknown protocol was accepted");
+    let listed = sandbox.ok(&["share", "list", "web"]);
+    assert!(listed.contains("project: ") && listed.contains("ro: ") && listed.contains("read-only"), "{}", listed);
+
+    sandbox.ok(&["start", "web", "--headless"]);
+    let argv = sandbox.argv("web");
+    assert!(argv.iter().any(|a| a.starts_with("memory-backend-memfd,") && a.ends_with(",share=on")), "{:?}", argv);
+    assert!(has_pair(&argv, "-device", "vhost-user-fs-pci,chardev=srqfs0,tag=project"), "{:?}", argv);
+    assert!(argv.iter().any(|a| a.starts_with("local,path=") && a.contains("mount_tag=ro") && a.ends_with(",readonly=on")), "{:?}", argv);
+    let served = fs::read_to_string(sandbox.home.join("mock/virtiofsd.argv")).unwrap();
+    assert!(served.contains(&format!("--shared-dir={}", folder.display())) && !served.contains("--readonly"), "{}", served);
+    sandbox.ok(&["stop", "web"]);
+
+    sandbox.ok(&["share", "remove", "web", "project"]);
+    sandbox.ok(&["share", "remove", "web", "ro"]);
+    assert!(!sandbox.config().contains("shares"), "{}", sandbox.config());
+    sandbox.ok(&["start", "web", "--headless"]);
+    assert!(!sandbox.argv("web").iter().any(|a| a.starts_with("memory-backend-memfd")), "{:?}", sandbox.argv("web"));
+    sandbox.ok(&["stop", "web"]);
+}
+
+#[test]
+fn usb_devices_are_passed_through_at_start_and_live() {
+    let sandbox = Sandbox::new("usb");
+    sandbox.ok(&["create", "web"]);
+    assert!(!sandbox.run(&["usb", "attach", "web", "logitech"]).status.success(), "a malformed device id was accepted");
+    let attached = sandbox.ok(&["usb", "attach", "web", "046D:C52B"]);
+    assert!(attached.contains("from its next start"), "{}", attached);
+    assert!(sandbox.config().contains("046d:c52b"), "{}", sandbox.config());
+    assert!(!sandbox.run(&["usb", "attach", "web", "046d:c52b"]).status.success(), "a device was attached twice");
+
+    sandbox.ok(&["start", "web", "--headless"]);
+    let argv = sandbox.argv("web");
+    assert!(has_pair(&argv, "-device", "qemu-xhci,id=usb"), "{:?}", argv);
+    assert!(has_pair(&argv, "-device", "usb-host,bus=usb.0,vendorid=0x046d,productid=0xc52b,id=usbhost-046d-c52b"), "{:?}", argv);
+
+    let live = sandbox.ok(&["usb", "attach", "web", "0781:5581"]);
+    assert!(live.contains("now and on every start"), "{}", live);
+    sandbox.ok(&["usb", "detach", "web", "046d:c52b"]);
+    let qmp = fs::read_to_string(sandbox.home.join("mock/web.qmp")).unwrap();
+    assert!(qmp.contains("\"device_add\"") && qmp.contains("usbhost-0781-5581"), "{}", qmp);
+    assert!(qmp.contains("\"device_del\"") && qmp.contains("usbhost-046d-c52b"), "{}", qmp);
+    assert!(!sandbox.config().contains("046d:c52b"), "{}", sandbox.config());
+    assert!(!sandbox.run(&["usb", "detach", "web", "046d:c52b"]).status.success(), "detaching an unknown device succeeded");
+    sandbox.ok(&["usb", "list"]);
+}
+
+#[test]
+fn restore_to_picks_the_latest_point_before_a_time() {
+    let sandbox = Sandbox::new("restore-points");
+    sandbox.ok(&["create", "web"]);
+    let none = sandbox.ok(&["restore-point", "web"]);
+    assert!(none.contains("no restore points"), "{}", none);
+
+    sandbox.ok(&["snapshot", "create", "web", "before-upgrade", "--description", "clean install"]);
+    let points = sandbox.ok(&["restore-point", "web"]);
+    assert!(points.contains("snapshot before-upgrade (disk only): clean install"), "{}", points);
+    let stamp = points.split("  ").next().unwrap().to_string();
+
+    let restored = sandbox.ok(&["restore-to", "web", &stamp]);
+    assert!(restored.contains("back at snapshot 'before-upgrade'"), "{}", restored);
+    let out = sandbox.run(&["restore-to", "web", "2000-01-01"]);
+    assert!(!out.status.success(), "restoring to before any snapshot succeeded");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("no restore point"));
+}
+
+#[test]
+fn serve_disk_exports_stopped_vms_only() {
+    let sandbox = Sandbox::new("serve-disk");
+    sandbox.ok(&["create", "web"]);
+    let out = sandbox.ok(&["serve-disk", "web", "--readonly", "--port", "10900"]);
+    assert!(out.contains("nbd://127.0.0.1:10900/web (read-only)"), "{}", out);
+    let argv: Vec<String> = fs::read_to_string(sandbox.home.join("mock/qemu-nbd.argv")).unwrap().lines().map(str::to_string).collect();
+    assert!(has_pair(&argv, "--export-name", "web"));
+    assert!(argv.iter().any(|a| a == "--read-only"));
+    assert!(argv.iter().any(|a| a == "--format=qcow2"));
+    assert_eq!(argv.last().unwrap(), &sandbox.vm_dir("web").join("web.qcow2").display().to_string());
+
+    sandbox.ok(&["start", "web", "--headless"]);
+    let out = sandbox.run(&["serve-disk", "web"]);
+    assert!(!out.status.success(), "serving a running VM's disk succeeded");
+    sandbox.ok(&["stop", "web"]);
+}
+
+#[test]
+fn suspend_saves_state_and_restore_resumes_it() {
+    let sandbox = Sandbox::new("suspend");
+    sandbox.ok(&["create", "web"]);
+    assert!(!sandbox.run(&["suspend", "web"]).status.success(), "suspending a stopped VM succeeded");
+    sandbox.ok(&["start", "web", "--headless"]);
+
+    let out = sandbox.ok(&["suspend", "web"]);
+    assert!(out.contains("suspended"), "{}", out);
+    let status = sandbox.ok(&["status", "web"]);
+    assert!(status.contains("stopped"), "{}", status);
+    let qmp = fs::read_to_string(sandbox.home.join("mock/web.qmp")).unwrap();
+    assert!(qmp.contains("savevm srqemu-suspend"), "{}", qmp);
+
+    let out = sandbox.ok(&["restore", "web", "--headless"]);
+    assert!(out.contains("Resuming suspended VM 'web'"), "{}", out);
+    assert!(has_pair(&sandbox.argv("web"), "-loadvm", "srqemu-suspend"));
+    sandbox.ok(&["stop", "web"]);
+
+    // The state is used up: the next start boots afresh.
+    sandbox.ok(&["start", "web", "--headless"]);
+    assert!(!sandbox.argv("web").iter().any(|a| a == "-loadvm"));
+    assert!(!sandbox.run(&["restore", "web"]).status.success(), "restoring a VM that is not suspended succeeded");
+    sandbox.ok(&["stop", "web"]);
+}
+
+#[test]
+fn console_relays_the_serial_port() {
+    let sandbox = Sandbox::new("console");
+    sandbox.ok(&["create", "web"]);
+    assert!(!sandbox.run(&["console", "web"]).status.success(), "attaching to a stopped VM succeeded");
+    sandbox.ok(&["start", "web", "--headless"]);
+    let argv = sandbox.argv("web");
+    assert!(has_pair(&argv, "-serial", "chardev:srqserial0"));
+
+    let mut console = sandbox.command(&["console", "web"]).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    {
+        use std::io::Write;
+        console.stdin.take().unwrap().write_all(b"root\n").unwrap();
+    }
+    let out = console.wait_with_output().unwrap();
+    let text = String::from_utf8_lossy(&out.stdout);
+    assert!(out.status.success());
+    assert!(text.contains("web login: root"), "{}", text);
+    sandbox.ok(&["stop", "web"]);
+    assert!(!sandbox.vm_dir("web").join("serial.sock").exists());
+}
+
+#[test]
+fn console_tmux_opens_a_window_per_running_vm() {
+    let sandbox = Sandbox::new("console-tmux");
+    if Command::new("tmux").arg("-V").output().is_err() {
+        return;
+    }
+    sandbox.ok(&["create", "web"]);
+    sandbox.ok(&["create", "db"]);
+    sandbox.ok(&["start", "web", "--headless"]);
+    sandbox.ok(&["start", "db", "--headless"]);
+    let tmux = |args: &[&str]| Command::new("tmux").args(args).env("TMUX_TMPDIR", &sandbox.home).env_remove("TMUX").output().unwrap();
+
+    let out = sandbox.command(&["console", "--tmux"]).env("TMUX_TMPDIR", &sandbox.home).env_remove("TMUX").output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(String::from_utf8_lossy(&out.stdout).contains("tmux attach -t srqemu"));
+    let windows = String::from_utf8_lossy(&tmux(&["list-windows", "-t", "srqemu", "-F", "#W"]).stdout).into_owned();
+    tmux(&["kill-server"]);
+    assert_eq!(windows.lines().collect::<Vec<_>>(), ["db", "web"]);
+    sandbox.ok(&["stop", "web"]);
+    sandbox.ok(&["stop", "db"]);
+}
+
+#[test]
+fn messages_follow_the_chosen_language() {
+    let sandbox = Sandbox::new("i18n");
+    sandbox.ok(&["create", "web"]);
+    let german = |args: &[&str]| {
+        let out = sandbox.command(args).env("SRQEMU_LANG", "de").output().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        String::from_utf8_lossy(&out.stdout).to_string()
+    };
+    assert!(german(&["list"]).contains("Definierte VMs:\n- web") && german(&["list"]).contains("Zustand: aus"), "{}", german(&["list"]));
+    let started = german(&["start", "web", "--headless"]);
+    assert!(started.contains("Starte VM 'web' im Headless-Modus"), "{}", started);
+    assert!(german(&["status"]).starts_with("NAME                 ZUSTAND"), "{}", german(&["status"]));
+    sandbox.ok(&["stop", "web"]);
+
+    // The setting applies unless SRQEMU_LANG says otherwise.
+    let config = sandbox.config().replacen("[settings]\n", "[settings]\nlanguage = 'de'\n", 1);
+    fs::write(sandbox.home.join(".config/qemuctl/default-config.toml"), config).unwrap();
+    let out = sandbox.command(&["list"]).env_remove("SRQEMU_LANG").env("LANG", "C").output().unwrap();
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Definierte VMs:"), "{}", String::from_utf8_lossy(&out.stdout));
+    assert!(sandbox.ok(&["list"]).contains("Defined VMs:"));
+}
+
+#[test]
+fn other_architectures_get_their_own_binary_and_board() {
+    let sandbox = Sandbox::new("arch");
+    assert!(!sandbox.run(&["create", "sparc", "--arch", "sparc64"]).status.success(), "an unknown architecture was accepted");
+    sandbox.ok(&["create", "rv", "--arch", "riscv64", "--forward", "2222->22"]);
+    assert!(sandbox.config().contains("riscv64"), "{}", sandbox.config());
+    let listed = sandbox.ok(&["list"]);
+    assert!(listed.contains("rv: riscv64"), "{}", listed);
+
+    sandbox.ok(&["start", "rv", "--headless"]);
+    let argv = sandbox.argv("rv");
+    assert_eq!(argv[0], "qemu-system-riscv64");
+    assert!(has_pair(&argv, "-machine", "virt"), "{:?}", argv);
+    assert!(has_pair(&argv, "-device", "virtio-blk-pci,drive=disk0,iothread=iothread0"), "{:?}", argv);
+    assert!(argv.iter().any(|a| a.starts_with("virtio-net-pci,")), "{:?}", argv);
+    assert!(argv.iter().any(|a| a.starts_with("if=none,media=cdrom,id=cd0")), "{:?}", argv);
+    // This suite runs on x86_64 or aarch64 hosts, neither of which has KVM for RISC-V.
+    assert!(has_pair(&argv, "-accel", "tcg,thread=multi") && has_pair(&argv, "-cpu", "max"), "{:?}", argv);
+    assert!(!argv.contains(&"-enable-kvm".to_string()), "{:?}", argv);
+    sandbox.ok(&["stop", "rv"]);
+
+    // 64-bit ARM only boots UEFI, which this host may lack.
+    sandbox.ok(&["create", "arm", "--arch", "arm64"]);
+    let out = sandbox.run(&["start", "arm", "--headless"]);
+    if sandbox.vm_dir("arm").join("OVMF_VARS.fd").exists() {
+        assert_eq!(sandbox.argv("arm")[0], "qemu-system-aarch64");
+        sandbox.ok(&["stop", "arm"]);
+    } else {
+        assert!(String::from_utf8_lossy(&out.stderr).contains("UEFI firmware for aarch64 is not installed"), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+}
+
+#[test]
+fn plain_output_has_no_tables_or_escapes() {
+    let sandbox = Sandbox::new("plain");
+    sandbox.ok(&["create", "web"]);
+    sandbox.ok(&["label", "web", "--color", "red"]);
+    sandbox.ok(&["start", "web", "--headless"]);
+    let status = sandbox.ok(&["status", "--plain"]);
+    assert!(status.starts_with("web: state running, PID "), "{}", status);
+    assert!(!status.contains("NAME ") && !status.contains('\x1b'), "{}", status);
+    sandbox.ok(&["stop", "web"]);
+    assert!(sandbox.ok(&["status", "--plain"]).starts_with("web: state stopped\n"));
+    let plan = sandbox.ok(&["plan", "--plain"]);
+    assert!(plan.lines().any(|l| l.starts_with("memory: configured ")), "{}", plan);
+    assert!(!plan.contains("CONFIGURED"), "{}", plan);
+
+    // A dumb terminal gets the same without asking.
+    let out = sandbox.command(&["status"]).env("TERM", "dumb").output().unwrap();
+    assert!(String::from_utf8_lossy(&out.stdout).starts_with("web: state stopped"), "{}", String::from_utf8_lossy(&out.stdout));
+}
+
+#[test]
+fn missing_kvm_falls_back_to_tcg_unless_the_vm_insists() {
+    let sandbox = Sandbox::new("accel");
+    sandbox.ok(&["create", "web"]);
+    let out = sandbox.command(&["start", "web", "--headless"]).env("SRQEMU_MOCK_NO_KVM", "1").output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("KVM is unavailable (/dev/kvm does not exist"), "{}", String::from_utf8_lossy(&out.stderr));
+    let argv = sandbox.argv("web");
+    assert!(has_pair(&argv, "-accel", "tcg,thread=multi") && !argv.contains(&"-enable-kvm".to_string()), "{:?}", argv);
+    sandbox.ok(&["stop", "web"]);
+
+    sandbox.ok(&["accel", "web", "kvm"]);
+    assert!(sandbox.config().contains("accel = 'kvm'"), "{}", sandbox.config());
+    let out = sandbox.command(&["start", "web", "--headless"]).env("SRQEMU_MOCK_NO_KVM", "1").output().unwrap();
+    assert!(String::from_utf8_lossy(&out.stderr).contains("it is set to KVM, but /dev/kvm does not exist"), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(!sandbox.ok(&["status", "web"]).contains("running"));
+
+    sandbox.ok(&["accel", "web", "tcg"]);
+    assert!(sandbox.ok(&["accel", "web"]).contains("'web' uses tcg."));
+    sandbox.ok(&["start", "web", "--headless"]);
+    assert!(has_pair(&sandbox.argv("web"), "-accel", "tcg,thread=multi"));
+    sandbox.ok(&["stop", "web"]);
+    sandbox.ok(&["accel", "web", "auto"]);
+    assert!(!sandbox.config().contains("accel"), "{}", sandbox.config());
+    assert!(!sandbox.run(&["accel", "web", "hvf"]).status.success());
+}
+
+#[test]
+fn setup_reports_the_host_and_saves_the_defaults() {
+    let sandbox = Sandbox::new("setup");
+    let answer = |answers: &str| {
+        let mut setup = sandbox.command(&["setup"]).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+        {
+            use std::io::Write;
+            setup.stdin.take().unwrap().write_all(answers.as_bytes()).unwrap();
+        }
+        setup.wait_with_output().unwrap()
+    };
+    let vm_dir = sandbox.home.join("big/vms");
+    let out = answer(&format!("{}\n2G\n2\n20G\n", vm_dir.display()));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(out.status.success(), "{}{}", stdout, String::from_utf8_lossy(&out.stderr));
+    assert!(stdout.contains("KVM: ") && stdout.contains("UEFI firmware: ") && stdout.contains("Bridge helper: "), "{}", stdout);
+    let config = sandbox.config();
+    assert!(config.contains("default_memory = '2G'") && config.contains("default_disk_size = '20G'"), "{}", config);
+
+    sandbox.ok(&["create", "web"]);
+    assert!(vm_dir.join("web").is_dir());
+    let vm = sandbox.ok(&["info", "web"]);
+    assert!(vm.contains("2 threads, 2G RAM"), "{}", vm);
+
+    // With VMs in it, the directory only moves through relocate.
+    let out = answer("/tmp/elsewhere\n\n\n\n");
+    assert!(!out.status.success() && String::from_utf8_lossy(&out.stderr).contains("SRQemu relocate"), "{}", String::from_utf8_lossy(&out.stderr));
+}
+
+#[test]
+fn guest_cron_refuses_names_systemd_would_misread() {
+    let sandbox = Sandbox::new("guest-cron");
+    sandbox.ok(&["create", "my vm%i", "--guest-agent"]);
+    let out = sandbox.run(&["guest", "cron", "my vm%i", "daily", "echo hi"]);
+    assert!(!out.status.success(), "a timer was installed for an unsafe VM name");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cannot name a systemd timer"), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(!sandbox.home.join(".config/systemd/user").exists());
+    assert!(!sandbox.config().contains("guest_cron"));
+}
+
+#[test]
+fn adopted_qemu_is_driven_over_its_own_qmp_socket() {
+    use std::os::unix::process::CommandExt;
+    let sandbox = Sandbox::new("adopt-qmp");
+    sandbox.ok(&["create", "web"]);
+    let socket = sandbox.home.join("hand.qmp");
+    let qemu = sandbox
+        .command(&["-name", "hand", "-m", "1G", "-qmp", &format!("unix:{},server=on,wait=off", socket.display())])
+        .arg0("qemu-system-x86_64")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let qemu = KillOnDrop(qemu);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !socket.exists() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let mut menu = sandbox.command(&["interactive"]).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    {
+        use std::io::Write;
+        menu.stdin.take().unwrap().write_all(format!("10\n{}\n\ny\n15\n", qemu.0.id()).as_bytes()).unwrap();
+    }
+    let out = menu.wait_with_output().unwrap();
+    assert!(String::from_utf8_lossy(&out.stdout).contains("over its own QMP socket"), "{}", String::from_utf8_lossy(&out.stdout));
+
+    sandbox.ok(&["pause", "hand"]);
+    let requests = fs::read_to_string(sandbox.home.join("mock/hand.qmp")).unwrap();
+    assert!(requests.contains("\"stop\""), "{}", requests);
+}
+
+#[test]
+fn memory_watcher_restores_what_an_earlier_watcher_relieved() {
+    let sandbox = Sandbox::new("pressure");
+    sandbox.ok(&["create", "web"]);
+    let config = sandbox.config().replace("[vms.web]\n", "[vms.web]\nmemory_pressure = \"pause\"\n");
+    fs::write(sandbox.home.join(".config/qemuctl/default-config.toml"), config).unwrap();
+    sandbox.ok(&["start", "web", "--headless"]);
+    // As if a watcher paused the VM and was killed before resuming it.
+    let relieved = sandbox.vm_dir("web").join("pressure-relieved");
+    fs::write(&relieved, "pause").unwrap();
+
+    let _watcher = KillOnDrop(sandbox.command(&["memory-watch"]).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap());
+    let qmp = sandbox.home.join("mock/web.qmp");
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while relieved.exists() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert!(!relieved.exists(), "the watcher left the VM relieved");
+    let requests = fs::read_to_string(qmp).unwrap();
+    assert!(requests.contains(r#""execute":"cont""#), "{}", requests);
+}
+
+#[test]
+fn failures_after_the_vm_was_found_exit_nonzero() {
+    let sandbox = Sandbox::new("exitcodes");
+    sandbox.ok(&["create", "web"]);
+    assert!(!sandbox.run(&["update", "no-such-tag"]).status.success());
+    // Growing offline needs qemu-nbd, which the sandbox lacks.
+    let out = sandbox.run(&["resize", "web", "+1G", "--grow"]);
+    assert!(!out.status.success(), "{}", String::from_utf8_lossy(&out.stdout));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Failed to grow the guest filesystem"));
+}
+
+#[test]
+fn menu_operations_have_subcommands() {
+    use std::os::unix::process::CommandExt;
+    let sandbox = Sandbox::new("subcommands");
+
+    let domain = sandbox.home.join("legacy.xml");
+    fs::write(&domain, "<domain><name>legacy</name><memory unit='KiB'>2097152</memory><vcpu>2</vcpu></domain>").unwrap();
+    let out = sandbox.ok(&["import", "--libvirt", domain.to_str().unwrap()]);
+    assert!(out.contains("VM 'legacy' imported"), "{}", out);
+    assert!(sandbox.config().contains("[vms.legacy]"));
+
+    // The mock only stays up with a QMP socket to serve.
+    let socket = sandbox.home.join("hand.qmp");
+    let qmp = format!("unix:{},server=on,wait=off", socket.display());
+    let qemu = sandbox.command(&["-name", "hand", "-m", "1G", "-qmp", &qmp]).arg0("qemu-system-x86_64").stdout(Stdio::null()).spawn().unwrap();
+    let qemu = KillOnDrop(qemu);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !socket.exists() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let out = sandbox.ok(&["adopt", &qemu.0.id().to_string()]);
+    assert!(out.contains("VM 'hand' adopted"), "{}", out);
+    assert!(sandbox.ok(&["status", "hand"]).contains("running"));
+
+    // Refusals exit nonzero instead of asking again.
+    assert!(!sandbox.run(&["inspect", "hand"]).status.success());
+    assert!(!sandbox.run(&["guest", "sync-time", "legacy"]).status.success());
+    assert!(!sandbox.run(&["disk", "cp", "a", "b"]).status.success());
+    assert!(!sandbox.run(&["create", "next", "--recipe", "/nonexistent.toml"]).status.success());
+    assert!(!sandbox.config().contains("[vms.next]"));
+}